@@ -0,0 +1,64 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Translates a file through the streaming session API, the audio is pushed in 20ms chunks as a
+// capture would deliver it and the text is printed as it is generated. This takes the same flags
+// as the gen subcommand, e.g. with the tiny model written by `hibiki tiny-model /tmp/tiny`:
+//
+//   cargo run -r --example stream -- --cpu --no-calibrate --config /tmp/tiny/config.toml \
+//     --lm-model-file /tmp/tiny/tiny-lm.safetensors \
+//     --mimi-model-file /tmp/tiny/tiny-mimi.safetensors \
+//     --text-tokenizer /tmp/tiny/tiny-tokenizer.model in.wav out.wav
+
+use anyhow::Result;
+use clap::Parser;
+use std::io::Write;
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    gen: hibiki::cli::GenArgs,
+
+    #[arg()]
+    input: String,
+
+    #[arg()]
+    output: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    hibiki::cli::init_logging();
+    let (gen_args, dev) = args.gen.resolve(args.input.clone(), args.output.clone())?;
+    let mut models = hibiki::gen::Models::load(&gen_args, &dev)?;
+    let sample_rate = models.codec.sample_rate();
+    let frame_size = models.codec.frame_size();
+    let (pcm, sr) = hibiki::audio_io::pcm_decode(&args.input)?;
+    let mut pcm = hibiki::audio_io::resample(&pcm, sr as usize, sample_rate)?;
+    // The translation lags behind the source, silence is pushed at the end to get the last words.
+    pcm.resize(pcm.len() + hibiki::dubbing::MAX_TAIL_STEPS * frame_size, 0.);
+
+    let mut session = hibiki::session::GenSession::new(&gen_args, &mut models, 0, &dev)?;
+    let mut out_pcm = vec![];
+    for chunk in pcm.chunks(sample_rate / 50) {
+        session.push_pcm(chunk);
+        // A new session would have to be started once the kv-cache is full, see the pipe mode.
+        while session.pending_frames() > 0 && session.state().step_idx() < gen_args.max_steps {
+            for output in session.step()? {
+                if let Some(text) = output.text.as_deref() {
+                    print!("{text}");
+                    std::io::stdout().flush()?
+                }
+                if let Some(pcm) = output.pcm {
+                    out_pcm.extend(pcm.flatten_all()?.to_vec1::<f32>()?)
+                }
+            }
+        }
+    }
+    println!();
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&args.output)?);
+    let format = hibiki::audio_io::WavFormat::S16;
+    hibiki::audio_io::write_wav(&mut writer, &out_pcm, sample_rate as u32, format)?;
+    Ok(())
+}
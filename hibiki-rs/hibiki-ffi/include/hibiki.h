@@ -0,0 +1,85 @@
+/* Copyright (c) Kyutai, all rights reserved. */
+
+#ifndef HIBIKI_H
+#define HIBIKI_H
+
+/* Generated by cbindgen from hibiki-ffi/src/lib.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A streaming translation session, opaque on the C side.
+typedef struct HibikiSession HibikiSession;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates a session from the same flags as `hibiki gen`, without the input and output files,
+// e.g. `{"--hf-repo", "kyutai/hibiki-1b-rs-bf16", "--cpu"}`. The models are downloaded from the
+// hub if needed. Returns NULL on failures.
+//
+// # Safety
+// `argv` must point to `argc` valid NUL-terminated strings.
+HibikiSession *hibiki_session_create(int argc, const char *const *argv);
+
+// Destroys a session and frees its models, NULL is ignored.
+//
+// # Safety
+// `session` must have been returned by `hibiki_session_create` and not destroyed yet.
+void hibiki_session_destroy(HibikiSession *session);
+
+// The sample rate of the pcm pushed to and polled from the session, 24kHz for mimi. Returns 0
+// for a NULL session.
+//
+// # Safety
+// `session` must be NULL or a valid session.
+uint32_t hibiki_session_sample_rate(const HibikiSession *session);
+
+// Pushes mono float pcm at the session sample rate, and runs the generation steps for the
+// complete frames. Returns 0 on success and -1 on failures.
+//
+// # Safety
+// `session` must be NULL or a valid session and `pcm` must point to `len` floats.
+int hibiki_session_push_pcm(HibikiSession *session, const float *pcm, uintptr_t len);
+
+// Signals the end of the source audio: silence is fed until the model has finished translating,
+// the remaining text and audio are then polled as usual. The session can be reused for another
+// source, which starts in a new context. Returns 0 on success and -1 on failures.
+//
+// # Safety
+// `session` must be NULL or a valid session.
+int hibiki_session_end(HibikiSession *session);
+
+// Returns the text generated since the last call, to be freed with `hibiki_string_free`, or
+// NULL if there is no new text or for a NULL session.
+//
+// # Safety
+// `session` must be NULL or a valid session.
+char *hibiki_session_poll_text(HibikiSession *session);
+
+// Copies up to `max_len` samples of the translated audio to `out`, and returns the number of
+// samples copied. The remaining audio is returned by the next calls, nothing is copied for a
+// NULL session or `out`.
+//
+// # Safety
+// `session` must be NULL or a valid session and `out` must have room for `max_len` floats.
+uintptr_t hibiki_session_poll_audio(HibikiSession *session, float *out, uintptr_t max_len);
+
+// Frees a string returned by `hibiki_session_poll_text`, NULL is ignored.
+//
+// # Safety
+// `text` must have been returned by this library and not freed yet.
+void hibiki_string_free(char *text);
+
+// The message of the last failure on the calling thread, or NULL. The string is owned by the
+// library and stays valid until the next failure on the same thread.
+const char *hibiki_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* HIBIKI_H */
@@ -0,0 +1,408 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// C bindings for embedding hibiki in other languages, see include/hibiki.h. A session keeps the
+// models loaded and translates the pcm pushed to it as a stream, the text and the translated
+// audio are then polled, and `hibiki_session_end` flushes the translation once the source audio
+// has ended. The functions return a null pointer or -1 on failures, the error message is
+// available with `hibiki_last_error`, and a NULL session is handled as a failure. A session must
+// only be used by one thread at a time.
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|v| *v.borrow_mut() = Some(msg))
+}
+
+// Runs the body of an exported function, the errors and panics are stored for
+// `hibiki_last_error` and `default` is returned instead.
+fn guard<T>(default: T, f: impl FnOnce() -> Result<T>) -> T {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => v,
+        Ok(Err(err)) => {
+            set_last_error(format!("{err:#}"));
+            default
+        }
+        Err(_) => {
+            set_last_error("hibiki panicked".to_string());
+            default
+        }
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "hibiki")]
+struct SessionArgs {
+    #[command(flatten)]
+    gen: hibiki::cli::GenArgs,
+}
+
+/// A streaming translation session, opaque on the C side.
+pub struct HibikiSession {
+    // Borrows the models, it is always dropped before them.
+    session: Option<hibiki::session::GenSession<'static>>,
+    models: *mut hibiki::gen::Models,
+    args: hibiki::gen::Args,
+    dev: candle::Device,
+    sample_rate: usize,
+    frame_size: usize,
+    segment: usize,
+    text: String,
+    audio: std::collections::VecDeque<f32>,
+}
+
+impl HibikiSession {
+    fn new(args: hibiki::gen::Args, dev: candle::Device) -> Result<Self> {
+        let models = hibiki::gen::Models::load(&args, &dev)?;
+        let sample_rate = models.codec.sample_rate();
+        let frame_size = models.codec.frame_size();
+        let mut session = Self {
+            session: None,
+            models: Box::into_raw(Box::new(models)),
+            args,
+            dev,
+            sample_rate,
+            frame_size,
+            segment: 0,
+            text: String::new(),
+            audio: Default::default(),
+        };
+        session.start_segment()?;
+        Ok(session)
+    }
+
+    // Starts a new context, as done by the pipe mode once `max_steps` steps have been generated.
+    fn start_segment(&mut self) -> Result<()> {
+        self.session = None;
+        // Safety: the previous session that borrowed the models has just been dropped.
+        let models = unsafe { &mut *self.models };
+        let session =
+            hibiki::session::GenSession::new(&self.args, models, self.segment, &self.dev)?;
+        self.session = Some(session);
+        self.segment += 1;
+        Ok(())
+    }
+
+    fn push_pcm(&mut self, pcm: &[f32]) -> Result<()> {
+        let mut pcm = pcm.to_vec();
+        loop {
+            let session = self.session.as_mut().context("no active session")?;
+            session.push_pcm(&pcm);
+            let max_steps = self.args.max_steps;
+            while session.pending_frames() > 0 && session.state().step_idx() < max_steps {
+                for output in session.step()? {
+                    Self::collect(&mut self.text, &mut self.audio, &output)?;
+                }
+            }
+            if session.state().step_idx() < max_steps {
+                return Ok(());
+            }
+            // The audio that has not been processed yet goes to the new context.
+            pcm = session.take_pending();
+            self.text.push('\n');
+            self.start_segment()?
+        }
+    }
+
+    // Feeds silence until the model has finished translating, as done by the pipe mode at the
+    // end of its input, and then starts a new context for the next source.
+    fn end(&mut self) -> Result<()> {
+        let silence = vec![0f32; self.frame_size];
+        let max_steps = self.args.max_steps;
+        let session = self.session.as_mut().context("no active session")?;
+        let mut tail_steps = 0;
+        let mut tail_pad_steps = 0;
+        while tail_pad_steps < hibiki::dubbing::END_PAD_STEPS
+            && tail_steps < hibiki::dubbing::MAX_TAIL_STEPS
+            && session.state().step_idx() < max_steps
+        {
+            // The last partial frame of the source is completed by the first silence.
+            session.push_pcm(&silence);
+            tail_steps += 1;
+            while session.pending_frames() > 0 && session.state().step_idx() < max_steps {
+                for output in session.step()? {
+                    tail_pad_steps = if output.is_pad() { tail_pad_steps + 1 } else { 0 };
+                    Self::collect(&mut self.text, &mut self.audio, &output)?;
+                }
+            }
+        }
+        self.text.push('\n');
+        self.start_segment()
+    }
+
+    fn collect(
+        text: &mut String,
+        audio: &mut std::collections::VecDeque<f32>,
+        output: &hibiki::session::StepOutput,
+    ) -> Result<()> {
+        if let Some(t) = output.text.as_deref() {
+            text.push_str(t)
+        }
+        if let Some(pcm) = output.pcm.as_ref() {
+            audio.extend(pcm.flatten_all()?.to_vec1::<f32>()?)
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HibikiSession {
+    fn drop(&mut self) {
+        self.session = None;
+        // Safety: the models were allocated in `new` and nothing borrows them anymore.
+        drop(unsafe { Box::from_raw(self.models) })
+    }
+}
+
+/// Creates a session from the same flags as `hibiki gen`, without the input and output files,
+/// e.g. `{"--hf-repo", "kyutai/hibiki-1b-rs-bf16", "--cpu"}`. The models are downloaded from the
+/// hub if needed. Returns NULL on failures.
+///
+/// # Safety
+/// `argv` must point to `argc` valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_session_create(
+    argc: c_int,
+    argv: *const *const c_char,
+) -> *mut HibikiSession {
+    guard(std::ptr::null_mut(), || {
+        let mut flags = vec!["hibiki".to_string()];
+        for idx in 0..argc.max(0) as usize {
+            let arg = unsafe { *argv.add(idx) };
+            if arg.is_null() {
+                anyhow::bail!("argument {idx} is NULL")
+            }
+            flags.push(unsafe { CStr::from_ptr(arg) }.to_str()?.to_string())
+        }
+        let args = SessionArgs::try_parse_from(flags)?;
+        if !args.gen.quiet {
+            // The logs go to stderr, the subscriber is only installed by the first session.
+            hibiki::cli::init_logging()
+        }
+        let (args, dev) = args.gen.resolve("-".to_string(), "-".to_string())?;
+        Ok(Box::into_raw(Box::new(HibikiSession::new(args, dev)?)))
+    })
+}
+
+/// Destroys a session and frees its models, NULL is ignored.
+///
+/// # Safety
+/// `session` must have been returned by `hibiki_session_create` and not destroyed yet.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_session_destroy(session: *mut HibikiSession) {
+    if !session.is_null() {
+        drop(unsafe { Box::from_raw(session) })
+    }
+}
+
+/// The sample rate of the pcm pushed to and polled from the session, 24kHz for mimi. Returns 0
+/// for a NULL session.
+///
+/// # Safety
+/// `session` must be NULL or a valid session.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_session_sample_rate(session: *const HibikiSession) -> u32 {
+    match unsafe { session.as_ref() } {
+        None => 0,
+        Some(session) => session.sample_rate as u32,
+    }
+}
+
+/// Pushes mono float pcm at the session sample rate, and runs the generation steps for the
+/// complete frames. Returns 0 on success and -1 on failures.
+///
+/// # Safety
+/// `session` must be NULL or a valid session and `pcm` must point to `len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_session_push_pcm(
+    session: *mut HibikiSession,
+    pcm: *const f32,
+    len: usize,
+) -> c_int {
+    guard(-1, || {
+        let session = unsafe { session.as_mut() }.context("the session is NULL")?;
+        let pcm = match len {
+            0 => &[][..],
+            _ if pcm.is_null() => anyhow::bail!("the pcm is NULL"),
+            _ => unsafe { std::slice::from_raw_parts(pcm, len) },
+        };
+        session.push_pcm(pcm)?;
+        Ok(0)
+    })
+}
+
+/// Signals the end of the source audio: silence is fed until the model has finished translating,
+/// the remaining text and audio are then polled as usual. The session can be reused for another
+/// source, which starts in a new context. Returns 0 on success and -1 on failures.
+///
+/// # Safety
+/// `session` must be NULL or a valid session.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_session_end(session: *mut HibikiSession) -> c_int {
+    guard(-1, || {
+        let session = unsafe { session.as_mut() }.context("the session is NULL")?;
+        session.end()?;
+        Ok(0)
+    })
+}
+
+/// Returns the text generated since the last call, to be freed with `hibiki_string_free`, or
+/// NULL if there is no new text or for a NULL session.
+///
+/// # Safety
+/// `session` must be NULL or a valid session.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_session_poll_text(session: *mut HibikiSession) -> *mut c_char {
+    let Some(session) = (unsafe { session.as_mut() }) else {
+        return std::ptr::null_mut();
+    };
+    if session.text.is_empty() {
+        return std::ptr::null_mut();
+    }
+    let text = std::mem::take(&mut session.text).replace('\0', " ");
+    CString::new(text).map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+/// Copies up to `max_len` samples of the translated audio to `out`, and returns the number of
+/// samples copied. The remaining audio is returned by the next calls, nothing is copied for a
+/// NULL session or `out`.
+///
+/// # Safety
+/// `session` must be NULL or a valid session and `out` must have room for `max_len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_session_poll_audio(
+    session: *mut HibikiSession,
+    out: *mut f32,
+    max_len: usize,
+) -> usize {
+    let Some(session) = (unsafe { session.as_mut() }) else {
+        return 0;
+    };
+    if out.is_null() {
+        return 0;
+    }
+    let len = max_len.min(session.audio.len());
+    for (idx, sample) in session.audio.drain(..len).enumerate() {
+        unsafe { *out.add(idx) = sample }
+    }
+    len
+}
+
+/// Frees a string returned by `hibiki_session_poll_text`, NULL is ignored.
+///
+/// # Safety
+/// `text` must have been returned by this library and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn hibiki_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(unsafe { CString::from_raw(text) })
+    }
+}
+
+/// The message of the last failure on the calling thread, or NULL. The string is owned by the
+/// library and stays valid until the next failure on the same thread.
+#[no_mangle]
+pub extern "C" fn hibiki_last_error() -> *const c_char {
+    LAST_ERROR.with(|v| v.borrow().as_ref().map_or(std::ptr::null(), |v| v.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates a session on a random tiny model through the C ABI.
+    fn tiny_session(dir: &std::path::Path) -> *mut HibikiSession {
+        hibiki::tiny::write(dir, 299_792_458).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let flags = [
+            "--cpu".to_string(),
+            "--no-calibrate".to_string(),
+            "--quiet".to_string(),
+            "--config".to_string(),
+            path("config.toml"),
+            "--lm-model-file".to_string(),
+            path("tiny-lm.safetensors"),
+            "--mimi-model-file".to_string(),
+            path("tiny-mimi.safetensors"),
+            "--text-tokenizer".to_string(),
+            path("tiny-tokenizer.model"),
+        ];
+        let flags: Vec<CString> = flags.into_iter().map(|v| CString::new(v).unwrap()).collect();
+        let argv: Vec<*const c_char> = flags.iter().map(|v| v.as_ptr()).collect();
+        unsafe { hibiki_session_create(argv.len() as c_int, argv.as_ptr()) }
+    }
+
+    #[test]
+    fn translate_and_end() {
+        let dir = std::env::temp_dir().join(format!("hibiki-ffi-{}", std::process::id()));
+        let session = tiny_session(&dir);
+        assert!(!session.is_null(), "{:?}", unsafe { CStr::from_ptr(hibiki_last_error()) });
+        let sample_rate = unsafe { hibiki_session_sample_rate(session) };
+        assert_eq!(sample_rate, 24000);
+        // Deterministic noise, with a partial last frame.
+        let mut seed = 42u32;
+        let pcm: Vec<f32> = (0..sample_rate as usize + 100)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 * 0.2 - 0.1
+            })
+            .collect();
+        assert_eq!(unsafe { hibiki_session_push_pcm(session, pcm.as_ptr(), pcm.len()) }, 0);
+        let mut out = vec![0f32; 4096];
+        let mut polled = 0;
+        loop {
+            let len = unsafe { hibiki_session_poll_audio(session, out.as_mut_ptr(), out.len()) };
+            if len == 0 {
+                break;
+            }
+            polled += len
+        }
+        assert_eq!(unsafe { hibiki_session_end(session) }, 0);
+        let mut flushed = 0;
+        loop {
+            let len = unsafe { hibiki_session_poll_audio(session, out.as_mut_ptr(), out.len()) };
+            if len == 0 {
+                break;
+            }
+            flushed += len
+        }
+        // The tail steps generate audio past the source, one frame per step.
+        assert!(flushed > 0);
+        assert_eq!((polled + flushed) % 1920, 0);
+        // The end of the source is marked in the text as a new context.
+        let text = unsafe { hibiki_session_poll_text(session) };
+        assert!(!text.is_null());
+        assert!(unsafe { CStr::from_ptr(text) }.to_str().unwrap().ends_with('\n'));
+        unsafe { hibiki_string_free(text) };
+        assert!(unsafe { hibiki_session_poll_text(session) }.is_null());
+        // The session can be reused after the end.
+        assert_eq!(unsafe { hibiki_session_push_pcm(session, pcm.as_ptr(), 1920) }, 0);
+        unsafe { hibiki_session_destroy(session) };
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn null_session() {
+        let null = std::ptr::null_mut();
+        let mut out = [0f32; 4];
+        unsafe {
+            assert_eq!(hibiki_session_sample_rate(null), 0);
+            assert_eq!(hibiki_session_push_pcm(null, out.as_ptr(), out.len()), -1);
+            let err = CStr::from_ptr(hibiki_last_error()).to_str().unwrap();
+            assert_eq!(err, "the session is NULL");
+            assert_eq!(hibiki_session_end(null), -1);
+            assert!(hibiki_session_poll_text(null).is_null());
+            assert_eq!(hibiki_session_poll_audio(null, out.as_mut_ptr(), out.len()), 0);
+            hibiki_session_destroy(null);
+            hibiki_string_free(std::ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Word level alignment of the generated text with the generated audio, so that downstream tools
+// can edit, bleep or emphasize individual words. The text tokens are aligned with the audio of
+// the same step, a word spans from its first token to the start of the next word.
+
+use anyhow::Result;
+
+// A word is considered over this many steps after its last token when followed by a pause,
+// i.e. 480ms, rather than extending over the whole pause.
+const MAX_WORD_TAIL_STEPS: usize = 6;
+
+#[derive(Debug, Clone)]
+pub struct Word {
+    pub text: String,
+    pub start_step: usize,
+    pub end_step: usize,
+}
+
+/// Splits the per-step text tokens, including the padding ones, into words.
+pub fn words(
+    text_tokens: &[u32],
+    text_tokenizer: &sentencepiece::SentencePieceProcessor,
+    text_start_token: u32,
+) -> Vec<Word> {
+    let mut words: Vec<Word> = vec![];
+    let mut prev_token = text_start_token;
+    let mut last_token_step = 0;
+    for (step, &token) in text_tokens.iter().enumerate() {
+        if token == 0 || token == 3 {
+            continue;
+        }
+        let text = crate::gen::text(text_tokenizer, prev_token, token, text_start_token)
+            .unwrap_or_default();
+        prev_token = token;
+        let starts_word = text.starts_with(char::is_whitespace) || words.is_empty();
+        match words.last_mut() {
+            Some(word) if !starts_word => word.text.push_str(&text),
+            _ => {
+                if let Some(word) = words.last_mut() {
+                    word.end_step = step.min(last_token_step + MAX_WORD_TAIL_STEPS)
+                }
+                words.push(Word {
+                    text: text.trim_start().to_string(),
+                    start_step: step,
+                    end_step: 0,
+                })
+            }
+        }
+        last_token_step = step;
+    }
+    if let Some(word) = words.last_mut() {
+        word.end_step = text_tokens.len().min(last_token_step + MAX_WORD_TAIL_STEPS)
+    }
+    words.retain(|w| !w.text.trim().is_empty());
+    words
+}
+
+/// Maps the generation steps to the samples of the final output audio. `step_offsets` gives for
+/// each step the number of samples generated before the audio decoded on that step followed by
+/// the total, the audio of step `i` is decoded `acoustic_delay` steps later. `skip` is the number
+/// of generated samples removed from the start of the output, and `scale` the ratio between the
+/// lengths of the final audio and of the remaining generated audio, e.g. when fitting the
+/// duration and resampling.
+pub struct Timeline<'a> {
+    pub step_offsets: &'a [usize],
+    pub acoustic_delay: usize,
+    pub skip: usize,
+    pub scale: f64,
+    pub sample_rate: usize,
+}
+
+impl Timeline<'_> {
+    fn sample(&self, step: usize) -> usize {
+        let total = self.step_offsets.last().copied().unwrap_or(0);
+        let offset = self.step_offsets.get(step + self.acoustic_delay).copied().unwrap_or(total);
+        (offset.saturating_sub(self.skip) as f64 * self.scale).round() as usize
+    }
+
+    /// The samples of the final output holding the audio of a step.
+    pub fn samples(&self, step: usize) -> std::ops::Range<usize> {
+        self.sample(step)..self.sample(step + 1)
+    }
+
+    /// The position of the audio of a step in the final output, in seconds.
+    pub fn secs(&self, step: usize) -> f64 {
+        self.sample(step) as f64 / self.sample_rate as f64
+    }
+
+    /// The source audio, with `frame_size` samples per step, placed at the positions of the
+    /// audio of its steps in the final output of `len` samples.
+    pub fn align_source(&self, source: &[f32], frame_size: usize, len: usize) -> Vec<f32> {
+        let mut aligned = vec![0f32; len];
+        for step in 0..self.step_offsets.len() {
+            let start = self.sample(step).min(len);
+            let end = self.sample(step + 1).min(len);
+            for (idx, dst) in aligned[start..end].iter_mut().enumerate() {
+                // The nearest sample, the frames can be stretched or resampled in the output.
+                let pos = step * frame_size + idx * frame_size / (end - start);
+                *dst = source.get(pos).copied().unwrap_or(0.)
+            }
+        }
+        aligned
+    }
+
+    /// The duration of the final output in seconds.
+    pub fn duration(&self) -> f64 {
+        let total = self.step_offsets.last().copied().unwrap_or(0);
+        total.saturating_sub(self.skip) as f64 * self.scale / self.sample_rate as f64
+    }
+}
+
+/// Writes the alignment as json, with the sample ranges in the final output audio.
+pub fn write(path: &std::path::Path, words: &[Word], timeline: &Timeline) -> Result<()> {
+    let sample_rate = timeline.sample_rate;
+    let sample = |step| timeline.sample(step);
+    let words: Vec<_> = words
+        .iter()
+        .map(|word| {
+            let (start, end) = (sample(word.start_step), sample(word.end_step));
+            serde_json::json!({
+                "word": word.text,
+                "start_sample": start,
+                "end_sample": end,
+                "start": start as f64 / sample_rate as f64,
+                "end": end as f64 / sample_rate as f64,
+            })
+        })
+        .collect();
+    let json = serde_json::json!({ "sample_rate": sample_rate, "words": words });
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(file, &json)?;
+    Ok(())
+}
+
+/// Writes each emitted text token as json with its decoded text, the step at which it was
+/// generated, the position of the matching audio in the output and its log-probability, e.g. for
+/// confidence filtering downstream. The non-speech events are listed after the tokens.
+#[allow(clippy::too_many_arguments)]
+pub fn write_tokens(
+    path: &std::path::Path,
+    history: &crate::longform::History,
+    text_tokenizer: &sentencepiece::SentencePieceProcessor,
+    text_start_token: u32,
+    step_duration: f64,
+    timeline: &Timeline,
+    events: &[crate::events::Marker],
+    language: &crate::lang::Language,
+) -> Result<usize> {
+    let mut prev_token = text_start_token;
+    let mut tokens = vec![];
+    let mut text = String::new();
+    for (step, (&token, &logprob)) in
+        history.text_tokens.iter().zip(history.text_logprobs.iter()).enumerate()
+    {
+        if token == 0 || token == 3 {
+            continue;
+        }
+        let token_text = crate::gen::text(text_tokenizer, prev_token, token, text_start_token)
+            .unwrap_or_default();
+        prev_token = token;
+        text.push_str(&token_text);
+        let audio_sample = timeline.sample(step);
+        tokens.push(serde_json::json!({
+            "token": token,
+            "text": token_text,
+            "step": step,
+            "time": step as f64 * step_duration,
+            "audio_sample": audio_sample,
+            "audio_time": audio_sample as f64 / timeline.sample_rate as f64,
+            "logprob": logprob,
+        }))
+    }
+    let json = serde_json::json!({
+        "language": language.tag(),
+        "step_duration": step_duration,
+        "sample_rate": timeline.sample_rate,
+        "text": text,
+        "tokens": tokens,
+        "events": events
+            .iter()
+            .map(|marker| {
+                let audio_sample = timeline.sample(marker.start_step);
+                serde_json::json!({
+                    "label": marker.event.label(),
+                    "step": marker.start_step,
+                    "end_step": marker.end_step,
+                    "time": marker.start_step as f64 * step_duration,
+                    "audio_time": audio_sample as f64 / timeline.sample_rate as f64,
+                })
+            })
+            .collect::<Vec<_>>(),
+    });
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(file, &json)?;
+    Ok(tokens.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeline_follows_the_acoustic_delay() {
+        // Nothing is decoded on the first two steps, then 10 samples per step, the audio of the
+        // first step comes out on the third one.
+        let step_offsets = [0, 0, 0, 10, 20, 30];
+        let timeline = Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 2,
+            skip: 0,
+            scale: 1.,
+            sample_rate: 10,
+        };
+        assert_eq!(timeline.samples(0), 0..10);
+        assert_eq!(timeline.samples(2), 20..30);
+        // The steps past the generated audio are at the end.
+        assert_eq!(timeline.samples(5), 30..30);
+        assert_eq!(timeline.secs(3), 3.);
+        assert_eq!(timeline.duration(), 3.);
+    }
+
+    #[test]
+    fn timeline_scale() {
+        let step_offsets = [0, 10, 20];
+        let timeline = Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 0,
+            skip: 0,
+            scale: 1.5,
+            sample_rate: 10,
+        };
+        assert_eq!(timeline.samples(1), 15..30);
+        assert_eq!(timeline.duration(), 3.);
+    }
+
+    #[test]
+    fn timeline_follows_the_duration_fit() {
+        // 80ms steps, the first 5 steps are the silent latency of the model and the audio of
+        // step 15 is louder than the rest.
+        let (sample_rate, frame_size) = (24000, 1920);
+        let pcm: Vec<f32> = (0..30 * frame_size)
+            .map(|idx| {
+                let step = idx / frame_size;
+                let amplitude = match step {
+                    0..5 => 0.,
+                    15 => 0.8,
+                    _ => 0.05,
+                };
+                amplitude * (idx as f32 * 0.05).sin()
+            })
+            .collect();
+        let step_offsets: Vec<usize> = (0..=30).map(|step| step * frame_size).collect();
+        let target_len = 22 * frame_size;
+        let (fitted, fit) = crate::dubbing::fit_to_duration(pcm, target_len, 0.15);
+        assert_eq!(fit.skip, 5 * frame_size);
+        assert_eq!(fitted.len(), target_len);
+        let timeline = Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 0,
+            skip: fit.skip,
+            scale: fit.ratio,
+            sample_rate,
+        };
+        let loud = fitted.iter().position(|v| v.abs() > 0.5).unwrap();
+        let start = timeline.samples(15).start;
+        assert!(start.abs_diff(loud) < sample_rate / 50, "{start} {loud}");
+        assert_eq!(timeline.samples(0), 0..0);
+    }
+
+    #[test]
+    fn align_source_stretches_the_frames() {
+        let step_offsets = [0, 2, 4];
+        let timeline = Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 0,
+            skip: 0,
+            scale: 2.,
+            sample_rate: 10,
+        };
+        let source = [1., 2., 3., 4.];
+        let aligned = timeline.align_source(&source, 2, 8);
+        assert_eq!(aligned, [1., 1., 2., 2., 3., 3., 4., 4.]);
+        // The output is truncated or padded with silence to the requested length.
+        assert_eq!(timeline.align_source(&source, 2, 3), [1., 1., 2.]);
+        assert_eq!(timeline.align_source(&source[..2], 2, 10)[4..], [0.; 6]);
+    }
+}
@@ -128,6 +128,618 @@ impl AudioOutputData_ {
 
 type AudioOutputData = Arc<Mutex<AudioOutputData_>>;
 
+/// Stateful resampler for audio arriving in small chunks, e.g. from a live source. The state of
+/// the filter is kept across calls so that there are no artifacts at the chunk boundaries, and
+/// the resampler delay is removed from the start of the output.
+pub struct StreamingResampler {
+    // None when the input and output sample rates are the same.
+    resampler: Option<rubato::FftFixedIn<f32>>,
+    ratio: f64,
+    pending: Vec<f32>,
+    output_buffer: Vec<Vec<f32>>,
+    // Number of output samples still to be dropped to compensate for the resampler delay.
+    to_skip: usize,
+    total_in: usize,
+    total_out: usize,
+}
+
+impl StreamingResampler {
+    // Input chunk size passed to the resampler, about 20ms of audio at 48kHz.
+    const CHUNK_SIZE: usize = 1024;
+
+    pub fn new(sr_in: usize, sr_out: usize) -> Result<Self> {
+        use rubato::Resampler;
+
+        let (resampler, output_buffer, to_skip) = if sr_in == sr_out {
+            (None, vec![], 0)
+        } else {
+            let resampler = rubato::FftFixedIn::<f32>::new(sr_in, sr_out, Self::CHUNK_SIZE, 2, 1)?;
+            let output_buffer = resampler.output_buffer_allocate(true);
+            let to_skip = resampler.output_delay();
+            (Some(resampler), output_buffer, to_skip)
+        };
+        Ok(Self {
+            resampler,
+            ratio: sr_out as f64 / sr_in as f64,
+            pending: Vec::with_capacity(Self::CHUNK_SIZE),
+            output_buffer,
+            to_skip,
+            total_in: 0,
+            total_out: 0,
+        })
+    }
+
+    fn process(&mut self, pcm: &[f32]) -> Result<Vec<f32>> {
+        use rubato::Resampler;
+
+        let Self { resampler, pending, output_buffer, to_skip, .. } = self;
+        let Some(resampler) = resampler.as_mut() else { return Ok(pcm.to_vec()) };
+        let mut out = vec![];
+        pending.extend_from_slice(pcm);
+        let mut pos = 0;
+        while pending.len() - pos >= resampler.input_frames_next() {
+            let (in_len, out_len) =
+                resampler.process_into_buffer(&[&pending[pos..]], output_buffer, None)?;
+            pos += in_len;
+            let skip = usize::min(*to_skip, out_len);
+            *to_skip -= skip;
+            out.extend_from_slice(&output_buffer[0][skip..out_len])
+        }
+        pending.drain(..pos);
+        Ok(out)
+    }
+
+    /// Resamples a chunk of audio, the returned samples only cover the input that has been
+    /// processed so far, the remainder is kept for the next call.
+    pub fn push(&mut self, pcm: &[f32]) -> Result<Vec<f32>> {
+        self.total_in += pcm.len();
+        let out = self.process(pcm)?;
+        self.total_out += out.len();
+        Ok(out)
+    }
+
+    /// Returns the output for the remaining buffered input, this is to be called at the end of
+    /// the stream and resets the resampler.
+    pub fn flush(&mut self) -> Result<Vec<f32>> {
+        let expected = (self.total_in as f64 * self.ratio).round() as usize;
+        let mut out = vec![];
+        if self.resampler.is_some() {
+            // Feed silence until the filter delay has been fully flushed out.
+            let silence = vec![0f32; Self::CHUNK_SIZE];
+            while self.total_out + out.len() < expected {
+                out.extend(self.process(&silence)?)
+            }
+        }
+        out.truncate(expected.saturating_sub(self.total_out));
+        self.reset();
+        Ok(out)
+    }
+
+    pub fn reset(&mut self) {
+        use rubato::Resampler;
+
+        self.pending.clear();
+        self.total_in = 0;
+        self.total_out = 0;
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+            self.to_skip = resampler.output_delay();
+        }
+    }
+}
+
+/// Keeps the most recent audio received before a session starts, e.g. before a push-to-talk
+/// press or a client connection, so that the beginning of the first words is not lost.
+pub struct PreRoll {
+    data: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl PreRoll {
+    pub fn new(capacity: usize) -> Self {
+        Self { data: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// A pre-roll buffer holding `secs` seconds of audio at the given sample rate.
+    pub fn from_duration(secs: f64, sample_rate: usize) -> Self {
+        Self::new((secs * sample_rate as f64).round() as usize)
+    }
+
+    /// Appends samples, dropping the oldest ones beyond the capacity.
+    pub fn push(&mut self, samples: &[f32]) {
+        let samples = &samples[samples.len().saturating_sub(self.capacity)..];
+        let overflow = (self.data.len() + samples.len()).saturating_sub(self.capacity);
+        self.data.drain(..overflow);
+        self.data.extend(samples.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the buffered samples, oldest first, and empties the buffer.
+    pub fn take(&mut self) -> Vec<f32> {
+        self.data.drain(..).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear()
+    }
+}
+
+/// Compensates the clock drift between a capture and a playback device on long live sessions.
+/// The played audio is resampled with a ratio slightly adjusted so that the level of the
+/// playback buffer stays around its target, rather than slowly growing or draining.
+pub struct DriftCompensator {
+    resampler: rubato::FastFixedIn<f32>,
+    pending: Vec<f32>,
+    output_buffer: Vec<Vec<f32>>,
+    target_level: f64,
+    // Smoothed playback buffer level in samples, the instantaneous level varies a lot with the
+    // scheduling of the audio callbacks.
+    level: Option<f64>,
+    ratio: f64,
+}
+
+impl DriftCompensator {
+    const CHUNK_SIZE: usize = 1024;
+    // Sound card clocks are usually within 100ppm, the correction is bounded well above this.
+    const MAX_DRIFT: f64 = 2e-3;
+    // Gain from the relative level error to the ratio correction, and the smoothing of the
+    // level, these are small so that the correction is inaudible.
+    const GAIN: f64 = 1e-3;
+    const LEVEL_SMOOTHING: f64 = 0.01;
+
+    /// `target_level` is the number of samples that the playback buffer should hold.
+    pub fn new(target_level: usize) -> Result<Self> {
+        use rubato::Resampler;
+
+        let resampler = rubato::FastFixedIn::new(
+            1.,
+            1. + Self::MAX_DRIFT,
+            rubato::PolynomialDegree::Septic,
+            Self::CHUNK_SIZE,
+            1,
+        )?;
+        let output_buffer = resampler.output_buffer_allocate(true);
+        Ok(Self {
+            resampler,
+            pending: Vec::with_capacity(Self::CHUNK_SIZE),
+            output_buffer,
+            target_level: target_level.max(1) as f64,
+            level: None,
+            ratio: 1.,
+        })
+    }
+
+    /// The current output/input ratio, above 1 when the playback device runs faster than the
+    /// capture one.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// Updates the ratio from the current level of the playback buffer, in samples.
+    pub fn update_level(&mut self, level: usize) -> Result<()> {
+        use rubato::Resampler;
+
+        let level = match self.level {
+            None => level as f64,
+            Some(l) => l + Self::LEVEL_SMOOTHING * (level as f64 - l),
+        };
+        self.level = Some(level);
+        let error = (level - self.target_level) / self.target_level;
+        // A buffer filling up means that the playback is slower, fewer samples get produced.
+        // The resampler only accepts ratios within a factor of MAX_DRIFT of 1.
+        let max_ratio = 1. + Self::MAX_DRIFT;
+        self.ratio = (1. - Self::GAIN * error).clamp(1. / max_ratio, max_ratio);
+        self.resampler.set_resample_ratio_relative(self.ratio, true)?;
+        Ok(())
+    }
+
+    /// Resamples a chunk of the audio to be played, the remainder that does not fill a chunk of
+    /// the resampler is kept for the next call.
+    pub fn push(&mut self, pcm: &[f32]) -> Result<Vec<f32>> {
+        use rubato::Resampler;
+
+        let Self { resampler, pending, output_buffer, .. } = self;
+        let mut out = vec![];
+        pending.extend_from_slice(pcm);
+        let mut pos = 0;
+        while pending.len() - pos >= resampler.input_frames_next() {
+            let (in_len, out_len) =
+                resampler.process_into_buffer(&[&pending[pos..]], output_buffer, None)?;
+            pos += in_len;
+            out.extend_from_slice(&output_buffer[0][..out_len])
+        }
+        pending.drain(..pos);
+        Ok(out)
+    }
+
+    /// Returns the remainder that does not fill a chunk of the resampler, as is.
+    pub fn flush(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.pending)
+    }
+
+    pub fn reset(&mut self) {
+        use rubato::Resampler;
+
+        self.resampler.reset();
+        self.pending.clear();
+        self.level = None;
+        self.ratio = 1.;
+    }
+}
+
+/// Sample format of raw mono PCM, as read from stdin or written to stdout with `-` as the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RawFormat {
+    S16le,
+    F32le,
+}
+
+impl RawFormat {
+    pub fn sample_size(&self) -> usize {
+        match self {
+            Self::S16le => 2,
+            Self::F32le => 4,
+        }
+    }
+
+    pub fn decode(&self, bytes: &[u8], pcm: &mut [f32]) {
+        match self {
+            Self::S16le => crate::pcm::s16le_to_f32(bytes, pcm),
+            Self::F32le => crate::pcm::f32le_to_f32(bytes, pcm),
+        }
+    }
+
+    pub fn encode(&self, pcm: &[f32]) -> Vec<u8> {
+        match self {
+            Self::S16le => {
+                let mut bytes = vec![0; 2 * pcm.len()];
+                crate::pcm::f32_to_s16le(pcm, &mut bytes);
+                bytes
+            }
+            Self::F32le => pcm.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        }
+    }
+}
+
+/// The channels of a multi-channel capture that are mixed down to the mono input, e.g. to take
+/// the floor feed from a 16 channels Dante or loopback device. The selected channels are
+/// numbered from 0, the flags number them from 1 as in the routing tools.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMap {
+    channels: usize,
+    selected: Vec<usize>,
+}
+
+impl Default for ChannelMap {
+    fn default() -> Self {
+        Self { channels: 1, selected: vec![0] }
+    }
+}
+
+impl ChannelMap {
+    /// Selects `selected`, numbered from 1, among `channels` channels, which defaults to the
+    /// highest selected channel.
+    pub fn new(channels: Option<usize>, selected: &[usize]) -> Result<Self> {
+        let max = selected.iter().copied().max().unwrap_or(1);
+        let channels = channels.unwrap_or(max);
+        if let Some(&channel) = selected.iter().find(|&&c| c == 0 || c > channels) {
+            anyhow::bail!("channel {channel} is not within the {channels} captured channels")
+        }
+        let selected = if selected.is_empty() { vec![0] } else { selected.to_vec() };
+        Ok(Self { channels, selected: selected.iter().map(|c| c.saturating_sub(1)).collect() })
+    }
+
+    // Averages the selected channels of the interleaved samples.
+    fn mix(&self, interleaved: &[f32], pcm: &mut [f32]) {
+        crate::pcm::downmix(interleaved, self.channels, &self.selected, pcm)
+    }
+}
+
+/// Parses a list of channels such as `3,4` or `1-2,7`.
+pub fn parse_channel_list(list: &str) -> Result<Vec<usize>> {
+    let mut channels = vec![];
+    for entry in list.split(',') {
+        let entry = entry.trim();
+        match entry.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.trim().parse()?, last.trim().parse()?);
+                if first > last {
+                    anyhow::bail!("invalid channel range {entry}")
+                }
+                channels.extend(first..=last)
+            }
+            None => channels.push(entry.parse()?),
+        }
+    }
+    Ok(channels)
+}
+
+/// Live capture from an ALSA device, as listed by the devices subcommand. The samples are read
+/// from an `arecord` process which takes care of the format and sample rate conversions, so that
+/// this does not require linking against the ALSA libraries. Raw PCM can also be captured from
+/// stdin, e.g. when piped from ffmpeg.
+pub struct Capture {
+    child: Option<std::process::Child>,
+    reader: Box<dyn std::io::Read>,
+    format: RawFormat,
+    channels: ChannelMap,
+    buf: Vec<u8>,
+    interleaved: Vec<f32>,
+}
+
+impl Capture {
+    fn new(
+        child: Option<std::process::Child>,
+        reader: Box<dyn std::io::Read>,
+        format: RawFormat,
+        channels: ChannelMap,
+    ) -> Self {
+        Self { child, reader, format, channels, buf: vec![], interleaved: vec![] }
+    }
+
+    /// Starts capturing 16-bit audio at the given sample rate, the channels of the map are mixed
+    /// down to mono.
+    pub fn open(device: &str, sample_rate: usize, channels: &ChannelMap) -> Result<Self> {
+        let mut child = std::process::Command::new("arecord")
+            .args(["-q", "-t", "raw", "-f", "S16_LE", "-D", device])
+            .arg(format!("-c{}", channels.channels))
+            .arg(format!("-r{sample_rate}"))
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .context("cannot run arecord, is alsa-utils installed?")?;
+        let stdout = child.stdout.take().context("no stdout for arecord")?;
+        let reader = Box::new(std::io::BufReader::new(stdout));
+        Ok(Self::new(Some(child), reader, RawFormat::S16le, channels.clone()))
+    }
+
+    /// Reads mono audio from stdin, the sample rate is up to the producer.
+    pub fn stdin(format: RawFormat) -> Self {
+        let reader = Box::new(std::io::BufReader::new(std::io::stdin()));
+        Self::new(None, reader, format, ChannelMap::default())
+    }
+
+    /// Replays mono audio at the given sample rate as if it was being captured, the samples are
+    /// only available once their time has come. This makes the live behavior reproducible.
+    pub fn replay(pcm: &[f32], sample_rate: usize) -> Self {
+        let format = RawFormat::F32le;
+        let bytes_per_sec = (sample_rate * format.sample_size()) as f64;
+        let reader = Paced {
+            bytes: format.encode(pcm),
+            pos: 0,
+            bytes_per_sec,
+            start: std::time::Instant::now(),
+        };
+        Self::new(None, Box::new(reader), format, ChannelMap::default())
+    }
+
+    /// Reads exactly `frame.len()` samples, blocking until they have been captured. Returns
+    /// false once the capture has ended, a partial last frame is padded with silence.
+    pub fn read_frame(&mut self, frame: &mut [f32]) -> Result<bool> {
+        use std::io::Read;
+        let num_samples = frame.len() * self.channels.channels;
+        self.buf.resize(num_samples * self.format.sample_size(), 0);
+        let mut len = 0;
+        while len < self.buf.len() {
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if len < self.format.sample_size() * self.channels.channels {
+            return Ok(false);
+        }
+        self.buf[len..].fill(0);
+        self.interleaved.resize(num_samples, 0.);
+        self.format.decode(&self.buf, &mut self.interleaved);
+        self.channels.mix(&self.interleaved, frame);
+        Ok(true)
+    }
+}
+
+// Serves bytes at a constant rate from the time it was created.
+struct Paced {
+    bytes: Vec<u8>,
+    pos: usize,
+    bytes_per_sec: f64,
+    start: std::time::Instant,
+}
+
+impl std::io::Read for Paced {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let end = usize::min(self.pos + buf.len(), self.bytes.len());
+        let due = std::time::Duration::from_secs_f64(end as f64 / self.bytes_per_sec);
+        if let Some(wait) = due.checked_sub(self.start.elapsed()) {
+            std::thread::sleep(wait)
+        }
+        let len = end - self.pos;
+        buf[..len].copy_from_slice(&self.bytes[self.pos..end]);
+        self.pos = end;
+        Ok(len)
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Raw PCM written to stdout as it is generated, flushed after each write so that the next
+/// process in the pipeline gets the audio without delay.
+pub struct RawOutput {
+    stdout: std::io::Stdout,
+    format: RawFormat,
+}
+
+impl RawOutput {
+    pub fn stdout(format: RawFormat) -> Self {
+        Self { stdout: std::io::stdout(), format }
+    }
+
+    pub fn write(&mut self, pcm: &[f32]) -> Result<()> {
+        use std::io::Write;
+        let mut stdout = self.stdout.lock();
+        stdout.write_all(&self.format.encode(pcm))?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+/// Buffering of the playback, larger buffers avoid underruns on slow or busy hardware at the
+/// cost of some latency.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlaybackBuffer {
+    /// Audio buffered before starting the playback, so that small variations in the generation
+    /// speed do not result in underruns.
+    pub prebuffer_secs: f64,
+    /// The size of the sound card buffer, the aplay default when not set.
+    pub device_secs: Option<f64>,
+    /// Compensate the clock drift with the capture device, see `DriftCompensator`. This is only
+    /// meaningful when the audio is generated at the pace of a capture.
+    pub compensate_drift: bool,
+}
+
+impl Default for PlaybackBuffer {
+    fn default() -> Self {
+        Self { prebuffer_secs: 0.16, device_secs: None, compensate_drift: false }
+    }
+}
+
+/// Playback of audio as it is generated on an ALSA device, through an `aplay` process fed from
+/// a separate thread so that the generation loop never blocks on the sound card.
+pub struct Playback {
+    tx: Option<std::sync::mpsc::Sender<Vec<f32>>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+    // The samples pushed that have not been written to aplay yet. Once the buffers of aplay are
+    // full the writes block, so this grows or drains with the drift of the sound card clock.
+    queued: Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl Playback {
+    pub fn open(device: &str, sample_rate: usize, buffer: PlaybackBuffer) -> Result<Self> {
+        let mut command = std::process::Command::new("aplay");
+        command
+            .args(["-q", "-t", "raw", "-f", "S16_LE", "-c", "1", "-D", device])
+            .arg(format!("-r{sample_rate}"));
+        if let Some(secs) = buffer.device_secs {
+            // The buffer time is given in microseconds.
+            command.arg(format!("--buffer-time={}", (secs * 1e6).round() as u64));
+        }
+        let mut child = command
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .context("cannot run aplay, is alsa-utils installed?")?;
+        let mut stdin = child.stdin.take().context("no stdin for aplay")?;
+        #[cfg(target_os = "linux")]
+        if buffer.compensate_drift {
+            use std::os::fd::AsRawFd;
+            // The pipe would otherwise hide more than a second of audio from the buffer level,
+            // shrink it to a single page.
+            unsafe { libc::fcntl(stdin.as_raw_fd(), libc::F_SETPIPE_SZ, 4096) };
+        }
+        let jitter_len = (buffer.prebuffer_secs * sample_rate as f64) as usize;
+        let mut drift = match buffer.compensate_drift {
+            false => None,
+            true => Some(DriftCompensator::new(jitter_len)?),
+        };
+        let (tx, rx) = std::sync::mpsc::channel::<Vec<f32>>();
+        let queued = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let handle = {
+            let queued = queued.clone();
+            std::thread::spawn(move || {
+                use std::io::Write;
+                use std::sync::atomic::Ordering;
+                let mut pending = vec![];
+                let mut started = false;
+                let mut write = |pending: &mut Vec<f32>| {
+                    let bytes = RawFormat::S16le.encode(pending);
+                    pending.clear();
+                    let res = stdin.write_all(&bytes);
+                    queued.fetch_sub(bytes.len() / 2, Ordering::Relaxed);
+                    res
+                };
+                while let Ok(pcm) = rx.recv() {
+                    let pcm = match drift.as_mut() {
+                        None => pcm,
+                        Some(drift) => {
+                            // The resampled audio is accounted for in place of the original.
+                            let level = queued.load(Ordering::Relaxed);
+                            let resampled =
+                                drift.update_level(level).and_then(|_| drift.push(&pcm));
+                            match resampled {
+                                Ok(resampled) => {
+                                    queued.fetch_add(resampled.len(), Ordering::Relaxed);
+                                    queued.fetch_sub(pcm.len(), Ordering::Relaxed);
+                                    resampled
+                                }
+                                Err(err) => {
+                                    tracing::warn!(?err, "drift compensation failed");
+                                    pcm
+                                }
+                            }
+                        }
+                    };
+                    pending.extend_from_slice(&pcm);
+                    started = started || pending.len() >= jitter_len;
+                    if started {
+                        if let Err(err) = write(&mut pending) {
+                            tracing::warn!(?err, "playback failed");
+                            return;
+                        }
+                    }
+                }
+                if let Some(drift) = drift.as_mut() {
+                    tracing::info!(ratio = drift.ratio(), "playback drift compensation");
+                    let remainder = drift.flush();
+                    queued.fetch_add(remainder.len(), Ordering::Relaxed);
+                    pending.extend(remainder)
+                }
+                if let Err(err) = write(&mut pending) {
+                    tracing::warn!(?err, "playback failed");
+                }
+                // Closing stdin lets aplay drain its buffer and exit.
+                drop(stdin);
+                let _ = child.wait();
+            })
+        };
+        Ok(Self { tx: Some(tx), handle: Some(handle), queued })
+    }
+
+    pub fn push(&self, pcm: &[f32]) {
+        if let Some(tx) = self.tx.as_ref() {
+            self.queued.fetch_add(pcm.len(), std::sync::atomic::Ordering::Relaxed);
+            let _ = tx.send(pcm.to_vec());
+        }
+    }
+
+    /// Waits for all the pushed audio to have been played.
+    pub fn finish(&mut self) {
+        self.tx = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        self.finish()
+    }
+}
+
 fn conv<T>(samples: &mut Vec<f32>, data: std::borrow::Cow<symphonia::core::audio::AudioBuffer<T>>)
 where
     T: symphonia::core::sample::Sample,
@@ -138,8 +750,32 @@ where
     samples.extend(data.chan(0).iter().map(|v| f32::from_sample(*v)))
 }
 
-pub(crate) fn pcm_decode<P: AsRef<std::path::Path>>(path: P) -> Result<(Vec<f32>, u32)> {
-    use symphonia::core::audio::{AudioBufferRef, Signal};
+/// Parses a position in an audio file, given as seconds or as [hh:]mm:ss[.ms].
+pub(crate) fn parse_timestamp(timestamp: &str) -> Result<f64> {
+    let mut secs = 0.;
+    for part in timestamp.split(':') {
+        let value: f64 = part.trim().parse()?;
+        if value < 0. {
+            anyhow::bail!("invalid timestamp '{timestamp}'")
+        }
+        secs = secs * 60. + value
+    }
+    Ok(secs)
+}
+
+pub fn pcm_decode<P: AsRef<std::path::Path>>(path: P) -> Result<(Vec<f32>, u32)> {
+    pcm_decode_range(path, 0., None)
+}
+
+/// Decodes the range from `start` to `end` seconds of the file. The decoder seeks to the start
+/// when the format supports it, and the decoded packets are trimmed so that the range is sample
+/// accurate.
+pub(crate) fn pcm_decode_range<P: AsRef<std::path::Path>>(
+    path: P,
+    start: f64,
+    end: Option<f64>,
+) -> Result<(Vec<f32>, u32)> {
+    use symphonia::core::formats::{SeekMode, SeekTo};
 
     let src = std::fs::File::open(path)?;
     let mss = symphonia::core::io::MediaSourceStream::new(Box::new(src), Default::default());
@@ -158,7 +794,20 @@ pub(crate) fn pcm_decode<P: AsRef<std::path::Path>>(path: P) -> Result<(Vec<f32>
         .expect("unsupported codec");
     let track_id = track.id;
     let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+    let time_base = track.codec_params.time_base;
+    let start_sample = (start * sample_rate as f64).round() as usize;
+    let end_sample = end.map_or(usize::MAX, |v| (v * sample_rate as f64).round() as usize);
+    if start_sample > 0 {
+        let to = SeekTo::Time { time: start.into(), track_id: Some(track_id) };
+        match format.seek(SeekMode::Accurate, to) {
+            Ok(_) => decoder.reset(),
+            Err(err) => tracing::warn!(?err, "cannot seek the input, decoding from the start"),
+        }
+    }
     let mut pcm_data = Vec::new();
+    let mut packet_pcm = Vec::new();
+    // Position in samples of the next decoded sample, used when the packets have no timestamps.
+    let mut position = 0;
     while let Ok(packet) = format.next_packet() {
         while !format.metadata().is_latest() {
             format.metadata().pop();
@@ -166,23 +815,40 @@ pub(crate) fn pcm_decode<P: AsRef<std::path::Path>>(path: P) -> Result<(Vec<f32>
         if packet.track_id() != track_id {
             continue;
         }
-        match decoder.decode(&packet)? {
-            AudioBufferRef::F32(buf) => pcm_data.extend(buf.chan(0)),
-            AudioBufferRef::U8(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::U16(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::U24(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::U32(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S8(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S16(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S24(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::S32(data) => conv(&mut pcm_data, data),
-            AudioBufferRef::F64(data) => conv(&mut pcm_data, data),
+        if let Some(time_base) = time_base {
+            let time = time_base.calc_time(packet.ts());
+            position = ((time.seconds as f64 + time.frac) * sample_rate as f64).round() as usize;
+        }
+        if position >= end_sample {
+            break;
         }
+        packet_pcm.clear();
+        decode_packet(&mut packet_pcm, decoder.decode(&packet)?);
+        let skip = start_sample.saturating_sub(position).min(packet_pcm.len());
+        let take = end_sample.saturating_sub(position + skip);
+        pcm_data.extend(packet_pcm.iter().skip(skip).take(take));
+        position += packet_pcm.len();
     }
     Ok((pcm_data, sample_rate))
 }
 
-pub(crate) fn resample(pcm_in: &[f32], sr_in: usize, sr_out: usize) -> Result<Vec<f32>> {
+fn decode_packet(pcm_data: &mut Vec<f32>, data: symphonia::core::audio::AudioBufferRef) {
+    use symphonia::core::audio::{AudioBufferRef, Signal};
+    match data {
+        AudioBufferRef::F32(buf) => pcm_data.extend(buf.chan(0)),
+        AudioBufferRef::U8(data) => conv(pcm_data, data),
+        AudioBufferRef::U16(data) => conv(pcm_data, data),
+        AudioBufferRef::U24(data) => conv(pcm_data, data),
+        AudioBufferRef::U32(data) => conv(pcm_data, data),
+        AudioBufferRef::S8(data) => conv(pcm_data, data),
+        AudioBufferRef::S16(data) => conv(pcm_data, data),
+        AudioBufferRef::S24(data) => conv(pcm_data, data),
+        AudioBufferRef::S32(data) => conv(pcm_data, data),
+        AudioBufferRef::F64(data) => conv(pcm_data, data),
+    }
+}
+
+pub fn resample(pcm_in: &[f32], sr_in: usize, sr_out: usize) -> Result<Vec<f32>> {
     use rubato::Resampler;
 
     let mut pcm_out =
@@ -209,3 +875,197 @@ pub(crate) fn resample(pcm_in: &[f32], sr_in: usize, sr_out: usize) -> Result<Ve
 
     Ok(pcm_out)
 }
+
+/// Resamples the output audio to `sample_rate` when set, returns the samples together with their
+/// sample rate.
+pub fn resample_output(
+    pcm: Vec<f32>,
+    sr_in: usize,
+    sample_rate: Option<usize>,
+) -> Result<(Vec<f32>, usize)> {
+    match sample_rate {
+        Some(sr_out) if sr_out != sr_in => Ok((resample(&pcm, sr_in, sr_out)?, sr_out)),
+        _ => Ok((pcm, sr_in)),
+    }
+}
+
+/// The sample format of the wav outputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WavFormat {
+    /// 16-bit integer pcm.
+    #[value(name = "16")]
+    S16,
+    /// 24-bit integer pcm.
+    #[value(name = "24")]
+    S24,
+    /// 32-bit float.
+    #[value(name = "f32")]
+    F32,
+}
+
+impl WavFormat {
+    fn bytes_per_sample(&self) -> usize {
+        match self {
+            Self::S16 => 2,
+            Self::S24 => 3,
+            Self::F32 => 4,
+        }
+    }
+}
+
+/// Writes mono samples as a wav file in the given format, the integer formats clamp the samples
+/// to [-1, 1].
+pub fn write_wav<W: std::io::Write>(
+    w: &mut W,
+    samples: &[f32],
+    sample_rate: u32,
+    format: WavFormat,
+) -> std::io::Result<()> {
+    write_wav_channels(w, samples, 1, sample_rate, format)
+}
+
+/// Writes a stereo wav file, the shorter channel is padded with silence.
+pub fn write_wav_stereo<W: std::io::Write>(
+    w: &mut W,
+    left: &[f32],
+    right: &[f32],
+    sample_rate: u32,
+    format: WavFormat,
+) -> std::io::Result<()> {
+    let len = left.len().max(right.len());
+    let mut samples = Vec::with_capacity(2 * len);
+    for idx in 0..len {
+        samples.push(left.get(idx).copied().unwrap_or(0.));
+        samples.push(right.get(idx).copied().unwrap_or(0.));
+    }
+    write_wav_channels(w, &samples, 2, sample_rate, format)
+}
+
+// The samples of the channels are interleaved.
+fn write_wav_channels<W: std::io::Write>(
+    w: &mut W,
+    samples: &[f32],
+    channels: u16,
+    sample_rate: u32,
+    format: WavFormat,
+) -> std::io::Result<()> {
+    let bytes_per_sample = format.bytes_per_sample();
+    let block_align = bytes_per_sample as u16 * channels;
+    let data_len = samples.len() * bytes_per_sample;
+    // The float format requires the extended fmt chunk and a fact chunk.
+    let (format_tag, fmt_len, fact_len) = match format {
+        WavFormat::S16 | WavFormat::S24 => (1u16, 16u32, 0u32),
+        WavFormat::F32 => (3u16, 18u32, 12u32),
+    };
+    // Chunks are word aligned, this only matters for 24-bit samples.
+    let pad_len = data_len % 2;
+    let riff_len = 4 + (8 + fmt_len) + fact_len + 8 + (data_len + pad_len) as u32;
+    w.write_all(b"RIFF")?;
+    w.write_all(&riff_len.to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&fmt_len.to_le_bytes())?;
+    w.write_all(&format_tag.to_le_bytes())?;
+    w.write_all(&channels.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&(bytes_per_sample as u16 * 8).to_le_bytes())?;
+    if format == WavFormat::F32 {
+        w.write_all(&0u16.to_le_bytes())?; // no extension
+        w.write_all(b"fact")?;
+        w.write_all(&4u32.to_le_bytes())?;
+        w.write_all(&((samples.len() / channels as usize) as u32).to_le_bytes())?;
+    }
+
+    w.write_all(b"data")?;
+    w.write_all(&(data_len as u32).to_le_bytes())?;
+    let mut data = Vec::with_capacity(data_len + pad_len);
+    match format {
+        WavFormat::S16 => {
+            data.resize(2 * samples.len(), 0);
+            crate::pcm::f32_to_s16le(samples, &mut data)
+        }
+        WavFormat::S24 => {
+            for &v in samples.iter() {
+                let v = (v.clamp(-1., 1.) * 8388607.) as i32;
+                data.extend_from_slice(&v.to_le_bytes()[..3])
+            }
+        }
+        WavFormat::F32 => {
+            for &v in samples.iter() {
+                data.extend_from_slice(&v.to_le_bytes())
+            }
+        }
+    }
+    data.resize(data_len + pad_len, 0);
+    w.write_all(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_formats() {
+        let pcm = [0., 0.5, -0.5, 1., -1.];
+        for format in [RawFormat::S16le, RawFormat::F32le] {
+            let bytes = format.encode(&pcm);
+            assert_eq!(bytes.len(), pcm.len() * format.sample_size());
+            let mut decoded = [0f32; 5];
+            format.decode(&bytes, &mut decoded);
+            for (v, d) in pcm.iter().zip(decoded.iter()) {
+                assert!((v - d).abs() < 1e-4, "{format:?} {v} {d}")
+            }
+        }
+    }
+
+    #[test]
+    fn channel_lists() {
+        assert_eq!(parse_channel_list("3,4").unwrap(), [3, 4]);
+        assert_eq!(parse_channel_list("1-2, 7").unwrap(), [1, 2, 7]);
+        assert!(parse_channel_list("2-1").is_err());
+        assert!(parse_channel_list("one").is_err());
+        let map = ChannelMap::new(None, &[3, 4]).unwrap();
+        assert_eq!(map, ChannelMap { channels: 4, selected: vec![2, 3] });
+        assert_eq!(ChannelMap::new(Some(2), &[]).unwrap().selected, [0]);
+        assert!(ChannelMap::new(Some(2), &[3]).is_err());
+        assert!(ChannelMap::new(None, &[0]).is_err());
+    }
+
+    #[test]
+    fn pre_roll() {
+        let mut pre_roll = PreRoll::new(4);
+        pre_roll.push(&[1., 2., 3.]);
+        pre_roll.push(&[4., 5.]);
+        assert_eq!(pre_roll.len(), 4);
+        assert_eq!(pre_roll.take(), [2., 3., 4., 5.]);
+        assert!(pre_roll.is_empty());
+        pre_roll.push(&[1., 2., 3., 4., 5., 6.]);
+        assert_eq!(pre_roll.take(), [3., 4., 5., 6.]);
+        assert!(PreRoll::new(0).take().is_empty());
+        assert_eq!(PreRoll::from_duration(0.5, 16000).capacity, 8000);
+    }
+
+    #[test]
+    fn drift_ratio() -> Result<()> {
+        let mut compensator = DriftCompensator::new(1000)?;
+        compensator.update_level(1000)?;
+        assert_eq!(compensator.ratio(), 1.);
+        // A filling buffer slows the output down, within the bounds.
+        for _ in 0..1000 {
+            compensator.update_level(100_000)?
+        }
+        assert_eq!(compensator.ratio(), 1. / (1. + DriftCompensator::MAX_DRIFT));
+        for _ in 0..1000 {
+            compensator.update_level(0)?
+        }
+        assert!(compensator.ratio() > 1.);
+        let out = compensator.push(&vec![0.; 10 * DriftCompensator::CHUNK_SIZE + 10])?;
+        let expected = 10. * DriftCompensator::CHUNK_SIZE as f64 * compensator.ratio();
+        assert!((out.len() as f64 - expected).abs() < 100., "{}", out.len());
+        assert_eq!(compensator.flush().len(), 10);
+        Ok(())
+    }
+}
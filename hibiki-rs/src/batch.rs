@@ -0,0 +1,392 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Batch translation of a directory of audio files or of a jsonl manifest, the models are loaded
+// once per worker and the files are processed in order. Each input gets a wav and a transcript
+// in the output directory, and a report with the outcome of every file is written at the end.
+// The manifest has one json object per line:
+//   {"input": "in.mp3", "output": "out.wav", "seed": 42}
+//   {"input": "meeting.mp3", "start": 12.5, "end": "01:02.3", "id": "spk1-003", "speaker": "spk1"}
+// where all the fields but the input are optional. Entries with a range are translated as
+// independent segments, so that the output of a segmentation or diarization pipeline can be used
+// directly. The outputs are named after the id when given. The translated segments of each
+// speaker are also laid out at their start on a track per speaker, `speakers/<speaker>.wav`, so
+// that the voices can be balanced independently when mixing.
+// Inputs with the same audio and settings as a file translated earlier in the batch are not
+// translated again, their report entry points to the earlier output with "duplicate_of". The
+// copies that different workers process at the same time are both translated.
+
+use anyhow::{Context, Result};
+use candle::Device;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const AUDIO_EXTENSIONS: &[&str] = &["wav", "mp3", "flac", "ogg", "m4a", "aac", "opus"];
+
+// Positions in the manifest are either seconds or timestamps.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Position {
+    Secs(f64),
+    Timestamp(String),
+}
+
+impl Position {
+    fn secs(&self) -> Result<f64> {
+        match self {
+            Self::Secs(secs) => Ok(*secs),
+            Self::Timestamp(timestamp) => crate::audio_io::parse_timestamp(timestamp),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ManifestEntry {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    seed: Option<u64>,
+    id: Option<String>,
+    start: Option<Position>,
+    end: Option<Position>,
+    speaker: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Item {
+    id: Option<String>,
+    input: PathBuf,
+    output: PathBuf,
+    seed: Option<u64>,
+    start: Option<f64>,
+    end: Option<f64>,
+    speaker: Option<String>,
+}
+
+fn list_items(input: &Path, output_dir: &Path) -> Result<Vec<Item>> {
+    let output_for = |input: &Path| {
+        let stem = input.file_stem().map_or_else(String::new, |v| v.to_string_lossy().to_string());
+        output_dir.join(format!("{stem}.wav"))
+    };
+    let item = |input, output| Item {
+        id: None,
+        input,
+        output,
+        seed: None,
+        start: None,
+        end: None,
+        speaker: None,
+    };
+    if input.is_dir() {
+        let mut inputs = vec![];
+        for entry in std::fs::read_dir(input)? {
+            let path = entry?.path();
+            let ext = path.extension().map(|v| v.to_string_lossy().to_lowercase());
+            if path.is_file() && ext.is_some_and(|v| AUDIO_EXTENSIONS.contains(&v.as_str())) {
+                inputs.push(path)
+            }
+        }
+        inputs.sort();
+        return Ok(inputs
+            .into_iter()
+            .map(|input| item(input.clone(), output_for(&input)))
+            .collect());
+    }
+    let manifest =
+        std::fs::read_to_string(input).with_context(|| format!("cannot read {input:?}"))?;
+    // Relative paths in the manifest are resolved from its directory.
+    let base = input.parent().unwrap_or(Path::new(""));
+    let mut items = vec![];
+    for (idx, line) in manifest.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ManifestEntry = serde_json::from_str(line)
+            .with_context(|| format!("invalid manifest entry on line {}", idx + 1))?;
+        let start = entry.start.as_ref().map(Position::secs).transpose()?;
+        let end = entry.end.as_ref().map(Position::secs).transpose()?;
+        if let (Some(start), Some(end)) = (start, end) {
+            if end <= start {
+                anyhow::bail!("the end is before the start on line {} of the manifest", idx + 1)
+            }
+        }
+        let input = base.join(entry.input);
+        let output = match (entry.output, entry.id.as_ref()) {
+            (Some(output), _) => base.join(output),
+            (None, Some(id)) => output_dir.join(format!("{id}.wav")),
+            // Segments of the same file without ids are told apart by their start in ms.
+            (None, None) => match start {
+                None => output_for(&input),
+                Some(start) => output_for(&input)
+                    .with_extension("")
+                    .with_extension(format!("{}.wav", (start * 1000.).round() as u64)),
+            },
+        };
+        items.push(Item {
+            id: entry.id,
+            input,
+            output,
+            seed: entry.seed,
+            start,
+            end,
+            speaker: entry.speaker,
+        })
+    }
+    Ok(items)
+}
+
+// The per-file path for an optional output given on the command line, e.g. `--subtitles
+// subs.srt` becomes `out_dir/stem.subs.srt` for `out_dir/stem.wav`.
+fn per_file(path: &Option<PathBuf>, output: &Path) -> Option<PathBuf> {
+    let path = path.as_ref()?;
+    let stem = output.file_stem().map_or_else(String::new, |v| v.to_string_lossy().to_string());
+    let name = path.file_name().map_or_else(String::new, |v| v.to_string_lossy().to_string());
+    Some(output.with_file_name(format!("{stem}.{name}")))
+}
+
+fn item_args(args: &crate::gen::Args, item: &Item) -> crate::gen::Args {
+    let transcript_file = match per_file(&args.transcript_file, &item.output) {
+        Some(path) => path,
+        None => item.output.with_extension("txt"),
+    };
+    crate::gen::Args {
+        audio_input_file: item.input.clone(),
+        audio_output_file: item.output.clone(),
+        seed: item.seed.unwrap_or(args.seed),
+        start: item.start.unwrap_or(args.start),
+        end: item.end.or(args.end),
+        transcript_file: Some(transcript_file),
+        emit_token_ids: per_file(&args.emit_token_ids, &item.output),
+        word_alignment: per_file(&args.word_alignment, &item.output),
+        subtitles: per_file(&args.subtitles, &item.output),
+        cue_stats: per_file(&args.cue_stats, &item.output),
+        chapters: per_file(&args.chapters, &item.output),
+        clip_markers: per_file(&args.clip_markers, &item.output),
+        json_output: per_file(&args.json_output, &item.output),
+        trace: per_file(&args.trace, &item.output),
+        ..args.clone()
+    }
+}
+
+enum Outcome {
+    Generated(crate::gen::Summary),
+    // The input was already translated to this output.
+    Duplicate(PathBuf),
+}
+
+fn process(
+    args: &crate::gen::Args,
+    models: &mut crate::gen::Models,
+    dev: &Device,
+    idx: usize,
+    duplicates: &Mutex<crate::memory::Duplicates>,
+) -> Result<Outcome> {
+    let input = crate::gen::Input::load(args, models.codec.as_ref(), dev)?;
+    let fingerprint = input.fingerprint(args)?;
+    if let Some((_, output)) = duplicates.lock().unwrap().get(None, &fingerprint) {
+        return Ok(Outcome::Duplicate(output));
+    }
+    let summary = match crate::gen::lookup_memory(args, &input, models.codec.as_mut())? {
+        crate::gen::MemoryLookup::Hit => Default::default(),
+        crate::gen::MemoryLookup::Miss(memory) => {
+            crate::gen::generate(args, models, &input, memory.as_ref(), dev, None, &mut |_| ())?
+        }
+    };
+    let output = args.audio_output_file.clone();
+    duplicates.lock().unwrap().insert(None, fingerprint, idx as u64, output);
+    Ok(Outcome::Generated(summary))
+}
+
+// Adds the samples to the track from the given offset, overlapping segments are mixed.
+fn lay_out(track: &mut Vec<f32>, pcm: &[f32], offset: usize) {
+    if track.len() < offset + pcm.len() {
+        track.resize(offset + pcm.len(), 0.)
+    }
+    for (dst, src) in track[offset..].iter_mut().zip(pcm.iter()) {
+        *dst += src
+    }
+}
+
+// Writes a track per speaker with the translated audio of its segments, `outputs` has the audio
+// of each item when it was translated.
+fn write_speaker_tracks(
+    items: &[Item],
+    outputs: &[Option<PathBuf>],
+    output_dir: &Path,
+    format: crate::audio_io::WavFormat,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let mut tracks: BTreeMap<&str, (Vec<f32>, u32)> = BTreeMap::new();
+    for (item, output) in items.iter().zip(outputs.iter()) {
+        let (Some(speaker), Some(output)) = (item.speaker.as_deref(), output) else { continue };
+        let (pcm, sample_rate) = crate::audio_io::pcm_decode(output)?;
+        let (track, track_sample_rate) =
+            tracks.entry(speaker).or_insert_with(|| (vec![], sample_rate));
+        if *track_sample_rate != sample_rate {
+            anyhow::bail!("{output:?} has a sample rate of {sample_rate}, not {track_sample_rate}")
+        }
+        let offset = (item.start.unwrap_or(0.) * sample_rate as f64).round() as usize;
+        lay_out(track, &pcm, offset)
+    }
+    let mut paths = BTreeMap::new();
+    if tracks.is_empty() {
+        return Ok(paths);
+    }
+    let dir = output_dir.join("speakers");
+    std::fs::create_dir_all(&dir)?;
+    for (speaker, (track, sample_rate)) in tracks {
+        let path = dir.join(format!("{speaker}.wav"));
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        crate::audio_io::write_wav(&mut file, &track, sample_rate, format)?;
+        paths.insert(speaker.to_string(), path);
+    }
+    Ok(paths)
+}
+
+fn report_entry(
+    item: &Item,
+    res: &Result<Outcome>,
+    duration: std::time::Duration,
+) -> serde_json::Value {
+    let (ok, error, summary, duplicate_of) = match res {
+        Ok(Outcome::Generated(summary)) => (true, None, summary.clone(), None),
+        Ok(Outcome::Duplicate(output)) => (true, None, Default::default(), Some(output)),
+        Err(err) => (false, Some(format!("{err:#}")), Default::default(), None),
+    };
+    serde_json::json!({
+        "id": item.id,
+        "input": item.input,
+        "start": item.start,
+        "end": item.end,
+        "speaker": item.speaker,
+        "output": item.output,
+        "ok": ok,
+        "error": error,
+        "duplicate_of": duplicate_of,
+        "duration_s": duration.as_secs_f64(),
+        "steps": summary.steps,
+        "text_tokens": summary.text_tokens,
+        "stats": summary.stats,
+    })
+}
+
+/// Translates all the inputs, a failed file is reported and does not stop the batch.
+pub fn run(
+    args: &crate::gen::Args,
+    dev: &Device,
+    input: &Path,
+    output_dir: &Path,
+    workers: usize,
+    report: Option<&Path>,
+) -> Result<()> {
+    std::fs::create_dir_all(output_dir)?;
+    let items = list_items(input, output_dir)?;
+    tracing::info!(files = items.len(), workers, "starting the batch");
+    let start_time = std::time::Instant::now();
+    let next_item = AtomicUsize::new(0);
+    let entries = Mutex::new(vec![serde_json::Value::Null; items.len()]);
+    let outputs = Mutex::new(vec![None; items.len()]);
+    let duplicates = Mutex::new(crate::memory::Duplicates::default());
+    std::thread::scope(|s| -> Result<()> {
+        let mut handles = vec![];
+        for worker in 0..workers.max(1) {
+            let (items, next_item, entries, outputs, duplicates) =
+                (&items, &next_item, &entries, &outputs, &duplicates);
+            handles.push(s.spawn(move || -> Result<()> {
+                let mut models = crate::gen::Models::load(args, dev)?;
+                loop {
+                    let idx = next_item.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(idx) else { return Ok(()) };
+                    tracing::info!(worker, idx, input = ?item.input, "processing");
+                    let item_start = std::time::Instant::now();
+                    let res = process(&item_args(args, item), &mut models, dev, idx, duplicates);
+                    let output = match res.as_ref() {
+                        Ok(Outcome::Generated(_)) => Some(item.output.clone()),
+                        Ok(Outcome::Duplicate(output)) => {
+                            tracing::info!(input = ?item.input, ?output, "already translated");
+                            Some(output.clone())
+                        }
+                        Err(err) => {
+                            tracing::error!(input = ?item.input, ?err, "failed to translate");
+                            None
+                        }
+                    };
+                    outputs.lock().unwrap()[idx] = output;
+                    entries.lock().unwrap()[idx] = report_entry(item, &res, item_start.elapsed());
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().map_err(|_| anyhow::anyhow!("batch worker panicked"))??
+        }
+        Ok(())
+    })?;
+    let entries = entries.into_inner().unwrap();
+    let outputs = outputs.into_inner().unwrap();
+    let speakers = write_speaker_tracks(&items, &outputs, output_dir, args.wav_format)?;
+    let failed = entries.iter().filter(|e| e["ok"] == false).count();
+    let report_path = match report {
+        Some(report) => report.to_path_buf(),
+        None => output_dir.join("report.json"),
+    };
+    let report = serde_json::json!({
+        "files": entries.len(),
+        "failed": failed,
+        "duration_s": start_time.elapsed().as_secs_f64(),
+        "speakers": speakers,
+        "entries": entries,
+    });
+    let file = std::io::BufWriter::new(std::fs::File::create(&report_path)?);
+    serde_json::to_writer_pretty(file, &report)?;
+    tracing::info!(files = items.len(), failed, report = ?report_path, "batch done");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speaker_tracks() {
+        let dir = std::env::temp_dir().join(format!("hibiki-batch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let manifest = dir.join("manifest.jsonl");
+        let lines = [
+            r#"{"input": "a.wav", "start": 0.5, "end": 1, "speaker": "alice"}"#,
+            r#"{"input": "a.wav", "start": 1, "end": 2, "speaker": "bob"}"#,
+            r#"{"input": "a.wav", "start": 2, "end": 3, "speaker": "alice"}"#,
+            r#"{"input": "a.wav", "start": 3, "end": 4}"#,
+        ];
+        std::fs::write(&manifest, lines.join("\n")).unwrap();
+        let items = list_items(&manifest, &dir).unwrap();
+        assert_eq!(items[0].speaker.as_deref(), Some("alice"));
+        assert_eq!(items[3].speaker, None);
+        // The translations are 2 samples long at 4Hz, the third one failed.
+        let mut outputs = vec![];
+        for (idx, item) in items.iter().enumerate() {
+            let pcm = [0.25 * (idx + 1) as f32; 2];
+            let mut file = std::fs::File::create(&item.output).unwrap();
+            crate::audio_io::write_wav(&mut file, &pcm, 4, crate::audio_io::WavFormat::F32)
+                .unwrap();
+            outputs.push((idx != 2).then(|| item.output.clone()))
+        }
+        let format = crate::audio_io::WavFormat::F32;
+        let tracks = write_speaker_tracks(&items, &outputs, &dir, format).unwrap();
+        assert_eq!(tracks.keys().collect::<Vec<_>>(), ["alice", "bob"]);
+        let (alice, sample_rate) = crate::audio_io::pcm_decode(&tracks["alice"]).unwrap();
+        assert_eq!(sample_rate, 4);
+        assert_eq!(alice, [0., 0., 0.25, 0.25]);
+        let (bob, _) = crate::audio_io::pcm_decode(&tracks["bob"]).unwrap();
+        assert_eq!(bob, [0., 0., 0., 0., 0.5, 0.5]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        let mut track = vec![];
+        lay_out(&mut track, &[1., 1.], 1);
+        lay_out(&mut track, &[0.5, 0.5], 2);
+        assert_eq!(track, [0., 1., 1.5, 0.5]);
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Beam search over the text stream for offline translation. Each beam has its own lm state, the
+// audio tokens are still sampled so that the beams are conditioned on plausible speech, and the
+// text of the best beam is then forced when generating the output audio.
+//
+// Beams cannot share a kv-cache as the moshi cache is updated in place, a beam that is selected
+// more than once is forked by replaying its history on a fresh copy of the lm.
+
+use anyhow::Result;
+use candle::{Device, IndexOp, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+
+// Exponent of the length normalization, as in GNMT, the score of a beam is its log-probability
+// divided by ((5 + n) / 6)^alpha where n is the number of non-padding text tokens. Without it
+// the beams that emit fewer words, i.e. more of the likely padding tokens, would be favored.
+const LENGTH_PENALTY_ALPHA: f64 = 0.6;
+
+fn length_penalty(num_text_tokens: usize) -> f64 {
+    ((5. + num_text_tokens as f64) / 6.).powf(LENGTH_PENALTY_ALPHA)
+}
+
+struct Beam {
+    state: crate::lm_state::State,
+    logprob: f64,
+    num_text_tokens: usize,
+}
+
+struct Candidate {
+    beam: usize,
+    token: u32,
+    logprob: f64,
+    num_text_tokens: usize,
+    score: f64,
+}
+
+// The log-probabilities of the text tokens, the padding bias is applied as when sampling.
+fn text_logprobs(logits: &Tensor, pad_token: u32, pad_bias: Option<f32>) -> Result<Vec<f32>> {
+    let mut logits = logits.to_dtype(candle::DType::F32)?.to_vec1::<f32>()?;
+    if let (Some(pad_bias), Some(logit)) = (pad_bias, logits.get_mut(pad_token as usize)) {
+        *logit += pad_bias
+    }
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|v| (v - max).exp()).sum::<f32>().ln() + max;
+    Ok(logits.into_iter().map(|v| v - log_sum).collect())
+}
+
+fn top_k(logprobs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut ids: Vec<usize> = (0..logprobs.len()).collect();
+    let k = k.min(ids.len());
+    if k < ids.len() {
+        ids.select_nth_unstable_by(k, |&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+        ids.truncate(k)
+    }
+    ids.into_iter().map(|id| (id as u32, logprobs[id])).collect()
+}
+
+// Runs the beam search on the steps of a chunk, the steps with a forced token are shared by all
+// the beams. Returns the text tokens of the best beam for all the steps of the chunk.
+fn search_chunk(
+    args: &crate::gen::Args,
+    models: &mut crate::gen::Models,
+    in_pcm: &[f32],
+    chunk: &crate::longform::Chunk,
+    forced_text_tokens: &[u32],
+    dev: &Device,
+) -> Result<Vec<u32>> {
+    let num_beams = args.text_beams.max(1);
+    let frame_size = models.codec.frame_size();
+    let config = crate::gen::multistream_config(&args.lm_config);
+    let text_start_token = config.text_start_token;
+    let is_pad = |t: u32| t == config.text_pad_token || t == config.text_eop_token;
+    let conditions = crate::gen::conditions(args, &models.lm_model)?;
+    let cfg_alpha = if args.cfg_alpha == Some(1.) { None } else { args.cfg_alpha };
+    let (audio_sampling, _) = crate::gen::samplings(args);
+    // Each beam samples its audio with its own seed.
+    let mut num_states = 0u64;
+    let mut processors = || {
+        let seed = args.seed + num_states;
+        num_states += 1;
+        let audio_lp = LogitsProcessor::from_sampling(seed, audio_sampling.clone());
+        (audio_lp, LogitsProcessor::from_sampling(seed, Sampling::ArgMax))
+    };
+
+    let mut codes = vec![];
+    models.codec.reset_state();
+    let frames_per_batch = args.frames_per_batch.max(1);
+    for start_index in (chunk.start..chunk.end).step_by(frames_per_batch) {
+        let end_index = usize::min(start_index + frames_per_batch, chunk.end);
+        let pcm = in_pcm[start_index * frame_size..end_index * frame_size].to_vec();
+        let pcm = Tensor::from_vec(pcm, (1, 1, (end_index - start_index) * frame_size), dev)?;
+        if let Some(step_codes) = models.codec.encode_step(&pcm)? {
+            for step in 0..step_codes.dim(2)? {
+                codes.push(step_codes.i((0, .., step))?.to_vec1::<u32>()?)
+            }
+        }
+    }
+
+    let (audio_lp, text_lp) = processors();
+    let state = crate::lm_state::State::new(
+        models.lm_model.clone(),
+        crate::resources::cache_len(args.max_steps),
+        audio_lp,
+        text_lp,
+        args.pad_bias,
+        None,
+        cfg_alpha,
+        config.clone(),
+    );
+    let mut beams = vec![Beam { state, logprob: 0., num_text_tokens: 0 }];
+    let prev_text_token = |beam: &Beam| -> u32 {
+        beam.state.text_tokens(false).last().copied().unwrap_or(text_start_token)
+    };
+    for (step_idx, codes) in codes.iter().enumerate() {
+        if let Some(&token) = forced_text_tokens.get(step_idx) {
+            for beam in beams.iter_mut() {
+                let prev = prev_text_token(beam);
+                let (logits, ys) =
+                    beam.state.text_logits(Some(prev), codes, conditions.as_ref())?;
+                beam.state.finish_step(token, &logits, &ys)?;
+            }
+            continue;
+        }
+        let mut candidates = vec![];
+        let mut outputs = vec![];
+        for (beam_idx, beam) in beams.iter_mut().enumerate() {
+            let prev = prev_text_token(beam);
+            let (logits, ys) = beam.state.text_logits(Some(prev), codes, conditions.as_ref())?;
+            let logprobs = text_logprobs(&logits, config.text_pad_token, args.pad_bias)?;
+            for (token, token_logprob) in top_k(&logprobs, num_beams) {
+                let logprob = beam.logprob + token_logprob as f64;
+                let num_text_tokens = beam.num_text_tokens + !is_pad(token) as usize;
+                let score = logprob / length_penalty(num_text_tokens);
+                candidates.push(Candidate {
+                    beam: beam_idx,
+                    token,
+                    logprob,
+                    num_text_tokens,
+                    score,
+                })
+            }
+            outputs.push((logits, ys));
+        }
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        candidates.truncate(num_beams);
+
+        // The beams selected more than once are forked before they move to the next step.
+        let mut next_beams = Vec::with_capacity(candidates.len());
+        let mut used = vec![false; beams.len()];
+        for candidate in candidates.iter() {
+            if !std::mem::replace(&mut used[candidate.beam], true) {
+                continue;
+            }
+            let parent = &beams[candidate.beam];
+            let (audio_lp, text_lp) = processors();
+            let mut state = parent.state.fork(
+                models.lm_model.clone(),
+                audio_lp,
+                text_lp,
+                conditions.as_ref(),
+            )?;
+            let prev = prev_text_token(parent);
+            let (logits, ys) = state.text_logits(Some(prev), codes, conditions.as_ref())?;
+            state.finish_step(candidate.token, &logits, &ys)?;
+            let Candidate { logprob, num_text_tokens, .. } = *candidate;
+            next_beams.push(Beam { state, logprob, num_text_tokens })
+        }
+        let mut parents: Vec<_> = beams.into_iter().zip(outputs).map(Some).collect();
+        for candidate in candidates.iter() {
+            if let Some((mut beam, (logits, ys))) = parents[candidate.beam].take() {
+                beam.state.finish_step(candidate.token, &logits, &ys)?;
+                beam.logprob = candidate.logprob;
+                beam.num_text_tokens = candidate.num_text_tokens;
+                next_beams.push(beam)
+            }
+        }
+        beams = next_beams;
+    }
+    let score = |beam: &Beam| beam.logprob / length_penalty(beam.num_text_tokens);
+    let best = beams.iter().max_by(|a, b| score(a).total_cmp(&score(b)));
+    Ok(best.map_or_else(Vec::new, |beam| beam.state.text_tokens(false).to_vec()))
+}
+
+/// Runs the beam search on the whole input, chunk by chunk, and returns the text token of each
+/// step. The warm-up steps of a chunk are forced to the text of the previous one.
+pub fn search(
+    args: &crate::gen::Args,
+    models: &mut crate::gen::Models,
+    in_pcm: &[f32],
+    chunks: &[crate::longform::Chunk],
+    dev: &Device,
+) -> Result<Vec<u32>> {
+    let start_time = std::time::Instant::now();
+    let mut text_tokens: Vec<u32> = vec![];
+    for chunk in chunks.iter() {
+        let forced = &text_tokens[chunk.start.min(text_tokens.len())..];
+        let tokens = search_chunk(args, models, in_pcm, chunk, forced, dev)?;
+        let own_start = (chunk.own_start - chunk.start).min(tokens.len());
+        let own_end = (chunk.own_end - chunk.start).clamp(own_start, tokens.len());
+        text_tokens.truncate(chunk.own_start);
+        text_tokens.extend_from_slice(&tokens[own_start..own_end]);
+    }
+    let dt = start_time.elapsed().as_secs_f32();
+    tracing::info!(beams = args.text_beams, steps = text_tokens.len(), "beam search in {dt:.2}s");
+    Ok(text_tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logprobs_with_pad_bias() -> Result<()> {
+        let logits = Tensor::new(&[0f32, 0., 0., 0.], &Device::Cpu)?;
+        let logprobs = text_logprobs(&logits, 3, None)?;
+        assert!(logprobs.iter().all(|v| (v - 0.25f32.ln()).abs() < 1e-6));
+        let logprobs = text_logprobs(&logits, 3, Some(2f32.ln()))?;
+        assert!((logprobs[3] - 0.4f32.ln()).abs() < 1e-6);
+        assert!((logprobs[0] - 0.2f32.ln()).abs() < 1e-6);
+        let total: f32 = logprobs.iter().map(|v| v.exp()).sum();
+        assert!((total - 1.).abs() < 1e-6);
+        Ok(())
+    }
+
+    #[test]
+    fn top_k_tokens() {
+        let logprobs = [-3., -0.5, -2., -1., -4.];
+        let mut top = top_k(&logprobs, 3);
+        top.sort_by(|a, b| b.1.total_cmp(&a.1));
+        assert_eq!(top, [(1, -0.5), (3, -1.), (2, -2.)]);
+        assert_eq!(top_k(&logprobs, 10).len(), 5);
+    }
+
+    #[test]
+    fn length_normalization() {
+        assert_eq!(length_penalty(1), 1.);
+        // With the same log-probability, the beam emitting more words scores higher.
+        assert!(-10. / length_penalty(8) > -10. / length_penalty(2));
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use anyhow::Result;
+use candle::quantized::GgmlDType;
+use candle::{DType, Device, DeviceLocation, Tensor};
+use std::collections::BTreeMap;
+
+const WARMUP_STEPS: usize = 3;
+const BENCH_STEPS: usize = 10;
+const BENCH_FRAMES: usize = 24;
+const FRAMES_PER_BATCH: [usize; 3] = [1, 2, 4];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub dtype: DType,
+    pub frames_per_batch: usize,
+}
+
+impl Settings {
+    pub fn default_for(dev: &Device) -> Self {
+        Self { dtype: dev.bf16_default_to_f32(), frames_per_batch: 1 }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Entry {
+    dtype: String,
+    frames_per_batch: usize,
+    lm_ms_per_step: f64,
+    mimi_ms_per_frame: f64,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct CalibrationFile {
+    #[serde(default)]
+    entries: BTreeMap<String, Entry>,
+}
+
+fn cache_file() -> Option<std::path::PathBuf> {
+    dirs::cache_dir().map(|d| d.join("hibiki").join("calibration.toml"))
+}
+
+pub fn device_key(dev: &Device) -> String {
+    match dev.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
+
+// The matrices of a quantized lm do not depend on the dtype, only the default one is tried.
+fn candidate_dtypes(dev: &Device, quantize_on_load: Option<GgmlDType>) -> Vec<DType> {
+    if quantize_on_load.is_some() {
+        vec![dev.bf16_default_to_f32()]
+    } else if dev.is_cpu() {
+        vec![DType::F32]
+    } else if dev.supports_bf16() {
+        vec![DType::BF16, DType::F16]
+    } else {
+        vec![DType::F16, DType::F32]
+    }
+}
+
+fn bench_lm(
+    lm_config: &moshi::lm::Config,
+    lm_model_file: &std::path::Path,
+    quantize_on_load: Option<GgmlDType>,
+    dtype: DType,
+    dev: &Device,
+) -> Result<f64> {
+    // The lm is loaded as for the generation, the quantized matrices run at a different speed.
+    let lm_model = match quantize_on_load {
+        None => crate::crypt::load_lm_model(lm_config.clone(), lm_model_file, dtype, dev)?,
+        Some(qdtype) => {
+            crate::quantize::load_lm_model(lm_config.clone(), lm_model_file, qdtype, dev)?
+        }
+    };
+    let config = crate::gen::multistream_config(lm_config);
+    let input_codes = vec![0u32; config.input_audio_codebooks];
+    let text_start_token = config.text_start_token;
+    let lp = |seed| {
+        candle_transformers::generation::LogitsProcessor::from_sampling(
+            seed,
+            candle_transformers::generation::Sampling::ArgMax,
+        )
+    };
+    let mut state = moshi::lm_generate_multistream::State::new(
+        lm_model,
+        WARMUP_STEPS + BENCH_STEPS + 20,
+        lp(0),
+        lp(0),
+        None,
+        None,
+        None,
+        config,
+    );
+    let mut text_token = text_start_token;
+    for _ in 0..WARMUP_STEPS {
+        text_token = state.step_(Some(text_token), &input_codes, None, None, None)?;
+    }
+    let start_time = std::time::Instant::now();
+    for _ in 0..BENCH_STEPS {
+        text_token = state.step_(Some(text_token), &input_codes, None, None, None)?;
+    }
+    Ok(start_time.elapsed().as_secs_f64() * 1000. / BENCH_STEPS as f64)
+}
+
+fn bench_mimi(
+    codec: &mut dyn crate::codec::AudioCodec,
+    frames_per_batch: usize,
+    dev: &Device,
+) -> Result<f64> {
+    codec.reset_state();
+    let pcm = Tensor::zeros((1, 1, codec.frame_size() * frames_per_batch), DType::F32, dev)?;
+    let nchunks = BENCH_FRAMES / frames_per_batch;
+    let start_time = std::time::Instant::now();
+    for _ in 0..nchunks {
+        if let Some(codes) = codec.encode_step(&pcm)? {
+            codes.to_device(&Device::Cpu)?;
+        }
+    }
+    Ok(start_time.elapsed().as_secs_f64() * 1000. / (nchunks * frames_per_batch) as f64)
+}
+
+fn calibrate(
+    lm_config: &moshi::lm::Config,
+    lm_model_file: &std::path::Path,
+    quantize_on_load: Option<GgmlDType>,
+    mimi_model_file: &std::path::Path,
+    dev: &Device,
+) -> Result<Entry> {
+    let mut best_dtype = None;
+    for dtype in candidate_dtypes(dev, quantize_on_load) {
+        match bench_lm(lm_config, lm_model_file, quantize_on_load, dtype, dev) {
+            Ok(ms) => {
+                tracing::info!(?dtype, ms, "calibration lm step");
+                if best_dtype.is_none_or(|(_, best_ms)| ms < best_ms) {
+                    best_dtype = Some((dtype, ms))
+                }
+            }
+            Err(err) => tracing::warn!(?dtype, ?err, "calibration failed for dtype"),
+        }
+    }
+    let (dtype, lm_ms_per_step) = match best_dtype {
+        None => anyhow::bail!("no dtype could be benchmarked on {dev:?}"),
+        Some(v) => v,
+    };
+    let generated_audio_codebooks = lm_config.depformer.as_ref().map_or(8, |v| v.num_slices);
+    let mut codec = crate::codec::load(mimi_model_file, generated_audio_codebooks, dev)?;
+    let mut best_fpb = (1, f64::INFINITY);
+    for frames_per_batch in FRAMES_PER_BATCH {
+        let ms = bench_mimi(codec.as_mut(), frames_per_batch, dev)?;
+        tracing::info!(frames_per_batch, ms, "calibration mimi frame");
+        if ms < best_fpb.1 {
+            best_fpb = (frames_per_batch, ms)
+        }
+    }
+    Ok(Entry {
+        dtype: dtype.as_str().to_string(),
+        frames_per_batch: best_fpb.0,
+        lm_ms_per_step,
+        mimi_ms_per_frame: best_fpb.1,
+    })
+}
+
+// The checkpoints are identified by their path together with their size and modification time,
+// so that a file replaced by another checkpoint gets calibrated again. The lm quantized on load
+// has its own entry.
+fn cache_key(
+    lm_model_file: &std::path::Path,
+    quantize_on_load: Option<GgmlDType>,
+    dev: &Device,
+) -> String {
+    let key = format!("{}/{}", device_key(dev), crate::memory::file_key(lm_model_file));
+    match quantize_on_load {
+        None => key,
+        Some(qdtype) => format!("{key}/{qdtype:?}"),
+    }
+}
+
+fn read_cache_file(cache_file: Option<&std::path::Path>) -> Result<CalibrationFile> {
+    match cache_file {
+        Some(f) if f.exists() => Ok(toml::from_str(&std::fs::read_to_string(f)?)?),
+        _ => Ok(CalibrationFile::default()),
+    }
+}
+
+/// Returns the settings and the measured time per step in ms from the calibration cache, without
+/// running the benchmark. `None` if this device and model have not been calibrated yet.
+pub fn cached(
+    lm_model_file: &std::path::Path,
+    quantize_on_load: Option<GgmlDType>,
+    dev: &Device,
+) -> Result<Option<(Settings, f64)>> {
+    let calibration = read_cache_file(cache_file().as_deref())?;
+    match calibration.entries.get(&cache_key(lm_model_file, quantize_on_load, dev)) {
+        None => Ok(None),
+        Some(entry) => {
+            let settings =
+                Settings { dtype: entry.dtype.parse()?, frames_per_batch: entry.frames_per_batch };
+            Ok(Some((settings, entry.lm_ms_per_step + entry.mimi_ms_per_frame)))
+        }
+    }
+}
+
+/// Returns the settings from the calibration cache for this device and model, running the
+/// micro-benchmarks and persisting their results if there is no such entry yet. The lm is
+/// benchmarked quantized to `quantize_on_load` if set, as it is then loaded for the generation.
+pub fn load_or_calibrate(
+    lm_config: &moshi::lm::Config,
+    lm_model_file: &std::path::Path,
+    quantize_on_load: Option<GgmlDType>,
+    mimi_model_file: &std::path::Path,
+    dev: &Device,
+) -> Result<Settings> {
+    let key = cache_key(lm_model_file, quantize_on_load, dev);
+    let cache_file = cache_file();
+    let mut calibration = read_cache_file(cache_file.as_deref())?;
+    let entry = match calibration.entries.get(&key) {
+        Some(entry) => entry.clone(),
+        None => {
+            tracing::info!(key, "no calibration found, running the benchmark");
+            let entry =
+                calibrate(lm_config, lm_model_file, quantize_on_load, mimi_model_file, dev)?;
+            calibration.entries.insert(key.clone(), entry.clone());
+            if let Some(f) = cache_file.as_ref() {
+                if let Some(parent) = f.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(f, toml::to_string(&calibration)?)?;
+                tracing::info!(?f, "saved calibration");
+            }
+            entry
+        }
+    };
+    tracing::info!(key, ?entry, "using calibrated settings");
+    Ok(Settings { dtype: entry.dtype.parse()?, frames_per_batch: entry.frames_per_batch })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_keys() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("hibiki-calibrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let lm_model_file = dir.join("lm.safetensors");
+        std::fs::write(&lm_model_file, b"weights")?;
+        let key = cache_key(&lm_model_file, None, &Device::Cpu);
+        assert!(key.starts_with("cpu/"), "{key}");
+        assert_eq!(key, cache_key(&lm_model_file, None, &Device::Cpu));
+        let quantized = cache_key(&lm_model_file, Some(GgmlDType::Q8_0), &Device::Cpu);
+        assert_eq!(quantized, format!("{key}/Q8_0"));
+        // A different checkpoint at the same path is calibrated again.
+        std::fs::write(&lm_model_file, b"other weights")?;
+        assert_ne!(key, cache_key(&lm_model_file, None, &Device::Cpu));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn dtypes() {
+        assert_eq!(candidate_dtypes(&Device::Cpu, None), [DType::F32]);
+        assert_eq!(candidate_dtypes(&Device::Cpu, Some(GgmlDType::Q4_0)), [DType::F32]);
+    }
+
+    #[test]
+    fn cache_file_roundtrip() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("hibiki-calibration-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("calibration.toml");
+        assert!(read_cache_file(Some(&path))?.entries.is_empty());
+        assert!(read_cache_file(None)?.entries.is_empty());
+        let entry = Entry {
+            dtype: "bf16".to_string(),
+            frames_per_batch: 2,
+            lm_ms_per_step: 40.,
+            mimi_ms_per_frame: 3.5,
+        };
+        let mut calibration = CalibrationFile::default();
+        calibration.entries.insert("cuda:0/lm".to_string(), entry);
+        std::fs::write(&path, toml::to_string(&calibration)?)?;
+        let entry = &read_cache_file(Some(&path))?.entries["cuda:0/lm"];
+        assert_eq!(entry.dtype.parse::<DType>()?, DType::BF16);
+        assert_eq!(entry.frames_per_batch, 2);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Chapters for long translated recordings, a new chapter starts on a long pause in the
+// translation once the current one is long enough, and it is titled with its first sentence.
+// The format is picked from the file extension, podcast namespace JSON chapters for `.json` and
+// ffmetadata otherwise, e.g. for `ffmpeg -i out.wav -i chapters.txt -map_metadata 1 out.m4a`.
+
+use anyhow::Result;
+
+// A pause of 2s in the translation, most of them happen on topic or speaker changes.
+const MIN_PAUSE_STEPS: usize = 25;
+// Chapters last at least a minute so that the pauses within a topic do not split it.
+const MIN_CHAPTER_STEPS: usize = 750;
+const MAX_TITLE_CHARS: usize = 60;
+
+struct Chapter {
+    title: String,
+    start_step: usize,
+}
+
+fn chapters(words: &[crate::alignment::Word]) -> Vec<Chapter> {
+    let mut chapters: Vec<Chapter> = vec![];
+    let mut title_done = false;
+    let mut prev_end_step = 0;
+    for word in words.iter() {
+        let split = match chapters.last() {
+            None => true,
+            Some(chapter) => {
+                word.start_step >= prev_end_step + MIN_PAUSE_STEPS
+                    && word.start_step >= chapter.start_step + MIN_CHAPTER_STEPS
+            }
+        };
+        if split {
+            // The first chapter starts with the recording.
+            let start_step = if chapters.is_empty() { 0 } else { word.start_step };
+            chapters.push(Chapter { title: String::new(), start_step });
+            title_done = false
+        }
+        if let Some(chapter) = chapters.last_mut().filter(|_| !title_done) {
+            let len = chapter.title.chars().count() + 1 + word.text.chars().count();
+            if len > MAX_TITLE_CHARS && !chapter.title.is_empty() {
+                chapter.title.push('…');
+                title_done = true
+            } else {
+                if !chapter.title.is_empty() {
+                    chapter.title.push(' ')
+                }
+                chapter.title.push_str(&word.text);
+                title_done = word.text.ends_with(['.', '!', '?'])
+            }
+        }
+        prev_end_step = word.end_step
+    }
+    chapters
+}
+
+// Escapes the characters that are special in ffmetadata values.
+fn ffmetadata_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\')
+        }
+        escaped.push(c)
+    }
+    escaped
+}
+
+/// Writes the chapters for the words, timed in the final output audio. Returns the number of
+/// chapters.
+pub fn write(
+    path: &std::path::Path,
+    words: &[crate::alignment::Word],
+    timeline: &crate::alignment::Timeline,
+    language: &crate::lang::Language,
+) -> Result<usize> {
+    let chapters = chapters(words);
+    let duration = timeline.duration();
+    let starts: Vec<f64> = chapters.iter().map(|c| timeline.secs(c.start_step)).collect();
+    let json = path.extension().is_some_and(|v| v.eq_ignore_ascii_case("json"));
+    if json {
+        let chapters: Vec<_> = chapters
+            .iter()
+            .zip(starts.iter())
+            .map(|(chapter, start)| serde_json::json!({ "startTime": start, "title": chapter.title }))
+            .collect();
+        let json = serde_json::json!({
+            "version": "1.2.0",
+            "language": language.tag(),
+            "chapters": chapters,
+        });
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(file, &json)?;
+    } else {
+        let ms = |secs: f64| (secs * 1000.).round() as u64;
+        let mut out = format!(";FFMETADATA1\nlanguage={}\n", language.tag());
+        for (idx, chapter) in chapters.iter().enumerate() {
+            let end = starts.get(idx + 1).copied().unwrap_or(duration);
+            out.push_str(&format!(
+                "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+                ms(starts[idx]),
+                ms(end),
+                ffmetadata_escape(&chapter.title)
+            ));
+        }
+        std::fs::write(path, out)?;
+    }
+    Ok(chapters.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words() -> Vec<crate::alignment::Word> {
+        let word = |text: &str, start_step: usize| crate::alignment::Word {
+            text: text.to_string(),
+            start_step,
+            end_step: start_step + 2,
+        };
+        let mut words = vec![word("Welcome", 5), word("everyone.", 8), word("Today", 12)];
+        // Long pauses before the first chapter is long enough, then a pause that is too short.
+        words.extend([word("we", 100), word("start.", 700), word("Soon", 740), word("now", 760)]);
+        let second = "This second part is about a very long topic that keeps going on and on";
+        words.extend(second.split(' ').enumerate().map(|(idx, text)| word(text, 810 + 3 * idx)));
+        words
+    }
+
+    #[test]
+    fn boundaries() {
+        let chapters = chapters(&words());
+        let chapters: Vec<_> = chapters.iter().map(|c| (c.start_step, c.title.as_str())).collect();
+        assert_eq!(
+            chapters,
+            [
+                (0, "Welcome everyone."),
+                (810, "This second part is about a very long topic that keeps going…"),
+            ]
+        );
+        assert!(chapters[1].1.chars().count() <= MAX_TITLE_CHARS + 1);
+        assert!(super::chapters(&[]).is_empty());
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(ffmetadata_escape("Plain text"), "Plain text");
+        assert_eq!(ffmetadata_escape("a=b;c#d\\e\nf"), "a\\=b\\;c\\#d\\\\e\\\nf");
+    }
+
+    #[test]
+    fn formats() {
+        let dir = std::env::temp_dir().join(format!("hibiki-chapters-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // 80ms per step, for 1000 steps.
+        let step_offsets: Vec<usize> = (0..=1000).map(|step| step * 80).collect();
+        let timeline = crate::alignment::Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 0,
+            skip: 0,
+            scale: 1.,
+            sample_rate: 1000,
+        };
+        let language: crate::lang::Language = "en".parse().unwrap();
+        let mut words = words();
+        words[0].text = "Q&A=fun;".to_string();
+
+        let path = dir.join("chapters.txt");
+        assert_eq!(write(&path, &words, &timeline, &language).unwrap(), 2);
+        let ffmetadata = std::fs::read_to_string(&path).unwrap();
+        let expected = ";FFMETADATA1\nlanguage=en\n\
+            \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=64800\ntitle=Q&A\\=fun\\; everyone.\n\
+            \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=64800\nEND=80000\n\
+            title=This second part is about a very long topic that keeps going…\n";
+        assert_eq!(ffmetadata, expected);
+
+        let path = dir.join("chapters.json");
+        assert_eq!(write(&path, &words, &timeline, &language).unwrap(), 2);
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["version"], "1.2.0");
+        assert_eq!(json["language"], "en");
+        assert_eq!(json["chapters"][0]["startTime"], 0.);
+        assert_eq!(json["chapters"][0]["title"], "Q&A=fun; everyone.");
+        assert_eq!(json["chapters"][1]["startTime"], 64.8);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
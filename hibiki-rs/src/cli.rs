@@ -0,0 +1,627 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The generation flags shared by the subcommands, and by the C bindings which take the same
+// flags as `hibiki gen`.
+
+use anyhow::{Context, Result};
+use candle::Device;
+
+use crate::{audio_io, calibrate, gen, hub, lang, quantize, quiet, resources};
+
+#[derive(Debug, clap::Args)]
+pub struct GenArgs {
+    #[arg(long)]
+    lm_model_file: Option<String>,
+
+    #[arg(long)]
+    mimi_model_file: Option<String>,
+
+    #[arg(long)]
+    config: Option<String>,
+
+    #[arg(long)]
+    text_tokenizer: Option<String>,
+
+    /// The hub repo the model files are downloaded from when not given as local paths, "1b" and
+    /// "2b" are short for the released models.
+    #[arg(long, default_value = "kyutai/hibiki-1b-rs-bf16")]
+    hf_repo: String,
+
+    #[arg(long, default_value_t = 299_792_458)]
+    seed: u64,
+
+    #[arg(long)]
+    cfg_alpha: Option<f64>,
+
+    /// Run on cpu
+    #[arg(long)]
+    cpu: bool,
+
+    /// The dtype for the lm weights (bf16, f16, f32), defaults to the calibrated value.
+    #[arg(long)]
+    dtype: Option<String>,
+
+    /// The number of 80ms frames passed to the audio tokenizer at once, defaults to the
+    /// calibrated value.
+    #[arg(long)]
+    frames_per_batch: Option<usize>,
+
+    /// Do not run the calibration benchmark, use the default settings for the device.
+    #[arg(long)]
+    no_calibrate: bool,
+
+    /// Annotate silences, laughter, applause and music as bracketed events in the transcript,
+    /// the subtitles and the json output.
+    #[arg(long)]
+    mark_events: bool,
+
+    /// Coalesce the printed text to at most this many updates per second, only committing
+    /// whole words.
+    #[arg(long)]
+    max_text_updates_per_sec: Option<f64>,
+
+    /// Directory used as a translation memory, inputs that have already been translated with
+    /// the same settings reuse the stored outputs instead of being regenerated. The sidecar
+    /// outputs, e.g. the transcript or the subtitles, are restored too, it is not used with
+    /// --trace.
+    #[arg(long)]
+    translation_memory: Option<String>,
+
+    /// Number of alternative takes to generate, the outputs are numbered out_1.wav,
+    /// out_2.wav, etc. Each take uses a different audio seed.
+    #[arg(long, default_value_t = 1)]
+    num_takes: usize,
+
+    /// Keep the text of the first take for all the subsequent takes so that only the audio
+    /// realization differs.
+    #[arg(long)]
+    keep_text: bool,
+
+    /// Bias added to the logit of the text padding token, also with greedy sampling, negative
+    /// values make the model speak earlier and more densely.
+    #[arg(long, allow_hyphen_values = true)]
+    pad_bias: Option<f32>,
+
+    /// Fit the generated audio to the duration of the input: the model is given some extra
+    /// time to complete the translation after the input ends, then the latency is trimmed
+    /// and the output is time-stretched, cut or padded to the source length. The whole output
+    /// is stretched with a single ratio, the segments of a batch manifest are fitted one by one.
+    #[arg(long)]
+    fit_duration: bool,
+
+    /// Maximum relative time-stretch used by --fit-duration, without changing the pitch.
+    #[arg(long, default_value_t = 0.15)]
+    max_stretch: f64,
+
+    /// Shift the pitch of the generated voice by this number of semitones, the formants are
+    /// preserved unless --formant-shift is also set.
+    #[arg(long, default_value_t = 0., allow_hyphen_values = true)]
+    pitch_shift: f64,
+
+    /// Shift the formants of the generated voice by this number of semitones.
+    #[arg(long, default_value_t = 0., allow_hyphen_values = true)]
+    formant_shift: f64,
+
+    /// Maximum number of 80ms generation steps, the kv-cache is sized accordingly and longer
+    /// inputs are translated in overlapping chunks of this many steps.
+    #[arg(long, default_value_t = resources::DEFAULT_MAX_STEPS)]
+    max_steps: usize,
+
+    /// Log a warning each time a batch of steps takes longer than the audio it covers, together
+    /// with the accumulated lag behind real-time.
+    #[arg(long)]
+    warn_slow_steps: bool,
+
+    /// Memory budget for the lm in MB, this caps the kv-cache size (and so --max-steps) so
+    /// that other workloads can share the device.
+    #[arg(long)]
+    gpu_mem_limit: Option<usize>,
+
+    /// Memory budget for the lm as a fraction of the total device memory.
+    #[arg(long)]
+    gpu_mem_fraction: Option<f64>,
+
+    /// Write the transcript to this file, it is saved periodically during the generation so that
+    /// the text is not lost if the process dies.
+    #[arg(long)]
+    transcript_file: Option<String>,
+
+    /// Interval in seconds between two saves of the transcript file.
+    #[arg(long, default_value_t = 30.)]
+    autosave_secs: f64,
+
+    /// Write the raw text token ids and audio token arrays generated at each step to this json
+    /// file, e.g. to compare the behavior with the PyTorch implementation.
+    #[arg(long)]
+    emit_token_ids: Option<String>,
+
+    /// Json file of reference tokens, in the --emit-token-ids format, e.g. produced by the
+    /// PyTorch implementation. Greedy decoding is used and the first step where the generated
+    /// tokens diverge from the reference is reported.
+    #[arg(long)]
+    parity_reference: Option<String>,
+
+    /// Write a trace of the generation to this file: the text tokens and their probabilities,
+    /// the input and output levels and the processing times of the steps. With a .html
+    /// extension the trace is embedded in a page that plots it.
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Replace the generated audio with the original one where the probability of the
+    /// translated text falls below this threshold, e.g. 0.3.
+    #[arg(long)]
+    min_text_confidence: Option<f32>,
+
+    /// Quantize the lm weights when loading them, int8 roughly halves the memory usage compared
+    /// to bf16 and int4 quarters it, this does not require a pre-converted gguf file.
+    #[arg(long)]
+    quantize_on_load: Option<Quantization>,
+
+    /// Run the lm with quantized weights: a gguf lm file is used as is, e.g. with
+    /// --lm-model-file model.q4k.gguf, and a safetensors file is quantized on load to int8
+    /// unless --quantize-on-load is given. The audio codec always runs in full precision.
+    #[arg(long)]
+    quantized: bool,
+
+    /// Skip the depformer and repeat the previous audio tokens while the generated audio is
+    /// silent and the model is not emitting text, this saves compute on mostly silent inputs.
+    #[arg(long)]
+    skip_silent_depformer: bool,
+
+    /// The sample format of the output wav files.
+    #[arg(long, value_enum, default_value = "16")]
+    bit_depth: audio_io::WavFormat,
+
+    /// Write the output wav in stereo, with the source on the left channel aligned with the
+    /// translation on the right one, e.g. to review the translations.
+    #[arg(long)]
+    stereo_mix: bool,
+
+    /// Write a single dubbed track, the translation being mixed over the source which is ducked
+    /// while the translation speaks.
+    #[arg(long, conflicts_with = "stereo_mix")]
+    dub_mix: bool,
+
+    /// The gain of the source under the translation with --dub-mix, in dB.
+    #[arg(long, default_value_t = -15., allow_hyphen_values = true)]
+    duck_db: f32,
+
+    /// Resample the output audio to this rate in Hz, e.g. 16000 for telephony or 48000 for
+    /// video, rather than the 24kHz of the codec.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(8000..=192000))]
+    output_sample_rate: Option<u32>,
+
+    /// Blend of description conditions used in place of "very_good", as comma separated
+    /// value:weight pairs, e.g. "very_good:0.7,good:0.3". The weights are used as is.
+    #[arg(long)]
+    condition_mix: Option<String>,
+
+    /// BCP-47 tag of the language of the generated text, used to tag the transcript outputs.
+    #[arg(long, default_value = "en")]
+    target_language: lang::Language,
+
+    /// Play the translated audio on the sound card as it is generated.
+    #[arg(long)]
+    play: bool,
+
+    /// The ALSA playback device used with --play, see the devices subcommand.
+    #[arg(long, default_value = "default")]
+    play_device: String,
+
+    /// The audio buffered before the playback starts, in ms. Raise it if the playback stutters
+    /// when the generation is barely faster than real-time.
+    #[arg(long, default_value_t = 160)]
+    play_prebuffer_ms: u64,
+
+    /// The size of the sound card buffer in ms, the aplay default when not set. Larger buffers
+    /// avoid underruns on some hardware, smaller ones reduce the latency.
+    #[arg(long)]
+    play_buffer_ms: Option<u64>,
+
+    /// In live mode, adjust the playback rate by up to 0.2% so that the playback neither lags
+    /// nor underruns as the clocks of the capture and playback devices drift apart on long
+    /// sessions. The sound card buffer is then kept full, --play-buffer-ms bounds the latency.
+    #[arg(long)]
+    play_drift_compensation: bool,
+
+    /// In live mode with --start-tone, the seconds of audio captured before the start tone that
+    /// are translated first, so that a speaker starting slightly early is not clipped.
+    #[arg(long, default_value_t = 0.)]
+    pre_roll_secs: f64,
+
+    /// Write the sample range of each generated word in the output audio to this json file.
+    #[arg(long)]
+    word_alignment: Option<String>,
+
+    /// Write subtitles for the translated text to this file, in WebVTT format for a .vtt
+    /// extension and SRT otherwise. In live mode the cues are appended as they are finalized.
+    #[arg(long)]
+    subtitles: Option<String>,
+
+    /// Write the mean log-probability of the text and the mean step latency of each subtitle
+    /// cue to this json file, to review the least confident cues first.
+    #[arg(long)]
+    cue_stats: Option<String>,
+
+    /// Write chapters for long recordings to this file, split on the long pauses and titled with
+    /// their first sentence, as podcast JSON chapters for a .json extension and ffmetadata
+    /// otherwise.
+    #[arg(long)]
+    chapters: Option<String>,
+
+    /// Write a marker for each sentence of the translation to this file, with the turns after
+    /// long pauses flagged, to split the track into clips. As json for a .json extension and an
+    /// Audacity label track otherwise.
+    #[arg(long)]
+    clip_markers: Option<String>,
+
+    /// Write each generated text token to this json file, with its text, generation step, output
+    /// audio timestamp and log-probability, and the non-speech events with --mark-events.
+    #[arg(long)]
+    json_output: Option<String>,
+
+    /// Sampling temperature for the audio tokens, 0 for greedy decoding.
+    #[arg(long, default_value_t = 0.8)]
+    audio_temperature: f64,
+
+    /// Only sample the audio tokens among the k most likely ones, 0 to disable.
+    #[arg(long, default_value_t = 250)]
+    audio_top_k: usize,
+
+    /// Only sample the audio tokens among the most likely ones with this cumulative probability.
+    #[arg(long)]
+    audio_top_p: Option<f64>,
+
+    /// Lower the audio temperature for this many steps after the start and the end of each
+    /// utterance, this reduces the garbled onsets at the cost of some variety. 0 to disable.
+    #[arg(long, default_value_t = 0)]
+    audio_anneal_window: usize,
+
+    /// The audio temperature used around the utterance boundaries.
+    #[arg(long, default_value_t = 0.5)]
+    audio_anneal_temperature: f64,
+
+    /// Sampling temperature for the text tokens, 0 for greedy decoding.
+    #[arg(long, default_value_t = 0.8)]
+    text_temperature: f64,
+
+    /// Only sample the text tokens among the k most likely ones, 0 to disable.
+    #[arg(long, default_value_t = 25)]
+    text_top_k: usize,
+
+    /// Only sample the text tokens among the most likely ones with this cumulative probability.
+    #[arg(long)]
+    text_top_p: Option<f64>,
+
+    /// Run a beam search over the text with this many beams rather than sampling it, for
+    /// offline translation where latency does not matter. The audio is generated afterwards
+    /// with the text of the best beam, each beam needs its own kv-cache.
+    #[arg(long, default_value_t = 1)]
+    text_beams: usize,
+
+    /// A text file with a draft translation, e.g. edited from a previous transcript, the text
+    /// is then constrained to follow it so that the audio matches the edited wording.
+    #[arg(long)]
+    draft: Option<String>,
+
+    /// The number of tokens that can differ from the draft, substituted, inserted or skipped.
+    #[arg(long, default_value_t = 0)]
+    draft_max_edits: usize,
+
+    /// End the generation once this text has been generated, e.g. the closing phrase of an
+    /// announcement. The audio of the phrase is completed first. Can be repeated, in pipe, live
+    /// and serve modes a new context is started instead.
+    #[arg(long = "stop", value_name = "TEXT")]
+    stop_sequences: Vec<String>,
+
+    /// Only translate the input from this position, as seconds or [hh:]mm:ss[.ms].
+    #[arg(long, value_parser = audio_io::parse_timestamp)]
+    start: Option<f64>,
+
+    /// Only translate the input up to this position, as seconds or [hh:]mm:ss[.ms].
+    #[arg(long, value_parser = audio_io::parse_timestamp)]
+    end: Option<f64>,
+
+    /// Resolve the models, validate the config and input files, then print the plan with the
+    /// memory and runtime estimates and exit, without loading the weights. Model files that are
+    /// not in the hub cache are not downloaded, except for the small config.toml of the repo
+    /// which gives their names.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Do not log nor print the text, only a json line with the status and the output paths
+    /// once done, with stable exit codes, for scripts and automation tools.
+    #[arg(long)]
+    pub quiet: bool,
+
+    /// Show a progress bar with the speed and the remaining time on stderr.
+    #[arg(long)]
+    progress: bool,
+}
+
+fn parse_condition_mix(mix: &str) -> Result<Vec<(String, f64)>> {
+    mix.split(',')
+        .map(|entry| match entry.split_once(':') {
+            Some((value, weight)) => Ok((value.trim().to_string(), weight.trim().parse()?)),
+            None => anyhow::bail!("invalid condition mix entry '{entry}', expected value:weight"),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Quantization {
+    Int8,
+    Int4,
+}
+
+impl Quantization {
+    pub fn ggml_dtype(&self) -> candle::quantized::GgmlDType {
+        match self {
+            Self::Int8 => candle::quantized::GgmlDType::Q8_0,
+            Self::Int4 => candle::quantized::GgmlDType::Q4K,
+        }
+    }
+}
+
+pub fn device(cpu: bool) -> Result<Device> {
+    if cpu {
+        Ok(Device::Cpu)
+    } else if candle::utils::cuda_is_available() {
+        Ok(Device::new_cuda(0)?)
+    } else if candle::utils::metal_is_available() {
+        Ok(Device::new_metal(0)?)
+    } else {
+        Ok(Device::Cpu)
+    }
+}
+
+/// Logs to stderr, unless a subscriber has already been installed e.g. for `--tracing` or by an
+/// earlier call.
+pub fn init_logging() {
+    let _ = tracing_subscriber::fmt().with_writer(std::io::stderr).try_init();
+}
+
+impl GenArgs {
+    /// Resolves the model files, downloading them from the hub if necessary, and returns the
+    /// generation arguments for the given input and output files.
+    pub fn resolve(
+        self,
+        audio_input_file: String,
+        audio_output_file: String,
+    ) -> Result<(gen::Args, Device)> {
+        let GenArgs {
+            seed,
+            text_tokenizer,
+            lm_model_file,
+            config,
+            mimi_model_file,
+            hf_repo,
+            cfg_alpha,
+            cpu,
+            dtype,
+            frames_per_batch,
+            no_calibrate,
+            mark_events,
+            max_text_updates_per_sec,
+            translation_memory,
+            num_takes,
+            keep_text,
+            pad_bias,
+            fit_duration,
+            max_stretch,
+            pitch_shift,
+            formant_shift,
+            max_steps,
+            warn_slow_steps,
+            gpu_mem_limit,
+            gpu_mem_fraction,
+            transcript_file,
+            autosave_secs,
+            emit_token_ids,
+            parity_reference,
+            trace,
+            min_text_confidence,
+            quantize_on_load,
+            quantized,
+            skip_silent_depformer,
+            bit_depth,
+            stereo_mix,
+            dub_mix,
+            duck_db,
+            output_sample_rate,
+            condition_mix,
+            target_language,
+            play,
+            play_device,
+            play_prebuffer_ms,
+            play_buffer_ms,
+            play_drift_compensation,
+            pre_roll_secs,
+            word_alignment,
+            subtitles,
+            cue_stats,
+            chapters,
+            clip_markers,
+            json_output,
+            start,
+            end,
+            audio_temperature,
+            audio_top_k,
+            audio_top_p,
+            audio_anneal_window,
+            audio_anneal_temperature,
+            text_temperature,
+            text_top_k,
+            text_top_p,
+            text_beams,
+            draft,
+            draft_max_edits,
+            stop_sequences,
+            dry_run,
+            quiet,
+            progress,
+        } = self;
+        let dev = device(cpu)?;
+        // The weights are only looked up in the local cache for dry runs.
+        let repo = hub::Repo::new(&hf_repo, dry_run).context(quiet::Failure::Models)?;
+        let overrides = hub::Overrides {
+            config_file: config.map(|v| v.into()),
+            lm_model_file: lm_model_file.map(|v| v.into()),
+            mimi_model_file: mimi_model_file.map(|v| v.into()),
+            text_tokenizer: text_tokenizer.map(|v| v.into()),
+        };
+        let hub::ModelFiles { config_file, config, lm_model_file, mimi_model_file, text_tokenizer } =
+            hub::resolve(&repo, overrides).context(quiet::Failure::Models)?;
+        let quantize_on_load = match (quantize_on_load, quantize::is_gguf(&lm_model_file)) {
+            (Some(_), true) => {
+                anyhow::bail!("{lm_model_file:?} is already quantized, drop --quantize-on-load")
+            }
+            (None, false) if quantized => Some(Quantization::Int8.ggml_dtype()),
+            (quantize_on_load, _) => quantize_on_load.map(|v| v.ggml_dtype()),
+        };
+        let lm_quantized = quantize_on_load.is_some() || quantize::is_gguf(&lm_model_file);
+
+        let settings = if no_calibrate || (dtype.is_some() && frames_per_batch.is_some()) {
+            calibrate::Settings::default_for(&dev)
+        } else if dry_run {
+            match calibrate::cached(&lm_model_file, quantize_on_load, &dev)? {
+                Some((settings, _)) => settings,
+                None => calibrate::Settings::default_for(&dev),
+            }
+        } else {
+            match calibrate::load_or_calibrate(
+                &config.model,
+                &lm_model_file,
+                quantize_on_load,
+                &mimi_model_file,
+                &dev,
+            ) {
+                Ok(settings) => settings,
+                Err(err) => {
+                    tracing::warn!(?err, "calibration failed, using the default settings");
+                    calibrate::Settings::default_for(&dev)
+                }
+            }
+        };
+        let dtype = match dtype {
+            Some(dtype) => dtype.parse()?,
+            None => settings.dtype,
+        };
+        let frames_per_batch = frames_per_batch.unwrap_or(settings.frames_per_batch);
+
+        let fraction_budget = match gpu_mem_fraction {
+            None => None,
+            Some(fraction) => match resources::total_memory(&dev) {
+                Some(total) => Some((total as f64 * fraction) as usize),
+                None => anyhow::bail!("cannot determine the total memory of {dev:?}"),
+            },
+        };
+        let budget = [gpu_mem_limit.map(|v| v << 20), fraction_budget].into_iter().flatten().min();
+        let max_steps = match budget {
+            None => max_steps,
+            Some(budget) => {
+                let batch_size = if cfg_alpha.is_some_and(|v| v != 1.) { 2 } else { 1 };
+                let weights_bytes =
+                    resources::lm_weights_bytes(&lm_model_file, dtype, quantize_on_load)?;
+                let budget_steps = resources::max_steps_for_budget(
+                    &config.model,
+                    weights_bytes,
+                    budget,
+                    resources::kv_cache_dtype(dtype, lm_quantized),
+                    batch_size,
+                )?;
+                if budget_steps < max_steps {
+                    tracing::warn!(budget_steps, "reducing --max-steps to fit the memory budget");
+                }
+                max_steps.min(budget_steps)
+            }
+        };
+
+        if let (Some(start), Some(end)) = (start, end) {
+            if end <= start {
+                anyhow::bail!("--end should be after --start")
+            }
+        }
+        let args = gen::Args {
+            lm_config: config.model,
+            config_file,
+            lm_model_file,
+            mimi_model_file,
+            text_tokenizer,
+            audio_input_file: audio_input_file.into(),
+            audio_output_file: audio_output_file.into(),
+            seed,
+            cfg_alpha,
+            dtype,
+            frames_per_batch,
+            mark_events,
+            max_text_updates_per_sec,
+            translation_memory: translation_memory.map(|v| v.into()),
+            num_takes,
+            keep_text,
+            pad_bias,
+            fit_duration,
+            max_stretch,
+            pitch_shift,
+            formant_shift,
+            max_steps,
+            warn_slow_steps,
+            transcript_file: transcript_file.map(|v| v.into()),
+            autosave_secs,
+            emit_token_ids: emit_token_ids.map(|v| v.into()),
+            parity_reference: parity_reference.map(|v| v.into()),
+            trace: trace.map(|v| v.into()),
+            min_text_confidence,
+            quantize_on_load,
+            skip_silent_depformer,
+            wav_format: bit_depth,
+            stereo_mix,
+            dub_mix: dub_mix.then_some(duck_db),
+            output_sample_rate: output_sample_rate.map(|v| v as usize),
+            condition_mix: condition_mix.as_deref().map(parse_condition_mix).transpose()?,
+            target_language,
+            play: play.then_some(play_device),
+            play_buffer: audio_io::PlaybackBuffer {
+                prebuffer_secs: play_prebuffer_ms as f64 / 1000.,
+                device_secs: play_buffer_ms.map(|v| v as f64 / 1000.),
+                compensate_drift: play_drift_compensation,
+            },
+            pre_roll_secs,
+            word_alignment: word_alignment.map(|v| v.into()),
+            subtitles: subtitles.map(|v| v.into()),
+            cue_stats: cue_stats.map(|v| v.into()),
+            chapters: chapters.map(|v| v.into()),
+            clip_markers: clip_markers.map(|v| v.into()),
+            json_output: json_output.map(|v| v.into()),
+            start: start.unwrap_or(0.),
+            end,
+            audio_sampling: gen::SamplingParams {
+                temperature: audio_temperature,
+                top_k: (audio_top_k > 0).then_some(audio_top_k),
+                top_p: audio_top_p,
+            },
+            text_sampling: gen::SamplingParams {
+                temperature: text_temperature,
+                top_k: (text_top_k > 0).then_some(text_top_k),
+                top_p: text_top_p,
+            },
+            text_beams,
+            draft: match draft {
+                None => None,
+                Some(path) => Some(
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("cannot read the draft {path:?}"))?,
+                ),
+            },
+            draft_max_edits,
+            audio_anneal_window,
+            audio_anneal_temperature,
+            stop_sequences,
+            quiet,
+            progress,
+        };
+        Ok((args, dev))
+    }
+}
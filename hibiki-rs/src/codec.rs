@@ -0,0 +1,122 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The audio tokenizer interface used by the generation loop, so that codecs with different
+// frame rates or numbers of codebooks can be used in place of mimi.
+
+use anyhow::Result;
+use candle::{Device, Tensor};
+
+pub trait AudioCodec: Send {
+    /// The sample rate of the pcm data, in Hz.
+    fn sample_rate(&self) -> usize;
+
+    /// The number of pcm samples per codec frame, each frame corresponds to a generation step.
+    fn frame_size(&self) -> usize;
+
+    /// The number of codebooks produced for each frame.
+    fn num_codebooks(&self) -> usize;
+
+    /// Encodes a full pcm tensor of shape (batch, channels, samples) to codes of shape
+    /// (batch, codebooks, frames), without using the streaming state.
+    fn encode(&mut self, pcm: &Tensor) -> Result<Tensor>;
+
+    /// Decodes codes of shape (batch, codebooks, frames) to pcm data of shape
+    /// (batch, channels, samples), without using the streaming state.
+    fn decode(&mut self, codes: &Tensor) -> Result<Tensor>;
+
+    /// Streaming encoding, returns `None` when not enough samples have been accumulated to
+    /// produce a frame.
+    fn encode_step(&mut self, pcm: &Tensor) -> Result<Option<Tensor>>;
+
+    /// Streaming decoding of codes of shape (batch, codebooks, frames).
+    fn decode_step(&mut self, codes: &Tensor) -> Result<Option<Tensor>>;
+
+    /// Resets the streaming state, this has to be called between independent inputs.
+    fn reset_state(&mut self);
+}
+
+impl AudioCodec for moshi::mimi::Mimi {
+    fn sample_rate(&self) -> usize {
+        self.config().sample_rate as usize
+    }
+
+    fn frame_size(&self) -> usize {
+        (self.config().sample_rate / self.config().frame_rate) as usize
+    }
+
+    fn num_codebooks(&self) -> usize {
+        self.config().quantizer_n_q
+    }
+
+    fn encode(&mut self, pcm: &Tensor) -> Result<Tensor> {
+        Ok(moshi::mimi::Mimi::encode(self, pcm)?)
+    }
+
+    fn decode(&mut self, codes: &Tensor) -> Result<Tensor> {
+        Ok(moshi::mimi::Mimi::decode(self, codes)?)
+    }
+
+    fn encode_step(&mut self, pcm: &Tensor) -> Result<Option<Tensor>> {
+        let codes = moshi::mimi::Mimi::encode_step(self, &pcm.clone().into())?;
+        Ok(codes.as_option().cloned())
+    }
+
+    fn decode_step(&mut self, codes: &Tensor) -> Result<Option<Tensor>> {
+        let pcm = moshi::mimi::Mimi::decode_step(self, &codes.clone().into())?;
+        Ok(pcm.as_option().cloned())
+    }
+
+    fn reset_state(&mut self) {
+        moshi::mimi::Mimi::reset_state(self)
+    }
+}
+
+/// The number of pcm samples per frame of the codec returned by `load`, known without loading
+/// its weights.
+pub fn frame_size() -> usize {
+    let config = moshi::mimi::Config::v0_1(None);
+    (config.sample_rate / config.frame_rate) as usize
+}
+
+/// Loads the audio codec from its weights, only mimi is supported for now.
+pub fn load(
+    model_file: &std::path::Path,
+    num_codebooks: usize,
+    dev: &Device,
+) -> Result<Box<dyn AudioCodec>> {
+    let vb = if crate::quantize::is_gguf(model_file) {
+        tracing::warn!(?model_file, "dequantizing the audio codec, it runs in full precision");
+        crate::quantize::dequantized_var_builder(model_file, dev)?
+    } else {
+        crate::crypt::var_builder(model_file, candle::DType::F32, dev)?
+    };
+    let mimi = moshi::mimi::Mimi::new(moshi::mimi::Config::v0_1(Some(num_codebooks)), vb)?;
+    Ok(Box::new(mimi))
+}
+
+/// Encodes and decodes an audio file through the codec only, the output gives the best quality
+/// that can be expected from the generated audio.
+pub fn roundtrip(
+    codec: &mut dyn AudioCodec,
+    input: &std::path::Path,
+    output: &std::path::Path,
+    dev: &Device,
+) -> Result<()> {
+    let (pcm, sample_rate) = crate::audio_io::pcm_decode(input)?;
+    let pcm = if sample_rate as usize != codec.sample_rate() {
+        crate::audio_io::resample(&pcm, sample_rate as usize, codec.sample_rate())?
+    } else {
+        pcm
+    };
+    let pcm_len = pcm.len();
+    let pcm = Tensor::from_vec(pcm, (1, 1, pcm_len), dev)?;
+    let codes = codec.encode(&pcm)?;
+    tracing::info!(shape = ?codes.shape(), "encoded the input");
+    let out_pcm = codec.decode(&codes)?.flatten_all()?.to_vec1::<f32>()?;
+    let mut out_wav = std::fs::File::create(output)?;
+    moshi::wav::write_pcm_as_wav(&mut out_wav, &out_pcm, codec.sample_rate() as u32)?;
+    tracing::info!(?output, "wrote the decoded audio");
+    Ok(())
+}
@@ -0,0 +1,114 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Replacement of the generated audio by the original one where the model is not confident in
+// the translated text.
+
+// Number of steps after a low confidence text token during which the audio is replaced, this
+// roughly covers the word being spoken.
+const HOLD_STEPS: usize = 12;
+/// Duration of the crossfade between the generated and original audio, in seconds.
+pub const FADE_SECS: f64 = 0.02;
+
+/// Returns for each step whether the most recent text token has a probability below
+/// `threshold`. Padding tokens do not reset the state so that the whole word is covered.
+pub fn low_confidence_steps(
+    text_tokens: &[u32],
+    text_logprobs: &[f32],
+    threshold: f32,
+    is_pad: impl Fn(u32) -> bool,
+) -> Vec<bool> {
+    let mut low_steps_left = 0;
+    text_tokens
+        .iter()
+        .zip(text_logprobs.iter())
+        .map(|(&token, &logprob)| {
+            if !is_pad(token) {
+                low_steps_left = if logprob.exp() < threshold { HOLD_STEPS } else { 0 };
+            }
+            let low = low_steps_left > 0;
+            low_steps_left = low_steps_left.saturating_sub(1);
+            low
+        })
+        .collect()
+}
+
+/// Crossfades to the source audio on the audio of the steps flagged in `mask`, `source` being
+/// aligned with the generated audio, see `Timeline::align_source`. The crossfades last
+/// `fade_len` samples. Returns the number of steps replaced.
+pub fn replace_low_confidence(
+    generated: &mut [f32],
+    source: &[f32],
+    mask: &[bool],
+    timeline: &crate::alignment::Timeline,
+    fade_len: usize,
+) -> usize {
+    let mut low = vec![false; generated.len()];
+    let mut replaced = 0;
+    for (step, _) in mask.iter().enumerate().filter(|(_, &low)| low) {
+        let samples = timeline.samples(step);
+        let end = samples.end.min(low.len());
+        low[samples.start.min(end)..end].fill(true);
+        replaced += 1
+    }
+    let mut gain = 1f32;
+    let step = 1. / fade_len.max(1) as f32;
+    for (i, v) in generated.iter_mut().enumerate() {
+        let target = if low[i] { 0. } else { 1. };
+        gain = if gain < target { (gain + step).min(1.) } else { (gain - step).max(target) };
+        *v = gain * *v + (1. - gain) * source.get(i).copied().unwrap_or(0.);
+    }
+    replaced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_steps_cover_the_word() {
+        let is_pad = |t| t == 0;
+        let low = (0.1f32).ln();
+        let high = (0.9f32).ln();
+        let tokens = [5, 0, 6, 0, 0];
+        let mask = low_confidence_steps(&tokens, &[low, 0., high, 0., 0.], 0.5, is_pad);
+        assert_eq!(mask, [true, true, false, false, false]);
+        // A low confidence token is held over HOLD_STEPS steps of padding.
+        let mut tokens = vec![0; HOLD_STEPS + 2];
+        let mut logprobs = vec![0.; HOLD_STEPS + 2];
+        tokens[1] = 5;
+        logprobs[1] = low;
+        let mask = low_confidence_steps(&tokens, &logprobs, 0.5, is_pad);
+        assert_eq!(mask.iter().filter(|v| **v).count(), HOLD_STEPS);
+        assert!(!mask[0] && mask[1] && !mask[HOLD_STEPS + 1]);
+    }
+
+    #[test]
+    fn replace_with_crossfades() {
+        let step_offsets = [0, 100, 200, 300];
+        let timeline = crate::alignment::Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 0,
+            skip: 0,
+            scale: 1.,
+            sample_rate: 1000,
+        };
+        let mut generated = vec![1f32; 300];
+        let source = vec![-1f32; 300];
+        let replaced =
+            replace_low_confidence(&mut generated, &source, &[false, true, false], &timeline, 10);
+        assert_eq!(replaced, 1);
+        assert_eq!(generated[..100], [1.; 100]);
+        // The fade to the source starts with the flagged step.
+        assert!(generated[100] < 1. && generated[100] > -1.);
+        assert_eq!(generated[110..200], [-1.; 90]);
+        assert!(generated[205] > -1. && generated[205] < 1.);
+        assert_eq!(generated[210..], [1.; 90]);
+        // A longer crossfade, e.g. at a higher sample rate.
+        let mut generated = vec![1f32; 300];
+        replace_low_confidence(&mut generated, &source, &[false, true, false], &timeline, 50);
+        assert!(generated[140] > -1.);
+        assert_eq!(generated[150..200], [-1.; 50]);
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Encrypted model files, for deployments where the weights cannot sit unencrypted on shared
+// disks. The files are decrypted in memory while loading, the plaintext is never written out.
+//
+// The container starts with a magic and a random salt, the file key is derived from the master
+// key and the salt with HKDF-SHA256. The content follows as AES-256-GCM chunks of CHUNK_SIZE
+// bytes, each sealed with its index as the nonce and whether it is the last one as associated
+// data so that reordered or truncated files are rejected.
+//
+// The master key is given as 64 hex characters in HIBIKI_MODEL_KEY, or printed to stdout by
+// the shell command in HIBIKI_MODEL_KEY_COMMAND, e.g. a call to a KMS client.
+
+use anyhow::{Context, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use std::io::{Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"HBKENC01";
+const SALT_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN;
+const CHUNK_SIZE: usize = 1 << 20;
+const TAG_LEN: usize = 16;
+const KEY_ENV: &str = "HIBIKI_MODEL_KEY";
+const KEY_COMMAND_ENV: &str = "HIBIKI_MODEL_KEY_COMMAND";
+
+fn parse_hex_key(hex: &str) -> Result<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        anyhow::bail!("the model key should be 64 hex characters")
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .context("the model key should be 64 hex characters")?;
+    }
+    Ok(key)
+}
+
+fn master_key() -> Result<[u8; 32]> {
+    if let Ok(hex) = std::env::var(KEY_ENV) {
+        return parse_hex_key(&hex).with_context(|| format!("invalid {KEY_ENV}"));
+    }
+    let command = match std::env::var(KEY_COMMAND_ENV) {
+        Ok(command) => command,
+        Err(_) => anyhow::bail!("the model is encrypted, set {KEY_ENV} or {KEY_COMMAND_ENV}"),
+    };
+    let output = std::process::Command::new("sh")
+        .args(["-c", &command])
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("cannot run {KEY_COMMAND_ENV}"))?;
+    if !output.status.success() {
+        anyhow::bail!("{KEY_COMMAND_ENV} failed with {}", output.status)
+    }
+    parse_hex_key(&String::from_utf8_lossy(&output.stdout))
+        .with_context(|| format!("invalid key printed by {KEY_COMMAND_ENV}"))
+}
+
+fn file_key(salt: &[u8]) -> Result<LessSafeKey> {
+    let master_key = master_key()?;
+    let salt = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, salt);
+    let prk = salt.extract(&master_key);
+    let okm = prk
+        .expand(&[b"hibiki model weights"], &AES_256_GCM)
+        .map_err(|_| anyhow::anyhow!("cannot derive the file key"))?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn nonce(chunk_idx: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&chunk_idx.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Whether the file is an encrypted container, based on its magic.
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("cannot open {path:?}"))?;
+    let mut magic = [0u8; MAGIC.len()];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Opens the container and returns the key, the reader positioned on the first chunk and the
+// number of chunks.
+fn open(path: &Path) -> Result<(LessSafeKey, impl Read, u64)> {
+    let file = std::fs::File::open(path).with_context(|| format!("cannot open {path:?}"))?;
+    let len = file.metadata()?.len() as usize;
+    let mut reader = std::io::BufReader::new(file);
+    let mut header = [0u8; HEADER_LEN];
+    reader.read_exact(&mut header).with_context(|| format!("truncated encrypted file {path:?}"))?;
+    if &header[..MAGIC.len()] != MAGIC {
+        anyhow::bail!("{path:?} is not an encrypted model file")
+    }
+    let key = file_key(&header[MAGIC.len()..])?;
+    let num_chunks = (len - HEADER_LEN).div_ceil(CHUNK_SIZE + TAG_LEN).max(1) as u64;
+    Ok((key, reader, num_chunks))
+}
+
+/// Decrypts the whole file in memory, chunk by chunk.
+pub fn decrypt(path: &Path) -> Result<Vec<u8>> {
+    let start_time = std::time::Instant::now();
+    let (key, mut reader, num_chunks) = open(path)?;
+    let mut data = Vec::with_capacity(num_chunks as usize * CHUNK_SIZE);
+    for chunk_idx in 0..num_chunks {
+        let offset = data.len();
+        reader.by_ref().take((CHUNK_SIZE + TAG_LEN) as u64).read_to_end(&mut data)?;
+        let is_last = chunk_idx + 1 == num_chunks;
+        let plaintext = key
+            .open_in_place(nonce(chunk_idx), Aad::from([is_last as u8]), &mut data[offset..])
+            .map_err(|_| anyhow::anyhow!("cannot decrypt {path:?}, wrong key or corrupted file"))?
+            .len();
+        data.truncate(offset + plaintext);
+    }
+    tracing::info!(?path, "decrypted in {:.2}s", start_time.elapsed().as_secs_f32());
+    Ok(data)
+}
+
+/// Checks that the key is available and matches the file by decrypting its first chunk.
+pub fn check(path: &Path) -> Result<()> {
+    let (key, reader, num_chunks) = open(path)?;
+    let mut chunk = vec![];
+    reader.take((CHUNK_SIZE + TAG_LEN) as u64).read_to_end(&mut chunk)?;
+    key.open_in_place(nonce(0), Aad::from([(num_chunks == 1) as u8]), &mut chunk)
+        .map_err(|_| anyhow::anyhow!("cannot decrypt {path:?}, wrong key or corrupted file"))?;
+    Ok(())
+}
+
+/// Writes an encrypted copy of `input` to `output`, using the key from the environment.
+pub fn encrypt(input: &Path, output: &Path) -> Result<()> {
+    use ring::rand::SecureRandom;
+
+    let mut salt = [0u8; SALT_LEN];
+    ring::rand::SystemRandom::new()
+        .fill(&mut salt)
+        .map_err(|_| anyhow::anyhow!("cannot generate the salt"))?;
+    let key = file_key(&salt)?;
+    let file = std::fs::File::open(input).with_context(|| format!("cannot open {input:?}"))?;
+    let len = file.metadata()?.len() as usize;
+    let num_chunks = len.div_ceil(CHUNK_SIZE).max(1) as u64;
+    let mut reader = std::io::BufReader::new(file);
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(output)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&salt)?;
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+    for chunk_idx in 0..num_chunks {
+        chunk.clear();
+        reader.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        let is_last = chunk_idx + 1 == num_chunks;
+        key.seal_in_place_append_tag(nonce(chunk_idx), Aad::from([is_last as u8]), &mut chunk)
+            .map_err(|_| anyhow::anyhow!("cannot encrypt {input:?}"))?;
+        writer.write_all(&chunk)?;
+    }
+    writer.flush()?;
+    tracing::info!(?input, ?output, "encrypted the model file");
+    Ok(())
+}
+
+/// A var builder for a safetensors file, decrypting it in memory if needed.
+pub fn var_builder(
+    path: &Path,
+    dtype: candle::DType,
+    dev: &candle::Device,
+) -> Result<candle_nn::VarBuilder<'static>> {
+    let vb = if is_encrypted(path)? {
+        candle_nn::VarBuilder::from_buffered_safetensors(decrypt(path)?, dtype, dev)?
+    } else {
+        unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(&[path], dtype, dev)? }
+    };
+    Ok(vb)
+}
+
+/// Loads the lm from a safetensors or gguf file, decrypting it in memory if needed.
+pub fn load_lm_model(
+    cfg: moshi::lm::Config,
+    path: &Path,
+    dtype: candle::DType,
+    dev: &candle::Device,
+) -> Result<moshi::lm::LmModel> {
+    if !is_encrypted(path)? {
+        return Ok(moshi::lm::load_lm_model(cfg, path, dtype, dev)?);
+    }
+    let vb = if crate::quantize::is_gguf(path) {
+        let data = decrypt(path)?;
+        let vb =
+            candle_transformers::quantized_var_builder::VarBuilder::from_gguf_buffer(&data, dev)?;
+        moshi::nn::MaybeQuantizedVarBuilder::Quantized(vb)
+    } else {
+        moshi::nn::MaybeQuantizedVarBuilder::Real(var_builder(path, dtype, dev)?)
+    };
+    Ok(moshi::lm::LmModel::new(&cfg, vb)?)
+}
+
+/// Opens a sentencepiece model, decrypting it in memory if needed.
+pub fn open_tokenizer(path: &Path) -> Result<sentencepiece::SentencePieceProcessor> {
+    let tokenizer = if is_encrypted(path)? {
+        sentencepiece::SentencePieceProcessor::from_serialized_proto(&decrypt(path)?)?
+    } else {
+        sentencepiece::SentencePieceProcessor::open(path)?
+    };
+    Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_keys() {
+        let hex = "00ff".repeat(16);
+        let key = parse_hex_key(&format!(" {hex}\n")).unwrap();
+        assert_eq!(key[..2], [0, 255]);
+        assert!(parse_hex_key(&hex[2..]).is_err());
+        assert!(parse_hex_key(&"zz".repeat(32)).is_err());
+    }
+
+    // The key comes from the environment, so a single test covers the whole container.
+    #[test]
+    fn roundtrip_and_truncation() -> Result<()> {
+        std::env::set_var(KEY_ENV, "2a".repeat(32));
+        let dir = std::env::temp_dir().join(format!("hibiki-crypt-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let (plain, sealed) = (dir.join("plain.bin"), dir.join("sealed.bin"));
+        // Two full chunks and a partial one.
+        let data: Vec<u8> = (0..2 * CHUNK_SIZE + 1000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&plain, &data)?;
+        encrypt(&plain, &sealed)?;
+        assert!(is_encrypted(&sealed)? && !is_encrypted(&plain)?);
+        let sealed_data = std::fs::read(&sealed)?;
+        assert_eq!(sealed_data.len(), HEADER_LEN + data.len() + 3 * TAG_LEN);
+        assert!(!sealed_data.windows(64).any(|v| v == &data[..64]));
+        check(&sealed)?;
+        assert_eq!(decrypt(&sealed)?, data);
+
+        // Dropping the last chunk is detected as the previous one is not marked as the last.
+        let file = std::fs::OpenOptions::new().write(true).open(&sealed)?;
+        file.set_len((HEADER_LEN + 2 * (CHUNK_SIZE + TAG_LEN)) as u64)?;
+        assert!(decrypt(&sealed).is_err());
+        file.set_len((HEADER_LEN + CHUNK_SIZE) as u64)?;
+        assert!(decrypt(&sealed).is_err());
+        assert!(check(&sealed).is_err());
+        file.set_len(10)?;
+        assert!(decrypt(&sealed).is_err());
+
+        // A modified byte fails the authentication.
+        let mut corrupted = sealed_data;
+        corrupted[HEADER_LEN + 5] ^= 1;
+        std::fs::write(&sealed, &corrupted)?;
+        assert!(decrypt(&sealed).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
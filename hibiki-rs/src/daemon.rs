@@ -0,0 +1,491 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Long running daemon keeping the models loaded and processing translation jobs submitted
+// over a unix socket. The protocol uses one json object per line in both directions:
+//   {"cmd": "submit", "input": "in.mp3", "output": "out.wav", "seed": 42}
+//   {"cmd": "status", "id": 1}   (omit the id to list all the jobs)
+//   {"cmd": "cancel", "id": 1}
+// Replies have an "ok" field, set to false together with an "error" message on failures.
+// Only the last 1000 finished jobs are kept, the ids of older jobs expire and are then unknown.
+// Inputs with the same audio and settings as a previous job are not translated again, the job
+// then points to the output of the previous one with its "duplicate_of" field.
+// With `--metrics-addr`, Prometheus metrics are also served over http, see the metrics module.
+// With `--tenants`, each request carries an "api_key" and the jobs are namespaced by tenant, see
+// the tenants module.
+
+use anyhow::Result;
+use candle::Device;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request {
+    Submit { input: PathBuf, output: PathBuf, seed: Option<u64> },
+    Status { id: Option<u64> },
+    Cancel { id: u64 },
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Envelope {
+    api_key: Option<String>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+// The number of finished jobs kept for the status requests, the oldest ones are forgotten.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+// The retention periods are given in days, checking them hourly is enough.
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+    seed: u64,
+    state: JobState,
+    error: Option<String>,
+    cancel: Arc<AtomicBool>,
+    duplicate_of: Option<(u64, PathBuf)>,
+    tenant: Option<String>,
+}
+
+impl Job {
+    fn to_json(&self, id: u64) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "state": self.state.as_str(),
+            "input": self.input,
+            "output": self.output,
+            "seed": self.seed,
+            "error": self.error,
+            "duplicate_of": self.duplicate_of.as_ref().map(|(id, output)| {
+                serde_json::json!({ "id": id, "output": output })
+            }),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+    queue: VecDeque<u64>,
+    // The finished jobs, in the order they finished.
+    finished: VecDeque<u64>,
+}
+
+impl Jobs {
+    // Records that a job is over, forgetting the oldest finished jobs past the retention count.
+    fn finish(&mut self, id: u64) {
+        self.finished.push_back(id);
+        while self.finished.len() > MAX_FINISHED_JOBS {
+            if let Some(id) = self.finished.pop_front() {
+                self.jobs.remove(&id);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    jobs: Mutex<Jobs>,
+    queued: Condvar,
+    // Locked after the jobs when both are needed.
+    metrics: Mutex<crate::metrics::Metrics>,
+    tenants: Option<crate::tenants::Tenants>,
+}
+
+impl Shared {
+    fn render_metrics(&self, dev: &Device) -> String {
+        let jobs = self.jobs.lock().unwrap();
+        let count = |state| jobs.jobs.values().filter(|job| job.state == state).count();
+        let (running, queued) = (count(JobState::Running), count(JobState::Queued));
+        self.metrics.lock().unwrap().render(running, queued, dev)
+    }
+}
+
+enum Outcome {
+    Generated { summary: crate::gen::Summary, fingerprint: String },
+    // The input was already translated by the previous job with this id and output.
+    Duplicate(u64, PathBuf),
+}
+
+fn process_job(
+    args: &crate::gen::Args,
+    models: &mut crate::gen::Models,
+    dev: &Device,
+    cancel: &AtomicBool,
+    duplicates: &crate::memory::Duplicates,
+    tenant: Option<&String>,
+    on_progress: &mut dyn FnMut(crate::progress::Progress),
+) -> Result<Outcome> {
+    let input = crate::gen::Input::load(args, models.codec.as_ref(), dev)?;
+    let fingerprint = input.fingerprint(args)?;
+    if let Some((id, output)) = duplicates.get(tenant.map(|v| v.as_str()), &fingerprint) {
+        return Ok(Outcome::Duplicate(id, output));
+    }
+    let memory = match crate::gen::lookup_memory(args, &input, models.codec.as_mut())? {
+        crate::gen::MemoryLookup::Hit => {
+            return Ok(Outcome::Generated { summary: Default::default(), fingerprint })
+        }
+        crate::gen::MemoryLookup::Miss(memory) => memory,
+    };
+    let summary = crate::gen::generate(
+        args,
+        models,
+        &input,
+        memory.as_ref(),
+        dev,
+        Some(cancel),
+        on_progress,
+    )?;
+    Ok(Outcome::Generated { summary, fingerprint })
+}
+
+fn file_len(path: &std::path::Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |m| m.len())
+}
+
+// Structured summary of a job for capacity planning, logged once the job is over whatever its
+// outcome.
+fn log_summary(
+    id: u64,
+    state: JobState,
+    args: &crate::gen::Args,
+    summary: &crate::gen::Summary,
+    duration: std::time::Duration,
+) {
+    let avg_step_ms = if summary.steps == 0 {
+        0.
+    } else {
+        summary.elapsed.as_secs_f64() * 1000. / summary.steps as f64
+    };
+    tracing::info!(
+        id,
+        state = state.as_str(),
+        duration_ms = duration.as_millis() as u64,
+        steps = summary.steps,
+        text_tokens = summary.text_tokens,
+        avg_step_ms,
+        peak_lag_ms = summary.peak_lag.as_millis() as u64,
+        real_time_factor = summary.stats.real_time_factor,
+        bytes_in = file_len(&args.audio_input_file),
+        bytes_out = file_len(&args.audio_output_file),
+        "job summary"
+    );
+}
+
+fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => panic.downcast_ref::<String>().map_or("unknown panic", |v| v.as_str()),
+    }
+}
+
+fn worker(
+    shared: Arc<Shared>,
+    args: crate::gen::Args,
+    mut models: crate::gen::Models,
+    dev: Device,
+) {
+    // The inputs of the completed jobs by tenant.
+    let mut duplicates = crate::memory::Duplicates::default();
+    loop {
+        let (id, job_args, cancel, tenant) = {
+            let mut jobs = shared.jobs.lock().unwrap();
+            let id = loop {
+                match jobs.queue.pop_front() {
+                    Some(id) => break id,
+                    None => jobs = shared.queued.wait(jobs).unwrap(),
+                }
+            };
+            let Some(job) = jobs.jobs.get_mut(&id) else { continue };
+            if job.state != JobState::Queued {
+                continue;
+            }
+            job.state = JobState::Running;
+            // The translation memory is not shared between tenants.
+            let translation_memory = match (args.translation_memory.as_ref(), job.tenant.as_ref()) {
+                (Some(dir), Some(tenant)) => Some(dir.join(tenant)),
+                (dir, _) => dir.cloned(),
+            };
+            let job_args = crate::gen::Args {
+                audio_input_file: job.input.clone(),
+                audio_output_file: job.output.clone(),
+                seed: job.seed,
+                translation_memory,
+                ..args.clone()
+            };
+            (id, job_args, job.cancel.clone(), job.tenant.clone())
+        };
+        tracing::info!(id, input = ?job_args.audio_input_file, "starting job");
+        let _ = crate::systemd::notify(&format!("STATUS=processing job {id}"));
+        let start_time = std::time::Instant::now();
+        let mut prev = (0, std::time::Duration::ZERO);
+        let mut on_progress = |progress: crate::progress::Progress| {
+            let num_steps = progress.steps.saturating_sub(prev.0);
+            let elapsed = progress.elapsed.saturating_sub(prev.1);
+            shared.metrics.lock().unwrap().record_batch(num_steps, elapsed);
+            prev = (progress.steps, progress.elapsed)
+        };
+        // A panic, e.g. on a shape error for an unexpected input, only fails its job. The next
+        // job starts from a fresh lm state and codec state.
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            process_job(
+                &job_args,
+                &mut models,
+                &dev,
+                &cancel,
+                &duplicates,
+                tenant.as_ref(),
+                &mut on_progress,
+            )
+        }))
+        .unwrap_or_else(|panic| {
+            Err(anyhow::anyhow!("the job panicked: {}", panic_message(&panic)))
+        });
+        let summary = match res.as_ref() {
+            Ok(Outcome::Generated { summary, .. }) => summary.clone(),
+            Ok(Outcome::Duplicate(..)) | Err(_) => Default::default(),
+        };
+        let mut jobs = shared.jobs.lock().unwrap();
+        if let Some(job) = jobs.jobs.get_mut(&id) {
+            job.state = match res {
+                // The partial output of a cancelled job is kept but not reused for duplicates.
+                Ok(Outcome::Generated { .. }) if summary.cancelled => JobState::Cancelled,
+                Ok(Outcome::Generated { fingerprint, .. }) => {
+                    let output = job_args.audio_output_file.clone();
+                    duplicates.insert(tenant.as_deref(), fingerprint, id, output);
+                    JobState::Done
+                }
+                Ok(Outcome::Duplicate(prior_id, output)) => {
+                    tracing::info!(id, prior_id, "skipping the input, already translated");
+                    job.duplicate_of = Some((prior_id, output));
+                    JobState::Done
+                }
+                Err(_) if cancel.load(Ordering::Relaxed) => JobState::Cancelled,
+                Err(err) => {
+                    tracing::error!(id, ?err, "job failed");
+                    job.error = Some(format!("{err:#}"));
+                    JobState::Failed
+                }
+            };
+            tracing::info!(id, state = job.state.as_str(), "finished job");
+            log_summary(id, job.state, &job_args, &summary, start_time.elapsed());
+            let duration = start_time.elapsed().as_secs_f64();
+            let mut metrics = shared.metrics.lock().unwrap();
+            metrics.record_job(job.state.as_str(), summary.text_tokens, duration);
+            jobs.finish(id)
+        }
+        if jobs.queue.is_empty() {
+            let _ = crate::systemd::notify("STATUS=waiting for requests");
+        }
+    }
+}
+
+fn handle_request(shared: &Shared, default_seed: u64, line: &str) -> Result<serde_json::Value> {
+    let Envelope { api_key, mut request } = serde_json::from_str(line)?;
+    let tenant = match shared.tenants.as_ref() {
+        None => None,
+        Some(tenants) => Some(tenants.authenticate(api_key.as_deref())?.to_string()),
+    };
+    if let (Some(tenants), Some(tenant), Request::Submit { input, output, .. }) =
+        (shared.tenants.as_ref(), tenant.as_deref(), &mut request)
+    {
+        *input = tenants.resolve(tenant, input)?;
+        *output = tenants.resolve(tenant, output)?;
+        if let Some(dir) = output.parent() {
+            std::fs::create_dir_all(dir)?
+        }
+    }
+    // The tenants only see their own jobs.
+    let visible = |job: &&mut Job| job.tenant == tenant;
+    let mut jobs = shared.jobs.lock().unwrap();
+    let reply = match request {
+        Request::Submit { input, output, seed } => {
+            jobs.next_id += 1;
+            let id = jobs.next_id;
+            let job = Job {
+                input,
+                output,
+                seed: seed.unwrap_or(default_seed),
+                state: JobState::Queued,
+                error: None,
+                cancel: Arc::new(AtomicBool::new(false)),
+                duplicate_of: None,
+                tenant: tenant.clone(),
+            };
+            jobs.jobs.insert(id, job);
+            jobs.queue.push_back(id);
+            shared.queued.notify_one();
+            serde_json::json!({ "ok": true, "id": id })
+        }
+        Request::Status { id: None } => {
+            let jobs: Vec<_> = jobs
+                .jobs
+                .iter_mut()
+                .filter(|(_, job)| visible(job))
+                .map(|(id, job)| job.to_json(*id))
+                .collect();
+            serde_json::json!({ "ok": true, "jobs": jobs })
+        }
+        Request::Status { id: Some(id) } => match jobs.jobs.get_mut(&id).filter(visible) {
+            None => anyhow::bail!("unknown job {id}"),
+            Some(job) => serde_json::json!({ "ok": true, "job": job.to_json(id) }),
+        },
+        Request::Cancel { id } => {
+            let Some(job) = jobs.jobs.get_mut(&id).filter(visible) else {
+                anyhow::bail!("unknown job {id}")
+            };
+            match job.state {
+                JobState::Queued => {
+                    job.state = JobState::Cancelled;
+                    jobs.finish(id)
+                }
+                JobState::Running => job.cancel.store(true, Ordering::Relaxed),
+                JobState::Done | JobState::Failed | JobState::Cancelled => {
+                    anyhow::bail!("job {id} is already {}", job.state.as_str())
+                }
+            }
+            serde_json::json!({ "ok": true, "id": id })
+        }
+    };
+    Ok(reply)
+}
+
+fn handle_client(shared: &Shared, default_seed: u64, stream: UnixStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match handle_request(shared, default_seed, &line) {
+            Ok(reply) => reply,
+            Err(err) => {
+                shared.metrics.lock().unwrap().record_request_error();
+                serde_json::json!({ "ok": false, "error": format!("{err:#}") })
+            }
+        };
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+pub fn run(
+    args: crate::gen::Args,
+    dev: Device,
+    socket: PathBuf,
+    metrics_addr: Option<std::net::SocketAddr>,
+    tenants: Option<crate::tenants::Tenants>,
+) -> Result<()> {
+    let models = crate::gen::Models::load(&args, &dev)?;
+    let listener = match crate::systemd::listener()? {
+        Some(listener) => {
+            tracing::info!("listening for requests on the socket passed by systemd");
+            listener
+        }
+        None => {
+            // A socket file left over by a previous run would make the bind fail.
+            if socket.exists() {
+                std::fs::remove_file(&socket)?;
+            }
+            let listener = UnixListener::bind(&socket)?;
+            tracing::info!(?socket, "listening for requests");
+            listener
+        }
+    };
+    crate::systemd::notify("READY=1\nSTATUS=models loaded, waiting for requests")?;
+    let shared = Arc::new(Shared { tenants, ..Default::default() });
+    let default_seed = args.seed;
+    if shared.tenants.is_some() {
+        let (shared, memory_dir) = (shared.clone(), args.translation_memory.clone());
+        std::thread::spawn(move || loop {
+            if let Some(tenants) = shared.tenants.as_ref() {
+                let deleted = tenants.sweep(memory_dir.as_deref());
+                if deleted > 0 {
+                    tracing::info!(deleted, "deleted the files past their retention period")
+                }
+            }
+            std::thread::sleep(SWEEP_INTERVAL)
+        });
+    }
+    if let Some(addr) = metrics_addr {
+        let (shared, dev) = (shared.clone(), dev.clone());
+        crate::metrics::serve(addr, move || shared.render_metrics(&dev))?
+    }
+    {
+        let shared = shared.clone();
+        std::thread::spawn(move || worker(shared, args, models, dev));
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::warn!(?err, "failed to accept a connection");
+                continue;
+            }
+        };
+        let shared = shared.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = handle_client(&shared, default_seed, stream) {
+                tracing::warn!(?err, "client error")
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finished_jobs_expire() {
+        let mut jobs = Jobs::default();
+        for id in 0..MAX_FINISHED_JOBS as u64 + 10 {
+            let job = Job {
+                input: "in.wav".into(),
+                output: "out.wav".into(),
+                seed: 0,
+                state: JobState::Done,
+                error: None,
+                cancel: Arc::new(AtomicBool::new(false)),
+                duplicate_of: None,
+                tenant: None,
+            };
+            jobs.jobs.insert(id, job);
+            jobs.finish(id)
+        }
+        assert_eq!(jobs.jobs.len(), MAX_FINISHED_JOBS);
+        assert_eq!(jobs.jobs.keys().next(), Some(&10));
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Enumeration of the ALSA audio devices based on /proc/asound.
+
+use anyhow::Result;
+use std::collections::BTreeSet;
+
+const ASOUND_DIR: &str = "/proc/asound";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamInfo {
+    pub rates: BTreeSet<u32>,
+    pub channels: BTreeSet<u32>,
+    /// The rates are the ones of the codec of the card, the device may only support some of
+    /// them.
+    pub card_rates: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioDevice {
+    /// The ALSA device name, e.g. `plughw:0,0`.
+    pub name: String,
+    pub description: String,
+    pub playback: Option<StreamInfo>,
+    pub capture: Option<StreamInfo>,
+}
+
+fn card_names() -> std::collections::HashMap<usize, String> {
+    // Lines look like: " 0 [PCH            ]: HDA-Intel - HDA Intel PCH"
+    let cards = std::fs::read_to_string(format!("{ASOUND_DIR}/cards")).unwrap_or_default();
+    cards
+        .lines()
+        .filter_map(|line| {
+            let (idx, rest) = line.trim_start().split_once(' ')?;
+            let idx = idx.parse().ok()?;
+            let name = rest.split_once(" - ").map_or(rest, |(_, name)| name);
+            Some((idx, name.trim().to_string()))
+        })
+        .collect()
+}
+
+fn parse_list(s: &str) -> impl Iterator<Item = u32> + '_ {
+    s.split(|c: char| c == ',' || c.is_whitespace()).filter_map(|v| v.trim().parse().ok())
+}
+
+// USB devices describe the altsettings of their pcm device N in a streamN file, with
+// Playback:/Capture: sections.
+fn parse_usb_stream(content: &str) -> (StreamInfo, StreamInfo) {
+    let mut playback = StreamInfo::default();
+    let mut capture = StreamInfo::default();
+    let mut current = None;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with("Playback:") {
+            current = Some(&mut playback)
+        } else if line.starts_with("Capture:") {
+            current = Some(&mut capture)
+        } else if let Some(info) = current.as_mut() {
+            if let Some(v) = line.strip_prefix("Channels:") {
+                info.channels.extend(parse_list(v))
+            } else if let Some(v) = line.strip_prefix("Rates:") {
+                info.rates.extend(parse_list(v))
+            }
+        }
+    }
+    (playback, capture)
+}
+
+fn usb_stream_info(card: usize, device: usize) -> (StreamInfo, StreamInfo) {
+    let path = format!("{ASOUND_DIR}/card{card}/stream{device}");
+    parse_usb_stream(&std::fs::read_to_string(path).unwrap_or_default())
+}
+
+fn parse_codec_rates(content: &str) -> impl Iterator<Item = u32> + '_ {
+    content
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("rates [")?.split_once(':'))
+        .flat_map(|(_, v)| parse_list(v))
+}
+
+// HDA codecs list their supported rates as "rates [0x560]: 44100 48000 96000 192000", this is
+// for the whole card as the codec nodes are not mapped to the pcm devices in /proc.
+fn codec_rates(card: usize) -> BTreeSet<u32> {
+    let mut rates = BTreeSet::new();
+    let Ok(entries) = std::fs::read_dir(format!("{ASOUND_DIR}/card{card}")) else {
+        return rates;
+    };
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("codec#") {
+            continue;
+        }
+        let content = std::fs::read_to_string(entry.path()).unwrap_or_default();
+        rates.extend(parse_codec_rates(&content))
+    }
+    rates
+}
+
+pub fn list() -> Result<Vec<AudioDevice>> {
+    let pcm = match std::fs::read_to_string(format!("{ASOUND_DIR}/pcm")) {
+        Ok(pcm) => pcm,
+        Err(err) => anyhow::bail!("cannot read {ASOUND_DIR}/pcm, is ALSA available? {err}"),
+    };
+    let card_names = card_names();
+    let mut devices = vec![];
+    // Lines look like: "00-00: ALC887-VD Analog : ALC887-VD Analog : playback 1 : capture 1"
+    for line in pcm.lines() {
+        let mut fields = line.split(" : ");
+        let Some((ids, id_name)) = fields.next().and_then(|v| v.split_once(": ")) else {
+            continue;
+        };
+        let Some((card, device)) = ids.split_once('-') else { continue };
+        let (Ok(card), Ok(device)) = (card.parse::<usize>(), device.parse::<usize>()) else {
+            continue;
+        };
+        let fields: Vec<&str> = fields.collect();
+        let has = |kind: &str| fields.iter().any(|f| f.trim().starts_with(kind));
+        let (mut playback, mut capture) = usb_stream_info(card, device);
+        let rates = codec_rates(card);
+        for info in [&mut playback, &mut capture] {
+            if info.rates.is_empty() && !rates.is_empty() {
+                info.rates = rates.clone();
+                info.card_rates = true
+            }
+        }
+        let card_name = card_names.get(&card).cloned().unwrap_or_else(|| format!("card {card}"));
+        devices.push(AudioDevice {
+            name: format!("plughw:{card},{device}"),
+            description: format!("{card_name}: {}", id_name.trim()),
+            playback: has("playback").then_some(playback),
+            capture: has("capture").then_some(capture),
+        })
+    }
+    Ok(devices)
+}
+
+fn format_set(set: &BTreeSet<u32>) -> String {
+    if set.is_empty() {
+        "unknown".to_string()
+    } else {
+        set.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+pub fn print(devices: &[AudioDevice]) {
+    if devices.is_empty() {
+        println!("no audio devices found");
+        return;
+    }
+    for device in devices.iter() {
+        println!("{}  {}", device.name, device.description);
+        for (kind, info) in [("capture", &device.capture), ("playback", &device.playback)] {
+            if let Some(info) = info {
+                let rates = if info.card_rates { "card rates" } else { "rates" };
+                println!(
+                    "    {kind:<8}  {rates}: {}  channels: {}",
+                    format_set(&info.rates),
+                    format_set(&info.channels)
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_stream() {
+        let content = "Focusrite Scarlett Solo USB at usb-0000:00:14.0-1, high speed : USB Audio
+
+Playback:
+  Status: Stop
+  Interface 1
+    Altset 1
+    Format: S32_LE
+    Channels: 2
+    Rates: 44100, 48000, 88200, 96000
+
+Capture:
+  Status: Stop
+  Interface 2
+    Altset 1
+    Format: S32_LE
+    Channels: 2
+    Rates: 48000
+";
+        let (playback, capture) = parse_usb_stream(content);
+        assert_eq!(playback.rates, BTreeSet::from([44100, 48000, 88200, 96000]));
+        assert_eq!(capture.rates, BTreeSet::from([48000]));
+        assert_eq!(capture.channels, BTreeSet::from([2]));
+        assert!(!capture.card_rates);
+    }
+
+    #[test]
+    fn hda_codec() {
+        let content = "Codec: Realtek ALC887-VD
+Node 0x02 [Audio Output] wcaps 0x41d: Stereo Amp-Out
+  PCM:
+    rates [0x560]: 44100 48000 96000 192000
+    bits [0xe]: 16 20 24
+Node 0x08 [Audio Input] wcaps 0x10051b: Stereo Amp-In
+  PCM:
+    rates [0x160]: 44100 48000 96000
+";
+        let rates: BTreeSet<u32> = parse_codec_rates(content).collect();
+        assert_eq!(rates, BTreeSet::from([44100, 48000, 96000, 192000]));
+    }
+}
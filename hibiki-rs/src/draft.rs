@@ -0,0 +1,140 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Decoding constrained to a draft translation, so that an editor can fix the wording of a
+// translation and regenerate the matching audio. The model still decides when to speak, the
+// padding tokens are always allowed, but the text tokens have to follow the draft with at most
+// a given number of edits.
+//
+// The alignment with the draft is greedy: a token matching the next draft token advances the
+// cursor, a token matching one of the following draft tokens skips the ones in between with an
+// edit each, and any other token is a substitution costing one edit. Once the edits are used
+// up, only the next draft token can be emitted.
+
+use anyhow::Result;
+use candle::Tensor;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cursor {
+    pos: usize,
+    edits: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Draft {
+    tokens: Vec<u32>,
+    max_edits: usize,
+    pad_tokens: [u32; 2],
+    start: Cursor,
+}
+
+impl Draft {
+    pub fn new(
+        text: &str,
+        tokenizer: &sentencepiece::SentencePieceProcessor,
+        max_edits: usize,
+        config: &moshi::lm_generate_multistream::Config,
+    ) -> Result<Self> {
+        let tokens: Vec<u32> = tokenizer.encode(text)?.into_iter().map(|p| p.id).collect();
+        if tokens.is_empty() {
+            anyhow::bail!("the draft translation is empty")
+        }
+        let pad_tokens = [config.text_pad_token, config.text_eop_token];
+        Ok(Self { tokens, max_edits, pad_tokens, start: Cursor::default() })
+    }
+
+    fn advance(&self, cursor: Cursor, token: u32) -> Cursor {
+        let Cursor { pos, edits } = cursor;
+        if self.pad_tokens.contains(&token) {
+            return cursor;
+        }
+        if self.tokens.get(pos) == Some(&token) {
+            return Cursor { pos: pos + 1, edits };
+        }
+        let reach = self.max_edits.saturating_sub(edits);
+        let end = usize::min(pos + 1 + reach, self.tokens.len());
+        let skipped =
+            self.tokens.get(pos + 1..end).and_then(|v| v.iter().position(|&t| t == token));
+        match skipped {
+            Some(skipped) => Cursor { pos: pos + skipped + 2, edits: edits + skipped + 1 },
+            None => Cursor { pos: usize::min(pos + 1, self.tokens.len()), edits: edits + 1 },
+        }
+    }
+
+    /// The position in the draft after the given tokens have been emitted.
+    pub fn cursor(&self, text_tokens: &[u32]) -> Cursor {
+        text_tokens.iter().fold(self.start, |cursor, &token| self.advance(cursor, token))
+    }
+
+    /// The draft restarting after the given tokens, e.g. for the next chunk of a long input.
+    pub fn after(&self, text_tokens: &[u32]) -> Self {
+        Self { start: self.cursor(text_tokens), ..self.clone() }
+    }
+
+    /// Masks the text logits of the tokens that would exceed the edit budget.
+    pub fn constrain(&self, logits: &Tensor, cursor: Cursor) -> candle::Result<Tensor> {
+        if cursor.edits < self.max_edits {
+            return Ok(logits.clone());
+        }
+        let device = logits.device();
+        let mut logits = logits.to_dtype(candle::DType::F32)?.to_vec1::<f32>()?;
+        let allowed = |id: usize| {
+            let id = id as u32;
+            self.pad_tokens.contains(&id) || self.tokens.get(cursor.pos) == Some(&id)
+        };
+        for (id, logit) in logits.iter_mut().enumerate() {
+            if !allowed(id) {
+                *logit = f32::NEG_INFINITY
+            }
+        }
+        let logits_len = logits.len();
+        Tensor::from_vec(logits, logits_len, device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAD: u32 = 3;
+
+    fn draft(tokens: &[u32], max_edits: usize) -> Draft {
+        Draft { tokens: tokens.to_vec(), max_edits, pad_tokens: [PAD, 0], start: Cursor::default() }
+    }
+
+    #[test]
+    fn follow_the_draft() {
+        let draft = draft(&[10, 11, 12, 13], 2);
+        assert_eq!(draft.cursor(&[10, PAD, 11, 0]), Cursor { pos: 2, edits: 0 });
+        // Skipping a draft token costs an edit per skipped token.
+        assert_eq!(draft.cursor(&[10, 12]), Cursor { pos: 3, edits: 1 });
+        // Other tokens are substitutions.
+        assert_eq!(draft.cursor(&[20, 11]), Cursor { pos: 2, edits: 1 });
+        // Skips are bounded by the remaining edits.
+        assert_eq!(draft.cursor(&[20, 13]), Cursor { pos: 2, edits: 2 });
+        // Past the end of the draft, tokens are still counted as edits.
+        assert_eq!(draft.cursor(&[10, 11, 12, 13, 20]), Cursor { pos: 4, edits: 1 });
+    }
+
+    #[test]
+    fn restart_after_tokens() {
+        let draft = draft(&[10, 11, 12], 1);
+        let next = draft.after(&[10, 20]);
+        assert_eq!(next.cursor(&[]), Cursor { pos: 2, edits: 1 });
+        assert_eq!(next.cursor(&[12]), Cursor { pos: 3, edits: 1 });
+    }
+
+    #[test]
+    fn constrain_once_the_edits_are_used() -> candle::Result<()> {
+        let draft = draft(&[1, 2], 1);
+        let logits = Tensor::new(&[0f32, 1., 2., 3., 4.], &candle::Device::Cpu)?;
+        let free = draft.constrain(&logits, Cursor { pos: 1, edits: 0 })?;
+        assert_eq!(free.to_vec1::<f32>()?, [0., 1., 2., 3., 4.]);
+        let constrained =
+            draft.constrain(&logits, Cursor { pos: 1, edits: 1 })?.to_vec1::<f32>()?;
+        let inf = f32::NEG_INFINITY;
+        assert_eq!(constrained, [0., inf, 2., 3., inf]);
+        Ok(())
+    }
+}
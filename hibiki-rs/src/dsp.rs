@@ -0,0 +1,286 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Post-processing of the generated audio, these operate on 24kHz mono pcm data.
+
+// 40ms analysis frames with 50% overlap.
+const FRAME_LEN: usize = 960;
+const HOP_OUT: usize = FRAME_LEN / 2;
+// Maximum offset of the similarity search around the nominal analysis position.
+const SEARCH_RADIUS: usize = 120;
+
+fn hann(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (2. * std::f32::consts::PI * i as f32 / len as f32).cos())
+        .collect()
+}
+
+// Cross-correlation between two segments, computed on every other sample to stay cheap.
+fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).step_by(2).map(|(x, y)| x * y).sum()
+}
+
+/// WSOLA (waveform similarity overlap-add) time-stretching, changes the duration of the audio
+/// without changing its pitch. `ratio` is the output duration divided by the input duration.
+pub fn time_stretch(pcm: &[f32], ratio: f64) -> Vec<f32> {
+    let out_len = (pcm.len() as f64 * ratio).round() as usize;
+    if pcm.len() < FRAME_LEN || ratio <= 0. || (ratio - 1.).abs() < 1e-4 {
+        let mut pcm = pcm.to_vec();
+        pcm.resize(out_len, 0.);
+        return pcm;
+    }
+    let mut input = pcm.to_vec();
+    input.resize(pcm.len() + 2 * FRAME_LEN + SEARCH_RADIUS, 0.);
+    let max_pos = input.len() - FRAME_LEN - HOP_OUT;
+    let window = hann(FRAME_LEN);
+    let mut out = vec![0f32; out_len + FRAME_LEN];
+    let mut norm = vec![0f32; out_len + FRAME_LEN];
+
+    let mut prev_pos = 0;
+    let mut out_pos = 0;
+    while out_pos < out_len {
+        let nominal = (out_pos as f64 / ratio) as usize;
+        let pos = if out_pos == 0 {
+            0
+        } else {
+            // Pick the analysis frame that best continues the previously copied one.
+            let template = &input[prev_pos + HOP_OUT..prev_pos + HOP_OUT + HOP_OUT];
+            let lo = nominal.saturating_sub(SEARCH_RADIUS).min(max_pos);
+            let hi = (nominal + SEARCH_RADIUS).min(max_pos);
+            let mut best = (lo, f32::NEG_INFINITY);
+            for p in lo..=hi {
+                let s = similarity(template, &input[p..p + HOP_OUT]);
+                if s > best.1 {
+                    best = (p, s)
+                }
+            }
+            best.0
+        };
+        for i in 0..FRAME_LEN {
+            out[out_pos + i] += input[pos + i] * window[i];
+            norm[out_pos + i] += window[i];
+        }
+        prev_pos = pos;
+        out_pos += HOP_OUT;
+    }
+    out.truncate(out_len);
+    for (o, n) in out.iter_mut().zip(norm.iter()) {
+        if *n > 1e-3 {
+            *o /= n
+        }
+    }
+    out
+}
+
+/// Resamples by an arbitrary ratio, `ratio` being the output length divided by the input length.
+fn resample_ratio(pcm: &[f32], ratio: f64) -> anyhow::Result<Vec<f32>> {
+    use rubato::Resampler;
+
+    let mut resampler =
+        rubato::FastFixedIn::<f32>::new(ratio, 1.0, rubato::PolynomialDegree::Septic, 1024, 1)?;
+    let mut out = Vec::with_capacity((pcm.len() as f64 * ratio) as usize + 1024);
+    let mut output_buffer = resampler.output_buffer_allocate(true);
+    let mut pos = 0;
+    while pos + resampler.input_frames_next() <= pcm.len() {
+        let (in_len, out_len) =
+            resampler.process_into_buffer(&[&pcm[pos..]], &mut output_buffer, None)?;
+        pos += in_len;
+        out.extend_from_slice(&output_buffer[0][..out_len]);
+    }
+    let (_, out_len) =
+        resampler.process_partial_into_buffer(Some(&[&pcm[pos..]]), &mut output_buffer, None)?;
+    out.extend_from_slice(&output_buffer[0][..out_len]);
+    // Flush the resampler delay, the output is then trimmed to the expected length.
+    let delay = resampler.output_delay();
+    let (_, out_len) =
+        resampler.process_partial_into_buffer(None::<&[&[f32]]>, &mut output_buffer, None)?;
+    out.extend_from_slice(&output_buffer[0][..out_len]);
+    let out_len = (pcm.len() as f64 * ratio).round() as usize;
+    Ok(out.into_iter().skip(delay).take(out_len).collect())
+}
+
+// Shifts both the pitch and the formants by `factor`, keeping the duration unchanged.
+fn shift_spectrum(pcm: &[f32], factor: f64) -> anyhow::Result<Vec<f32>> {
+    let stretched = time_stretch(pcm, factor);
+    let mut pcm = resample_ratio(&stretched, 1. / factor)?;
+    pcm.resize(pcm.len().max(1), 0.);
+    Ok(pcm)
+}
+
+const LPC_ORDER: usize = 24;
+
+// Linear prediction coefficients via the autocorrelation method and Levinson-Durbin, the
+// returned vector starts with the leading 1.
+fn lpc(frame: &[f32]) -> Vec<f64> {
+    let mut r = [0f64; LPC_ORDER + 1];
+    for (lag, r) in r.iter_mut().enumerate() {
+        *r = frame.iter().zip(frame[lag..].iter()).map(|(a, b)| *a as f64 * *b as f64).sum();
+    }
+    let mut a = vec![0f64; LPC_ORDER + 1];
+    a[0] = 1.;
+    if r[0] <= 1e-9 {
+        return a;
+    }
+    // Slight lag windowing (white noise correction) for numerical stability.
+    r[0] *= 1. + 1e-4;
+    let mut err = r[0];
+    for i in 1..=LPC_ORDER {
+        let acc: f64 = (0..i).map(|j| a[j] * r[i - j]).sum();
+        let k = -acc / err;
+        let prev = a.clone();
+        for j in 1..i {
+            a[j] = prev[j] + k * prev[i - j];
+        }
+        a[i] = k;
+        err *= 1. - k * k;
+        if err <= 1e-12 {
+            break;
+        }
+    }
+    a
+}
+
+fn energy(pcm: &[f32]) -> f64 {
+    pcm.iter().map(|v| *v as f64 * *v as f64).sum()
+}
+
+/// Shifts the pitch and the formants of the generated voice by the given number of semitones,
+/// the two can be controlled independently. The formants are handled by whitening each frame
+/// with its own spectral envelope (LPC) and re-coloring it with the target envelope.
+pub fn pitch_formant_shift(
+    pcm: &[f32],
+    pitch_semitones: f64,
+    formant_semitones: f64,
+) -> anyhow::Result<Vec<f32>> {
+    if pitch_semitones == 0. && formant_semitones == 0. {
+        return Ok(pcm.to_vec());
+    }
+    let pitch = 2f64.powf(pitch_semitones / 12.);
+    let formant = 2f64.powf(formant_semitones / 12.);
+    let excitation = if pitch_semitones == 0. { pcm.to_vec() } else { shift_spectrum(pcm, pitch)? };
+    let envelope =
+        if formant_semitones == 0. { pcm.to_vec() } else { shift_spectrum(pcm, formant)? };
+    if pitch_semitones == formant_semitones {
+        return Ok(excitation);
+    }
+
+    let window = hann(FRAME_LEN);
+    let mut out = vec![0f32; pcm.len() + FRAME_LEN];
+    let mut pos = 0;
+    let sample = |v: &[f32], i: usize| v.get(i).copied().unwrap_or(0.);
+    while pos < pcm.len() {
+        let xs: Vec<f32> =
+            (0..FRAME_LEN).map(|i| sample(&excitation, pos + i) * window[i]).collect();
+        let es: Vec<f32> = (0..FRAME_LEN).map(|i| sample(&envelope, pos + i) * window[i]).collect();
+        let a_src = lpc(&xs);
+        let a_tgt = lpc(&es);
+        // Whitening (FIR) with the source envelope then synthesis (IIR) with the target one.
+        let mut ys = vec![0f64; FRAME_LEN];
+        for n in 0..FRAME_LEN {
+            let mut res = 0f64;
+            for (k, a) in a_src.iter().enumerate() {
+                if n >= k {
+                    res += a * xs[n - k] as f64
+                }
+            }
+            let mut y = res;
+            for (k, a) in a_tgt.iter().enumerate().skip(1) {
+                if n >= k {
+                    y -= a * ys[n - k]
+                }
+            }
+            ys[n] = y;
+        }
+        let ys: Vec<f32> = ys.iter().map(|v| *v as f32).collect();
+        let gain = (energy(&xs) / energy(&ys).max(1e-12)).sqrt() as f32;
+        // The analysis windows already sum to one with a 50% overlap.
+        for (i, y) in ys.iter().enumerate() {
+            out[pos + i] += y * gain;
+        }
+        pos += HOP_OUT;
+    }
+    out.truncate(pcm.len());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (2. * std::f32::consts::PI * freq * i as f32 / 24000.).sin()).collect()
+    }
+
+    // The frequency of a sine from its zero crossings, away from the edges.
+    fn frequency(pcm: &[f32]) -> f32 {
+        let pcm = &pcm[FRAME_LEN..pcm.len() - FRAME_LEN];
+        let crossings = pcm.windows(2).filter(|v| (v[0] < 0.) != (v[1] < 0.)).count();
+        crossings as f32 * 24000. / pcm.len() as f32 / 2.
+    }
+
+    // The pitch period in samples from the autocorrelation peak, between 2ms and 12ms.
+    fn period(pcm: &[f32]) -> usize {
+        let pcm = &pcm[FRAME_LEN..pcm.len() - FRAME_LEN];
+        (48..288)
+            .max_by(|&a, &b| similarity(pcm, &pcm[a..]).total_cmp(&similarity(pcm, &pcm[b..])))
+            .unwrap()
+    }
+
+    #[test]
+    fn time_stretch_keeps_the_pitch() {
+        let pcm = sine(200., 24000);
+        for ratio in [0.7, 1.5] {
+            let out = time_stretch(&pcm, ratio);
+            assert_eq!(out.len(), (24000. * ratio) as usize);
+            let freq = frequency(&out);
+            assert!((freq - 200.).abs() < 10., "{ratio} {freq}");
+        }
+    }
+
+    #[test]
+    fn time_stretch_short_inputs() {
+        let pcm = sine(200., 24000);
+        assert_eq!(time_stretch(&pcm, 1.), pcm);
+        // Inputs shorter than a frame are padded or truncated.
+        let short = &pcm[..100];
+        assert_eq!(time_stretch(short, 2.)[..100], *short);
+        assert_eq!(time_stretch(short, 2.).len(), 200);
+        assert_eq!(time_stretch(short, 0.5), short[..50]);
+    }
+
+    #[test]
+    fn pitch_shift_keeps_the_duration() -> anyhow::Result<()> {
+        let pcm: Vec<f32> = sine(200., 24000).iter().map(|v| v * 0.5).collect();
+        assert_eq!(pitch_formant_shift(&pcm, 0., 0.)?, pcm);
+        for semitones in [-12., 12.] {
+            let out = pitch_formant_shift(&pcm, semitones, semitones)?;
+            assert_eq!(out.len(), pcm.len());
+            let expected = 200. * 2f32.powf(semitones as f32 / 12.);
+            let freq = frequency(&out);
+            assert!((freq - expected).abs() < expected * 0.05, "{semitones} {freq}");
+        }
+        // Shifting the formants alone keeps the pitch period of a voice-like harmonic signal, a
+        // pure sine has no envelope to separate from its pitch.
+        let mut pcm = vec![0f32; 24000];
+        for k in 1..=10 {
+            for (v, h) in pcm.iter_mut().zip(sine(200. * k as f32, 24000)) {
+                *v += h * 0.3 / k as f32
+            }
+        }
+        let out = pitch_formant_shift(&pcm, 0., 4.)?;
+        assert_eq!(out.len(), pcm.len());
+        assert_eq!(period(&out), 120);
+        let ratio = (energy(&out) / energy(&pcm)).sqrt();
+        assert!((0.5..2.).contains(&ratio), "{ratio}");
+        Ok(())
+    }
+
+    #[test]
+    fn lpc_of_silence() {
+        let a = lpc(&[0.; FRAME_LEN]);
+        assert_eq!(a.len(), LPC_ORDER + 1);
+        assert_eq!(a[0], 1.);
+        assert!(a[1..].iter().all(|v| *v == 0.));
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Helpers to fit the generated audio of a segment to the duration of its source, as required for
+// dubbing, and to mix it over the source in a single dubbed track.
+
+// Number of steps fed with silence after the end of the input so that the model can complete
+// the translation, the generation stops earlier once the text stream goes quiet.
+pub const MAX_TAIL_STEPS: usize = 50;
+// Number of consecutive text padding steps after the end of the input before stopping.
+pub const END_PAD_STEPS: usize = 12;
+
+const SILENCE_THRESHOLD: f32 = 1e-3;
+// The translation is speaking when the level of its 20ms windows is above this.
+const SPEECH_DB: f32 = -40.;
+const DUCK_WINDOW_SECS: f64 = 0.02;
+// The source is ducked quickly when the translation starts, and only comes back after a pause
+// longer than the gaps between words so that it does not pump.
+const DUCK_ATTACK_SECS: f64 = 0.05;
+const DUCK_HOLD_SECS: f64 = 0.3;
+const DUCK_RELEASE_SECS: f64 = 0.5;
+
+/// The number of samples of silence at the start of the pcm data, this is mostly the latency of
+/// the model.
+pub fn leading_silence(pcm: &[f32]) -> usize {
+    pcm.iter().position(|v| v.abs() > SILENCE_THRESHOLD).unwrap_or(pcm.len())
+}
+
+/// How the generated audio was fitted to the duration of the source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fit {
+    /// The samples of leading silence removed from the generated audio.
+    pub skip: usize,
+    /// The time-stretch ratio applied to the remaining audio.
+    pub ratio: f64,
+}
+
+impl Fit {
+    /// The audio is used as generated.
+    pub const NONE: Self = Self { skip: 0, ratio: 1. };
+}
+
+/// Returns pcm data of exactly `target_len` samples. The leading silence is removed first, then
+/// the audio is time-stretched by at most `max_stretch` (e.g. 0.15 for ±15%), and finally the end
+/// gets truncated or padded with silence. A single ratio is used for the whole audio, segments
+/// have to be generated and fitted separately to get their own ratio.
+pub fn fit_to_duration(mut pcm: Vec<f32>, target_len: usize, max_stretch: f64) -> (Vec<f32>, Fit) {
+    let mut fit = Fit::NONE;
+    if pcm.len() > target_len {
+        let excess = pcm.len() - target_len;
+        fit.skip = usize::min(excess, leading_silence(&pcm));
+        pcm.drain(..fit.skip);
+    }
+    if !pcm.is_empty() && pcm.len() != target_len && max_stretch > 0. {
+        fit.ratio =
+            (target_len as f64 / pcm.len() as f64).clamp(1. - max_stretch, 1. + max_stretch);
+        pcm = crate::dsp::time_stretch(&pcm, fit.ratio);
+    }
+    pcm.resize(target_len, 0.);
+    (pcm, fit)
+}
+
+/// Mixes the translation over the source, both at `sample_rate`, the source being attenuated by
+/// `duck_db` (e.g. -15) while the translation is speaking. The output is as long as the longest.
+pub fn duck_mix(source: &[f32], translation: &[f32], duck_db: f32, sample_rate: usize) -> Vec<f32> {
+    let window = ((DUCK_WINDOW_SECS * sample_rate as f64) as usize).max(1);
+    let hold_windows = (DUCK_HOLD_SECS / DUCK_WINDOW_SECS).round() as usize;
+    let duck_gain = 10f32.powf(duck_db.min(0.) / 20.);
+    let attack = 1. / (DUCK_ATTACK_SECS * sample_rate as f64) as f32;
+    let release = 1. / (DUCK_RELEASE_SECS * sample_rate as f64) as f32;
+    let len = source.len().max(translation.len());
+    let mut out = Vec::with_capacity(len);
+    let mut gain = 1f32;
+    let mut quiet_windows = hold_windows;
+    for start in (0..len).step_by(window) {
+        let end = (start + window).min(len);
+        let speech = translation.get(start..end.min(translation.len())).unwrap_or(&[]);
+        if crate::events::frame_features(speech).db > SPEECH_DB {
+            quiet_windows = 0
+        } else {
+            quiet_windows += 1
+        }
+        let target = if quiet_windows <= hold_windows { duck_gain } else { 1. };
+        for idx in start..end {
+            gain = if gain > target {
+                (gain - attack).max(target)
+            } else {
+                (gain + release).min(target)
+            };
+            let src = source.get(idx).copied().unwrap_or(0.);
+            let tr = translation.get(idx).copied().unwrap_or(0.);
+            out.push(gain * src + tr)
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(len: usize) -> Vec<f32> {
+        (0..len).map(|idx| 0.5 * (idx as f32 * 0.1).sin()).collect()
+    }
+
+    // The number of samples before the trailing silence.
+    fn sound_len(pcm: &[f32]) -> usize {
+        pcm.len() - pcm.iter().rev().position(|v| v.abs() > SILENCE_THRESHOLD).unwrap_or(pcm.len())
+    }
+
+    #[test]
+    fn fit_trims_the_leading_silence() {
+        let mut pcm = vec![0.; 100];
+        pcm.extend(tone(1000));
+        let (fitted, fit) = fit_to_duration(pcm.clone(), 1000, 0.15);
+        assert_eq!(fit, Fit { skip: 100, ratio: 1. });
+        assert_eq!(fitted, pcm[100..]);
+        // Only the silence in excess of the target is removed.
+        let (fitted, fit) = fit_to_duration(pcm.clone(), 1050, 0.15);
+        assert_eq!(fit, Fit { skip: 50, ratio: 1. });
+        assert_eq!(fitted, pcm[50..]);
+        assert_eq!(leading_silence(&pcm), 101);
+    }
+
+    #[test]
+    fn fit_clamps_the_stretch() {
+        for (target_len, ratio) in [(1100, 1.1), (2000, 1.15), (500, 0.85)] {
+            let (fitted, fit) = fit_to_duration(tone(1000), target_len, 0.15);
+            assert_eq!(fitted.len(), target_len);
+            assert!((fit.ratio - ratio).abs() < 1e-9, "{target_len} {fit:?}");
+            // The stretched audio is padded with silence or truncated.
+            let expected = usize::min(target_len, (1000. * ratio) as usize);
+            assert!(sound_len(&fitted).abs_diff(expected) < 20, "{target_len}");
+        }
+        // Without stretching the audio is only padded or truncated.
+        let (fitted, fit) = fit_to_duration(tone(1000), 1200, 0.);
+        assert_eq!(fit, Fit::NONE);
+        assert_eq!(fitted[..1000], tone(1000));
+        assert_eq!(fitted[1000..], [0.; 200]);
+        let (fitted, fit) = fit_to_duration(vec![], 100, 0.15);
+        assert_eq!((fitted, fit), (vec![0.; 100], Fit::NONE));
+    }
+
+    #[test]
+    fn duck_mix_follows_the_translation() {
+        let sample_rate = 1000;
+        let source = vec![0.1; 2000];
+        // The translation speaks for the first half second.
+        let mut translation = tone(500);
+        translation.extend([0.; 500]);
+        let mix = duck_mix(&source, &translation, -20., sample_rate);
+        assert_eq!(mix.len(), 2000);
+        let source_gain = |idx: usize| (mix[idx] - translation.get(idx).unwrap_or(&0.)) / 0.1;
+        assert!((source_gain(0) - 0.98).abs() < 1e-3, "{}", source_gain(0));
+        // Ducked once the attack is over and during the hold after the translation.
+        for idx in [100, 499, 700] {
+            assert!((source_gain(idx) - 0.1).abs() < 1e-3, "{idx} {}", source_gain(idx));
+        }
+        // Back to the full level after the release.
+        assert!(source_gain(1000) > 0.1 && source_gain(1000) < 1.);
+        assert!((source_gain(1999) - 1.).abs() < 1e-3);
+        // A silent translation or a positive attenuation leave the source as it is.
+        assert_eq!(duck_mix(&source, &[0.; 300], -20., sample_rate), source);
+        assert_eq!(duck_mix(&source, &[], 6., sample_rate), source);
+    }
+}
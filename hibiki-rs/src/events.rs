@@ -0,0 +1,285 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Heuristic detection of non-speech events, this relies on the input energy and on the model
+// emitting text padding tokens, i.e. not translating anything, for a sustained period.
+// Non-speech sounds are split by their zero-crossing rate: noise-like sounds are applause, the
+// others are laughter when their energy comes in bursts within the steps and music otherwise.
+
+const SILENCE_DB: f32 = -50.;
+// Zero-crossing rate above which a non-speech sound is considered as noise-like, e.g. applause.
+const NOISE_ZCR: f32 = 0.25;
+// Spread between the loudest and quietest parts of a step above which its energy is bursty.
+const BURST_DB: f32 = 12.;
+// Each step is split in this many parts to measure the energy spread.
+const NUM_BURST_PARTS: usize = 8;
+// Minimum durations of the events, in 80ms steps.
+const MIN_SILENCE_STEPS: usize = 25;
+const MIN_LAUGHTER_STEPS: usize = 12;
+const MIN_SOUND_STEPS: usize = 38;
+// A run of non-speech is only interrupted by more than this many steps of something else.
+const MAX_GAP_STEPS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Silence,
+    Laughter,
+    Applause,
+    Music,
+}
+
+impl Event {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Silence => "[silence]",
+            Self::Laughter => "[laughter]",
+            Self::Applause => "[applause]",
+            Self::Music => "[music]",
+        }
+    }
+}
+
+/// An event, over the steps of the non-speech run up to its detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Marker {
+    pub event: Event,
+    pub start_step: usize,
+    pub end_step: usize,
+}
+
+/// Inserts the event labels as words, in step order.
+pub fn with_words(
+    words: &[crate::alignment::Word],
+    markers: &[Marker],
+) -> Vec<crate::alignment::Word> {
+    let mut words = words.to_vec();
+    for marker in markers {
+        let idx = words.partition_point(|w| w.start_step <= marker.start_step);
+        let word = crate::alignment::Word {
+            text: marker.event.label().to_string(),
+            start_step: marker.start_step,
+            end_step: marker.end_step,
+        };
+        words.insert(idx, word)
+    }
+    words
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameFeatures {
+    pub db: f32,
+    pub zcr: f32,
+    // The spread in dB between the loudest and quietest parts of the frame.
+    pub burst_db: f32,
+}
+
+fn db(pcm: &[f32]) -> f32 {
+    let mean_squares = pcm.iter().map(|v| v * v).sum::<f32>() / pcm.len() as f32;
+    10. * (mean_squares + 1e-10).log10()
+}
+
+pub fn frame_features(pcm: &[f32]) -> FrameFeatures {
+    if pcm.is_empty() {
+        return FrameFeatures { db: f32::NEG_INFINITY, zcr: 0., burst_db: 0. };
+    }
+    let crossings = pcm.windows(2).filter(|w| (w[0] >= 0.) != (w[1] >= 0.)).count();
+    let part_len = pcm.len().div_ceil(NUM_BURST_PARTS);
+    let (min_db, max_db) = pcm
+        .chunks(part_len)
+        .map(|part| db(part).max(SILENCE_DB))
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+    FrameFeatures {
+        db: db(pcm),
+        zcr: crossings as f32 / pcm.len() as f32,
+        burst_db: max_db - min_db,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sound {
+    Silence,
+    Noise,
+    Tonal,
+}
+
+#[derive(Debug, Default)]
+pub struct Detector {
+    current: Option<Sound>,
+    run_len: usize,
+    // The steps of the run with a bursty energy.
+    bursty_steps: usize,
+    // The category of the consecutive steps that do not belong to the current run, and their
+    // number.
+    candidate: Option<Sound>,
+    gap_len: usize,
+    emitted: bool,
+}
+
+impl Detector {
+    /// Processes a single step, returns an event the first time a sustained run of the same
+    /// non-speech category gets long enough.
+    pub fn step(&mut self, features: FrameFeatures, text_is_pad: bool) -> Option<Event> {
+        let sound = if features.db < SILENCE_DB {
+            Some(Sound::Silence)
+        } else if !text_is_pad {
+            None
+        } else if features.zcr > NOISE_ZCR {
+            Some(Sound::Noise)
+        } else {
+            Some(Sound::Tonal)
+        };
+        if sound != self.current {
+            if self.gap_len == 0 || sound != self.candidate {
+                self.candidate = sound;
+                self.gap_len = 0;
+            }
+            self.gap_len += 1;
+            if self.gap_len <= MAX_GAP_STEPS {
+                return None;
+            }
+            // The steps of the gap were all of the new category.
+            self.current = sound;
+            self.run_len = MAX_GAP_STEPS;
+            self.bursty_steps = 0;
+            self.emitted = false;
+        }
+        self.gap_len = 0;
+        let sound = sound?;
+        self.run_len += 1;
+        if features.burst_db > BURST_DB {
+            self.bursty_steps += 1
+        }
+        if self.emitted {
+            return None;
+        }
+        let event = match sound {
+            Sound::Silence if self.run_len >= MIN_SILENCE_STEPS => Some(Event::Silence),
+            Sound::Noise if self.run_len >= MIN_SOUND_STEPS => Some(Event::Applause),
+            Sound::Tonal
+                if self.run_len >= MIN_LAUGHTER_STEPS && 2 * self.bursty_steps >= self.run_len =>
+            {
+                Some(Event::Laughter)
+            }
+            Sound::Tonal if self.run_len >= MIN_SOUND_STEPS => Some(Event::Music),
+            _ => None,
+        };
+        self.emitted = event.is_some();
+        event
+    }
+
+    /// The number of steps in the current run of non-speech.
+    pub fn run_len(&self) -> usize {
+        self.run_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUIET: FrameFeatures = FrameFeatures { db: -60., zcr: 0., burst_db: 0. };
+    const NOISE: FrameFeatures = FrameFeatures { db: -20., zcr: 0.4, burst_db: 3. };
+    const TONAL: FrameFeatures = FrameFeatures { db: -20., zcr: 0.05, burst_db: 3. };
+    const BURSTY: FrameFeatures = FrameFeatures { db: -20., zcr: 0.05, burst_db: 20. };
+
+    // The steps at which events are returned, and the events.
+    fn run(detector: &mut Detector, steps: &[(FrameFeatures, bool)]) -> Vec<(usize, Event)> {
+        steps
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, &(features, is_pad))| Some((idx, detector.step(features, is_pad)?)))
+            .collect()
+    }
+
+    #[test]
+    fn features() {
+        let tone: Vec<f32> = (0..1920).map(|i| 0.1 * (i as f32 * 0.05).sin()).collect();
+        let features = frame_features(&tone);
+        assert!((features.db + 23.).abs() < 0.1, "{features:?}");
+        assert!(features.zcr < NOISE_ZCR);
+        assert!(features.burst_db < BURST_DB);
+        // Half of the frame is silent.
+        let burst: Vec<f32> =
+            tone.iter().enumerate().map(|(i, v)| if i < 960 { *v } else { 0. }).collect();
+        assert!(frame_features(&burst).burst_db > BURST_DB);
+        let alternating: Vec<f32> =
+            (0..1920).map(|i| if i % 2 == 0 { 0.1 } else { -0.1 }).collect();
+        assert!(frame_features(&alternating).zcr > NOISE_ZCR);
+        assert_eq!(frame_features(&[]).db, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn thresholds() {
+        // Each event is returned once, when its run reaches the minimum duration.
+        let mut detector = Detector::default();
+        let steps = vec![(QUIET, true); 40];
+        assert_eq!(run(&mut detector, &steps), [(MIN_SILENCE_STEPS - 1, Event::Silence)]);
+        assert_eq!(detector.run_len(), 40);
+        let cases = [
+            (NOISE, Event::Applause, MIN_SOUND_STEPS),
+            (TONAL, Event::Music, MIN_SOUND_STEPS),
+            (BURSTY, Event::Laughter, MIN_LAUGHTER_STEPS),
+        ];
+        for (features, event, min_steps) in cases {
+            let mut detector = Detector::default();
+            let steps = vec![(features, true); 50];
+            assert_eq!(run(&mut detector, &steps), [(min_steps - 1, event)]);
+        }
+        // The model translating means that there is speech, unless the input is silent.
+        let mut detector = Detector::default();
+        assert_eq!(run(&mut detector, &vec![(NOISE, false); 50]), []);
+        let mut detector = Detector::default();
+        assert_eq!(run(&mut detector, &vec![(QUIET, false); 30]).len(), 1);
+    }
+
+    #[test]
+    fn hysteresis() {
+        // Short interruptions do not reset the run, nor emit the event a second time.
+        let mut detector = Detector::default();
+        let mut steps = vec![(NOISE, true); 20];
+        steps.extend([(TONAL, false); MAX_GAP_STEPS]);
+        steps.extend([(NOISE, true); 100]);
+        steps.extend([(TONAL, false); MAX_GAP_STEPS]);
+        steps.extend([(NOISE, true); 10]);
+        assert_eq!(
+            run(&mut detector, &steps),
+            [(MIN_SOUND_STEPS + MAX_GAP_STEPS - 1, Event::Applause)]
+        );
+        // A longer interruption starts a new run.
+        let mut detector = Detector::default();
+        let mut steps = vec![(NOISE, true); 40];
+        steps.extend([(TONAL, false); MAX_GAP_STEPS + 1]);
+        steps.extend([(NOISE, true); 40]);
+        let gap_end = 40 + MAX_GAP_STEPS + 1;
+        assert_eq!(
+            run(&mut detector, &steps),
+            [
+                (MIN_SOUND_STEPS - 1, Event::Applause),
+                (gap_end + MIN_SOUND_STEPS - 1, Event::Applause)
+            ]
+        );
+        // Music is only labelled as laughter when most of its steps are bursty.
+        let mut detector = Detector::default();
+        let steps: Vec<_> =
+            (0..50).map(|i| (if i % 3 == 0 { BURSTY } else { TONAL }, true)).collect();
+        assert_eq!(run(&mut detector, &steps), [(MIN_SOUND_STEPS - 1, Event::Music)]);
+    }
+
+    #[test]
+    fn markers_as_words() {
+        let word = |text: &str, start_step, end_step| crate::alignment::Word {
+            text: text.to_string(),
+            start_step,
+            end_step,
+        };
+        let words = [word("Hello", 0, 4), word("world.", 40, 44)];
+        let markers = [Marker { event: Event::Applause, start_step: 6, end_step: 44 }];
+        let texts: Vec<_> =
+            with_words(&words, &markers).into_iter().map(|w| (w.text, w.start_step)).collect();
+        assert_eq!(
+            texts,
+            [("Hello".to_string(), 0), ("[applause]".to_string(), 6), ("world.".to_string(), 40)]
+        );
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+/// The default number of chunks buffered per subscriber, at 80ms per chunk this is ~4s.
+pub const DEFAULT_BUFFER_LEN: usize = 50;
+
+struct Subscriber<T> {
+    id: usize,
+    tx: SyncSender<T>,
+}
+
+struct Inner<T> {
+    next_id: usize,
+    buffer_len: usize,
+    subscribers: Vec<Subscriber<T>>,
+}
+
+/// Broadcasts the chunks produced by a single session (e.g. the generated audio) to an arbitrary
+/// number of listeners. Each listener gets its own bounded buffer, listeners that do not keep up
+/// with the producer get evicted rather than stalling the generation loop.
+pub struct Fanout<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Clone for Fanout<T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<T: Clone> Fanout<T> {
+    pub fn new(buffer_len: usize) -> Self {
+        let inner = Inner { next_id: 0, buffer_len, subscribers: vec![] };
+        Self { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    /// Registers a new listener, the receiver gets disconnected when the listener is evicted or
+    /// when the fanout is closed.
+    pub fn subscribe(&self) -> (usize, Receiver<T>) {
+        let mut inner = self.inner.lock().unwrap();
+        let (tx, rx) = mpsc::sync_channel(inner.buffer_len);
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.subscribers.push(Subscriber { id, tx });
+        tracing::info!(id, "new fanout subscriber");
+        (id, rx)
+    }
+
+    pub fn unsubscribe(&self, id: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.subscribers.retain(|s| s.id != id)
+    }
+
+    pub fn num_subscribers(&self) -> usize {
+        self.inner.lock().unwrap().subscribers.len()
+    }
+
+    /// Sends a chunk to all the subscribers without blocking.
+    pub fn publish(&self, v: T) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.subscribers.retain(|s| match s.tx.try_send(v.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!(id = s.id, "evicting slow fanout subscriber");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        })
+    }
+
+    /// Drops all the subscribers, their receivers will return an error once they have consumed
+    /// the buffered chunks.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().subscribers.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broadcast_to_all_subscribers() {
+        let fanout = Fanout::new(4);
+        let (_, rx1) = fanout.subscribe();
+        let (id2, rx2) = fanout.subscribe();
+        fanout.publish(1);
+        fanout.unsubscribe(id2);
+        fanout.publish(2);
+        assert_eq!(fanout.num_subscribers(), 1);
+        assert_eq!(rx1.try_iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(rx2.recv(), Ok(1));
+        assert!(rx2.recv().is_err());
+    }
+
+    #[test]
+    fn evict_slow_subscribers() {
+        let fanout = Fanout::new(2);
+        let (_, slow) = fanout.subscribe();
+        let (_, fast) = fanout.subscribe();
+        for v in 0..2 {
+            fanout.publish(v);
+            assert_eq!(fast.recv(), Ok(v));
+        }
+        // The slow subscriber's buffer is full, it is dropped and the other one is not blocked.
+        fanout.publish(2);
+        assert_eq!(fanout.num_subscribers(), 1);
+        assert_eq!(fast.recv(), Ok(2));
+        assert_eq!(slow.try_iter().collect::<Vec<_>>(), [0, 1]);
+        assert!(slow.recv().is_err());
+        // A dropped receiver is removed on the next chunk.
+        drop(fast);
+        fanout.publish(3);
+        assert_eq!(fanout.num_subscribers(), 0);
+    }
+
+    #[test]
+    fn close_disconnects_after_the_buffered_chunks() {
+        let fanout = Fanout::new(4);
+        let (_, rx) = fanout.subscribe();
+        fanout.clone().publish("chunk");
+        fanout.close();
+        assert_eq!(rx.recv(), Ok("chunk"));
+        assert!(rx.recv().is_err());
+        assert_eq!(fanout.num_subscribers(), 0);
+    }
+}
@@ -1,5 +1,7 @@
-use anyhow::Result;
-use candle::{Device, IndexOp, Tensor};
+use anyhow::{Context, Result};
+use candle::{DType, Device, Tensor};
+
+use crate::codec::AudioCodec;
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Config {
@@ -9,8 +11,10 @@ pub struct Config {
     pub model: moshi::lm::Config,
 }
 
+#[derive(Debug, Clone)]
 pub struct Args {
     pub lm_config: moshi::lm::Config,
+    pub config_file: std::path::PathBuf,
     pub lm_model_file: std::path::PathBuf,
     pub mimi_model_file: std::path::PathBuf,
     pub audio_input_file: std::path::PathBuf,
@@ -18,9 +22,109 @@ pub struct Args {
     pub audio_output_file: std::path::PathBuf,
     pub seed: u64,
     pub cfg_alpha: Option<f64>,
+    pub dtype: candle::DType,
+    pub frames_per_batch: usize,
+    pub mark_events: bool,
+    pub max_text_updates_per_sec: Option<f64>,
+    pub translation_memory: Option<std::path::PathBuf>,
+    pub num_takes: usize,
+    pub keep_text: bool,
+    pub pad_bias: Option<f32>,
+    pub fit_duration: bool,
+    pub max_stretch: f64,
+    pub pitch_shift: f64,
+    pub formant_shift: f64,
+    pub max_steps: usize,
+    pub warn_slow_steps: bool,
+    pub transcript_file: Option<std::path::PathBuf>,
+    pub autosave_secs: f64,
+    pub emit_token_ids: Option<std::path::PathBuf>,
+    pub parity_reference: Option<std::path::PathBuf>,
+    pub trace: Option<std::path::PathBuf>,
+    pub min_text_confidence: Option<f32>,
+    pub quantize_on_load: Option<candle::quantized::GgmlDType>,
+    pub skip_silent_depformer: bool,
+    pub wav_format: crate::audio_io::WavFormat,
+    /// Write the source on the left channel of the output and the translation on the right one.
+    pub stereo_mix: bool,
+    /// Mix the translation over the source, ducked by this gain in dB, in a single track.
+    pub dub_mix: Option<f32>,
+    /// The sample rate of the output audio, the codec sample rate when not set.
+    pub output_sample_rate: Option<usize>,
+    /// Do not write the generated text to stdout, see `quiet`.
+    pub quiet: bool,
+    /// Show a progress bar on stderr when running from the command line.
+    pub progress: bool,
+    pub condition_mix: Option<Vec<(String, f64)>>,
+    pub target_language: crate::lang::Language,
+    /// The playback device when the audio is played as it is generated.
+    pub play: Option<String>,
+    pub play_buffer: crate::audio_io::PlaybackBuffer,
+    /// The source audio preceding the start tone of live mode translated first, in seconds.
+    pub pre_roll_secs: f64,
+    pub word_alignment: Option<std::path::PathBuf>,
+    pub subtitles: Option<std::path::PathBuf>,
+    pub cue_stats: Option<std::path::PathBuf>,
+    pub chapters: Option<std::path::PathBuf>,
+    pub clip_markers: Option<std::path::PathBuf>,
+    pub json_output: Option<std::path::PathBuf>,
+    /// The range of the input to translate, in seconds.
+    pub start: f64,
+    pub end: Option<f64>,
+    pub audio_sampling: SamplingParams,
+    pub text_sampling: SamplingParams,
+    /// Number of beams for the beam search over the text, 1 to sample the text.
+    pub text_beams: usize,
+    /// A draft translation that the text has to follow, with at most `draft_max_edits` edits.
+    pub draft: Option<String>,
+    pub draft_max_edits: usize,
+    /// The audio temperature is lowered to `audio_anneal_temperature` for this many steps around
+    /// the utterance boundaries, 0 to disable.
+    pub audio_anneal_window: usize,
+    pub audio_anneal_temperature: f64,
+    /// The generation ends once one of these sequences appears in the text.
+    pub stop_sequences: Vec<String>,
+}
+
+/// Sampling parameters for one of the streams, a temperature of 0 means greedy decoding and the
+/// top-k and top-p filters are only applied when set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingParams {
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
 }
 
-fn text(
+impl SamplingParams {
+    pub fn sampling(&self) -> candle_transformers::generation::Sampling {
+        use candle_transformers::generation::Sampling;
+        let temperature = self.temperature;
+        if temperature <= 0. {
+            return Sampling::ArgMax;
+        }
+        match (self.top_k, self.top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+}
+
+pub fn multistream_config(lm_config: &moshi::lm::Config) -> moshi::lm_generate_multistream::Config {
+    let generated_audio_codebooks = lm_config.depformer.as_ref().map_or(8, |v| v.num_slices);
+    moshi::lm_generate_multistream::Config {
+        acoustic_delay: 2,
+        audio_vocab_size: lm_config.audio_vocab_size,
+        generated_audio_codebooks,
+        input_audio_codebooks: lm_config.audio_codebooks - generated_audio_codebooks,
+        text_start_token: lm_config.text_out_vocab_size as u32,
+        text_eop_token: 0,
+        text_pad_token: 3,
+    }
+}
+
+pub fn text(
     text_tokenizer: &sentencepiece::SentencePieceProcessor,
     prev_text_token: u32,
     text_token: u32,
@@ -43,142 +147,926 @@ fn text(
     }
 }
 
-pub fn run(args: &Args, dev: &Device) -> Result<()> {
-    let dtype = dev.bf16_default_to_f32();
-    let lm_config = &args.lm_config;
-    tracing::info!(?dtype, ?dev);
+/// The condition embedding for the generation, this is the "very_good" entry of the description
+/// lut unless a weighted mix of entries is provided.
+fn condition(args: &Args, cp: &moshi::conditioner::ConditionProvider) -> Result<Tensor> {
+    use moshi::conditioner::Condition::AddToInput;
+    let mix = match args.condition_mix.as_ref() {
+        None => {
+            let AddToInput(c) = cp.condition_lut("description", "very_good")?;
+            return Ok(c);
+        }
+        Some(mix) => mix,
+    };
+    let mut sum: Option<Tensor> = None;
+    for (value, weight) in mix.iter() {
+        let AddToInput(c) = cp.condition_lut("description", value)?;
+        let c = (c * *weight)?;
+        sum = Some(match sum {
+            None => c,
+            Some(sum) => (sum + c)?,
+        })
+    }
+    sum.ok_or_else(|| anyhow::anyhow!("empty condition mix"))
+}
+
+/// The audio and text sampling, greedy decoding is used when comparing with reference tokens.
+pub fn samplings(
+    args: &Args,
+) -> (candle_transformers::generation::Sampling, candle_transformers::generation::Sampling) {
+    use candle_transformers::generation::Sampling;
+    if args.parity_reference.is_some() {
+        (Sampling::ArgMax, Sampling::ArgMax)
+    } else {
+        (args.audio_sampling.sampling(), args.text_sampling.sampling())
+    }
+}
 
-    tracing::info!("loading the audio input");
-    let (in_pcm, in_pcm_len) = {
-        let (mut pcm, sample_rate) = crate::audio_io::pcm_decode(&args.audio_input_file)?;
+/// The audio sampling around the utterance boundaries, if enabled.
+pub fn boundary_sampling(args: &Args) -> Option<candle_transformers::generation::Sampling> {
+    if args.audio_anneal_window == 0 || args.parity_reference.is_some() {
+        return None;
+    }
+    let temperature = args.audio_sampling.temperature.min(args.audio_anneal_temperature);
+    Some(SamplingParams { temperature, ..args.audio_sampling }.sampling())
+}
+
+/// Returns the output path for a given take, takes are numbered from 1 when there are more than
+/// one of them, e.g. `out_1.wav`, `out_2.wav`, ...
+pub fn take_path(path: &std::path::Path, take: usize, num_takes: usize) -> std::path::PathBuf {
+    if num_takes <= 1 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map_or_else(String::new, |v| v.to_string_lossy().to_string());
+    let file_name = match path.extension() {
+        None => format!("{stem}_{}", take + 1),
+        Some(ext) => format!("{stem}_{}.{}", take + 1, ext.to_string_lossy()),
+    };
+    path.with_file_name(file_name)
+}
+
+/// The audio input prepared for generation, resampled to the codec sample rate and padded.
+pub struct Input {
+    pcm: Tensor,
+    pcm_len: usize,
+    // Length of the source audio at the codec sample rate, before any padding.
+    source_len: usize,
+    frame_features: Vec<crate::events::FrameFeatures>,
+    metadata: crate::metadata::Metadata,
+}
+
+impl Input {
+    pub fn load(args: &Args, codec: &dyn AudioCodec, dev: &Device) -> Result<Self> {
+        tracing::info!("loading the audio input");
+        let codec_sample_rate = codec.sample_rate();
+        let frame_size = codec.frame_size();
+        let (mut pcm, sample_rate) =
+            crate::audio_io::pcm_decode_range(&args.audio_input_file, args.start, args.end)?;
+        if pcm.is_empty() {
+            anyhow::bail!("no audio in the requested range of {:?}", args.audio_input_file)
+        }
+        let metadata = crate::metadata::Metadata::read(&args.audio_input_file)?;
+        let source_len =
+            (pcm.len() as f64 * codec_sample_rate as f64 / sample_rate as f64).round() as usize;
         pcm.extend_from_slice(&vec![0.0; 12000]);
-        let pcm = if sample_rate != 24_000 {
-            crate::audio_io::resample(&pcm, sample_rate as usize, 24_000)?
+        let mut pcm = if sample_rate as usize != codec_sample_rate {
+            crate::audio_io::resample(&pcm, sample_rate as usize, codec_sample_rate)?
         } else {
             pcm
         };
+        if args.fit_duration {
+            pcm.extend_from_slice(&vec![0.0; crate::dubbing::MAX_TAIL_STEPS * frame_size]);
+        }
         let pcm_len = pcm.len();
+        let frame_features: Vec<_> = if args.mark_events {
+            pcm.chunks(frame_size).map(crate::events::frame_features).collect()
+        } else {
+            vec![]
+        };
         let pcm = Tensor::from_vec(pcm, (1, 1, pcm_len), dev)?;
-        (pcm, pcm_len)
-    };
-    tracing::info!(in_pcm_len, "loaded the audio input");
+        tracing::info!(pcm_len, "loaded the audio input");
+        Ok(Self { pcm, pcm_len, source_len, frame_features, metadata })
+    }
 
-    tracing::info!("loading the lm");
-    let lm_model = moshi::lm::load_lm_model(lm_config.clone(), &args.lm_model_file, dtype, dev)?;
+    /// The source audio at the codec sample rate, padded.
+    pub fn pcm(&self) -> &Tensor {
+        &self.pcm
+    }
+
+    /// Identifies the input audio together with the settings that the outputs depend on.
+    pub fn fingerprint(&self, args: &Args) -> Result<String> {
+        let pcm = self.pcm.flatten_all()?.to_vec1::<f32>()?;
+        Ok(crate::memory::audio_fingerprint(&pcm, &settings_key(args)))
+    }
+}
+
+fn load_codec(args: &Args, dev: &Device) -> Result<Box<dyn AudioCodec>> {
+    let generated_audio_codebooks = multistream_config(&args.lm_config).generated_audio_codebooks;
     tracing::info!("loading the audio tokenizer");
-    let mut mimi = moshi::mimi::load(
-        args.mimi_model_file.to_str().unwrap(),
-        Some(lm_model.generated_audio_codebooks()),
-        dev,
-    )?;
+    let codec = crate::codec::load(&args.mimi_model_file, generated_audio_codebooks, dev)?;
+    if codec.num_codebooks() < generated_audio_codebooks {
+        anyhow::bail!(
+            "the audio tokenizer has {} codebooks but the lm uses {generated_audio_codebooks}",
+            codec.num_codebooks()
+        )
+    }
+    Ok(codec)
+}
+
+fn load_lm(args: &Args, dev: &Device) -> Result<moshi::lm::LmModel> {
+    tracing::info!("loading the lm");
+    // The kv-cache is sized for the maximum number of steps rather than the model context.
+    let mut lm_config = args.lm_config.clone();
+    lm_config.transformer.max_seq_len = crate::resources::cache_len(args.max_steps);
+    let lm_model = match args.quantize_on_load {
+        None => crate::crypt::load_lm_model(lm_config, &args.lm_model_file, args.dtype, dev)?,
+        Some(qdtype) => {
+            crate::quantize::load_lm_model(lm_config, &args.lm_model_file, qdtype, dev)?
+        }
+    };
+    Ok(lm_model)
+}
+
+fn load_text_tokenizer(args: &Args) -> Result<sentencepiece::SentencePieceProcessor> {
     tracing::info!("loading the text tokenizer");
-    let text_tokenizer = sentencepiece::SentencePieceProcessor::open(&args.text_tokenizer)?;
-    tracing::info!("done loading models");
+    let text_tokenizer = crate::crypt::open_tokenizer(&args.text_tokenizer)?;
+    Ok(text_tokenizer)
+}
 
-    let audio_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
+/// The models used for generation, these can be kept around to process multiple inputs.
+pub struct Models {
+    pub lm_model: moshi::lm::LmModel,
+    pub codec: Box<dyn AudioCodec>,
+    pub text_tokenizer: sentencepiece::SentencePieceProcessor,
+}
+
+impl Models {
+    pub fn load(args: &Args, dev: &Device) -> Result<Self> {
+        let codec = load_codec(args, dev)?;
+        let lm_model = load_lm(args, dev)?;
+        let text_tokenizer = load_text_tokenizer(args)?;
+        tracing::info!("done loading models");
+        Ok(Self { lm_model, codec, text_tokenizer })
+    }
+}
+
+pub type MemoryEntry = (crate::memory::TranslationMemory, String);
+
+pub enum MemoryLookup {
+    /// The output has been restored from the translation memory.
+    Hit,
+    /// The output has to be generated, and stored afterwards if a memory entry is provided.
+    Miss(Option<MemoryEntry>),
+}
+
+/// The generation settings that the outputs depend on, used to identify identical jobs. The
+/// model files are identified by their path, size and modification time so that different
+/// checkpoints with the same name do not collide.
+pub fn settings_key(args: &Args) -> String {
+    let models = [&args.lm_model_file, &args.mimi_model_file, &args.text_tokenizer]
+        .map(|v| crate::memory::file_key(v));
+    let generation = format!(
+        "{:?} {:?} {} {:?} {:?} {:?} {:?} {:?} {} {:?} {} {:?} {} {} {}",
+        models,
+        args.lm_config,
         args.seed,
-        candle_transformers::generation::Sampling::TopK { k: 250, temperature: 0.8 },
+        args.cfg_alpha,
+        args.dtype,
+        args.quantize_on_load,
+        args.condition_mix,
+        samplings(args),
+        args.text_beams,
+        args.draft,
+        args.draft_max_edits,
+        boundary_sampling(args).map(|v| (v, args.audio_anneal_window)),
+        args.keep_text,
+        args.skip_silent_depformer,
+        args.max_steps,
     );
-    let text_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
-        args.seed,
-        candle_transformers::generation::Sampling::TopK { k: 25, temperature: 0.8 },
+    let outputs = format!(
+        "{:?} {} {} {} {} {} {:?} {:?} {:?} {:?} {} {:?} {}",
+        args.pad_bias,
+        args.mark_events,
+        args.fit_duration,
+        args.max_stretch,
+        args.pitch_shift,
+        args.formant_shift,
+        args.min_text_confidence,
+        args.wav_format,
+        args.output_sample_rate,
+        args.stop_sequences,
+        args.stereo_mix,
+        args.dub_mix,
+        args.target_language.tag(),
     );
-    let generated_audio_codebooks = lm_config.depformer.as_ref().map_or(8, |v| v.num_slices);
+    format!("{generation} {outputs}")
+}
+
+// The sidecar outputs requested with their kind, these are stored in the translation memory
+// with the audio. The trace is not as it measures the generation itself.
+fn sidecars(args: &Args) -> Vec<(&'static str, &std::path::Path)> {
+    let sidecars = [
+        ("transcript", &args.transcript_file),
+        ("token_ids", &args.emit_token_ids),
+        ("word_alignment", &args.word_alignment),
+        ("subtitles", &args.subtitles),
+        ("cue_stats", &args.cue_stats),
+        ("chapters", &args.chapters),
+        ("clip_markers", &args.clip_markers),
+        ("json_output", &args.json_output),
+    ];
+    sidecars.into_iter().filter_map(|(kind, path)| Some((kind, path.as_deref()?))).collect()
+}
+
+/// Checks the translation memory for the input, on a hit the stored outputs are copied to the
+/// output file and to the requested sidecar outputs. Entries without some of the requested
+/// sidecar outputs are generated again.
+pub fn lookup_memory(
+    args: &Args,
+    input: &Input,
+    codec: &mut dyn AudioCodec,
+) -> Result<MemoryLookup> {
+    let dir = match args.translation_memory.as_ref() {
+        None => return Ok(MemoryLookup::Miss(None)),
+        Some(_) if args.num_takes > 1 => {
+            tracing::warn!("the translation memory is not used when generating multiple takes");
+            return Ok(MemoryLookup::Miss(None));
+        }
+        Some(_) if args.trace.is_some() => {
+            tracing::warn!("the translation memory is not used when writing a trace");
+            return Ok(MemoryLookup::Miss(None));
+        }
+        Some(dir) => dir,
+    };
+    let codes = codec.encode(&input.pcm)?.flatten_all()?.to_vec1::<u32>()?;
+    codec.reset_state();
+    let key = crate::memory::fingerprint(&codes, &settings_key(args));
+    let memory = crate::memory::TranslationMemory::new(dir);
+    if let Some(entry) = memory.lookup(&key)? {
+        let stored: Option<Vec<_>> = sidecars(args)
+            .into_iter()
+            .map(|(kind, path)| Some((entry.sidecar(kind, path)?, path)))
+            .collect();
+        match stored {
+            None => tracing::info!(key, "the memory entry lacks some requested outputs"),
+            Some(stored) => {
+                tracing::info!(key, "found the input in the translation memory");
+                if !args.quiet {
+                    println!("{}", entry.text);
+                }
+                std::fs::copy(&entry.audio_file, &args.audio_output_file)?;
+                for (stored, path) in stored {
+                    std::fs::copy(stored, path)?;
+                }
+                tracing::info!(audio = ?args.audio_output_file, "generated audio");
+                return Ok(MemoryLookup::Hit);
+            }
+        }
+    }
+    Ok(MemoryLookup::Miss(Some((memory, key))))
+}
+
+/// Dumps the per-step text and audio token ids as generated by the model, the audio tokens are
+/// stored with the acoustic delay applied, i.e. as fed back to the model.
+fn write_token_ids(
+    path: &std::path::Path,
+    args: &Args,
+    input: &Input,
+    history: &crate::longform::History,
+    text: &str,
+    provenance: &serde_json::Value,
+) -> Result<()> {
+    let config = multistream_config(&args.lm_config);
+    let mut metadata = serde_json::to_value(&input.metadata)?;
+    for (key, value) in crate::metadata::generator_tags(&args.lm_model_file) {
+        metadata[key] = value.into()
+    }
+    let json = serde_json::json!({
+        "language": args.target_language.tag(),
+        "direction": args.target_language.direction(),
+        "metadata": metadata,
+        "provenance": provenance,
+        "acoustic_delay": config.acoustic_delay,
+        "generated_audio_codebooks": config.generated_audio_codebooks,
+        "text_tokens": history.text_tokens,
+        "audio_tokens": history.audio_tokens,
+        "text": text,
+    });
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer(file, &json)?;
+    Ok(())
+}
+
+pub fn run(args: &Args, dev: &Device) -> Result<Summary> {
+    use crate::quiet::Failure;
+
+    tracing::info!(dtype = ?args.dtype, ?dev);
+    // The translation memory is checked before loading the lm so that hits are cheap.
+    let mut codec = load_codec(args, dev).context(Failure::Models)?;
+    let input = Input::load(args, codec.as_ref(), dev).context(Failure::Input)?;
+    let memory = match lookup_memory(args, &input, codec.as_mut())? {
+        MemoryLookup::Hit => return Ok(Summary::default()),
+        MemoryLookup::Miss(memory) => memory,
+    };
+    let mut text_tokenizer = load_text_tokenizer(args).context(Failure::Models)?;
+    // On out of memory errors while loading the lm or generating, the whole generation is
+    // retried with degraded settings until there is nothing left to degrade.
+    let mut args = args.clone();
+    loop {
+        let lm_model = match load_lm(&args, dev) {
+            Ok(lm_model) => lm_model,
+            Err(err) => {
+                args = degrade_on_oom(&args, dev, err).context(Failure::Models)?;
+                continue;
+            }
+        };
+        tracing::info!("done loading models");
+        let mut models = Models { lm_model, codec, text_tokenizer };
+        let cancel = crate::interrupt::flag();
+        let bar = args.progress.then(crate::progress::Bar::stderr);
+        let mut on_progress = |progress| {
+            if let Some(bar) = bar.as_ref() {
+                bar.update(progress)
+            }
+        };
+        let result = generate(
+            &args,
+            &mut models,
+            &input,
+            memory.as_ref(),
+            dev,
+            Some(cancel),
+            &mut on_progress,
+        );
+        if let Some(bar) = bar.as_ref() {
+            bar.finish()
+        }
+        match result {
+            Ok(summary) => return Ok(summary),
+            Err(err) => {
+                // Drop the lm before retrying so that its memory is released.
+                let Models { lm_model, codec: c, text_tokenizer: t } = models;
+                drop(lm_model);
+                (codec, text_tokenizer) = (c, t);
+                args = degrade_on_oom(&args, dev, err)?;
+            }
+        }
+    }
+}
 
+/// Returns the settings to retry with after an out of memory error, in order: a 16 bits dtype,
+/// int8 weights, int4 weights, then no classifier free guidance. Other errors are returned as
+/// is. The number of codebooks cannot be reduced as it is fixed by the depformer of the
+/// checkpoint.
+fn degrade_on_oom(args: &Args, dev: &Device, err: anyhow::Error) -> Result<Args> {
+    if !crate::resources::is_out_of_memory(&err) {
+        return Err(err);
+    }
+    let mut degraded = args.clone();
+    let quantized = crate::quantize::lm_is_quantized(args);
+    let change = if args.dtype == DType::F32 && !dev.is_cpu() && !quantized {
+        degraded.dtype = if dev.supports_bf16() { DType::BF16 } else { DType::F16 };
+        format!("with --dtype {}", degraded.dtype.as_str())
+    } else if !quantized {
+        degraded.quantize_on_load = Some(candle::quantized::GgmlDType::Q8_0);
+        "with --quantize-on-load int8".to_string()
+    } else if args.quantize_on_load == Some(candle::quantized::GgmlDType::Q8_0) {
+        degraded.quantize_on_load = Some(candle::quantized::GgmlDType::Q4K);
+        "with --quantize-on-load int4".to_string()
+    } else if args.cfg_alpha.is_some_and(|v| v != 1.) {
+        degraded.cfg_alpha = None;
+        "without --cfg-alpha".to_string()
+    } else {
+        return Err(err.context("out of memory with the most degraded settings"));
+    };
+    tracing::warn!(?err, "out of memory, retrying {change}");
+    Ok(degraded)
+}
+
+/// The conditions of the lm, with the negative condition appended on the batch dimension when
+/// using classifier free guidance.
+pub fn conditions(
+    args: &Args,
+    lm_model: &moshi::lm::LmModel,
+) -> Result<Option<moshi::conditioner::Condition>> {
     let conditions = match lm_model.condition_provider() {
         None => None,
         Some(cp) => {
+            use moshi::conditioner::Condition::AddToInput;
+            let c1 = condition(args, cp)?;
             let conditions = if args.cfg_alpha.is_some() {
-                use moshi::conditioner::Condition::AddToInput;
-                let AddToInput(c1) = cp.condition_lut("description", "very_good")?;
                 let AddToInput(c2) = cp.condition_lut("description", "very_bad")?;
                 AddToInput(Tensor::cat(&[c1, c2], 0)?)
             } else {
-                cp.condition_lut("description", "very_good")?
+                AddToInput(c1)
             };
             tracing::info!(?conditions, "generated conditions");
             Some(conditions)
         }
     };
-    let max_steps = (in_pcm_len / 1920).min(2500);
+    Ok(conditions)
+}
+
+/// Usage summary of a generation, accumulated over all the takes.
+#[derive(Debug, Clone, Default)]
+pub struct Summary {
+    pub steps: usize,
+    pub text_tokens: usize,
+    /// Time spent in the inference loops.
+    pub elapsed: std::time::Duration,
+    pub peak_lag: std::time::Duration,
+    /// Whether the generation was cancelled, the outputs then only cover part of the input.
+    pub cancelled: bool,
+    pub stats: crate::stats::GenStats,
+}
+
+/// Runs the generation for an input, the cancel flag is checked between batches of frames. Once
+/// it is set the generation stops and the outputs are written for the steps generated so far.
+/// The progress is reported after each batch.
+pub fn generate(
+    args: &Args,
+    models: &mut Models,
+    input: &Input,
+    memory: Option<&MemoryEntry>,
+    dev: &Device,
+    cancel: Option<&std::sync::atomic::AtomicBool>,
+    on_progress: &mut dyn FnMut(crate::progress::Progress),
+) -> Result<Summary> {
+    let wall_start = std::time::Instant::now();
+    let frame_size = models.codec.frame_size();
+    let sample_rate = models.codec.sample_rate();
+    let config = multistream_config(&args.lm_config);
+    let generated_audio_codebooks = config.generated_audio_codebooks;
+    let Input { pcm: in_pcm, pcm_len: in_pcm_len, source_len, frame_features, metadata } = input;
+    let (in_pcm_len, source_len) = (*in_pcm_len, *source_len);
+    let in_pcm = in_pcm.flatten_all()?.to_vec1::<f32>()?;
+
+    let num_steps = in_pcm_len / frame_size;
+    let chunks = crate::longform::chunks(num_steps, args.max_steps);
+    if chunks.len() > 1 {
+        tracing::info!(num_chunks = chunks.len(), "the input is translated in chunks");
+    } else if num_steps > args.max_steps {
+        tracing::warn!(max_steps = args.max_steps, "the input is truncated to the maximum steps");
+    }
+    let source_steps = source_len / frame_size;
+    let step_duration = frame_size as f64 / sample_rate as f64;
     let cfg_alpha = if args.cfg_alpha == Some(1.) { None } else { args.cfg_alpha };
-    let mut state = {
-        let config = moshi::lm_generate_multistream::Config {
-            acoustic_delay: 2,
-            audio_vocab_size: lm_config.audio_vocab_size,
-            generated_audio_codebooks,
-            input_audio_codebooks: lm_config.audio_codebooks - generated_audio_codebooks,
-            text_start_token: lm_config.text_out_vocab_size as u32,
-            text_eop_token: 0,
-            text_pad_token: 3,
-        };
-        moshi::lm_generate_multistream::State::new(
-            lm_model,
-            max_steps + 20,
-            audio_lp,
-            text_lp,
-            None,
-            None,
-            cfg_alpha,
-            config,
-        )
+    // Check that the kv-cache fits before starting, rather than failing on the first step.
+    let cache_len = crate::resources::cache_len(args.max_steps);
+    // Each beam has its own kv-cache.
+    let batch_size = if cfg_alpha.is_some() { 2 } else { 1 } * args.text_beams.max(1);
+    let kv_dtype =
+        crate::resources::kv_cache_dtype(args.dtype, crate::quantize::lm_is_quantized(args));
+    let kv_cache_bytes =
+        crate::resources::kv_cache_bytes(&args.lm_config, cache_len, kv_dtype, batch_size);
+    if let Some(available) = crate::resources::available_memory(dev) {
+        if kv_cache_bytes > available {
+            let msg = format!(
+                "the kv-cache for {} steps requires {}MB but only {}MB are available on {dev:?}, \
+                 try a lower --max-steps",
+                args.max_steps,
+                kv_cache_bytes >> 20,
+                available >> 20
+            );
+            return Err(crate::resources::OutOfMemory(msg).into());
+        }
+    }
+    let num_takes = args.num_takes.max(1);
+    // The full text token sequence of the first take, including the padding tokens, this is
+    // used to force the text of the subsequent takes when keep_text is set.
+    let mut first_take_text_tokens: Option<Vec<u32>> = None;
+    let text_writer = if args.quiet {
+        crate::output::TextWriter::discard()
+    } else {
+        crate::output::TextWriter::stdout()
     };
-
-    let text_start_token = state.config().text_start_token;
-    let mut prev_text_token = text_start_token;
-    let mut out_pcms = vec![];
-    let mut text_tokens = vec![];
-    let mut nsteps = 0;
-    tracing::info!("starting the inference loop");
-    let start_time = std::time::Instant::now();
-    for start_index in 0..max_steps {
-        nsteps += 1;
-        let in_pcm = in_pcm.i((.., .., start_index * 1920..(start_index + 1) * 1920))?;
-        let codes = mimi.encode_step(&in_pcm.into())?;
-        if let Some(codes) = codes.as_option() {
-            let (_b, _codebooks, steps) = codes.dims3()?;
-            for step in 0..steps {
-                let codes = codes.i((.., .., step..step + 1))?;
-                let codes = codes.i((0, .., 0))?.to_vec1::<u32>()?;
-                let text_token =
-                    state.step_(Some(prev_text_token), &codes, None, None, conditions.as_ref())?;
-                if text_token != 0 && text_token != 3 {
-                    text_tokens.push(text_token);
-                    if let Some(text) =
-                        text(&text_tokenizer, prev_text_token, text_token, text_start_token)
-                    {
-                        use std::io::Write;
-                        print!("{text}");
-                        std::io::stdout().flush().unwrap();
-                    }
+    let parity_reference = match args.parity_reference.as_ref() {
+        None => None,
+        Some(path) => {
+            tracing::info!(?path, "using greedy decoding to compare with the reference tokens");
+            Some(crate::parity::Reference::load(path)?)
+        }
+    };
+    let provenance = match args.emit_token_ids {
+        None => serde_json::Value::Null,
+        Some(_) => crate::provenance::provenance(args)?,
+    };
+    let playback = match args.play.as_deref() {
+        None => None,
+        Some(device) => {
+            // The generation is not paced by a capture, there is no drift to compensate.
+            let buffer =
+                crate::audio_io::PlaybackBuffer { compensate_drift: false, ..args.play_buffer };
+            Some(crate::audio_io::Playback::open(device, sample_rate, buffer)?)
+        }
+    };
+    // With a beam search, the text of the best beam is forced for all the takes.
+    let beam_text_tokens = if args.text_beams > 1 {
+        Some(crate::beam::search(args, models, &in_pcm, &chunks, dev)?)
+    } else {
+        None
+    };
+    let draft = match args.draft.as_deref() {
+        None => None,
+        Some(text) => {
+            let draft = crate::draft::Draft::new(
+                text,
+                &models.text_tokenizer,
+                args.draft_max_edits,
+                &config,
+            )?;
+            Some(draft)
+        }
+    };
+    let mut summary = Summary::default();
+    let progress_start = std::time::Instant::now();
+    let total_steps = num_takes * chunks.iter().map(|c| c.end - c.start).sum::<usize>();
+    let mut steps_done = 0;
+    let mut recorder = crate::stats::GenStatsRecorder::default();
+    for take in 0..num_takes {
+        let mut history = crate::longform::History::default();
+        let mut out_pcm = vec![];
+        // The generated audio of the previous chunk after its last owned step.
+        let mut crossfade_tail: Vec<f32> = vec![];
+        // The number of output samples before the audio decoded on each step.
+        let mut step_offsets = vec![];
+        let mut num_samples = 0;
+        let mut text_tokens = vec![];
+        // The latency of each step, the steps of a batch get the average over the batch.
+        let mut step_latency_ms = vec![];
+        let mut nsteps = 0;
+        let mut event_detector = crate::events::Detector::default();
+        let mut events = vec![];
+        let mut pacer = crate::pacing::TextPacer::new(args.max_text_updates_per_sec);
+        let mut tail_pad_steps = 0;
+        let mut stop = crate::stop::StopSequences::new(&args.stop_sequences);
+        let mut transcript = String::new();
+        let mut autosave = args.transcript_file.as_ref().map(|path| {
+            let interval = std::time::Duration::from_secs_f64(args.autosave_secs);
+            let path = take_path(path, take, num_takes);
+            crate::transcript::Autosave::new(path, interval, args.target_language.clone())
+        });
+        let mut lag_monitor =
+            crate::realtime::LagMonitor::new(std::time::Duration::from_secs_f64(step_duration));
+        let mut trace = args
+            .trace
+            .as_ref()
+            .map(|_| crate::trace::Trace::new(step_duration, frame_size, config.text_start_token));
+        let mut stats = crate::stats::Stats::default();
+        tracing::info!(take, "starting the inference loop");
+        let start_time = std::time::Instant::now();
+        let frames_per_batch = args.frames_per_batch.max(1);
+        let mut done = false;
+        for chunk in chunks.iter() {
+            // The warm-up steps replay the text generated by the previous chunk, the whole text
+            // of the first take is replayed with keep_text.
+            let whole_text = beam_text_tokens
+                .as_deref()
+                .or(first_take_text_tokens.as_deref().filter(|_| args.keep_text));
+            let forced_text_tokens: Vec<u32> = match whole_text {
+                Some(tokens) => tokens.get(chunk.start..).unwrap_or(&[]).to_vec(),
+                None => history.text_tokens[chunk.start..chunk.own_start].to_vec(),
+            };
+            let mut session = crate::session::GenSession::new(args, models, take, dev)?;
+            if !forced_text_tokens.is_empty() {
+                session = session.with_forced_text_tokens(&forced_text_tokens)
+            }
+            if let Some(draft) = draft.as_ref() {
+                session = session.with_draft(draft.after(&history.text_tokens[..chunk.start]))
+            }
+            let mut next_tail = vec![];
+            'steps: for start_index in (chunk.start..chunk.end).step_by(frames_per_batch) {
+                if cancel.is_some_and(|v| v.load(std::sync::atomic::Ordering::Relaxed)) {
+                    tracing::warn!(step = start_index, "generation cancelled, writing the outputs");
+                    summary.cancelled = true;
+                    done = true;
+                    break 'steps;
                 }
-                prev_text_token = text_token;
-                if let Some(audio_tokens) = state.last_audio_tokens() {
-                    let audio_tokens =
-                        Tensor::new(&audio_tokens[..generated_audio_codebooks], dev)?
-                            .reshape((1, 1, ()))?
-                            .t()?;
-                    let out_pcm = mimi.decode_step(&audio_tokens.into())?;
-                    if let Some(out_pcm) = out_pcm.as_option() {
-                        out_pcms.push(out_pcm.clone());
+                let batch_start = std::time::Instant::now();
+                let end_index = usize::min(start_index + frames_per_batch, chunk.end);
+                nsteps += end_index - start_index;
+                session.push_pcm(&in_pcm[start_index * frame_size..end_index * frame_size]);
+                for output in session.step()? {
+                    let step_idx = chunk.start + output.step_idx;
+                    let is_pad = output.is_pad();
+                    let pcm = match output.pcm.as_ref() {
+                        None => None,
+                        Some(pcm) => Some(pcm.flatten_all()?.to_vec1::<f32>()?),
+                    };
+                    if step_idx < chunk.own_start {
+                        continue;
+                    }
+                    if step_idx >= chunk.own_end {
+                        next_tail.extend(pcm.unwrap_or_default());
+                        continue;
+                    }
+                    stats.record(output.text.as_deref());
+                    if let Some(&features) = frame_features.get(step_idx) {
+                        if let Some(event) = event_detector.step(features, is_pad) {
+                            if let Some(text) = pacer.push(&format!(" {} ", event.label())) {
+                                text_writer.write(&text);
+                                transcript.push_str(&text)
+                            }
+                            events.push(crate::events::Marker {
+                                event,
+                                start_step: step_idx + 1 - event_detector.run_len(),
+                                end_step: step_idx + 1,
+                            });
+                        }
+                    }
+                    let stop_state = stop.step(output.text.as_deref());
+                    let text_stopped = stop.matched() && stop_state != crate::stop::Stop::Matched;
+                    if !is_pad && !text_stopped {
+                        text_tokens.push(output.text_token);
+                        if let Some(text) = output.text.as_deref().and_then(|v| pacer.push(v)) {
+                            text_writer.write(&text);
+                            transcript.push_str(&text)
+                        }
+                    }
+                    step_offsets.push(num_samples);
+                    if let Some(mut pcm) = pcm {
+                        let offset = (step_idx - chunk.own_start) * frame_size;
+                        crate::longform::crossfade(&crossfade_tail, &mut pcm, offset);
+                        num_samples += pcm.len();
+                        if let Some(playback) = playback.as_ref() {
+                            playback.push(&pcm)
+                        }
+                        out_pcm.extend(pcm);
+                    }
+                    match stop_state {
+                        crate::stop::Stop::Continue => {}
+                        crate::stop::Stop::Matched => {
+                            tracing::info!(step = step_idx, "stop sequence generated");
+                            session.end_text()
+                        }
+                        crate::stop::Stop::Done => {
+                            done = true;
+                            break 'steps;
+                        }
                     }
+                    // Once the input is over, stop as soon as the model has finished translating.
+                    if args.fit_duration && step_idx >= source_steps {
+                        tail_pad_steps = if is_pad { tail_pad_steps + 1 } else { 0 };
+                        if tail_pad_steps >= crate::dubbing::END_PAD_STEPS {
+                            done = true;
+                            break 'steps;
+                        }
+                    }
+                }
+                let num_steps = end_index - start_index;
+                steps_done += num_steps;
+                on_progress(crate::progress::Progress {
+                    steps: steps_done,
+                    total_steps,
+                    elapsed: progress_start.elapsed(),
+                });
+                let elapsed = batch_start.elapsed();
+                recorder.record_batch(num_steps, elapsed);
+                let own_steps = start_index.max(chunk.own_start)..end_index.min(chunk.own_end);
+                let ms = elapsed.as_secs_f64() * 1000. / num_steps as f64;
+                step_latency_ms.extend(own_steps.map(|_| ms));
+                let breach = lag_monitor.record(start_index, num_steps, elapsed);
+                if let Some(trace) = trace.as_mut() {
+                    trace.record_batch(start_index, num_steps, elapsed, lag_monitor.lag())
+                }
+                if let Some(breach) = breach.filter(|_| args.warn_slow_steps) {
+                    tracing::warn!(
+                        step = breach.step_idx,
+                        elapsed_ms = breach.elapsed.as_millis() as u64,
+                        budget_ms = breach.budget.as_millis() as u64,
+                        lag_ms = breach.lag.as_millis() as u64,
+                        "processing is slower than real-time"
+                    );
                 }
+                if let Some(autosave) = autosave.as_mut() {
+                    autosave.maybe_save(&transcript)?
+                }
+                stats.maybe_dump(&lag_monitor, dev);
+            }
+            recorder.record_session(session.timings());
+            history.append(&session.into_state(), chunk);
+            crossfade_tail = next_tail;
+            if done {
+                break;
             }
         }
+        if args.warn_slow_steps {
+            tracing::info!(
+                breaches = lag_monitor.num_breaches(),
+                lag_ms = lag_monitor.lag().as_millis() as u64,
+                "real-time budget"
+            );
+        }
+        step_offsets.push(num_samples);
+        summary.steps += nsteps;
+        summary.text_tokens += text_tokens.len();
+        summary.elapsed += start_time.elapsed();
+        summary.peak_lag = summary.peak_lag.max(lag_monitor.peak_lag());
+        if args.skip_silent_depformer {
+            tracing::info!(steps = history.num_skipped, "skipped the depformer on silent steps");
+        }
+        if let Some(text) = pacer.flush() {
+            text_writer.write(&text);
+            transcript.push_str(&text)
+        }
+        if let Some(autosave) = autosave.as_mut() {
+            autosave.save(&transcript)?
+        }
+        text_writer.write("\n");
+        let dt = start_time.elapsed().as_secs_f32();
+        tracing::info!(
+            "generated {nsteps} steps in {dt:.2}s, {:.0}ms/token",
+            dt * 1000. / (nsteps as f32)
+        );
+        if first_take_text_tokens.is_none() {
+            first_take_text_tokens = Some(history.text_tokens.clone());
+        }
+        let str = models.text_tokenizer.decode_piece_ids(&text_tokens)?;
+        tracing::info!(str, "generated text");
+        if let Some(reference) = parity_reference.as_ref() {
+            let text_tokens = &history.text_tokens;
+            let audio_tokens = &history.audio_tokens;
+            let divergence = crate::parity::first_divergence(
+                reference,
+                text_tokens,
+                audio_tokens,
+                generated_audio_codebooks,
+            );
+            match divergence {
+                Some(divergence) => text_writer
+                    .write(&format!("first divergence from the reference: {divergence}\n")),
+                None => text_writer.write(&format!(
+                    "no divergence from the reference over {} steps (reference has {} steps)\n",
+                    text_tokens.len(),
+                    reference.text_tokens.len()
+                )),
+            }
+        }
+        if let Some(path) = args.emit_token_ids.as_ref() {
+            let path = take_path(path, take, num_takes);
+            write_token_ids(&path, args, input, &history, &str, &provenance)?;
+            tracing::info!(?path, "wrote the token ids");
+        }
+        if args.mark_events {
+            tracing::info!(?events, "non-speech events");
+        }
+        tracing::info!(samples = out_pcm.len(), "generated audio");
+        let mut out_pcms = out_pcm;
+        // The generated audio before it gets stretched or resampled.
+        let generated_timeline = crate::alignment::Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: config.acoustic_delay,
+            skip: 0,
+            scale: 1.,
+            sample_rate,
+        };
+        if let (Some(trace), Some(path)) = (trace.as_ref(), args.trace.as_ref()) {
+            let path = take_path(path, take, num_takes);
+            trace.write(
+                &path,
+                &history,
+                &models.text_tokenizer,
+                &in_pcm,
+                &out_pcms,
+                &generated_timeline,
+            )?;
+            tracing::info!(?path, "wrote the trace");
+        }
+        if let Some(threshold) = args.min_text_confidence {
+            let mask = crate::confidence::low_confidence_steps(
+                &history.text_tokens,
+                &history.text_logprobs,
+                threshold,
+                |t| t == 0 || t == 3,
+            );
+            let source = generated_timeline.align_source(&in_pcm, frame_size, out_pcms.len());
+            let fade_len = (crate::confidence::FADE_SECS * sample_rate as f64).round() as usize;
+            let steps = crate::confidence::replace_low_confidence(
+                &mut out_pcms,
+                &source,
+                &mask,
+                &generated_timeline,
+                fade_len,
+            );
+            tracing::info!(steps, "replaced low confidence audio with the original");
+        }
+        let (out_pcms, fit) = if args.fit_duration {
+            crate::dubbing::fit_to_duration(out_pcms, source_len, args.max_stretch)
+        } else {
+            (out_pcms, crate::dubbing::Fit::NONE)
+        };
+        let out_pcms =
+            crate::dsp::pitch_formant_shift(&out_pcms, args.pitch_shift, args.formant_shift)?;
+        let fitted_len = out_pcms.len();
+        let (out_pcms, sample_rate) =
+            crate::audio_io::resample_output(out_pcms, sample_rate, args.output_sample_rate)?;
+        let need_words = args.word_alignment.is_some()
+            || args.subtitles.is_some()
+            || args.cue_stats.is_some()
+            || args.chapters.is_some()
+            || args.clip_markers.is_some();
+        let words = if need_words {
+            crate::alignment::words(
+                &history.text_tokens,
+                &models.text_tokenizer,
+                config.text_start_token,
+            )
+        } else {
+            vec![]
+        };
+        let timeline = crate::alignment::Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: config.acoustic_delay,
+            skip: fit.skip,
+            scale: fit.ratio * out_pcms.len() as f64 / fitted_len.max(1) as f64,
+            sample_rate,
+        };
+        if let Some(path) = args.subtitles.as_ref() {
+            let path = take_path(path, take, num_takes);
+            let words = crate::events::with_words(&words, &events);
+            let cues = crate::subtitles::write(&path, &words, &timeline, &args.target_language)?;
+            tracing::info!(?path, cues, "wrote the subtitles");
+        }
+        if let Some(path) = args.cue_stats.as_ref() {
+            let path = take_path(path, take, num_takes);
+            let cues = crate::subtitles::write_stats(
+                &path,
+                &words,
+                &history,
+                &step_latency_ms,
+                &timeline,
+            )?;
+            tracing::info!(?path, cues, "wrote the cue statistics");
+        }
+        if let Some(path) = args.word_alignment.as_ref() {
+            let path = take_path(path, take, num_takes);
+            crate::alignment::write(&path, &words, &timeline)?;
+            tracing::info!(?path, words = words.len(), "wrote the word alignment");
+        }
+        if let Some(path) = args.chapters.as_ref() {
+            let path = take_path(path, take, num_takes);
+            let chapters = crate::chapters::write(&path, &words, &timeline, &args.target_language)?;
+            tracing::info!(?path, chapters, "wrote the chapters");
+        }
+        if let Some(path) = args.clip_markers.as_ref() {
+            let path = take_path(path, take, num_takes);
+            let regions = crate::markers::write(&path, &words, &timeline)?;
+            tracing::info!(?path, regions, "wrote the clip markers");
+        }
+        if let Some(path) = args.json_output.as_ref() {
+            let path = take_path(path, take, num_takes);
+            let tokens = crate::alignment::write_tokens(
+                &path,
+                &history,
+                &models.text_tokenizer,
+                config.text_start_token,
+                step_duration,
+                &timeline,
+                &events,
+                &args.target_language,
+            )?;
+            tracing::info!(?path, tokens, "wrote the json transcript");
+        }
+        let audio_output_file = take_path(&args.audio_output_file, take, num_takes);
+        let mut out_wav = vec![];
+        if args.stereo_mix {
+            let source = timeline.align_source(&in_pcm, frame_size, out_pcms.len());
+            crate::audio_io::write_wav_stereo(
+                &mut out_wav,
+                &source,
+                &out_pcms,
+                sample_rate as u32,
+                args.wav_format,
+            )?
+        } else if let Some(duck_db) = args.dub_mix {
+            let source = timeline.align_source(&in_pcm, frame_size, out_pcms.len());
+            let mix = crate::dubbing::duck_mix(&source, &out_pcms, duck_db, sample_rate);
+            crate::audio_io::write_wav(&mut out_wav, &mix, sample_rate as u32, args.wav_format)?
+        } else {
+            crate::audio_io::write_wav(
+                &mut out_wav,
+                &out_pcms,
+                sample_rate as u32,
+                args.wav_format,
+            )?
+        }
+        std::fs::write(&audio_output_file, out_wav)?;
+        crate::metadata::write_wav_info(&audio_output_file, metadata, &args.lm_model_file)?;
+        tracing::info!(audio = ?audio_output_file, "generated audio");
+        if let Some((memory, key)) = memory {
+            if take == 0 && !summary.cancelled {
+                // The printed transcript has the event labels, the decoded tokens do not.
+                let text = if args.mark_events { transcript.trim() } else { str.as_str() };
+                memory.store(key, &audio_output_file, &sidecars(args), text)?;
+                tracing::info!(key, "added the input to the translation memory");
+            }
+        }
+        if summary.cancelled {
+            break;
+        }
     }
-    println!();
-    let dt = start_time.elapsed().as_secs_f32();
-    tracing::info!(
-        "generated {nsteps} steps in {dt:.2}s, {:.0}ms/token",
-        dt * 1000. / (nsteps as f32)
-    );
-    let str = text_tokenizer.decode_piece_ids(&text_tokens)?;
-    tracing::info!(str, "generated text");
-    let out_pcms = Tensor::cat(&out_pcms, 2)?;
-    tracing::info!(shape = ?out_pcms.shape(), "generated audio");
-    let out_pcms = out_pcms.i((0, 0))?.to_vec1::<f32>()?;
-    let mut out_wav = std::fs::File::create(&args.audio_output_file)?;
-    moshi::wav::write_pcm_as_wav(&mut out_wav, &out_pcms, 24_000)?;
-    tracing::info!(audio = ?args.audio_output_file, "generated audio");
-    Ok(())
+    summary.stats = recorder.finish(wall_start.elapsed(), step_duration);
+    let stats = serde_json::to_string(&summary.stats)?;
+    tracing::info!(stats, "performance");
+    Ok(summary)
 }
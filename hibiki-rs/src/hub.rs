@@ -0,0 +1,103 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Resolution of the model files from a Hugging Face hub repo: the config gives the names of the
+// weight and tokenizer files, which are downloaded on first use and then served from the hub
+// cache. HF_HOME and HF_ENDPOINT are honored as in the python tooling, and each file can be
+// overridden with a local path.
+
+use anyhow::Result;
+use std::path::PathBuf;
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Expands the short model names to their hub repos.
+pub fn repo_id(hf_repo: &str) -> String {
+    match hf_repo {
+        "1b" => "kyutai/hibiki-1b-rs-bf16".to_string(),
+        "2b" => "kyutai/hibiki-2b-rs-bf16".to_string(),
+        _ => hf_repo.to_string(),
+    }
+}
+
+pub struct Repo {
+    api: hf_hub::api::sync::ApiRepo,
+    cache: hf_hub::CacheRepo,
+    cache_only: bool,
+}
+
+impl Repo {
+    /// With `cache_only`, the files that are not in the cache are reported rather than
+    /// downloaded, except for the small config file.
+    pub fn new(hf_repo: &str, cache_only: bool) -> Result<Self> {
+        let repo_id = repo_id(hf_repo);
+        let api = hf_hub::api::sync::ApiBuilder::from_env().build()?.model(repo_id.clone());
+        let cache = hf_hub::Cache::from_env().model(repo_id);
+        Ok(Self { api, cache, cache_only })
+    }
+
+    pub fn get(&self, name: &str) -> Result<PathBuf> {
+        if !self.cache_only {
+            return Ok(self.api.get(name)?);
+        }
+        match self.cache.get(name) {
+            Some(path) => Ok(path),
+            None => anyhow::bail!(
+                "{name} is not in the hub cache, it would be downloaded from {}",
+                self.api.url(name)
+            ),
+        }
+    }
+
+    /// Reads the config of the repo, it is downloaded if needed even with `cache_only` as it is
+    /// required to know the names of the other files.
+    pub fn config(&self) -> Result<(PathBuf, crate::gen::Config)> {
+        let config_file = self.api.get(CONFIG_FILE)?;
+        let config = read_config(&config_file)?;
+        Ok((config_file, config))
+    }
+}
+
+fn read_config(config_file: &std::path::Path) -> Result<crate::gen::Config> {
+    let config = std::fs::read_to_string(config_file)?;
+    Ok(toml::from_str(&config)?)
+}
+
+/// The files needed for generation, the config is parsed as it gives the names of the others.
+pub struct ModelFiles {
+    pub config_file: PathBuf,
+    pub config: crate::gen::Config,
+    pub lm_model_file: PathBuf,
+    pub mimi_model_file: PathBuf,
+    pub text_tokenizer: PathBuf,
+}
+
+/// Local paths that take precedence over the files of the hub repo.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub config_file: Option<PathBuf>,
+    pub lm_model_file: Option<PathBuf>,
+    pub mimi_model_file: Option<PathBuf>,
+    pub text_tokenizer: Option<PathBuf>,
+}
+
+/// Resolves the model files, the repo is only used for the files without an override.
+pub fn resolve(repo: &Repo, overrides: Overrides) -> Result<ModelFiles> {
+    tracing::info!("loading the config");
+    let (config_file, config) = match overrides.config_file {
+        None => repo.config()?,
+        Some(config_file) => {
+            let config = read_config(&config_file)?;
+            (config_file, config)
+        }
+    };
+    let get = |path: Option<PathBuf>, name: &str| match path {
+        Some(path) => Ok(path),
+        None => repo.get(name),
+    };
+    let lm_model_file = get(overrides.lm_model_file, &config.moshi_name)?;
+    let mimi_model_file = get(overrides.mimi_model_file, &config.mimi_name)?;
+    let text_tokenizer = get(overrides.text_tokenizer, &config.tokenizer_name)?;
+    Ok(ModelFiles { config_file, config, lm_model_file, mimi_model_file, text_tokenizer })
+}
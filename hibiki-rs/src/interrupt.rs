@@ -0,0 +1,38 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Clean cancellation on Ctrl-C or SIGTERM, the generation stops at the next batch of frames and
+// the outputs are still written for what has been translated so far. A second signal kills the
+// process as usual.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static CANCEL: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_signal(signal: libc::c_int) {
+    CANCEL.store(true, Ordering::Relaxed);
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
+
+/// Installs the SIGINT and SIGTERM handlers, the signals only set the flag returned by `flag`.
+pub fn install_handler() {
+    #[cfg(unix)]
+    unsafe {
+        let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// The cancellation flag, to be passed to `gen::generate`.
+pub fn flag() -> &'static AtomicBool {
+    &CANCEL
+}
+
+pub fn requested() -> bool {
+    CANCEL.load(Ordering::Relaxed)
+}
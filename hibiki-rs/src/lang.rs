@@ -0,0 +1,136 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Language of the generated text as a BCP-47 tag, used to tag the transcript outputs and to
+// mark right-to-left text so that it is rendered properly by caption tooling.
+
+use anyhow::Result;
+
+// Primary language subtags of the languages written right-to-left by default.
+const RTL_LANGUAGES: [&str; 12] =
+    ["ar", "arc", "ckb", "dv", "fa", "he", "iw", "ps", "sd", "syr", "ug", "ur"];
+const RTL_SCRIPTS: [&str; 6] = ["Arab", "Hebr", "Nkoo", "Syrc", "Thaa", "Adlm"];
+
+// Unicode right-to-left isolate and pop directional isolate.
+const RLI: char = '\u{2067}';
+const PDI: char = '\u{2069}';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Language {
+    tag: String,
+}
+
+impl std::str::FromStr for Language {
+    type Err = anyhow::Error;
+
+    /// Parses a tag of the form language[-script][-region][-variant...], e.g. "en", "en-US",
+    /// "ar-EG" or "zh-Hant-TW". Only the syntax is checked, not the registry.
+    fn from_str(tag: &str) -> Result<Self> {
+        let mut subtags = tag.split('-');
+        let language = subtags.next().unwrap_or_default();
+        if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            anyhow::bail!("invalid language tag '{tag}', expected e.g. 'en' or 'en-US'")
+        }
+        let mut normalized = vec![language.to_ascii_lowercase()];
+        for subtag in subtags {
+            if !(1..=8).contains(&subtag.len())
+                || !subtag.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                anyhow::bail!("invalid subtag '{subtag}' in language tag '{tag}'")
+            }
+            // Canonical casing: title case scripts, upper case regions, lower case otherwise.
+            let subtag = match subtag.len() {
+                4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                    subtag[..1].to_ascii_uppercase() + &subtag[1..].to_ascii_lowercase()
+                }
+                2 => subtag.to_ascii_uppercase(),
+                _ => subtag.to_ascii_lowercase(),
+            };
+            normalized.push(subtag)
+        }
+        Ok(Self { tag: normalized.join("-") })
+    }
+}
+
+impl std::fmt::Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.tag)
+    }
+}
+
+impl Language {
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    fn script(&self) -> Option<&str> {
+        let script = self.tag.split('-').nth(1)?;
+        (script.len() == 4 && script.chars().all(|c| c.is_ascii_alphabetic())).then_some(script)
+    }
+
+    /// Whether the text is written right-to-left, an explicit script subtag takes precedence
+    /// over the language, e.g. "az-Arab" is right-to-left.
+    pub fn is_rtl(&self) -> bool {
+        match self.script() {
+            Some(script) => RTL_SCRIPTS.contains(&script),
+            None => RTL_LANGUAGES.contains(&self.tag.split('-').next().unwrap_or_default()),
+        }
+    }
+
+    pub fn direction(&self) -> &'static str {
+        if self.is_rtl() {
+            "rtl"
+        } else {
+            "ltr"
+        }
+    }
+
+    /// Wraps each line of right-to-left text in a directional isolate so that it renders
+    /// correctly when embedded in left-to-right contexts, other text is returned as is.
+    pub fn isolate(&self, text: &str) -> String {
+        if !self.is_rtl() {
+            return text.to_string();
+        }
+        text.split('\n')
+            .map(|line| if line.is_empty() { String::new() } else { format!("{RLI}{line}{PDI}") })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(tag: &str) -> Result<String> {
+        Ok(tag.parse::<Language>()?.to_string())
+    }
+
+    #[test]
+    fn normalized_tags() {
+        assert_eq!(tag("en").unwrap(), "en");
+        assert_eq!(tag("EN-us").unwrap(), "en-US");
+        assert_eq!(tag("zh-hant-tw").unwrap(), "zh-Hant-TW");
+        assert_eq!(tag("sr-latn-rs-1994").unwrap(), "sr-Latn-RS-1994");
+        assert_eq!(tag("es-419").unwrap(), "es-419");
+    }
+
+    #[test]
+    fn invalid_tags() {
+        for invalid in ["", "e", "engl", "e1", "en-", "en--US", "en_US", "en-toolongsub", "fr-é"] {
+            assert!(tag(invalid).is_err(), "{invalid}")
+        }
+    }
+
+    #[test]
+    fn direction() {
+        let lang = |tag: &str| tag.parse::<Language>().unwrap();
+        assert!(lang("ar-EG").is_rtl());
+        assert!(lang("az-Arab").is_rtl());
+        assert!(!lang("en-US").is_rtl());
+        assert_eq!(lang("he").isolate("a\n\nb"), format!("{RLI}a{PDI}\n\n{RLI}b{PDI}"));
+        assert_eq!(lang("fr").isolate("a\nb"), "a\nb");
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The library behind the hibiki command line, it is also used by the C bindings in hibiki-ffi.
+
+pub mod alignment;
+pub mod audio_io;
+pub mod batch;
+pub mod beam;
+pub mod calibrate;
+pub mod chapters;
+pub mod cli;
+pub mod codec;
+pub mod confidence;
+pub mod crypt;
+pub mod daemon;
+pub mod devices;
+pub mod draft;
+pub mod dsp;
+pub mod dubbing;
+pub mod events;
+pub mod fanout;
+pub mod gen;
+pub mod hub;
+pub mod interrupt;
+pub mod lang;
+pub mod live;
+pub mod lm_state;
+pub mod longform;
+pub mod markers;
+pub mod memory;
+pub mod metadata;
+pub mod metrics;
+pub mod output;
+pub mod pacing;
+pub mod parity;
+pub mod pcm;
+pub mod pipe;
+pub mod plan;
+pub mod progress;
+pub mod protocol;
+pub mod provenance;
+pub mod quantize;
+pub mod quiet;
+pub mod realtime;
+pub mod recording;
+pub mod resources;
+pub mod router;
+pub mod serve;
+pub mod session;
+pub mod stats;
+pub mod stop;
+pub mod stream;
+pub mod subtitles;
+pub mod systemd;
+pub mod tenants;
+pub mod tiny;
+pub mod trace;
+pub mod transcript;
+pub mod trigger;
+pub mod websocket;
@@ -0,0 +1,353 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Live translation of a capture device, the text is printed as it is generated so that hibiki
+// can be used as an interpreter from the terminal, the audio can be played back with --play.
+// With --protocol, the streaming protocol messages are printed as json lines instead, including
+// periodic estimates of the lag behind the speaker. With --simulate-realtime, a file is fed at
+// the speed it would be captured instead of the device, to evaluate the live behavior without a
+// microphone. The --subtitles are written as the cues are finalized, and can also be published
+// as a HLS stream for players to pick up during the event. With --start-tone and --stop-tone,
+// only the audio between the two signal tones is translated rather than the ambient noise.
+// Typing `pause` on stdin stops the translation until `resume`, an empty line toggles it, e.g.
+// during the breaks of an event: the capture is dropped meanwhile and the context is kept.
+
+use anyhow::{Context, Result};
+use candle::Device;
+
+use crate::protocol::{ErrorCode, Hypothesis, TextMessage};
+
+// Segments stay tentative while at most this many follow them.
+pub const TENTATIVE_SEGMENTS: usize = 4;
+// The lag estimates are sent every second.
+pub const LAG_INTERVAL_STEPS: usize = 12;
+// A warning is sent once the processing is this far behind, and again after it caught up.
+pub const BEHIND_WARNING_LAG: std::time::Duration = std::time::Duration::from_millis(500);
+
+// The text is written raw or as protocol messages.
+struct TextSink {
+    writer: crate::output::TextWriter,
+    hypothesis: Option<Hypothesis>,
+}
+
+impl TextSink {
+    fn send(&self, msg: &TextMessage) {
+        if let Ok(line) = serde_json::to_string(msg) {
+            self.writer.write(&format!("{line}\n"))
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        let msgs = match self.hypothesis.as_mut() {
+            None => return self.writer.write(text),
+            Some(hypothesis) => hypothesis.push(text),
+        };
+        for msg in msgs.iter() {
+            self.send(msg)
+        }
+    }
+
+    fn lag(&self, lag: &crate::realtime::InterpretationLag, processing_lag: std::time::Duration) {
+        if self.hypothesis.is_none() {
+            return;
+        }
+        self.send(&TextMessage::lag(lag, processing_lag))
+    }
+
+    fn error(&self, code: ErrorCode, message: String) {
+        if self.hypothesis.is_some() {
+            self.send(&TextMessage::Error { code, message })
+        }
+    }
+
+    fn warning(&self, code: ErrorCode, message: String) {
+        if self.hypothesis.is_some() {
+            self.send(&TextMessage::Warning { code, message })
+        }
+    }
+
+    fn commit(&mut self) {
+        if let Some(msg) = self.hypothesis.as_mut().and_then(|v| v.commit()) {
+            self.send(&msg)
+        }
+    }
+}
+
+// The pause state toggled from stdin, read on its own thread.
+struct PauseControl {
+    paused: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl PauseControl {
+    fn stdin() -> Self {
+        let paused = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        {
+            let paused = paused.clone();
+            std::thread::spawn(move || {
+                for line in std::io::stdin().lines() {
+                    let Ok(line) = line else { break };
+                    let was_paused = paused.load(std::sync::atomic::Ordering::SeqCst);
+                    let pause = match line.trim() {
+                        "pause" => true,
+                        "resume" => false,
+                        "" => !was_paused,
+                        cmd => {
+                            tracing::warn!(cmd, "unknown command, expected pause or resume");
+                            continue;
+                        }
+                    };
+                    if pause != was_paused {
+                        tracing::info!("{}", if pause { "paused" } else { "resumed" })
+                    }
+                    paused.store(pause, std::sync::atomic::Ordering::SeqCst)
+                }
+            });
+        }
+        Self { paused }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+/// Translates the audio captured from the `channels` of `device`, or replayed from `replay`,
+/// until the capture ends. The subtitles are also published as HLS in `hls_dir` if set. Once
+/// `max_steps` steps have been generated the kv-cache is full, the lm state is then reset and the
+/// translation continues from a fresh context. With `tones`, the start and stop frequencies, the
+/// translation waits for the start tone and a new context is started after each stop tone.
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    args: &crate::gen::Args,
+    dev: &Device,
+    device: &str,
+    channels: &crate::audio_io::ChannelMap,
+    protocol: bool,
+    replay: Option<&std::path::Path>,
+    hls_dir: Option<&std::path::Path>,
+    tones: Option<(f32, f32)>,
+) -> Result<()> {
+    let hypothesis = protocol.then(|| Hypothesis::new(TENTATIVE_SEGMENTS));
+    let mut sink = TextSink { writer: crate::output::TextWriter::stdout(), hypothesis };
+    let res = translate(args, dev, device, channels, replay, hls_dir, tones, &mut sink);
+    if let Err(err) = res.as_ref() {
+        sink.error(ErrorCode::of(err), format!("{err:#}"))
+    }
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+fn translate(
+    args: &crate::gen::Args,
+    dev: &Device,
+    device: &str,
+    channels: &crate::audio_io::ChannelMap,
+    replay: Option<&std::path::Path>,
+    hls_dir: Option<&std::path::Path>,
+    tones: Option<(f32, f32)>,
+    sink: &mut TextSink,
+) -> Result<()> {
+    let mut models = crate::gen::Models::load(args, dev)?;
+    let frame_size = models.codec.frame_size();
+    let sample_rate = models.codec.sample_rate();
+    let step_duration = frame_size as f64 / sample_rate as f64;
+    let mut interpretation_lag = crate::realtime::InterpretationLag::new(step_duration);
+    let mut pacer = crate::pacing::TextPacer::new(args.max_text_updates_per_sec);
+
+    let playback = match args.play.as_deref() {
+        None => None,
+        Some(device) => {
+            Some(crate::audio_io::Playback::open(device, sample_rate, args.play_buffer)?)
+        }
+    };
+    let mut capture = match replay {
+        None => {
+            let capture = crate::audio_io::Capture::open(device, sample_rate, channels)
+                .context(ErrorCode::DeviceLost)?;
+            tracing::info!(device, ?channels, "listening");
+            capture
+        }
+        Some(path) => {
+            let (pcm, sr) = crate::audio_io::pcm_decode_range(path, args.start, args.end)
+                .with_context(|| format!("cannot decode {path:?}"))
+                .context(ErrorCode::UnsupportedInput)?;
+            let pcm = if sr as usize != sample_rate {
+                crate::audio_io::resample(&pcm, sr as usize, sample_rate)?
+            } else {
+                pcm
+            };
+            tracing::info!(?path, "replaying in real-time");
+            crate::audio_io::Capture::replay(&pcm, sample_rate)
+        }
+    };
+    let mut subtitles = if args.subtitles.is_some() || hls_dir.is_some() {
+        let subtitles = crate::subtitles::LiveSubtitles::new(
+            args.subtitles.as_deref(),
+            hls_dir,
+            step_duration,
+            &args.target_language,
+        )?;
+        Some(subtitles)
+    } else {
+        None
+    };
+    let mut frame = vec![0f32; frame_size];
+    // The steps since the start of the capture, the subtitles are timed with them.
+    let mut frames = 0;
+    let mut segment = 0;
+    let mut stop = crate::stop::StopSequences::new(&args.stop_sequences);
+    let mut trigger =
+        tones.map(|(start, stop)| crate::trigger::ToneTrigger::new(start, stop, sample_rate));
+    if let Some((start, _)) = tones {
+        tracing::info!(start, "waiting for the start tone")
+    }
+    // Whole frames of the capture preceding the start tone, translated first on the tone.
+    let pre_roll_frames = (args.pre_roll_secs / step_duration).round() as usize;
+    if pre_roll_frames > 0 && tones.is_none() {
+        tracing::warn!("--pre-roll-secs only applies to the start tone of --start-tone")
+    }
+    let mut pre_roll = crate::audio_io::PreRoll::new(pre_roll_frames * frame_size);
+    let pause = PauseControl::stdin();
+    'segments: loop {
+        let mut session = crate::session::GenSession::new(args, &mut models, segment, dev)?;
+        stop.reset();
+        // The pre-roll only holds audio that has already been translated when the context is
+        // reset, it is translated first once the start tone is detected.
+        pre_roll.clear();
+        let mut rolled = vec![];
+        // After the stop tone, silence is fed until the model has finished translating.
+        let mut tail_steps = 0;
+        let mut tail_pad_steps = 0;
+        let mut lag_monitor =
+            crate::realtime::LagMonitor::new(std::time::Duration::from_secs_f64(step_duration));
+        let mut stats = crate::stats::Stats::default();
+        let mut behind = false;
+        while session.state().step_idx() < args.max_steps {
+            if crate::interrupt::requested() {
+                break 'segments;
+            }
+            if !rolled.is_empty() {
+                // The capture is caught up on once the pre-roll has been translated.
+                frame.copy_from_slice(&rolled[..frame_size]);
+                rolled.drain(..frame_size);
+            } else {
+                if !capture.read_frame(&mut frame).context(ErrorCode::DeviceLost)? {
+                    // The capture only ends on its own when the device is gone, e.g. unplugged.
+                    if replay.is_none() {
+                        let err = anyhow::anyhow!("the capture from '{device}' stopped");
+                        return Err(err.context(ErrorCode::DeviceLost));
+                    }
+                    break 'segments;
+                }
+                frames += 1;
+                // The generation waits for the end of the pause with the state it had.
+                if pause.is_paused() {
+                    continue;
+                }
+                if let Some(trigger) = trigger.as_mut() {
+                    pre_roll.push(&frame);
+                    let active = trigger.is_active();
+                    if trigger.step(&frame) {
+                        tail_steps = 0
+                    } else if active || tail_steps > 0 {
+                        frame.fill(0.);
+                        tail_steps += 1
+                    } else {
+                        if trigger.is_active() {
+                            // On the start tone, the audio that preceded it is translated too.
+                            rolled = pre_roll.take()
+                        }
+                        continue;
+                    }
+                }
+            }
+            let step_start = std::time::Instant::now();
+            let step_idx = session.state().step_idx();
+            session.push_pcm(&frame);
+            let mut stopped = false;
+            for output in session.step()? {
+                stats.record(output.text.as_deref());
+                let stop_state = stop.step(output.text.as_deref());
+                let text_stopped = stop.matched() && stop_state != crate::stop::Stop::Matched;
+                let text = output.text.as_deref().filter(|_| !text_stopped);
+                if let Some(subtitles) = subtitles.as_mut() {
+                    subtitles.push(frames, text)?
+                }
+                if let Some(text) = text.and_then(|v| pacer.push(v)) {
+                    sink.text(&text)
+                }
+                let pcm = match output.pcm.as_ref() {
+                    Some(pcm) => Some(pcm.flatten_all()?.to_vec1::<f32>()?),
+                    None => None,
+                };
+                interpretation_lag.record(&frame, pcm.as_deref());
+                if let (Some(playback), Some(pcm)) = (playback.as_ref(), pcm) {
+                    playback.push(&pcm)
+                }
+                if tail_steps > 0 {
+                    tail_pad_steps = if output.is_pad() { tail_pad_steps + 1 } else { 0 };
+                }
+                match stop_state {
+                    crate::stop::Stop::Continue => {}
+                    crate::stop::Stop::Matched => session.end_text(),
+                    crate::stop::Stop::Done => stopped = true,
+                }
+            }
+            let breach = lag_monitor.record(step_idx, 1, step_start.elapsed());
+            if let Some(breach) = breach.filter(|_| args.warn_slow_steps) {
+                tracing::warn!(
+                    step = breach.step_idx,
+                    lag_ms = breach.lag.as_millis() as u64,
+                    "processing is slower than real-time"
+                );
+            }
+            let lag = lag_monitor.lag();
+            if lag.is_zero() {
+                behind = false
+            } else if lag >= BEHIND_WARNING_LAG && !behind {
+                behind = true;
+                let msg = format!("the processing is {}ms behind the speaker", lag.as_millis());
+                sink.warning(ErrorCode::StepDeadlineMissed, msg)
+            }
+            stats.maybe_dump(&lag_monitor, dev);
+            if (step_idx + 1) % LAG_INTERVAL_STEPS == 0 {
+                sink.lag(&interpretation_lag, lag_monitor.lag())
+            }
+            if stopped {
+                break;
+            }
+            if tail_steps > 0
+                && (tail_pad_steps >= crate::dubbing::END_PAD_STEPS
+                    || tail_steps >= crate::dubbing::MAX_TAIL_STEPS)
+            {
+                break;
+            }
+        }
+        if stop.matched() {
+            tracing::info!(segment, "stop sequence generated, starting a new context")
+        } else if tail_steps > 0 {
+            tracing::info!(segment, "stop tone, waiting for the start tone")
+        } else {
+            tracing::info!(segment, "reached --max-steps, starting a new context")
+        }
+        if let Some(text) = pacer.flush() {
+            sink.text(&text)
+        }
+        sink.text("\n");
+        sink.commit();
+        if let Some(subtitles) = subtitles.as_mut() {
+            subtitles.flush(frames)?
+        }
+        segment += 1;
+    }
+    if let Some(text) = pacer.flush() {
+        sink.text(&text)
+    }
+    sink.text("\n");
+    sink.commit();
+    if let Some(subtitles) = subtitles.as_mut() {
+        subtitles.finish(frames)?
+    }
+    Ok(())
+}
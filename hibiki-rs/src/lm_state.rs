@@ -0,0 +1,460 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Multistream generation state, this follows moshi::lm_generate_multistream::State but also
+// gives access to the text distribution at each step, e.g. to get the confidence of the model
+// in the generated text.
+
+use candle::{IndexOp, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use moshi::lm_generate_multistream::{Config, UNGENERATED};
+
+// The number of padding steps, 400ms, after which the next text token starts a new utterance.
+const MIN_PAUSE_STEPS: usize = 5;
+
+// Adds the padding bias to the logit of the padding token. This is done on the logits rather
+// than on the probabilities so that the greedy sampling is biased too.
+fn bias_pad(logits: &Tensor, pad_token: u32, pad_mult: Option<f32>) -> candle::Result<Tensor> {
+    let Some(pad_mult) = pad_mult else { return Ok(logits.clone()) };
+    let device = logits.device();
+    let mut logits = logits.to_dtype(candle::DType::F32)?.to_vec1::<f32>()?;
+    if let Some(logit) = logits.get_mut(pad_token as usize) {
+        *logit += pad_mult
+    }
+    let logits_len = logits.len();
+    Tensor::from_vec(logits, logits_len, device)
+}
+
+pub struct State {
+    model: moshi::lm::LmModel,
+    audio_tokens: Vec<Vec<u32>>,
+    text_tokens: Vec<u32>,
+    // Log-probability of each text token under the model distribution, before sampling
+    // adjustments such as the temperature or the padding bias.
+    text_logprobs: Vec<f32>,
+    audio_lp: LogitsProcessor,
+    text_lp: LogitsProcessor,
+    step_idx: usize,
+    pad_mult: Option<f32>,
+    // For repetition penalty, we provide the context len (in text tokens) and the penalty.
+    repetition_penalty: Option<(usize, f32)>,
+    forced_audio_tokens: moshi::lm::ForcedAudioTokens,
+    cfg_alpha: Option<f64>,
+    config: Config,
+    // When set, the depformer is skipped on padding text steps and the previously sampled audio
+    // tokens are repeated.
+    audio_silent: bool,
+    last_sampled: Option<Vec<u32>>,
+    num_skipped: usize,
+    draft: Option<crate::draft::Draft>,
+    // Audio sampling with a lower temperature, used for `anneal_window` steps after the start of
+    // an utterance and after its end is detected.
+    boundary_lp: Option<LogitsProcessor>,
+    anneal_window: usize,
+    utterance_start: usize,
+    last_text_step: Option<usize>,
+}
+
+impl State {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        model: moshi::lm::LmModel,
+        max_step_idx: usize,
+        audio_lp: LogitsProcessor,
+        text_lp: LogitsProcessor,
+        pad_mult: Option<f32>,
+        repetition_penalty: Option<(usize, f32)>,
+        cfg_alpha: Option<f64>,
+        config: Config,
+    ) -> Self {
+        let audio_tokens: Vec<Vec<u32>> = vec![
+            vec![UNGENERATED; config.total_audio_codebooks()];
+            max_step_idx + config.acoustic_delay
+        ];
+        let text_tokens = vec![UNGENERATED; max_step_idx + config.acoustic_delay];
+        let text_logprobs = vec![0f32; max_step_idx + config.acoustic_delay];
+        let forced_audio_tokens = moshi::lm::ForcedAudioTokens::new(
+            config.acoustic_delay,
+            config.audio_pad_token(),
+            &[8, 8],
+        );
+        Self {
+            model,
+            audio_tokens,
+            text_tokens,
+            text_logprobs,
+            audio_lp,
+            text_lp,
+            step_idx: 0,
+            pad_mult,
+            repetition_penalty,
+            forced_audio_tokens,
+            cfg_alpha,
+            config,
+            audio_silent: false,
+            last_sampled: None,
+            num_skipped: 0,
+            draft: None,
+            boundary_lp: None,
+            anneal_window: 0,
+            utterance_start: 0,
+            last_text_step: None,
+        }
+    }
+
+    /// Samples the audio tokens with `boundary_lp` for `window` steps around the utterance
+    /// boundaries, these are detected from the text which leads the audio.
+    pub fn set_audio_annealing(&mut self, window: usize, boundary_lp: LogitsProcessor) {
+        self.anneal_window = window;
+        self.boundary_lp = Some(boundary_lp)
+    }
+
+    // Whether the current step is close to an utterance boundary. The start is the first text
+    // token after a pause, the end can only be detected once the pause is long enough but the
+    // audio of the last word is still being generated at that point.
+    fn near_boundary(&self) -> bool {
+        let Some(last_text_step) = self.last_text_step else { return false };
+        let pause = self.step_idx - last_text_step;
+        if pause >= MIN_PAUSE_STEPS {
+            pause < MIN_PAUSE_STEPS + self.anneal_window
+        } else {
+            self.step_idx - self.utterance_start < self.anneal_window
+        }
+    }
+
+    /// Constrains the sampled text to follow a draft translation.
+    pub fn set_draft(&mut self, draft: crate::draft::Draft) {
+        self.draft = Some(draft)
+    }
+
+    /// Marks the generated audio as silent, the depformer is then skipped for the steps where
+    /// the text is padding.
+    pub fn set_audio_silent(&mut self, audio_silent: bool) {
+        self.audio_silent = audio_silent
+    }
+
+    /// The number of steps for which the depformer was skipped.
+    pub fn num_skipped(&self) -> usize {
+        self.num_skipped
+    }
+
+    pub fn step_idx(&self) -> usize {
+        self.step_idx
+    }
+
+    fn audio_pad_token(&self) -> u32 {
+        self.config.audio_pad_token()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn apply_repetition_penalty(&self, logits: Tensor) -> candle::Result<Tensor> {
+        let logits = match self.repetition_penalty {
+            None => logits,
+            Some((_, 1.)) => logits,
+            Some((context_size, penalty)) => {
+                let device = logits.device();
+                let mut logits = logits.to_dtype(candle::DType::F32)?.to_vec1::<f32>()?;
+                let mut already_seen = std::collections::HashSet::new();
+                let mut non_pad_tokens = 0;
+                for &token_id in self.text_tokens(false).iter().rev() {
+                    if token_id == self.config.text_pad_token
+                        || token_id == self.config.text_eop_token
+                        || token_id == self.config.text_start_token
+                    {
+                        continue;
+                    }
+                    // Look at the last [context_size] tokens at most, count all tokens there even
+                    // if we already saw them.
+                    if non_pad_tokens >= context_size {
+                        break;
+                    }
+                    non_pad_tokens += 1;
+
+                    if already_seen.contains(&token_id) {
+                        continue;
+                    }
+
+                    already_seen.insert(token_id);
+                    if let Some(logit) = logits.get_mut(token_id as usize) {
+                        if *logit >= 0. {
+                            *logit /= penalty
+                        } else {
+                            *logit *= penalty
+                        }
+                    }
+                }
+                let logits_len = logits.len();
+                Tensor::from_vec(logits, logits_len, device)?
+            }
+        };
+        Ok(logits)
+    }
+
+    fn batch_size(&self) -> usize {
+        if self.cfg_alpha.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+
+    // The audio tokens given as input to the lm for a step, the acoustic tokens are written with
+    // a delay, positions that have not been written yet are UNGENERATED.
+    fn input_codes(&self, step_idx: usize) -> candle::Result<Vec<u32>> {
+        let mut codes = Vec::with_capacity(self.config.total_audio_codebooks());
+        for codebook in 0..self.config.total_audio_codebooks() {
+            let t = if codebook == 0 || codebook == self.config.generated_audio_codebooks {
+                if step_idx == 0 {
+                    self.audio_pad_token()
+                } else {
+                    self.audio_tokens[step_idx - 1][codebook]
+                }
+            } else if step_idx <= self.config.acoustic_delay {
+                self.audio_pad_token()
+            } else {
+                self.audio_tokens[step_idx - self.config.acoustic_delay - 1][codebook]
+            };
+            if t == UNGENERATED {
+                candle::bail!("internal error, ungenerated {step_idx} {codebook}")
+            }
+            codes.push(t)
+        }
+        Ok(codes)
+    }
+
+    pub fn step_(
+        &mut self,
+        text_token: Option<u32>,
+        input_audio_tokens: &[u32],
+        force_text_token: Option<u32>,
+        conditions: Option<&moshi::conditioner::Condition>,
+    ) -> candle::Result<u32> {
+        let (text_logits, ys) = self.text_logits(text_token, input_audio_tokens, conditions)?;
+        // The constraints only apply to the sampling, the log-probabilities are still the ones
+        // of the model.
+        let sampled_logits = match self.draft.as_ref() {
+            None => text_logits.clone(),
+            Some(draft) => draft.constrain(&text_logits, draft.cursor(self.text_tokens(false)))?,
+        };
+        let text_token = match force_text_token {
+            Some(tt) => tt,
+            None => {
+                let pad_token = self.config.text_pad_token;
+                let sampled_logits = bias_pad(&sampled_logits, pad_token, self.pad_mult)?;
+                self.text_lp.sample(&sampled_logits)?
+            }
+        };
+        self.finish_step(text_token, &text_logits, &ys)
+    }
+
+    /// Runs the lm on the inputs of the current step and returns the text logits together with
+    /// the hidden state for the depformer, `finish_step` then has to be called with the selected
+    /// text token.
+    pub fn text_logits(
+        &mut self,
+        text_token: Option<u32>,
+        input_audio_tokens: &[u32],
+        conditions: Option<&moshi::conditioner::Condition>,
+    ) -> candle::Result<(Tensor, Tensor)> {
+        let dev = self.model.device().clone();
+        for (c_idx, &t) in input_audio_tokens.iter().enumerate() {
+            self.audio_tokens[self.step_idx][c_idx + self.config.generated_audio_codebooks] = t
+        }
+        let batch_size = self.batch_size();
+        let mut codes = Vec::with_capacity(self.config.total_audio_codebooks());
+        for t in self.input_codes(self.step_idx)? {
+            codes.push(Some(Tensor::from_vec(vec![t; batch_size], (batch_size, 1), &dev)?))
+        }
+        let text_token = match text_token {
+            Some(text_token) => {
+                Some(Tensor::from_vec(vec![text_token; batch_size], (batch_size, 1), &dev)?)
+            }
+            None => None,
+        };
+        let (logits, ys) = self.model.forward_cond(text_token, codes, conditions)?;
+        let text_logits = match self.cfg_alpha {
+            None => logits.i((0, 0))?,
+            Some(a) => match logits.dim(0)? {
+                2 => ((logits.i((0, 0))? * a)? - (logits.i((1, 0))? * (a - 1.))?)?,
+                b_size => candle::bail!("unexpected batch size {b_size}"),
+            },
+        };
+        let text_logits = self.apply_repetition_penalty(text_logits)?;
+        Ok((text_logits, ys))
+    }
+
+    /// Records the text token for the current step, samples the audio tokens with the depformer
+    /// and moves to the next step.
+    pub fn finish_step(
+        &mut self,
+        text_token: u32,
+        text_logits: &Tensor,
+        ys: &Tensor,
+    ) -> candle::Result<u32> {
+        let text_logprobs =
+            candle_nn::ops::log_softmax(&text_logits.to_dtype(candle::DType::F32)?, 0)?;
+        self.text_logprobs[self.step_idx] = text_logprobs.i(text_token as usize)?.to_scalar()?;
+        self.text_tokens[self.step_idx] = text_token;
+        let text_is_pad =
+            text_token == self.config.text_pad_token || text_token == self.config.text_eop_token;
+        let skip = self.audio_silent && text_is_pad && self.step_idx > self.config.acoustic_delay;
+        if !text_is_pad {
+            if self.last_text_step.is_none_or(|s| self.step_idx - s > MIN_PAUSE_STEPS) {
+                self.utterance_start = self.step_idx
+            }
+            self.last_text_step = Some(self.step_idx)
+        }
+        let near_boundary = self.near_boundary();
+        let audio_lp = match self.boundary_lp.as_mut() {
+            Some(boundary_lp) if near_boundary => boundary_lp,
+            _ => &mut self.audio_lp,
+        };
+        let last_audio_tokens = match self.cfg_alpha {
+            _ if skip && self.last_sampled.is_some() => {
+                self.num_skipped += 1;
+                self.last_sampled.clone()
+            }
+            None => self.model.depformer_sample(
+                ys,
+                Some(text_token),
+                self.forced_audio_tokens.forced_tokens(self.step_idx),
+                audio_lp,
+            )?,
+            Some(cfg_alpha) => self.model.depformer_sample_cfg(
+                ys,
+                cfg_alpha,
+                Some(text_token),
+                self.forced_audio_tokens.forced_tokens(self.step_idx),
+                audio_lp,
+            )?,
+        };
+        self.last_sampled.clone_from(&last_audio_tokens);
+        let audio_pad_token = self.audio_pad_token();
+        for c_idx in 0..self.config.generated_audio_codebooks {
+            let delay = if c_idx == 0 { 0 } else { self.config.acoustic_delay };
+            let pos = &mut self.audio_tokens[self.step_idx.saturating_sub(delay)][c_idx];
+            // Overwrite existing positions even if there are non-UNGENERATED values. This
+            // actually happens for the first few slices because of the saturating_sub.
+            *pos = last_audio_tokens.as_ref().map_or(audio_pad_token, |l| l[c_idx]);
+        }
+        self.step_idx += 1;
+        if self.step_idx >= self.audio_tokens.len() {
+            candle::bail!("max step-idx reached")
+        }
+        Ok(text_token)
+    }
+
+    /// A copy of the state on `model`, which should have an empty kv-cache. The kv-cache is
+    /// rebuilt by running the lm on the history, in slices of `REPLAY_STEPS` steps to bound the
+    /// size of the attention matrices.
+    pub fn fork(
+        &self,
+        model: moshi::lm::LmModel,
+        audio_lp: LogitsProcessor,
+        text_lp: LogitsProcessor,
+        conditions: Option<&moshi::conditioner::Condition>,
+    ) -> candle::Result<Self> {
+        const REPLAY_STEPS: usize = 256;
+        let mut state = Self::new(
+            model,
+            self.text_tokens.len() - self.config.acoustic_delay,
+            audio_lp,
+            text_lp,
+            self.pad_mult,
+            self.repetition_penalty,
+            self.cfg_alpha,
+            self.config.clone(),
+        );
+        state.audio_tokens.clone_from(&self.audio_tokens);
+        state.text_tokens.clone_from(&self.text_tokens);
+        state.text_logprobs.clone_from(&self.text_logprobs);
+        state.last_sampled.clone_from(&self.last_sampled);
+        state.draft.clone_from(&self.draft);
+        state.step_idx = self.step_idx;
+        let dev = state.model.device().clone();
+        let batch_size = self.batch_size();
+        for start in (0..self.step_idx).step_by(REPLAY_STEPS) {
+            let end = usize::min(start + REPLAY_STEPS, self.step_idx);
+            let len = end - start;
+            let text: Vec<u32> = (start..end)
+                .map(
+                    |s| if s == 0 { self.config.text_start_token } else { self.text_tokens[s - 1] },
+                )
+                .collect();
+            let mut codes = vec![vec![]; self.config.total_audio_codebooks()];
+            for step_idx in start..end {
+                for (codebook, t) in self.input_codes(step_idx)?.into_iter().enumerate() {
+                    codes[codebook].push(t)
+                }
+            }
+            let batched =
+                |v: Vec<u32>| Tensor::new(v, &dev)?.reshape((1, len))?.repeat((batch_size, 1));
+            let codes =
+                codes.into_iter().map(|v| batched(v).map(Some)).collect::<Result<_, _>>()?;
+            state.model.forward_cond(Some(batched(text)?), codes, conditions)?;
+        }
+        Ok(state)
+    }
+
+    /// If include_all is set, all the time steps are returned. Otherwise only the timesteps that
+    /// have been generated are handled.
+    pub fn audio_tokens(&self, include_all: bool) -> &[Vec<u32>] {
+        if include_all {
+            &self.audio_tokens
+        } else {
+            let max_idx = usize::min(self.step_idx, self.audio_tokens.len());
+            &self.audio_tokens[..max_idx]
+        }
+    }
+
+    pub fn text_tokens(&self, include_all: bool) -> &[u32] {
+        if include_all {
+            &self.text_tokens
+        } else {
+            let max_idx = usize::min(self.step_idx, self.text_tokens.len());
+            &self.text_tokens[..max_idx]
+        }
+    }
+
+    /// The log-probabilities of the generated text tokens, one per step.
+    pub fn text_logprobs(&self) -> &[f32] {
+        let max_idx = usize::min(self.step_idx, self.text_logprobs.len());
+        &self.text_logprobs[..max_idx]
+    }
+
+    pub fn last_audio_tokens(&self) -> Option<Vec<u32>> {
+        if self.step_idx <= self.config.acoustic_delay {
+            None
+        } else {
+            // step_idx is in advance by 1 + there is a 2 token delay on audio tokens.
+            let audio_tokens = &self.audio_tokens[self.step_idx - self.config.acoustic_delay - 1];
+            if audio_tokens.iter().any(|v| *v as usize >= self.config.audio_vocab_size - 1) {
+                None
+            } else {
+                Some(audio_tokens.clone())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_transformers::generation::Sampling;
+
+    #[test]
+    fn pad_bias_changes_the_greedy_pick() -> candle::Result<()> {
+        let logits = Tensor::new(&[0.5f32, 2.0, 1.5], &candle::Device::Cpu)?;
+        let mut lp = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+        assert_eq!(lp.sample(&bias_pad(&logits, 2, None)?)?, 1);
+        assert_eq!(lp.sample(&bias_pad(&logits, 2, Some(1.))?)?, 2);
+        assert_eq!(lp.sample(&bias_pad(&logits, 1, Some(-1.))?)?, 2);
+        // Out of range padding tokens leave the logits untouched.
+        assert_eq!(bias_pad(&logits, 7, Some(1.))?.to_vec1::<f32>()?, [0.5, 2.0, 1.5]);
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Long inputs are translated in overlapping chunks that each fit the kv-cache. A chunk starts
+// some steps before its predecessor ends, these warm-up steps have their text forced to what the
+// previous chunk generated so that the translation carries over, and the audio of the two chunks
+// is crossfaded at the seam.
+
+// Context given to a chunk before the seam, long enough for the translation to catch up, i.e. 10s.
+pub const WARMUP_STEPS: usize = 125;
+// Length of the audio crossfade at the seams, i.e. 2s.
+pub const CROSSFADE_STEPS: usize = 25;
+
+/// A chunk is run on the steps from `start` to `end`, the outputs before `own_start` are only
+/// there to warm up the lm and the ones after `own_end` are only used to crossfade the audio
+/// with the next chunk.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk {
+    pub start: usize,
+    pub own_start: usize,
+    pub own_end: usize,
+    pub end: usize,
+}
+
+/// Splits the steps into chunks of at most `max_steps` steps. When `max_steps` is too small to
+/// leave room for the warm-up and the crossfade, a single truncated chunk is returned.
+pub fn chunks(num_steps: usize, max_steps: usize) -> Vec<Chunk> {
+    if num_steps <= max_steps || max_steps < 2 * (WARMUP_STEPS + CROSSFADE_STEPS) {
+        let end = num_steps.min(max_steps);
+        return vec![Chunk { start: 0, own_start: 0, own_end: end, end }];
+    }
+    let mut chunks = vec![];
+    let (mut start, mut own_start) = (0, 0);
+    loop {
+        let end = start + max_steps;
+        if end >= num_steps {
+            chunks.push(Chunk { start, own_start, own_end: num_steps, end: num_steps });
+            return chunks;
+        }
+        let seam = end - CROSSFADE_STEPS;
+        chunks.push(Chunk { start, own_start, own_end: seam, end });
+        start = seam - WARMUP_STEPS;
+        own_start = seam;
+    }
+}
+
+/// Crossfades the start of `pcm` with the tail of the previous chunk, in place.
+pub fn crossfade(prev_tail: &[f32], pcm: &mut [f32], offset: usize) {
+    let len = prev_tail.len();
+    for (idx, v) in pcm.iter_mut().enumerate() {
+        let pos = offset + idx;
+        if let Some(&prev) = prev_tail.get(pos) {
+            let w = pos as f32 / len as f32;
+            *v = prev * (1. - w) + *v * w
+        }
+    }
+}
+
+/// The tokens generated for the whole input, gathered from the steps owned by each chunk.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    pub text_tokens: Vec<u32>,
+    pub text_logprobs: Vec<f32>,
+    pub audio_tokens: Vec<Vec<u32>>,
+    pub num_skipped: usize,
+}
+
+impl History {
+    pub fn append(&mut self, state: &crate::lm_state::State, chunk: &Chunk) {
+        let range = |len: usize| {
+            let start = (chunk.own_start - chunk.start).min(len);
+            start..(chunk.own_end - chunk.start).min(len).max(start)
+        };
+        let text_tokens = state.text_tokens(false);
+        self.text_tokens.extend_from_slice(&text_tokens[range(text_tokens.len())]);
+        let text_logprobs = state.text_logprobs();
+        self.text_logprobs.extend_from_slice(&text_logprobs[range(text_logprobs.len())]);
+        let audio_tokens = state.audio_tokens(false);
+        self.audio_tokens.extend_from_slice(&audio_tokens[range(audio_tokens.len())]);
+        self.num_skipped += state.num_skipped()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_chunk() {
+        let short = chunks(100, 1000);
+        assert_eq!(short.len(), 1);
+        assert_eq!((short[0].start, short[0].own_start, short[0].own_end), (0, 0, 100));
+        // Too small for the warm-up and the crossfade, the input is truncated.
+        let max_steps = 2 * (WARMUP_STEPS + CROSSFADE_STEPS) - 1;
+        let truncated = chunks(1000, max_steps);
+        assert_eq!(truncated.len(), 1);
+        assert_eq!((truncated[0].own_end, truncated[0].end), (max_steps, max_steps));
+    }
+
+    #[test]
+    fn chunks_cover_the_steps() {
+        for (num_steps, max_steps) in [(1000, 400), (1201, 400), (5000, 300), (401, 400)] {
+            let all = chunks(num_steps, max_steps);
+            assert!(all.len() > 1);
+            assert_eq!(all[0].own_start, 0);
+            assert_eq!(all.last().unwrap().own_end, num_steps);
+            for chunk in all.iter() {
+                assert!(chunk.end - chunk.start <= max_steps);
+                assert!(chunk.start <= chunk.own_start && chunk.own_end <= chunk.end);
+            }
+            for pair in all.windows(2) {
+                assert_eq!(pair[0].own_end, pair[1].own_start);
+                assert_eq!(pair[1].own_start - pair[1].start, WARMUP_STEPS);
+                assert_eq!(pair[0].end - pair[0].own_end, CROSSFADE_STEPS);
+            }
+        }
+    }
+
+    #[test]
+    fn crossfade_ramps() {
+        let prev_tail = vec![1.; 4];
+        let mut pcm = vec![0.; 6];
+        crossfade(&prev_tail, &mut pcm, 0);
+        assert_eq!(pcm, [1., 0.75, 0.5, 0.25, 0., 0.]);
+    }
+}
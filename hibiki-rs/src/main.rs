@@ -5,10 +5,11 @@
 use anyhow::Result;
 use clap::Parser;
 
-mod audio_io;
-mod gen;
-
-use candle::Device;
+use hibiki::cli::{device, init_logging, GenArgs, Quantization};
+use hibiki::{
+    audio_io, batch, codec, crypt, daemon, devices, gen, hub, interrupt, live, pipe, plan,
+    quantize, quiet, router, serve, stats, tenants, tiny,
+};
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -21,34 +22,221 @@ struct Args {
 }
 
 #[derive(Debug, clap::Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Command {
     Gen {
+        #[command(flatten)]
+        gen: GenArgs,
+
+        /// The audio to translate, `-` to read raw mono pcm at 24kHz from stdin.
+        #[arg()]
+        audio_input_file: String,
+
+        /// The translated audio, `-` to write raw pcm to stdout as it is generated.
+        #[arg()]
+        audio_output_file: String,
+
+        /// The sample format of the raw pcm read from stdin or written to stdout.
+        #[arg(long, default_value = "s16le")]
+        raw_format: audio_io::RawFormat,
+    },
+    /// Translate all the audio files of a directory, or the files listed in a jsonl manifest,
+    /// loading the models once.
+    Batch {
+        #[command(flatten)]
+        gen: GenArgs,
+
+        /// A directory of audio files, or a jsonl manifest with one {"input", "output", "seed"}
+        /// object per line.
+        #[arg()]
+        input: String,
+
+        /// Directory for the translated audio and transcripts.
+        #[arg()]
+        output_dir: String,
+
+        /// Number of files processed in parallel, each worker loads its own copy of the models.
+        #[arg(long, default_value_t = 1)]
+        workers: usize,
+
+        /// Path of the json report, defaults to report.json in the output directory.
         #[arg(long)]
-        lm_model_file: Option<String>,
+        report: Option<String>,
+    },
+    /// Run as a daemon keeping the models loaded and accepting requests on a unix socket.
+    Daemon {
+        #[command(flatten)]
+        gen: GenArgs,
+
+        /// Path of the unix socket to listen on.
+        #[arg(long, default_value = "/tmp/hibiki.sock")]
+        socket: String,
 
+        /// Serve Prometheus metrics on /metrics at this address, e.g. 127.0.0.1:9184.
         #[arg(long)]
-        mimi_model_file: Option<String>,
+        metrics_addr: Option<std::net::SocketAddr>,
 
+        /// A toml file with the tenants, their api keys and retention periods. The jobs of each
+        /// tenant are then kept in its own directory.
         #[arg(long)]
-        config: Option<String>,
+        tenants: Option<String>,
+    },
+    /// Translate the audio captured from a microphone, printing the text as it is generated.
+    Live {
+        #[command(flatten)]
+        gen: GenArgs,
+
+        /// The ALSA capture device, see the devices subcommand.
+        #[arg(long, default_value = "default")]
+        device: String,
 
+        /// The number of channels to capture from the device, defaults to the highest channel of
+        /// --channel-map.
         #[arg(long)]
-        text_tokenizer: Option<String>,
+        input_channels: Option<usize>,
 
-        #[arg(long, default_value = "kyutai/hibiki-1b-rs-bf16")]
-        hf_repo: String,
+        /// The channels to translate, numbered from 1, e.g. `3,4` or `1-2`. They are mixed down to
+        /// mono, the first channel is used by default.
+        #[arg(long)]
+        channel_map: Option<String>,
 
+        /// Print the streaming protocol messages as json lines rather than the raw text, with
+        /// periodic estimates of the lag behind the speaker.
+        #[arg(long)]
+        protocol: bool,
+
+        /// Feed this file at real-time speed instead of capturing the device, e.g. to measure
+        /// the live latency reproducibly. --start and --end select a range of the file.
+        #[arg(long, value_name = "FILE")]
+        simulate_realtime: Option<String>,
+
+        /// Publish the subtitles as a HLS stream of WebVTT segments in this directory, with a
+        /// subtitles.m3u8 playlist that is updated as the segments are completed.
+        #[arg(long, value_name = "DIR")]
+        hls_subtitles: Option<String>,
+
+        /// Only translate after a signal tone at this frequency in Hz, lasting at least 240ms,
+        /// until the tone of --stop-tone. The translation is paused again on the stop tone.
+        #[arg(long, value_name = "HZ", requires = "stop_tone")]
+        start_tone: Option<f32>,
+
+        /// The frequency in Hz of the tone pausing the translation, see --start-tone.
+        #[arg(long, value_name = "HZ", requires = "start_tone")]
+        stop_tone: Option<f32>,
+    },
+    /// Serve translations over WebSocket, the clients stream pcm audio and receive the
+    /// translated audio and text as they are generated.
+    Serve {
+        #[command(flatten)]
+        gen: GenArgs,
+
+        /// The address to listen on.
+        #[arg(long, default_value = "127.0.0.1:8998")]
+        addr: String,
+
+        /// Finalize the session of a client that has not sent anything for this many seconds,
+        /// the rest of the translation is sent and the connection is closed, 0 to disable.
+        #[arg(long, default_value_t = 30)]
+        idle_timeout_secs: u64,
+
+        /// Record the messages received by each session in this directory, with their timing, so
+        /// that the sessions can be reproduced with --replay.
+        #[arg(long, value_name = "DIR")]
+        record_dir: Option<String>,
+
+        /// Translate a session recorded with --record-dir again at its original pace instead of
+        /// listening, the protocol messages are printed as json lines.
+        #[arg(long, value_name = "FILE", conflicts_with = "record_dir")]
+        replay: Option<String>,
+
+        /// Keep the full quality when the processing gets close to real-time, rather than
+        /// skipping the depformer on the silent steps until the load drops.
+        #[arg(long)]
+        fixed_quality: bool,
+
+        /// Serve Prometheus metrics on /metrics at this address, e.g. 127.0.0.1:9184.
+        #[arg(long)]
+        metrics_addr: Option<std::net::SocketAddr>,
+    },
+    /// Serve translations on multiple gpus, the connections are forwarded to one serve worker
+    /// process per gpu. The arguments after `--` are passed to the workers, e.g. the models.
+    Router {
+        /// The address to listen on.
+        #[arg(long, default_value = "127.0.0.1:8998")]
+        addr: String,
+
+        /// The number of worker processes, worker i uses the gpu i.
+        #[arg(long)]
+        workers: usize,
+
+        /// The workers listen on 127.0.0.1 from this port onwards.
+        #[arg(long, default_value_t = 9000)]
+        worker_base_port: u16,
+
+        #[arg(last = true)]
+        serve_args: Vec<String>,
+    },
+    /// Audio tokenizer utilities.
+    Mimi {
+        #[command(subcommand)]
+        command: MimiCommand,
+    },
+    /// List the available audio capture and playback devices.
+    Devices,
+    /// Encrypt a weight or tokenizer file so that it can be stored on shared disks, the key is
+    /// read from HIBIKI_MODEL_KEY or HIBIKI_MODEL_KEY_COMMAND. Encrypted files are detected and
+    /// decrypted in memory when loading.
+    EncryptModel {
         #[arg()]
-        audio_input_file: String,
+        input: String,
 
         #[arg()]
-        audio_output_file: String,
+        output: String,
+    },
+    /// Write a quantized gguf copy of a safetensors lm file, to be used with --quantized and
+    /// --lm-model-file.
+    QuantizeModel {
+        #[arg()]
+        input: String,
+
+        #[arg()]
+        output: String,
+
+        #[arg(long, default_value = "int8")]
+        quantization: Quantization,
+    },
+    /// Write a tiny random-weight model with the hibiki structure, for testing the pipeline
+    /// without downloading the checkpoints.
+    TinyModel {
+        /// Directory where the config, weights and tokenizer are written.
+        #[arg()]
+        out_dir: String,
 
         #[arg(long, default_value_t = 299_792_458)]
         seed: u64,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum MimiCommand {
+    /// Encode and decode an audio file through mimi only, to hear the quality ceiling of the
+    /// audio tokenizer.
+    Roundtrip {
+        #[arg()]
+        audio_input_file: String,
 
+        #[arg()]
+        audio_output_file: String,
+
+        #[arg(long)]
+        mimi_model_file: Option<String>,
+
+        #[arg(long, default_value = "kyutai/hibiki-1b-rs-bf16")]
+        hf_repo: String,
+
+        /// The number of codebooks to use, defaults to the number generated by the model.
         #[arg(long)]
-        cfg_alpha: Option<f64>,
+        num_codebooks: Option<usize>,
 
         /// Run on cpu
         #[arg(long)]
@@ -56,18 +244,6 @@ enum Command {
     },
 }
 
-pub fn device(cpu: bool) -> Result<Device> {
-    if cpu {
-        Ok(Device::Cpu)
-    } else if candle::utils::cuda_is_available() {
-        Ok(Device::new_cuda(0)?)
-    } else if candle::utils::metal_is_available() {
-        Ok(Device::new_metal(0)?)
-    } else {
-        Ok(Device::Cpu)
-    }
-}
-
 fn main() -> Result<()> {
     use tracing_chrome::ChromeLayerBuilder;
     use tracing_subscriber::prelude::*;
@@ -80,60 +256,196 @@ fn main() -> Result<()> {
     } else {
         None
     };
+    stats::install_handler();
     match args.command {
-        Command::Gen {
-            seed,
-            text_tokenizer,
-            lm_model_file,
-            config,
-            mimi_model_file,
-            hf_repo,
-            audio_input_file,
-            audio_output_file,
-            cfg_alpha,
-            cpu,
-        } => {
-            let dev = device(cpu)?;
-            tracing_subscriber::fmt::init();
-            let api = hf_hub::api::sync::Api::new()?;
-            let hf_repo = match hf_repo.as_str() {
-                "1b" => "kyutai/hibiki-1b-rs-bf16".to_string(),
-                "2b" => "kyutai/hibiki-2b-rs-bf16".to_string(),
-                _ => hf_repo,
+        Command::Gen { gen, audio_input_file, audio_output_file, raw_format } => {
+            let (quiet, dry_run) = (gen.quiet, gen.dry_run);
+            if !quiet {
+                init_logging()
+            }
+            let res = if quiet && dry_run {
+                // The plan would be mixed with the status line on stdout.
+                let err = anyhow::anyhow!("--dry-run cannot be used with --quiet");
+                Err(err.context(quiet::Failure::Usage))
+            } else {
+                gen.resolve(audio_input_file, audio_output_file).map_err(|err| {
+                    if quiet {
+                        quiet::or_usage(err)
+                    } else {
+                        err
+                    }
+                })
             };
-            let repo = api.model(hf_repo);
-            let config = match config {
-                None => repo.get("config.toml")?,
-                Some(f) => std::path::PathBuf::from(f),
-            };
-            tracing::info!("loading the config");
-            let config = std::fs::read_to_string(&config)?;
-            let config: gen::Config = toml::from_str(&config)?;
-
-            let lm_model_file = match lm_model_file {
-                None => repo.get(&config.moshi_name)?,
-                Some(v) => std::path::PathBuf::from(v),
+            let res = res.and_then(|(args, dev)| {
+                if quiet && args.audio_output_file.as_os_str() == pipe::STDIO {
+                    let err = anyhow::anyhow!("--quiet cannot be used when writing to stdout");
+                    return Err(err.context(quiet::Failure::Usage));
+                }
+                interrupt::install_handler();
+                let summary = if dry_run {
+                    plan::print(&args, &dev)?;
+                    Default::default()
+                } else if pipe::is_pipe(&args) {
+                    pipe::run(&args, &dev, raw_format)?;
+                    Default::default()
+                } else {
+                    gen::run(&args, &dev)?
+                };
+                Ok((args, summary))
+            });
+            if quiet {
+                std::process::exit(quiet::report(res))
+            }
+            res?;
+        }
+        Command::Batch { gen, input, output_dir, workers, report } => {
+            let dry_run = gen.dry_run;
+            if !gen.quiet {
+                init_logging()
+            }
+            let (args, dev) = gen.resolve(String::new(), String::new())?;
+            if dry_run {
+                plan::print(&args, &dev)?
+            } else {
+                let report = report.as_deref().map(std::path::Path::new);
+                batch::run(&args, &dev, input.as_ref(), output_dir.as_ref(), workers, report)?
+            }
+        }
+        Command::Daemon { gen, socket, metrics_addr, tenants } => {
+            let dry_run = gen.dry_run;
+            if !gen.quiet {
+                init_logging()
+            }
+            let (args, dev) = gen.resolve(String::new(), String::new())?;
+            if dry_run {
+                plan::print(&args, &dev)?
+            } else {
+                let tenants = tenants.map(|v| tenants::Tenants::load(v.as_ref())).transpose()?;
+                daemon::run(args, dev, socket.into(), metrics_addr, tenants)?
+            }
+        }
+        Command::Live {
+            gen,
+            device,
+            input_channels,
+            channel_map,
+            protocol,
+            simulate_realtime,
+            hls_subtitles,
+            start_tone,
+            stop_tone,
+        } => {
+            let dry_run = gen.dry_run;
+            if !gen.quiet {
+                init_logging()
+            }
+            let (args, dev) = gen.resolve(String::new(), String::new())?;
+            let selected = match channel_map.as_deref() {
+                None => vec![],
+                Some(list) => audio_io::parse_channel_list(list)?,
             };
+            let channels = audio_io::ChannelMap::new(input_channels, &selected)?;
+            if dry_run {
+                plan::print(&args, &dev)?
+            } else {
+                interrupt::install_handler();
+                live::run(
+                    &args,
+                    &dev,
+                    &device,
+                    &channels,
+                    protocol,
+                    simulate_realtime.as_ref().map(|v| v.as_ref()),
+                    hls_subtitles.as_ref().map(|v| v.as_ref()),
+                    start_tone.zip(stop_tone),
+                )?
+            }
+        }
+        Command::Serve {
+            gen,
+            addr,
+            idle_timeout_secs,
+            record_dir,
+            replay,
+            fixed_quality,
+            metrics_addr,
+        } => {
+            let dry_run = gen.dry_run;
+            if !gen.quiet {
+                init_logging()
+            }
+            let (args, dev) = gen.resolve(String::new(), String::new())?;
+            if dry_run {
+                plan::print(&args, &dev)?
+            } else {
+                let idle_timeout = (idle_timeout_secs > 0)
+                    .then(|| std::time::Duration::from_secs(idle_timeout_secs));
+                let record_dir = record_dir.map(|v| v.into());
+                let adaptive_quality = !fixed_quality;
+                let options = serve::Options {
+                    addr,
+                    idle_timeout,
+                    record_dir,
+                    adaptive_quality,
+                    metrics_addr,
+                };
+                match replay {
+                    None => serve::run(&args, &dev, &options)?,
+                    Some(path) => serve::replay(&args, &dev, &options, path.as_ref())?,
+                }
+            }
+        }
+        Command::Router { addr, workers, worker_base_port, serve_args } => {
+            init_logging();
+            let options =
+                router::Options { addr, num_workers: workers, worker_base_port, serve_args };
+            router::run(&options)?
+        }
+        Command::Mimi {
+            command:
+                MimiCommand::Roundtrip {
+                    audio_input_file,
+                    audio_output_file,
+                    mimi_model_file,
+                    hf_repo,
+                    num_codebooks,
+                    cpu,
+                },
+        } => {
+            let dev = device(cpu)?;
+            init_logging();
+            let repo = hub::Repo::new(&hf_repo, false)?;
             let mimi_model_file = match mimi_model_file {
-                None => repo.get(&config.mimi_name)?,
                 Some(v) => std::path::PathBuf::from(v),
+                None => repo.get(&repo.config()?.1.mimi_name)?,
             };
-            let text_tokenizer = match text_tokenizer {
-                None => repo.get(&config.tokenizer_name)?,
-                Some(v) => std::path::PathBuf::from(v),
+            let num_codebooks = match num_codebooks {
+                Some(v) => v,
+                None => gen::multistream_config(&repo.config()?.1.model).generated_audio_codebooks,
             };
-
-            let args = gen::Args {
-                lm_config: config.model,
-                lm_model_file,
-                mimi_model_file,
-                text_tokenizer,
-                audio_input_file: audio_input_file.into(),
-                audio_output_file: audio_output_file.into(),
-                seed,
-                cfg_alpha,
-            };
-            gen::run(&args, &dev)?
+            let mut codec = codec::load(&mimi_model_file, num_codebooks, &dev)?;
+            codec::roundtrip(
+                codec.as_mut(),
+                audio_input_file.as_ref(),
+                audio_output_file.as_ref(),
+                &dev,
+            )?
+        }
+        Command::Devices => {
+            let devices = devices::list()?;
+            devices::print(&devices)
+        }
+        Command::EncryptModel { input, output } => {
+            init_logging();
+            crypt::encrypt(input.as_ref(), output.as_ref())?
+        }
+        Command::QuantizeModel { input, output, quantization } => {
+            init_logging();
+            quantize::write_gguf(input.as_ref(), output.as_ref(), quantization.ggml_dtype())?
+        }
+        Command::TinyModel { out_dir, seed } => {
+            init_logging();
+            tiny::write(out_dir.as_ref(), seed)?
         }
     }
     Ok(())
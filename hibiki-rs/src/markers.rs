@@ -0,0 +1,171 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Clip markers for editing the translated track, one region per sentence of the translation
+// timed in the output audio. The regions that follow a long pause are marked as turns, they
+// usually match a change of speaker in the source. The format is picked from the file
+// extension, json for `.json` and an Audacity label track otherwise, which most editors can
+// import.
+
+use anyhow::Result;
+
+// A pause of 1.5s in the translation is taken as a turn.
+const TURN_PAUSE_STEPS: usize = 19;
+
+struct Region {
+    text: String,
+    start_step: usize,
+    end_step: usize,
+    turn: bool,
+}
+
+fn regions(words: &[crate::alignment::Word]) -> Vec<Region> {
+    let mut regions: Vec<Region> = vec![];
+    let mut ends_sentence = true;
+    for word in words.iter() {
+        let turn = regions.last().is_none_or(|r| word.start_step >= r.end_step + TURN_PAUSE_STEPS);
+        match regions.last_mut() {
+            Some(region) if !turn && !ends_sentence => {
+                region.text.push(' ');
+                region.text.push_str(&word.text);
+                region.end_step = word.end_step
+            }
+            _ => regions.push(Region {
+                text: word.text.clone(),
+                start_step: word.start_step,
+                end_step: word.end_step,
+                turn,
+            }),
+        }
+        ends_sentence = word.text.ends_with(['.', '!', '?']);
+    }
+    regions
+}
+
+/// Writes the markers for the words, timed in the final output audio. Returns the number of
+/// regions.
+pub fn write(
+    path: &std::path::Path,
+    words: &[crate::alignment::Word],
+    timeline: &crate::alignment::Timeline,
+) -> Result<usize> {
+    let regions = regions(words);
+    let json = path.extension().is_some_and(|v| v.eq_ignore_ascii_case("json"));
+    if json {
+        let regions: Vec<_> = regions
+            .iter()
+            .map(|region| {
+                serde_json::json!({
+                    "start": timeline.secs(region.start_step),
+                    "end": timeline.secs(region.end_step),
+                    "kind": if region.turn { "turn" } else { "sentence" },
+                    "text": region.text,
+                })
+            })
+            .collect();
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(file, &serde_json::json!({ "regions": regions }))?;
+    } else {
+        let mut out = String::new();
+        for region in regions.iter() {
+            let start = timeline.secs(region.start_step);
+            let end = timeline.secs(region.end_step);
+            let mark = if region.turn { "[turn] " } else { "" };
+            // Tabs and newlines would break the label track.
+            let text = region.text.replace(['\t', '\n'], " ");
+            out.push_str(&format!("{start:.6}\t{end:.6}\t{mark}{text}\n"))
+        }
+        std::fs::write(path, out)?;
+    }
+    Ok(regions.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[(&str, usize)]) -> Vec<crate::alignment::Word> {
+        words
+            .iter()
+            .map(|&(text, start_step)| crate::alignment::Word {
+                text: text.to_string(),
+                start_step,
+                end_step: start_step + 2,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sentences_and_turns() {
+        let words = words(&[
+            ("Hello", 0),
+            ("there.", 3),
+            ("How", 6),
+            ("are", 8),
+            ("you?", 10),
+            // A pause long enough for a turn within a sentence.
+            ("Fine", 40),
+            ("thanks", 43),
+            // A shorter pause after the end of a sentence.
+            ("and", 60),
+        ]);
+        let regions: Vec<_> = regions(&words)
+            .into_iter()
+            .map(|r| (r.text, r.start_step, r.end_step, r.turn))
+            .collect();
+        let expected = [
+            ("Hello there.", 0, 5, true),
+            ("How are you?", 6, 12, false),
+            ("Fine thanks and", 40, 62, true),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(text, start, end, turn)| (text.to_string(), start, end, turn))
+            .collect();
+        assert_eq!(regions, expected);
+        assert!(super::regions(&[]).is_empty());
+    }
+
+    #[test]
+    fn turn_threshold() {
+        let words = words(&[("One.", 0), ("Two.", 2 + TURN_PAUSE_STEPS - 1), ("Three.", 100)]);
+        let turns: Vec<_> = regions(&words).iter().map(|r| r.turn).collect();
+        assert_eq!(turns, [true, false, true]);
+    }
+
+    #[test]
+    fn formats() {
+        let dir = std::env::temp_dir().join(format!("hibiki-markers-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // 80ms per step.
+        let step_offsets: Vec<usize> = (0..=100).map(|step| step * 80).collect();
+        let timeline = crate::alignment::Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 0,
+            skip: 0,
+            scale: 1.,
+            sample_rate: 1000,
+        };
+        let words = words(&[("Hello", 0), ("there.", 3), ("Tab\there.", 6), ("Bye.", 40)]);
+
+        let path = dir.join("markers.txt");
+        assert_eq!(write(&path, &words, &timeline).unwrap(), 3);
+        let labels = std::fs::read_to_string(&path).unwrap();
+        let expected = "0.000000\t0.400000\t[turn] Hello there.\n\
+            0.480000\t0.640000\tTab here.\n\
+            3.200000\t3.360000\t[turn] Bye.\n";
+        assert_eq!(labels, expected);
+
+        let path = dir.join("markers.json");
+        assert_eq!(write(&path, &words, &timeline).unwrap(), 3);
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let region = &json["regions"][1];
+        assert_eq!(region["kind"], "sentence");
+        assert_eq!(region["text"], "Tab\there.");
+        assert_eq!(region["start"], 0.48);
+        assert_eq!(json["regions"][2]["kind"], "turn");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A translation memory mapping the mimi codes of an input, together with the generation
+// settings, to the outputs that were produced for it. Identical inputs in repetitive batch
+// workloads (e.g. recurring announcements) can then be served without running the lm.
+// The sidecar outputs of the generation, e.g. the transcript or the subtitles, are stored in the
+// entry with the audio, named after their kind and extension as the extension picks the format.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+const AUDIO_FILE: &str = "out.wav";
+const TEXT_FILE: &str = "text.txt";
+
+// FNV-1a, the std hasher is not guaranteed to be stable across releases.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Identifies a file by its canonical path, size and modification time, e.g. to tell apart
+/// checkpoints that have the same name or that were replaced in place.
+pub fn file_key(path: &Path) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let (len, mtime) = match std::fs::metadata(&path) {
+        Err(_) => (0, 0),
+        Ok(metadata) => {
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|v| v.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |v| v.as_secs());
+            (metadata.len(), mtime)
+        }
+    };
+    format!("{}:{len}:{mtime}", path.display())
+}
+
+pub fn fingerprint(codes: &[u32], settings: &str) -> String {
+    let hash = codes.iter().fold(0xcbf29ce484222325, |h, c| fnv1a(h, &c.to_le_bytes()));
+    let hash = fnv1a(hash, settings.as_bytes());
+    format!("{hash:016x}")
+}
+
+/// Fingerprint of the decoded samples of an input, the samples are quantized to 16 bits so that
+/// copies of the same audio in different containers get the same fingerprint.
+pub fn audio_fingerprint(pcm: &[f32], settings: &str) -> String {
+    let hash = pcm.iter().fold(0xcbf29ce484222325, |h, v| {
+        fnv1a(h, &((v.clamp(-1., 1.) * 32767.).round() as i16).to_le_bytes())
+    });
+    let hash = fnv1a(hash, settings.as_bytes());
+    format!("{hash:016x}")
+}
+
+/// The inputs already translated, by namespace (e.g. the tenant) and fingerprint, with the id and
+/// output of the request that translated them, so that the duplicates are not translated again.
+#[derive(Default)]
+pub struct Duplicates {
+    outputs: std::collections::HashMap<(Option<String>, String), (u64, PathBuf)>,
+}
+
+impl Duplicates {
+    /// The id and output of a previous translation of the same input, if its output still
+    /// exists.
+    pub fn get(&self, namespace: Option<&str>, fingerprint: &str) -> Option<(u64, PathBuf)> {
+        let key = (namespace.map(|v| v.to_string()), fingerprint.to_string());
+        self.outputs.get(&key).filter(|(_, output)| output.exists()).cloned()
+    }
+
+    pub fn insert(
+        &mut self,
+        namespace: Option<&str>,
+        fingerprint: String,
+        id: u64,
+        output: PathBuf,
+    ) {
+        self.outputs.insert((namespace.map(|v| v.to_string()), fingerprint), (id, output));
+    }
+}
+
+// The name of a stored sidecar output, e.g. `subtitles.vtt` for `--subtitles out.vtt`.
+fn sidecar_name(kind: &str, path: &Path) -> String {
+    match path.extension() {
+        None => kind.to_string(),
+        Some(ext) => format!("{kind}.{}", ext.to_string_lossy()),
+    }
+}
+
+pub struct Entry {
+    dir: PathBuf,
+    pub audio_file: PathBuf,
+    pub text: String,
+}
+
+impl Entry {
+    /// The stored sidecar output of this kind for a file requested at `path`, if any.
+    pub fn sidecar(&self, kind: &str, path: &Path) -> Option<PathBuf> {
+        Some(self.dir.join(sidecar_name(kind, path))).filter(|v| v.exists())
+    }
+}
+
+pub struct TranslationMemory {
+    dir: PathBuf,
+}
+
+impl TranslationMemory {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn lookup(&self, key: &str) -> Result<Option<Entry>> {
+        let dir = self.dir.join(key);
+        let audio_file = dir.join(AUDIO_FILE);
+        let text_file = dir.join(TEXT_FILE);
+        if !audio_file.exists() || !text_file.exists() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(text_file)?;
+        Ok(Some(Entry { dir, audio_file, text }))
+    }
+
+    /// Stores the outputs of a generation, `sidecars` gives the kind and path of the other
+    /// outputs that were written.
+    pub fn store(
+        &self,
+        key: &str,
+        audio_file: &Path,
+        sidecars: &[(&str, &Path)],
+        text: &str,
+    ) -> Result<()> {
+        let dir = self.dir.join(key);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::copy(audio_file, dir.join(AUDIO_FILE))?;
+        for (kind, path) in sidecars.iter() {
+            std::fs::copy(path, dir.join(sidecar_name(kind, path)))?;
+        }
+        // The text is written last so that lookups never see a partial entry.
+        std::fs::write(dir.join(TEXT_FILE), text)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_fingerprints() {
+        // The keys of existing memories must not change, this is the FNV-1a hash of "a".
+        assert_eq!(fingerprint(&[], "a"), "af63dc4c8601ec8c");
+        assert_ne!(fingerprint(&[1, 2], "a"), fingerprint(&[2, 1], "a"));
+        assert_ne!(fingerprint(&[1, 2], "a"), fingerprint(&[1, 2], "b"));
+    }
+
+    #[test]
+    fn audio_fingerprints_ignore_the_container() {
+        let pcm = [0.5, -0.25, 1.];
+        let settings = "seed=1";
+        // Differences below 16 bits precision, and samples out of range that are clipped anyway.
+        let decoded = [0.5 + 1e-6, -0.25 - 1e-6, 1.5];
+        assert_eq!(audio_fingerprint(&pcm, settings), audio_fingerprint(&decoded, settings));
+        assert_ne!(audio_fingerprint(&pcm, settings), audio_fingerprint(&pcm[..2], settings));
+    }
+
+    #[test]
+    fn store_and_lookup() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("hibiki-memory-{}", std::process::id()));
+        let memory = TranslationMemory::new(&dir);
+        let key = fingerprint(&[1, 2, 3], "");
+        assert!(memory.lookup(&key)?.is_none());
+        let audio_file = dir.join("in.wav");
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(&audio_file, b"RIFF")?;
+        let subtitles = dir.join("out.vtt");
+        std::fs::write(&subtitles, b"WEBVTT")?;
+        memory.store(&key, &audio_file, &[("subtitles", &subtitles)], "hello")?;
+        let entry = memory.lookup(&key)?.unwrap();
+        assert_eq!(entry.text, "hello");
+        assert_eq!(std::fs::read(entry.audio_file.clone())?, b"RIFF");
+        let stored = entry.sidecar("subtitles", Path::new("other.vtt")).unwrap();
+        assert_eq!(std::fs::read(stored)?, b"WEBVTT");
+        // The format depends on the extension.
+        assert_eq!(entry.sidecar("subtitles", Path::new("out.srt")), None);
+        assert_eq!(entry.sidecar("chapters", Path::new("out.vtt")), None);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Metadata of the input file propagated to the outputs, together with the version of hibiki
+// and the model used, so that the outputs of batch jobs remain traceable.
+
+use anyhow::Result;
+use std::io::{Seek, Write};
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+}
+
+impl Metadata {
+    fn update(&mut self, tags: &[symphonia::core::meta::Tag]) {
+        use symphonia::core::meta::StandardTagKey as K;
+        for tag in tags.iter() {
+            let field = match tag.std_key {
+                Some(K::TrackTitle) => &mut self.title,
+                Some(K::Artist) => &mut self.artist,
+                Some(K::Date | K::OriginalDate) => &mut self.date,
+                _ => continue,
+            };
+            if field.is_none() {
+                *field = Some(tag.value.to_string())
+            }
+        }
+    }
+
+    /// Reads the tags of an audio file, both from the container and from the metadata found
+    /// while probing, e.g. id3 tags.
+    pub fn read<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let src = std::fs::File::open(path)?;
+        let mss = symphonia::core::io::MediaSourceStream::new(Box::new(src), Default::default());
+        let hint = symphonia::core::probe::Hint::new();
+        let mut probed = symphonia::default::get_probe().format(
+            &hint,
+            mss,
+            &Default::default(),
+            &Default::default(),
+        )?;
+        let mut metadata = Self::default();
+        if let Some(rev) = probed.format.metadata().current() {
+            metadata.update(rev.tags())
+        }
+        if let Some(rev) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            metadata.update(rev.tags())
+        }
+        Ok(metadata)
+    }
+}
+
+/// The tags identifying how an output was generated.
+pub fn generator_tags(lm_model_file: &std::path::Path) -> Vec<(&'static str, String)> {
+    let model = lm_model_file.file_name().map_or_else(String::new, |v| v.to_string_lossy().into());
+    vec![("hibiki_version", env!("CARGO_PKG_VERSION").to_string()), ("model", model)]
+}
+
+/// Appends a LIST/INFO chunk with the tags to a wav file and updates the RIFF header.
+pub fn write_wav_info(
+    path: &std::path::Path,
+    metadata: &Metadata,
+    lm_model_file: &std::path::Path,
+) -> Result<()> {
+    let generator = generator_tags(lm_model_file)
+        .into_iter()
+        .map(|(k, v)| format!("{k}: {v}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let fields = [
+        (b"INAM", metadata.title.as_deref()),
+        (b"IART", metadata.artist.as_deref()),
+        (b"ICRD", metadata.date.as_deref()),
+        (b"ISFT", Some(generator.as_str())),
+    ];
+    let mut info = b"INFO".to_vec();
+    for (id, value) in fields.iter() {
+        let value = match value {
+            None => continue,
+            Some(value) => value,
+        };
+        // The values are nul terminated and the sub-chunks are padded to an even size.
+        let size = value.len() + 1;
+        info.extend_from_slice(*id);
+        info.extend_from_slice(&(size as u32).to_le_bytes());
+        info.extend_from_slice(value.as_bytes());
+        info.push(0);
+        if size % 2 == 1 {
+            info.push(0)
+        }
+    }
+    let mut file = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let file_len = file.seek(std::io::SeekFrom::End(0))?;
+    // The riff chunks have to be word aligned.
+    if file_len % 2 == 1 {
+        file.write_all(&[0])?;
+    }
+    file.write_all(b"LIST")?;
+    file.write_all(&(info.len() as u32).to_le_bytes())?;
+    file.write_all(&info)?;
+    let file_len = file.stream_position()?;
+    file.seek(std::io::SeekFrom::Start(4))?;
+    file.write_all(&((file_len - 8) as u32).to_le_bytes())?;
+    Ok(())
+}
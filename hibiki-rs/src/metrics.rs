@@ -0,0 +1,408 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Prometheus metrics for the daemon and the WebSocket server, served in the text exposition
+// format on `/metrics` by a minimal http listener, e.g. `hibiki daemon --metrics-addr
+// 127.0.0.1:9184`.
+
+use anyhow::Result;
+use std::fmt::Write;
+use std::io::{BufRead, BufReader};
+
+const JOB_DURATION_BUCKETS: &[f64] = &[1., 5., 10., 30., 60., 120., 300., 600., 1800., 3600.];
+const SESSION_DURATION_BUCKETS: &[f64] =
+    &[10., 60., 300., 600., 1800., 3600., 7200., 14400., 28800.];
+// The steps last 80ms, the buckets above it are the ones that fall behind real-time.
+const STEP_LATENCY_BUCKETS: &[f64] = &[0.01, 0.02, 0.04, 0.06, 0.08, 0.1, 0.15, 0.25, 0.5, 1.];
+// A scraper that stalls in its request is disconnected after this long, and only the start of
+// the requests is read.
+const CLIENT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_REQUEST_LEN: u64 = 16 << 10;
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self { buckets, counts: vec![0; buckets.len()], sum: 0., count: 0 }
+    }
+
+    fn observe(&mut self, value: f64, times: u64) {
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += times
+            }
+        }
+        self.sum += value * times as f64;
+        self.count += times
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        self.write_series(out, name, "")
+    }
+
+    // The samples of the histogram, `labels` being e.g. `session="3"` or empty.
+    fn write_series(&self, out: &mut String, name: &str, labels: &str) {
+        let prefix = if labels.is_empty() { String::new() } else { format!("{labels},") };
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter()) {
+            let _ = writeln!(out, "{name}_bucket{{{prefix}le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{prefix}le=\"+Inf\"}} {}", self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{labels}}}") };
+        let _ =
+            writeln!(out, "{name}_sum{labels} {}\n{name}_count{labels} {}", self.sum, self.count);
+    }
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+}
+
+/// The metrics accumulated since the daemon started, the gauges are passed when rendering.
+pub struct Metrics {
+    job_duration: Histogram,
+    step_latency: Histogram,
+    steps: u64,
+    text_tokens: u64,
+    // The finished jobs by final state.
+    jobs: std::collections::BTreeMap<&'static str, u64>,
+    request_errors: u64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            job_duration: Histogram::new(JOB_DURATION_BUCKETS),
+            step_latency: Histogram::new(STEP_LATENCY_BUCKETS),
+            steps: 0,
+            text_tokens: 0,
+            jobs: Default::default(),
+            request_errors: 0,
+        }
+    }
+}
+
+impl Metrics {
+    /// Records a batch of generation steps, the latency is the average over the batch.
+    pub fn record_batch(&mut self, num_steps: usize, elapsed: std::time::Duration) {
+        if num_steps == 0 {
+            return;
+        }
+        let latency = elapsed.as_secs_f64() / num_steps as f64;
+        self.step_latency.observe(latency, num_steps as u64);
+        self.steps += num_steps as u64
+    }
+
+    pub fn record_job(&mut self, state: &'static str, text_tokens: usize, duration: f64) {
+        self.job_duration.observe(duration, 1);
+        self.text_tokens += text_tokens as u64;
+        *self.jobs.entry(state).or_default() += 1
+    }
+
+    pub fn record_request_error(&mut self) {
+        self.request_errors += 1
+    }
+
+    pub fn render(&self, running: usize, queued: usize, dev: &candle::Device) -> String {
+        let mut out = String::new();
+        write_metric(&mut out, "hibiki_jobs_running", "gauge", "Jobs being translated.", running);
+        write_metric(&mut out, "hibiki_jobs_queued", "gauge", "Jobs waiting in the queue.", queued);
+        let _ = writeln!(
+            out,
+            "# HELP hibiki_jobs_total Finished jobs by state.\n# TYPE hibiki_jobs_total counter"
+        );
+        for state in ["done", "failed", "cancelled"] {
+            let count = self.jobs.get(state).copied().unwrap_or(0);
+            let _ = writeln!(out, "hibiki_jobs_total{{state=\"{state}\"}} {count}");
+        }
+        write_metric(
+            &mut out,
+            "hibiki_request_errors_total",
+            "counter",
+            "Requests rejected by the daemon.",
+            self.request_errors,
+        );
+        self.job_duration.write(
+            &mut out,
+            "hibiki_job_duration_seconds",
+            "Wall time of the finished jobs.",
+        );
+        self.step_latency.write(
+            &mut out,
+            "hibiki_step_latency_seconds",
+            "Processing time of the generation steps, averaged per batch.",
+        );
+        write_metric(&mut out, "hibiki_steps_total", "counter", "Generation steps.", self.steps);
+        write_metric(
+            &mut out,
+            "hibiki_text_tokens_total",
+            "counter",
+            "Text tokens generated by the finished jobs.",
+            self.text_tokens,
+        );
+        write_memory(&mut out, dev);
+        out
+    }
+}
+
+fn write_memory(out: &mut String, dev: &candle::Device) {
+    if let Some(bytes) = crate::resources::process_memory() {
+        write_metric(
+            out,
+            "hibiki_process_memory_bytes",
+            "gauge",
+            "Resident memory of the process.",
+            bytes,
+        );
+    }
+    // The cpu memory is already covered by the resident memory.
+    if !dev.is_cpu() {
+        let total = crate::resources::total_memory(dev);
+        let available = crate::resources::available_memory(dev);
+        if let (Some(total), Some(available)) = (total, available) {
+            write_metric(
+                out,
+                "hibiki_device_memory_used_bytes",
+                "gauge",
+                "Memory used on the gpu, by all the processes.",
+                total.saturating_sub(available),
+            );
+        }
+    }
+}
+
+/// The metrics of the WebSocket server since it started. The step latencies are kept for each
+/// active session, labeled with its id, and for all the sessions together.
+pub struct ServeMetrics {
+    next_session: u64,
+    active: std::collections::BTreeMap<u64, Histogram>,
+    sessions: u64,
+    session_duration: Histogram,
+    step_latency: Histogram,
+    steps: u64,
+    text_tokens: u64,
+    // The failed sessions by error code.
+    errors: std::collections::BTreeMap<&'static str, u64>,
+}
+
+impl Default for ServeMetrics {
+    fn default() -> Self {
+        Self {
+            next_session: 0,
+            active: Default::default(),
+            sessions: 0,
+            session_duration: Histogram::new(SESSION_DURATION_BUCKETS),
+            step_latency: Histogram::new(STEP_LATENCY_BUCKETS),
+            steps: 0,
+            text_tokens: 0,
+            errors: Default::default(),
+        }
+    }
+}
+
+/// A session counted in the metrics, it stops being active when dropped.
+pub struct ActiveSession<'a> {
+    metrics: &'a std::sync::Mutex<ServeMetrics>,
+    id: u64,
+    start: std::time::Instant,
+}
+
+impl ActiveSession<'_> {
+    /// Records a generation step that produced `text_tokens` text tokens.
+    pub fn record_step(&self, elapsed: std::time::Duration, text_tokens: usize) {
+        let mut metrics = self.metrics.lock().unwrap();
+        let latency = elapsed.as_secs_f64();
+        if let Some(histogram) = metrics.active.get_mut(&self.id) {
+            histogram.observe(latency, 1)
+        }
+        metrics.step_latency.observe(latency, 1);
+        metrics.steps += 1;
+        metrics.text_tokens += text_tokens as u64
+    }
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.active.remove(&self.id);
+        metrics.session_duration.observe(self.start.elapsed().as_secs_f64(), 1)
+    }
+}
+
+impl ServeMetrics {
+    pub fn start_session(metrics: &std::sync::Mutex<Self>) -> ActiveSession<'_> {
+        let mut inner = metrics.lock().unwrap();
+        let id = inner.next_session;
+        inner.next_session += 1;
+        inner.sessions += 1;
+        inner.active.insert(id, Histogram::new(STEP_LATENCY_BUCKETS));
+        ActiveSession { metrics, id, start: std::time::Instant::now() }
+    }
+
+    pub fn record_error(&mut self, code: crate::protocol::ErrorCode) {
+        *self.errors.entry(code.as_str()).or_default() += 1
+    }
+
+    pub fn render(&self, listeners: usize, dev: &candle::Device) -> String {
+        let mut out = String::new();
+        let active = self.active.len();
+        write_metric(&mut out, "hibiki_sessions_active", "gauge", "Sessions being served.", active);
+        write_metric(&mut out, "hibiki_sessions_total", "counter", "Sessions.", self.sessions);
+        write_metric(&mut out, "hibiki_listeners", "gauge", "Listen-only clients.", listeners);
+        let _ = writeln!(
+            out,
+            "# HELP hibiki_session_errors_total Failed sessions by error code.\n\
+             # TYPE hibiki_session_errors_total counter"
+        );
+        for (code, count) in self.errors.iter() {
+            let _ = writeln!(out, "hibiki_session_errors_total{{code=\"{code}\"}} {count}");
+        }
+        self.session_duration.write(
+            &mut out,
+            "hibiki_session_duration_seconds",
+            "Wall time of the finished sessions.",
+        );
+        self.step_latency.write(
+            &mut out,
+            "hibiki_step_latency_seconds",
+            "Processing time of the generation steps of all the sessions.",
+        );
+        let name = "hibiki_session_step_latency_seconds";
+        let help = "Processing time of the generation steps of the active sessions.";
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for (id, histogram) in self.active.iter() {
+            histogram.write_series(&mut out, name, &format!("session=\"{id}\""))
+        }
+        write_metric(&mut out, "hibiki_steps_total", "counter", "Generation steps.", self.steps);
+        write_metric(
+            &mut out,
+            "hibiki_text_tokens_total",
+            "counter",
+            "Text tokens generated by the sessions.",
+            self.text_tokens,
+        );
+        write_memory(&mut out, dev);
+        out
+    }
+}
+
+fn handle_client(stream: std::net::TcpStream, render: &dyn Fn() -> String) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(std::io::Read::take(stream, MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not used but have to be read before replying.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear()
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let header = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    std::io::Write::write_all(&mut writer, header.as_bytes())?;
+    std::io::Write::write_all(&mut writer, body.as_bytes())?;
+    Ok(())
+}
+
+/// Serves the rendered metrics on `/metrics` from a background thread, each client is handled
+/// on its own thread so that a slow one does not block the others.
+pub fn serve(
+    addr: std::net::SocketAddr,
+    render: impl Fn() -> String + Send + Sync + 'static,
+) -> Result<()> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    tracing::info!(%addr, "serving the metrics");
+    let render = std::sync::Arc::new(render);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::warn!(?err, "cannot accept metrics client");
+                    continue;
+                }
+            };
+            let render = render.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = handle_client(stream, render.as_ref()) {
+                    tracing::warn!(?err, "metrics client error")
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::new(&[0.1, 1.]);
+        histogram.observe(0.05, 2);
+        histogram.observe(0.5, 1);
+        histogram.observe(5., 1);
+        let mut out = String::new();
+        histogram.write_series(&mut out, "h", "session=\"0\"");
+        let expected = "h_bucket{session=\"0\",le=\"0.1\"} 2\n\
+                        h_bucket{session=\"0\",le=\"1\"} 3\n\
+                        h_bucket{session=\"0\",le=\"+Inf\"} 4\n\
+                        h_sum{session=\"0\"} 5.6\n\
+                        h_count{session=\"0\"} 4\n";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn serve_sessions() {
+        let metrics = std::sync::Mutex::new(ServeMetrics::default());
+        let first = ServeMetrics::start_session(&metrics);
+        let second = ServeMetrics::start_session(&metrics);
+        first.record_step(std::time::Duration::from_millis(50), 1);
+        second.record_step(std::time::Duration::from_millis(50), 0);
+        second.record_step(std::time::Duration::from_millis(200), 1);
+        drop(first);
+        metrics.lock().unwrap().record_error(crate::protocol::ErrorCode::OutOfMemory);
+        let out = metrics.lock().unwrap().render(3, &candle::Device::Cpu);
+        for line in [
+            "hibiki_sessions_active 1",
+            "hibiki_sessions_total 2",
+            "hibiki_listeners 3",
+            "hibiki_session_errors_total{code=\"out_of_memory\"} 1",
+            "hibiki_session_duration_seconds_count 1",
+            "hibiki_step_latency_seconds_count 3",
+            "hibiki_session_step_latency_seconds_count{session=\"1\"} 2",
+            "hibiki_steps_total 3",
+            "hibiki_text_tokens_total 2",
+        ] {
+            assert!(out.lines().any(|v| v == line), "{line}")
+        }
+        // The finished sessions are no longer labeled.
+        assert!(!out.contains("session=\"0\""));
+        drop(second);
+        let out = metrics.lock().unwrap().render(0, &candle::Device::Cpu);
+        assert!(out.lines().any(|v| v == "hibiki_sessions_active 0"));
+        assert!(!out.contains("session=\""));
+    }
+}
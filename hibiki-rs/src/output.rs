@@ -0,0 +1,70 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Text output written from a separate thread so that a slow terminal, e.g. over ssh, does not
+// stall the generation loop.
+
+use std::io::Write;
+use std::sync::mpsc;
+
+pub struct TextWriter {
+    tx: Option<mpsc::Sender<String>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl TextWriter {
+    /// Spawns the thread writing the text to stdout, the text is flushed after each write.
+    pub fn stdout() -> Self {
+        Self::spawn(std::io::stdout())
+    }
+
+    /// Writes the text to stderr instead, for when stdout carries the audio.
+    pub fn stderr() -> Self {
+        Self::spawn(std::io::stderr())
+    }
+
+    /// Drops the text, e.g. in the quiet mode.
+    pub fn discard() -> Self {
+        Self::spawn(std::io::sink())
+    }
+
+    fn spawn<W: Write + Send + 'static>(mut stdout: W) -> Self {
+        // The channel is unbounded: the text is small compared to the audio and dropping some
+        // of it would corrupt the transcript.
+        let (tx, rx) = mpsc::channel::<String>();
+        let handle = std::thread::spawn(move || {
+            while let Ok(text) = rx.recv() {
+                let mut text = text;
+                // Coalesce the pending writes when the terminal is lagging behind.
+                while let Ok(next) = rx.try_recv() {
+                    text.push_str(&next)
+                }
+                if stdout.write_all(text.as_bytes()).and_then(|()| stdout.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { tx: Some(tx), handle: Some(handle) }
+    }
+
+    pub fn write(&self, text: &str) {
+        if let Some(tx) = self.tx.as_ref() {
+            let _ = tx.send(text.to_string());
+        }
+    }
+
+    /// Waits for all the pending text to be written.
+    pub fn finish(&mut self) {
+        self.tx = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for TextWriter {
+    fn drop(&mut self) {
+        self.finish()
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+use std::time::{Duration, Instant};
+
+/// Coalesces text emissions so that at most a given number of updates are produced per second,
+/// some caption devices and chat integrations cannot handle token-by-token updates. Only whole
+/// words are committed, the last (possibly incomplete) word is kept until the next update.
+pub struct TextPacer {
+    min_interval: Option<Duration>,
+    pending: String,
+    last_emit: Option<Instant>,
+}
+
+impl TextPacer {
+    pub fn new(max_updates_per_sec: Option<f64>) -> Self {
+        let min_interval =
+            max_updates_per_sec.filter(|v| *v > 0.).map(|v| Duration::from_secs_f64(1. / v));
+        Self { min_interval, pending: String::new(), last_emit: None }
+    }
+
+    /// Appends some text and returns what should be displayed now, if anything.
+    pub fn push(&mut self, text: &str) -> Option<String> {
+        self.pending.push_str(text);
+        let min_interval = match self.min_interval {
+            None => return self.flush(),
+            Some(v) => v,
+        };
+        match self.last_emit {
+            Some(last_emit) if last_emit.elapsed() < min_interval => return None,
+            _ => {}
+        }
+        // Words start with a whitespace so everything before the last whitespace is complete.
+        let end = self.pending.rfind(char::is_whitespace)?;
+        if end == 0 {
+            return None;
+        }
+        let rest = self.pending.split_off(end);
+        self.last_emit = Some(Instant::now());
+        Some(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// Returns all the pending text, to be called at the end of the generation.
+    pub fn flush(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            self.last_emit = Some(Instant::now());
+            Some(std::mem::take(&mut self.pending))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unpaced() {
+        let mut pacer = TextPacer::new(None);
+        assert_eq!(pacer.push(" Hel").as_deref(), Some(" Hel"));
+        assert_eq!(pacer.push("lo").as_deref(), Some("lo"));
+        assert_eq!(pacer.flush(), None);
+    }
+
+    #[test]
+    fn whole_words_only() {
+        let mut pacer = TextPacer::new(Some(1000.));
+        assert_eq!(pacer.push(" Hel"), None);
+        assert_eq!(pacer.push("lo"), None);
+        assert_eq!(pacer.push(" wor").as_deref(), Some(" Hello"));
+        assert_eq!(pacer.flush().as_deref(), Some(" wor"));
+    }
+
+    #[test]
+    fn rate_limited() {
+        let mut pacer = TextPacer::new(Some(0.001));
+        assert_eq!(pacer.push(" one two").as_deref(), Some(" one"));
+        // The next update is only due in 1000s.
+        assert_eq!(pacer.push(" three four"), None);
+        assert_eq!(pacer.flush().as_deref(), Some(" two three four"));
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Comparison of the generated tokens against reference tokens produced by another
+// implementation, e.g. the PyTorch one, to locate conversion or precision issues.
+
+use anyhow::Result;
+use moshi::lm_generate_multistream::UNGENERATED;
+
+/// Reference tokens, using the same layout as the `--emit-token-ids` output: one text token per
+/// step and one row of audio tokens per step with the acoustic delay applied. Negative values
+/// mark tokens that have not been generated.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Reference {
+    pub text_tokens: Vec<i64>,
+    pub audio_tokens: Vec<Vec<i64>>,
+}
+
+impl Reference {
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let file = std::io::BufReader::new(std::fs::File::open(path.as_ref())?);
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Text,
+    /// A generated audio codebook.
+    Audio(usize),
+    /// A codebook of the input audio, as produced by the audio tokenizer.
+    InputAudio(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub step_idx: usize,
+    pub stream: Stream,
+    pub expected: i64,
+    pub actual: u32,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stream = match self.stream {
+            Stream::Text => "text".to_string(),
+            Stream::Audio(c) => format!("audio codebook {c}"),
+            Stream::InputAudio(c) => format!("input audio codebook {c}"),
+        };
+        write!(
+            f,
+            "step {} ({:.2}s), {stream}: expected {} got {}",
+            self.step_idx,
+            self.step_idx as f64 * 0.08,
+            self.expected,
+            self.actual
+        )
+    }
+}
+
+fn differs(expected: i64, actual: u32) -> bool {
+    expected >= 0 && actual != UNGENERATED && expected != actual as i64
+}
+
+/// Returns the first step at which the tokens differ from the reference, within a step the
+/// input audio is checked first as it conditions everything else, then the text and finally
+/// the generated audio.
+pub fn first_divergence(
+    reference: &Reference,
+    text_tokens: &[u32],
+    audio_tokens: &[Vec<u32>],
+    generated_audio_codebooks: usize,
+) -> Option<Divergence> {
+    let num_steps = text_tokens.len().max(audio_tokens.len());
+    for step_idx in 0..num_steps {
+        let no_tokens = vec![];
+        let ref_audio = reference.audio_tokens.get(step_idx).unwrap_or(&no_tokens);
+        let audio = audio_tokens.get(step_idx).map_or(&[][..], |v| v.as_slice());
+        let pairs = ref_audio.iter().zip(audio.iter()).enumerate();
+        for (c, (&expected, &actual)) in pairs.clone().skip(generated_audio_codebooks) {
+            if differs(expected, actual) {
+                let stream = Stream::InputAudio(c - generated_audio_codebooks);
+                return Some(Divergence { step_idx, stream, expected, actual });
+            }
+        }
+        if let (Some(&expected), Some(&actual)) =
+            (reference.text_tokens.get(step_idx), text_tokens.get(step_idx))
+        {
+            if differs(expected, actual) {
+                return Some(Divergence { step_idx, stream: Stream::Text, expected, actual });
+            }
+        }
+        for (c, (&expected, &actual)) in pairs.take(generated_audio_codebooks) {
+            if differs(expected, actual) {
+                return Some(Divergence { step_idx, stream: Stream::Audio(c), expected, actual });
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two generated audio codebooks followed by a single input codebook.
+    const GENERATED: usize = 2;
+
+    fn generation() -> (Vec<u32>, Vec<Vec<u32>>) {
+        let text_tokens = vec![3, 3, 42, 7];
+        let audio_tokens = vec![
+            vec![UNGENERATED, UNGENERATED, 10],
+            vec![1, UNGENERATED, 11],
+            vec![2, 20, 12],
+            vec![3, 21, 13],
+        ];
+        (text_tokens, audio_tokens)
+    }
+
+    // The reference as the json output of the other implementation, with -1 for the tokens
+    // that have not been generated.
+    fn reference(text_tokens: &[u32], audio_tokens: &[Vec<u32>]) -> Reference {
+        let token = |v: u32| if v == UNGENERATED { -1 } else { v as i64 };
+        Reference {
+            text_tokens: text_tokens.iter().map(|&v| token(v)).collect(),
+            audio_tokens: audio_tokens
+                .iter()
+                .map(|v| v.iter().map(|&v| token(v)).collect())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn identical() {
+        let (text_tokens, audio_tokens) = generation();
+        let reference = reference(&text_tokens, &audio_tokens);
+        assert_eq!(first_divergence(&reference, &text_tokens, &audio_tokens, GENERATED), None);
+    }
+
+    #[test]
+    fn text_divergence() {
+        let (text_tokens, audio_tokens) = generation();
+        let mut reference = reference(&text_tokens, &audio_tokens);
+        reference.text_tokens[3] = 8;
+        // The audio of the same step differs too, the text is reported as it comes first.
+        reference.audio_tokens[3][1] = 99;
+        let divergence = first_divergence(&reference, &text_tokens, &audio_tokens, GENERATED);
+        let expected = Divergence { step_idx: 3, stream: Stream::Text, expected: 8, actual: 7 };
+        assert_eq!(divergence, Some(expected));
+        assert_eq!(expected.to_string(), "step 3 (0.24s), text: expected 8 got 7");
+    }
+
+    #[test]
+    fn audio_divergence() {
+        let (text_tokens, audio_tokens) = generation();
+        let mut reference = reference(&text_tokens, &audio_tokens);
+        reference.audio_tokens[2][1] = 99;
+        let divergence = first_divergence(&reference, &text_tokens, &audio_tokens, GENERATED);
+        let expected =
+            Divergence { step_idx: 2, stream: Stream::Audio(1), expected: 99, actual: 20 };
+        assert_eq!(divergence, Some(expected));
+        // The input audio is checked before the text and the generated audio of the step.
+        reference.text_tokens[2] = 43;
+        reference.audio_tokens[2][2] = 98;
+        let divergence = first_divergence(&reference, &text_tokens, &audio_tokens, GENERATED);
+        let expected =
+            Divergence { step_idx: 2, stream: Stream::InputAudio(0), expected: 98, actual: 12 };
+        assert_eq!(divergence, Some(expected));
+    }
+
+    #[test]
+    fn ungenerated_tokens_are_skipped() {
+        let (text_tokens, audio_tokens) = generation();
+        let mut reference = reference(&text_tokens, &audio_tokens);
+        // The reference has a token where the generation has none, and the other way around.
+        reference.audio_tokens[0][0] = 5;
+        reference.audio_tokens[2][0] = -1;
+        assert_eq!(first_divergence(&reference, &text_tokens, &audio_tokens, GENERATED), None);
+    }
+
+    #[test]
+    fn shorter_reference() {
+        let (text_tokens, audio_tokens) = generation();
+        let mut shorter = reference(&text_tokens[..2], &audio_tokens[..2]);
+        assert_eq!(first_divergence(&shorter, &text_tokens, &audio_tokens, GENERATED), None);
+        shorter.audio_tokens[1][0] = 4;
+        let divergence = first_divergence(&shorter, &text_tokens, &audio_tokens, GENERATED);
+        assert_eq!(divergence.map(|v| (v.step_idx, v.stream)), Some((1, Stream::Audio(0))));
+        // A longer reference is only compared over the generated steps.
+        let longer = reference(&text_tokens, &audio_tokens);
+        let divergence =
+            first_divergence(&longer, &text_tokens[..3], &audio_tokens[..3], GENERATED);
+        assert_eq!(divergence, None);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Conversions of the raw pcm that run on every captured and played sample, which adds up when
+// many live sessions share a host. The loops work on fixed size chunks so that they get
+// vectorized, with NEON on aarch64 where it is always available and with AVX2 on x86_64 when the
+// cpu supports it, which is detected at runtime.
+
+// Samples per chunk, two AVX2 registers of f32.
+const LANES: usize = 16;
+
+// Compiles the body for AVX2 on x86_64 and picks that version at runtime when supported.
+macro_rules! multiversion {
+    ($(#[$meta:meta])* pub fn $name:ident($($arg:ident: $ty:ty),*) $body:block) => {
+        $(#[$meta])*
+        pub fn $name($($arg: $ty),*) {
+            #[inline(always)]
+            fn imp($($arg: $ty),*) $body
+
+            #[cfg(target_arch = "x86_64")]
+            #[target_feature(enable = "avx2")]
+            unsafe fn avx2($($arg: $ty),*) {
+                imp($($arg),*)
+            }
+
+            #[cfg(target_arch = "x86_64")]
+            if std::is_x86_feature_detected!("avx2") {
+                // Safety: avx2 is supported by this cpu.
+                return unsafe { avx2($($arg),*) };
+            }
+            imp($($arg),*)
+        }
+    };
+}
+
+#[inline(always)]
+fn s16_to_f32(v: i16) -> f32 {
+    v as f32 / 32768.
+}
+
+#[inline(always)]
+fn f32_to_s16(v: f32) -> i16 {
+    // NaN maps to 0 as with `as`, the saturating cast is not needed within the clamped range and
+    // would prevent the vectorization.
+    let v = if v.is_nan() { 0. } else { v.clamp(-1., 1.) * 32767. };
+    // Safety: v is finite and within the range of i16.
+    unsafe { v.to_int_unchecked::<i32>() as i16 }
+}
+
+multiversion! {
+    /// Decodes little-endian 16 bits samples, `bytes` holds two bytes per sample of `pcm`.
+    pub fn s16le_to_f32(bytes: &[u8], pcm: &mut [f32]) {
+        let len = pcm.len().min(bytes.len() / 2);
+        let (bytes, pcm) = (&bytes[..2 * len], &mut pcm[..len]);
+        let mut src = bytes.chunks_exact(2 * LANES);
+        let mut dst = pcm.chunks_exact_mut(LANES);
+        for (src, dst) in (&mut src).zip(&mut dst) {
+            for (idx, dst) in dst.iter_mut().enumerate() {
+                *dst = s16_to_f32(i16::from_le_bytes([src[2 * idx], src[2 * idx + 1]]))
+            }
+        }
+        for (src, dst) in src.remainder().chunks_exact(2).zip(dst.into_remainder()) {
+            *dst = s16_to_f32(i16::from_le_bytes([src[0], src[1]]))
+        }
+    }
+}
+
+multiversion! {
+    /// Encodes to little-endian 16 bits samples clamped to [-1, 1], `bytes` holds two bytes per
+    /// sample of `pcm`.
+    pub fn f32_to_s16le(pcm: &[f32], bytes: &mut [u8]) {
+        let len = pcm.len().min(bytes.len() / 2);
+        let (pcm, bytes) = (&pcm[..len], &mut bytes[..2 * len]);
+        let mut src = pcm.chunks_exact(LANES);
+        let mut dst = bytes.chunks_exact_mut(2 * LANES);
+        for (src, dst) in (&mut src).zip(&mut dst) {
+            let mut samples = [0i16; LANES];
+            for (sample, &v) in samples.iter_mut().zip(src.iter()) {
+                *sample = f32_to_s16(v)
+            }
+            for (idx, sample) in samples.iter().enumerate() {
+                let [lo, hi] = sample.to_le_bytes();
+                dst[2 * idx] = lo;
+                dst[2 * idx + 1] = hi
+            }
+        }
+        for (&v, dst) in src.remainder().iter().zip(dst.into_remainder().chunks_exact_mut(2)) {
+            dst.copy_from_slice(&f32_to_s16(v).to_le_bytes())
+        }
+    }
+}
+
+multiversion! {
+    /// Decodes little-endian f32 samples, `bytes` holds four bytes per sample of `pcm`.
+    pub fn f32le_to_f32(bytes: &[u8], pcm: &mut [f32]) {
+        for (dst, src) in pcm.iter_mut().zip(bytes.chunks_exact(4)) {
+            *dst = f32::from_le_bytes([src[0], src[1], src[2], src[3]])
+        }
+    }
+}
+
+multiversion! {
+    /// Averages the `selected` channels of the interleaved samples into `pcm`, one sample per
+    /// frame of `channels` samples.
+    pub fn downmix(interleaved: &[f32], channels: usize, selected: &[usize], pcm: &mut [f32]) {
+        let frames = interleaved.chunks_exact(channels);
+        match (channels, selected) {
+            (1, [0]) => {
+                let len = pcm.len().min(interleaved.len());
+                pcm[..len].copy_from_slice(&interleaved[..len])
+            }
+            // The common stereo mix, the pairs are contiguous.
+            (2, [0, 1]) | (2, [1, 0]) => {
+                for (dst, src) in pcm.iter_mut().zip(frames) {
+                    *dst = (src[0] + src[1]) * 0.5
+                }
+            }
+            (_, [channel]) => {
+                for (dst, src) in pcm.iter_mut().zip(frames) {
+                    *dst = src[*channel]
+                }
+            }
+            _ => {
+                let scale = 1. / selected.len() as f32;
+                for (dst, src) in pcm.iter_mut().zip(frames) {
+                    *dst = selected.iter().map(|&c| src[c]).sum::<f32>() * scale
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s16le_roundtrip() {
+        // Not a multiple of the chunk size so that the remainder loops run too.
+        let pcm: Vec<f32> = (0..2 * LANES + 5).map(|v| (v as f32 - 20.) / 20.).collect();
+        let mut bytes = vec![0u8; 2 * pcm.len()];
+        f32_to_s16le(&pcm, &mut bytes);
+        let mut decoded = vec![0f32; pcm.len()];
+        s16le_to_f32(&bytes, &mut decoded);
+        for (v, d) in pcm.iter().zip(decoded.iter()) {
+            assert!((v - d).abs() < 1e-4, "{v} {d}")
+        }
+    }
+
+    #[test]
+    fn s16le_clamps() {
+        let pcm = [2., -2., f32::NAN, 1., -1., 0.5];
+        let mut bytes = vec![0u8; 2 * pcm.len()];
+        f32_to_s16le(&pcm, &mut bytes);
+        let samples: Vec<i16> =
+            bytes.chunks_exact(2).map(|v| i16::from_le_bytes([v[0], v[1]])).collect();
+        assert_eq!(samples, [32767, -32767, 0, 32767, -32767, 16383]);
+        assert_eq!(s16_to_f32(i16::MIN), -1.);
+    }
+
+    #[test]
+    fn f32le_decode() {
+        let pcm = [0.25f32, -1.5, 3.];
+        let bytes: Vec<u8> = pcm.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut decoded = [0f32; 4];
+        // The trailing partial sample is ignored.
+        f32le_to_f32(&bytes[..bytes.len() - 1], &mut decoded);
+        assert_eq!(decoded, [0.25, -1.5, 0., 0.]);
+    }
+
+    #[test]
+    fn downmix_channels() {
+        let interleaved = [1., 2., 3., 4., 5., 6.];
+        let mut pcm = [0f32; 3];
+        downmix(&interleaved, 2, &[0, 1], &mut pcm);
+        assert_eq!(pcm, [1.5, 3.5, 5.5]);
+        let mut pcm = [0f32; 2];
+        downmix(&interleaved, 3, &[2], &mut pcm);
+        assert_eq!(pcm, [3., 6.]);
+        downmix(&interleaved, 3, &[0, 1, 2], &mut pcm);
+        assert_eq!(pcm, [2., 5.]);
+        let mut pcm = [0f32; 6];
+        downmix(&interleaved, 1, &[0], &mut pcm);
+        assert_eq!(pcm, interleaved);
+    }
+}
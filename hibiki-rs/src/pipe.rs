@@ -0,0 +1,172 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Streaming translation for shell pipelines, `-` as the input reads raw mono PCM at the codec
+// sample rate from stdin and `-` as the output writes the translated PCM to stdout as it is
+// generated, e.g.
+//   ffmpeg -i in.mp4 -f s16le -ac 1 -ar 24000 - | hibiki gen - - | ffplay -f s16le -ar 24000 -
+// The text then goes to stderr. The post-processing that needs the whole output, e.g. the
+// duration fitting or the subtitles, is not available in this mode.
+
+use anyhow::Result;
+use candle::Device;
+
+pub const STDIO: &str = "-";
+
+enum Source {
+    Stdin(crate::audio_io::Capture),
+    File { pcm: Vec<f32>, pos: usize },
+}
+
+impl Source {
+    fn read_frame(&mut self, frame: &mut [f32]) -> Result<bool> {
+        match self {
+            Self::Stdin(capture) => capture.read_frame(frame),
+            Self::File { pcm, pos } => {
+                if *pos >= pcm.len() {
+                    return Ok(false);
+                }
+                let end = usize::min(*pos + frame.len(), pcm.len());
+                frame[..end - *pos].copy_from_slice(&pcm[*pos..end]);
+                frame[end - *pos..].fill(0.);
+                *pos = end;
+                Ok(true)
+            }
+        }
+    }
+}
+
+enum Sink {
+    Stdout(crate::audio_io::RawOutput, Box<crate::audio_io::StreamingResampler>),
+    Wav(Vec<f32>),
+}
+
+/// Whether the input or the output of a generation is a pipe.
+pub fn is_pipe(args: &crate::gen::Args) -> bool {
+    args.audio_input_file.as_os_str() == STDIO || args.audio_output_file.as_os_str() == STDIO
+}
+
+/// Translates from stdin or to stdout. As in the live mode, the lm state is reset once
+/// `max_steps` steps have been generated so that streams of any length can be processed.
+pub fn run(
+    args: &crate::gen::Args,
+    dev: &Device,
+    format: crate::audio_io::RawFormat,
+) -> Result<()> {
+    let mut models = crate::gen::Models::load(args, dev)?;
+    let frame_size = models.codec.frame_size();
+    let sample_rate = models.codec.sample_rate();
+    let mut source = if args.audio_input_file.as_os_str() == STDIO {
+        if args.start > 0. || args.end.is_some() {
+            anyhow::bail!("--start and --end cannot be used when reading from stdin")
+        }
+        tracing::info!(?format, sample_rate, "reading the audio from stdin");
+        Source::Stdin(crate::audio_io::Capture::stdin(format))
+    } else {
+        let input = crate::gen::Input::load(args, models.codec.as_ref(), dev)?;
+        Source::File { pcm: input.pcm().flatten_all()?.to_vec1::<f32>()?, pos: 0 }
+    };
+    let mut sink = if args.audio_output_file.as_os_str() == STDIO {
+        if args.fit_duration
+            || args.subtitles.is_some()
+            || args.cue_stats.is_some()
+            || args.word_alignment.is_some()
+            || args.trace.is_some()
+            || args.chapters.is_some()
+            || args.clip_markers.is_some()
+            || args.stereo_mix
+            || args.dub_mix.is_some()
+        {
+            tracing::warn!("the post-processing options are ignored when writing to stdout")
+        }
+        let output_sample_rate = args.output_sample_rate.unwrap_or(sample_rate);
+        let resampler = crate::audio_io::StreamingResampler::new(sample_rate, output_sample_rate)?;
+        Sink::Stdout(crate::audio_io::RawOutput::stdout(format), Box::new(resampler))
+    } else {
+        Sink::Wav(vec![])
+    };
+    let text_writer = match sink {
+        Sink::Stdout(..) => crate::output::TextWriter::stderr(),
+        Sink::Wav(_) => crate::output::TextWriter::stdout(),
+    };
+    let mut pacer = crate::pacing::TextPacer::new(args.max_text_updates_per_sec);
+    let mut transcript = String::new();
+    let mut autosave = args.transcript_file.as_ref().map(|path| {
+        let interval = std::time::Duration::from_secs_f64(args.autosave_secs);
+        crate::transcript::Autosave::new(path, interval, args.target_language.clone())
+    });
+
+    let mut frame = vec![0f32; frame_size];
+    let mut segment = 0;
+    let mut stop = crate::stop::StopSequences::new(&args.stop_sequences);
+    'segments: loop {
+        let mut session = crate::session::GenSession::new(args, &mut models, segment, dev)?;
+        stop.reset();
+        'steps: while session.state().step_idx() < args.max_steps {
+            if crate::interrupt::requested() {
+                tracing::warn!("translation cancelled, writing the outputs");
+                break 'segments;
+            }
+            if !source.read_frame(&mut frame)? {
+                break 'segments;
+            }
+            session.push_pcm(&frame);
+            for output in session.step()? {
+                let stop_state = stop.step(output.text.as_deref());
+                let text_stopped = stop.matched() && stop_state != crate::stop::Stop::Matched;
+                let text = output.text.as_deref().filter(|_| !text_stopped);
+                if let Some(text) = text.and_then(|v| pacer.push(v)) {
+                    text_writer.write(&text);
+                    transcript.push_str(&text)
+                }
+                if let Some(pcm) = output.pcm {
+                    let pcm = pcm.flatten_all()?.to_vec1::<f32>()?;
+                    match &mut sink {
+                        Sink::Stdout(out, resampler) => out.write(&resampler.push(&pcm)?)?,
+                        Sink::Wav(out_pcm) => out_pcm.extend(pcm),
+                    }
+                }
+                match stop_state {
+                    crate::stop::Stop::Continue => {}
+                    crate::stop::Stop::Matched => session.end_text(),
+                    crate::stop::Stop::Done => break 'steps,
+                }
+            }
+            if let Some(autosave) = autosave.as_mut() {
+                autosave.maybe_save(&transcript)?
+            }
+        }
+        if stop.matched() {
+            tracing::info!(segment, "stop sequence generated, starting a new context")
+        } else {
+            tracing::info!(segment, "reached --max-steps, starting a new context")
+        }
+        segment += 1;
+    }
+    if let Some(text) = pacer.flush() {
+        text_writer.write(&text);
+        transcript.push_str(&text)
+    }
+    text_writer.write("\n");
+    if let Some(autosave) = autosave.as_mut() {
+        autosave.save(&transcript)?
+    }
+    match sink {
+        Sink::Stdout(mut out, mut resampler) => out.write(&resampler.flush()?)?,
+        Sink::Wav(out_pcm) => {
+            let (out_pcm, sample_rate) =
+                crate::audio_io::resample_output(out_pcm, sample_rate, args.output_sample_rate)?;
+            let mut out_wav = vec![];
+            crate::audio_io::write_wav(
+                &mut out_wav,
+                &out_pcm,
+                sample_rate as u32,
+                args.wav_format,
+            )?;
+            std::fs::write(&args.audio_output_file, out_wav)?;
+            tracing::info!(audio = ?args.audio_output_file, "generated audio");
+        }
+    }
+    Ok(())
+}
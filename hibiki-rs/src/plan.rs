@@ -0,0 +1,177 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// The plan of a generation for dry runs: the resolved files, the settings and the memory and
+// runtime estimates, computed without loading the weights so that invalid inputs or budgets are
+// caught before a long run.
+
+use anyhow::{Context, Result};
+use candle::Device;
+
+// The padding appended to the input before encoding it, see `gen::Input::load`.
+const INPUT_PADDING: usize = 12000;
+
+fn mb(bytes: usize) -> String {
+    format!("{}MB", bytes >> 20)
+}
+
+fn file_size(path: &std::path::Path) -> Result<usize> {
+    Ok(std::fs::metadata(path).with_context(|| format!("cannot read {path:?}"))?.len() as usize)
+}
+
+// Only reads the safetensors header, the tensor data is mapped but never accessed. For encrypted
+// files only the first chunk is decrypted to check the key.
+fn check_safetensors(path: &std::path::Path) -> Result<String> {
+    if crate::crypt::is_encrypted(path)? {
+        crate::crypt::check(path)?;
+        return Ok("encrypted".to_string());
+    }
+    if crate::quantize::is_gguf(path) {
+        let mut file = std::fs::File::open(path)?;
+        let content = candle::quantized::gguf_file::Content::read(&mut file)
+            .with_context(|| format!("invalid gguf file {path:?}"))?;
+        return Ok(format!("{} quantized tensors", content.tensor_infos.len()));
+    }
+    let st = unsafe { candle::safetensors::MmapedSafetensors::new(path) }
+        .with_context(|| format!("invalid weight file {path:?}"))?;
+    Ok(format!("{} tensors", st.tensors().len()))
+}
+
+/// The number of steps for the input file, this decodes the whole file so that unsupported or
+/// corrupted inputs are reported.
+fn input_steps(args: &crate::gen::Args) -> Result<(usize, f64, u32)> {
+    let path = &args.audio_input_file;
+    let (pcm, sample_rate) = crate::audio_io::pcm_decode_range(path, args.start, args.end)
+        .with_context(|| format!("cannot decode the audio input {path:?}"))?;
+    if pcm.is_empty() {
+        anyhow::bail!("no audio in the requested range of {path:?}")
+    }
+    let duration = pcm.len() as f64 / sample_rate as f64;
+    let frame_size = crate::codec::frame_size();
+    let mut pcm_len = ((pcm.len() + INPUT_PADDING) as f64 * crate::audio_io::SAMPLE_RATE as f64
+        / sample_rate as f64) as usize;
+    if args.fit_duration {
+        pcm_len += crate::dubbing::MAX_TAIL_STEPS * frame_size
+    }
+    Ok((pcm_len / frame_size, duration, sample_rate))
+}
+
+/// Validates the files used by the generation and prints the plan to stdout.
+pub fn print(args: &crate::gen::Args, dev: &Device) -> Result<()> {
+    let lm_tensors = check_safetensors(&args.lm_model_file)?;
+    let mimi_tensors = check_safetensors(&args.mimi_model_file)?;
+    let text_tokenizer = crate::crypt::open_tokenizer(&args.text_tokenizer)
+        .with_context(|| format!("invalid text tokenizer {:?}", args.text_tokenizer))?;
+    println!("config      {:?}", args.config_file);
+    println!(
+        "lm          {:?} ({}, {lm_tensors})",
+        args.lm_model_file,
+        mb(file_size(&args.lm_model_file)?)
+    );
+    println!(
+        "mimi        {:?} ({}, {mimi_tensors})",
+        args.mimi_model_file,
+        mb(file_size(&args.mimi_model_file)?)
+    );
+    println!("tokenizer   {:?} ({} pieces)", args.text_tokenizer, text_tokenizer.len());
+    println!(
+        "device      {dev:?}, dtype {:?}{}, {} frames per batch",
+        args.dtype,
+        match args.quantize_on_load {
+            Some(qdtype) => format!(" quantized to {qdtype:?}"),
+            None if crate::quantize::is_gguf(&args.lm_model_file) => " with gguf weights".into(),
+            None => String::new(),
+        },
+        args.frames_per_batch
+    );
+
+    let (audio_sampling, text_sampling) = crate::gen::samplings(args);
+    println!("sampling    audio {audio_sampling:?}, text {text_sampling:?}");
+    if let Some(sampling) = crate::gen::boundary_sampling(args) {
+        println!("annealing   audio {sampling:?} for {} steps", args.audio_anneal_window);
+    }
+    if !args.stop_sequences.is_empty() {
+        println!("stop        on {:?}", args.stop_sequences);
+    }
+    if args.text_beams > 1 {
+        println!("beam search {} text beams, one lm state each", args.text_beams);
+    }
+
+    // The daemon and the pipes have no input file, the estimates are then given for the maximum
+    // steps.
+    let input = args.audio_input_file.as_os_str();
+    let steps = if input.is_empty() || input == crate::pipe::STDIO {
+        args.max_steps
+    } else {
+        let (steps, duration, sample_rate) = input_steps(args)?;
+        println!("input       {:?} ({duration:.1}s at {sample_rate}Hz)", args.audio_input_file);
+        if let Some(parent) = args.audio_output_file.parent() {
+            if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                anyhow::bail!("the output directory {parent:?} does not exist")
+            }
+        }
+        let channels = match args.dub_mix {
+            _ if args.stereo_mix => ", source on the left".to_string(),
+            Some(duck_db) => format!(", mixed over the source ducked by {duck_db}dB"),
+            None => String::new(),
+        };
+        match args.output_sample_rate {
+            None => println!("output      {:?}{channels}", args.audio_output_file),
+            Some(sr) => println!("output      {:?} (at {sr}Hz{channels})", args.audio_output_file),
+        }
+        let chunks = crate::longform::chunks(steps, args.max_steps);
+        // The warm-up steps of the chunks are generated twice.
+        let processed: usize = chunks.iter().map(|c| c.end - c.start).sum();
+        if chunks.len() > 1 {
+            println!(
+                "steps       {steps} in {} chunks, {processed} with the overlaps",
+                chunks.len()
+            );
+        } else if steps > args.max_steps {
+            println!("steps       {} (truncated from {steps})", args.max_steps);
+        } else {
+            println!("steps       {steps}")
+        }
+        processed
+    };
+
+    let cfg = args.cfg_alpha.is_some_and(|v| v != 1.);
+    let weights_bytes =
+        crate::resources::lm_weights_bytes(&args.lm_model_file, args.dtype, args.quantize_on_load)?;
+    let kv_cache_bytes = crate::resources::kv_cache_bytes(
+        &args.lm_config,
+        crate::resources::cache_len(args.max_steps),
+        crate::resources::kv_cache_dtype(args.dtype, crate::quantize::lm_is_quantized(args)),
+        if cfg { 2 } else { 1 },
+    ) * args.text_beams.max(1);
+    let required = weights_bytes + kv_cache_bytes;
+    let available = match crate::resources::available_memory(dev) {
+        None => "unknown available".to_string(),
+        Some(available) if available < required => {
+            format!("{} available, DOES NOT FIT", mb(available))
+        }
+        Some(available) => format!("{} available", mb(available)),
+    };
+    println!(
+        "memory      {} weights + {} kv-cache = {}, {available}",
+        mb(weights_bytes),
+        mb(kv_cache_bytes),
+        mb(required)
+    );
+
+    match crate::calibrate::cached(&args.lm_model_file, args.quantize_on_load, dev)? {
+        None => println!("runtime     unknown, no calibration for this device and model"),
+        Some((_, ms_per_step)) => {
+            let secs = steps as f64 * args.num_takes.max(1) as f64 * ms_per_step / 1000.;
+            let realtime = steps as f64 * crate::codec::frame_size() as f64
+                / crate::audio_io::SAMPLE_RATE as f64;
+            println!(
+                "runtime     ~{secs:.0}s for {} takes ({ms_per_step:.1}ms per step, {:.2}x real-time)",
+                args.num_takes.max(1),
+                realtime / (secs / args.num_takes.max(1) as f64)
+            )
+        }
+    }
+    Ok(())
+}
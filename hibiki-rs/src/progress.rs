@@ -0,0 +1,57 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Progress of long offline generations, reported after each batch of frames and shown as a
+// progress bar on stderr with `--progress`.
+
+/// The steps include the warm-up steps of the long inputs and the takes after the first one.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub steps: usize,
+    pub total_steps: usize,
+    pub elapsed: std::time::Duration,
+}
+
+impl Progress {
+    pub fn steps_per_sec(&self) -> f64 {
+        self.steps as f64 / self.elapsed.as_secs_f64().max(1e-3)
+    }
+
+    /// The remaining time at the current speed.
+    pub fn eta(&self) -> Option<std::time::Duration> {
+        if self.steps == 0 {
+            return None;
+        }
+        let remaining = self.total_steps.saturating_sub(self.steps) as f64;
+        Some(std::time::Duration::from_secs_f64(remaining / self.steps_per_sec()))
+    }
+}
+
+/// A progress bar on stderr updated with the reported progress.
+pub struct Bar(indicatif::ProgressBar);
+
+impl Bar {
+    pub fn stderr() -> Self {
+        let style = indicatif::ProgressStyle::with_template("{wide_bar} {pos}/{len} steps, {msg}")
+            .expect("invalid progress template");
+        Self(indicatif::ProgressBar::new(0).with_style(style))
+    }
+
+    pub fn update(&self, progress: Progress) {
+        let eta = match progress.eta() {
+            None => String::new(),
+            Some(eta) => {
+                let secs = eta.as_secs();
+                format!(", eta {:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+            }
+        };
+        self.0.set_length(progress.total_steps as u64);
+        self.0.set_position(progress.steps as u64);
+        self.0.set_message(format!("{:.1} steps/s{eta}", progress.steps_per_sec()));
+    }
+
+    pub fn finish(&self) {
+        self.0.finish_and_clear()
+    }
+}
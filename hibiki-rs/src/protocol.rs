@@ -0,0 +1,284 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Messages of the streaming text protocol. The text is sent as numbered segments that stay
+// tentative until committed, a revision retracts the tentative segments from a given index and
+// the replacement segments follow. The current models never revise their output, but future
+// models or post-processors can do so without changing the protocol, and clients can render the
+// committed text as stable from the start. Live sessions also send periodic lag estimates so that
+// frontends can tell the listeners how far behind the speaker the translation is. Failures are
+// sent as typed error events before the stream ends, and the recoverable issues as warnings, so
+// that clients can show an actionable message.
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextMessage {
+    /// A new tentative segment, `index` is its position in the whole transcript. The text
+    /// includes the leading whitespace if any so that clients only have to concatenate segments.
+    Text { index: usize, text: String },
+    /// Retracts the tentative segments starting at index `from`.
+    Revise { from: usize },
+    /// The segments before index `upto` are final and will not be revised anymore.
+    Commit { upto: usize },
+    /// The translation at `output_ms` in the source has been emitted while the source is at
+    /// `input_ms`, `lag_ms` is how far behind the speaker the listeners are, including the
+    /// processing lag.
+    Lag { input_ms: u64, output_ms: u64, lag_ms: u64 },
+    /// The stream ends because of this failure.
+    Error { code: ErrorCode, message: String },
+    /// The stream continues but the listeners may notice the issue.
+    Warning { code: ErrorCode, message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The capture device cannot be opened or stopped delivering audio.
+    DeviceLost,
+    OutOfMemory,
+    /// The processing is slower than real-time and the translation falls behind the speaker.
+    StepDeadlineMissed,
+    /// The input audio cannot be decoded.
+    UnsupportedInput,
+    Internal,
+}
+
+impl ErrorCode {
+    /// The code for an error, the codes can be attached to the errors as a context.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(code) = err.downcast_ref::<Self>() {
+            *code
+        } else if crate::resources::is_out_of_memory(err) {
+            Self::OutOfMemory
+        } else if err.downcast_ref::<crate::quiet::Failure>() == Some(&crate::quiet::Failure::Input)
+        {
+            Self::UnsupportedInput
+        } else {
+            Self::Internal
+        }
+    }
+
+    /// The name of the code, as serialized in the messages.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::DeviceLost => "device_lost",
+            Self::OutOfMemory => "out_of_memory",
+            Self::StepDeadlineMissed => "step_deadline_missed",
+            Self::UnsupportedInput => "unsupported_input",
+            Self::Internal => "internal",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DeviceLost => write!(f, "the capture device is not available"),
+            Self::OutOfMemory => write!(f, "out of memory"),
+            Self::StepDeadlineMissed => write!(f, "the processing is slower than real-time"),
+            Self::UnsupportedInput => write!(f, "unsupported input"),
+            Self::Internal => write!(f, "internal error"),
+        }
+    }
+}
+
+impl TextMessage {
+    /// The lag message for the current estimate of the interpretation lag, `processing_lag` is
+    /// how far the generation is behind real-time.
+    pub fn lag(
+        lag: &crate::realtime::InterpretationLag,
+        processing_lag: std::time::Duration,
+    ) -> Self {
+        let (input, output) = lag.positions();
+        let input_ms = (input * 1000.) as u64;
+        let output_ms = (output * 1000.) as u64;
+        let lag_ms = input_ms - output_ms.min(input_ms) + processing_lag.as_millis() as u64;
+        Self::Lag { input_ms, output_ms, lag_ms }
+    }
+}
+
+/// The sender side of the protocol, this keeps track of the tentative segments and emits the
+/// corresponding messages.
+#[derive(Debug, Clone)]
+pub struct Hypothesis {
+    segments: Vec<String>,
+    committed: usize,
+    max_tentative: usize,
+}
+
+impl Hypothesis {
+    /// Segments are committed once more than `max_tentative` segments follow them.
+    pub fn new(max_tentative: usize) -> Self {
+        Self { segments: vec![], committed: 0, max_tentative }
+    }
+
+    fn maybe_commit(&mut self, msgs: &mut Vec<TextMessage>) {
+        if self.segments.len() > self.committed + self.max_tentative {
+            self.committed = self.segments.len() - self.max_tentative;
+            msgs.push(TextMessage::Commit { upto: self.committed })
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> Vec<TextMessage> {
+        let mut msgs = vec![TextMessage::Text { index: self.segments.len(), text: text.into() }];
+        self.segments.push(text.into());
+        self.maybe_commit(&mut msgs);
+        msgs
+    }
+
+    /// Replaces the segments starting at index `from` with `texts`, committed segments cannot be
+    /// revised. The current models never revise their output, this is only exercised by the tests.
+    #[cfg(test)]
+    pub fn revise(&mut self, from: usize, texts: &[&str]) -> anyhow::Result<Vec<TextMessage>> {
+        if from < self.committed || from > self.segments.len() {
+            anyhow::bail!(
+                "cannot revise from segment {from}, {} segments out of {} are committed",
+                self.committed,
+                self.segments.len()
+            )
+        }
+        self.segments.truncate(from);
+        let mut msgs = vec![TextMessage::Revise { from }];
+        for text in texts.iter() {
+            msgs.push(TextMessage::Text { index: self.segments.len(), text: text.to_string() });
+            self.segments.push(text.to_string());
+        }
+        self.maybe_commit(&mut msgs);
+        Ok(msgs)
+    }
+
+    /// Commits all the segments, e.g. at the end of a stream.
+    pub fn commit(&mut self) -> Option<TextMessage> {
+        if self.committed == self.segments.len() {
+            return None;
+        }
+        self.committed = self.segments.len();
+        Some(TextMessage::Commit { upto: self.committed })
+    }
+
+    #[cfg(test)]
+    pub fn committed_text(&self) -> String {
+        self.segments[..self.committed].concat()
+    }
+
+    #[cfg(test)]
+    pub fn tentative_text(&self) -> String {
+        self.segments[self.committed..].concat()
+    }
+}
+
+/// The receiver side of the protocol, this applies the messages to rebuild the text as clients
+/// do, to check the messages of the sender side.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    segments: Vec<String>,
+    committed: usize,
+    error: Option<(ErrorCode, String)>,
+}
+
+#[cfg(test)]
+impl Transcript {
+    pub fn apply(&mut self, msg: &TextMessage) -> anyhow::Result<()> {
+        match msg {
+            TextMessage::Text { index, text } => {
+                if *index != self.segments.len() {
+                    anyhow::bail!("unexpected segment {index}, expected {}", self.segments.len())
+                }
+                self.segments.push(text.clone())
+            }
+            TextMessage::Revise { from } => {
+                if *from < self.committed {
+                    anyhow::bail!("revision from {from} of committed segments")
+                }
+                self.segments.truncate(*from)
+            }
+            TextMessage::Commit { upto } => {
+                if *upto > self.segments.len() {
+                    anyhow::bail!("commit up to {upto} of missing segments")
+                }
+                self.committed = self.committed.max(*upto)
+            }
+            TextMessage::Error { code, message } => self.error = Some((*code, message.clone())),
+            TextMessage::Lag { .. } | TextMessage::Warning { .. } => {}
+        }
+        Ok(())
+    }
+
+    pub fn committed_text(&self) -> String {
+        self.segments[..self.committed].concat()
+    }
+
+    pub fn tentative_text(&self) -> String {
+        self.segments[self.committed..].concat()
+    }
+
+    /// The failure that ended the stream, if any.
+    pub fn error(&self) -> Option<&(ErrorCode, String)> {
+        self.error.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hypothesis_commits_old_segments() {
+        let mut hypothesis = Hypothesis::new(2);
+        let mut msgs = vec![];
+        for text in ["Hello", " my", " dear", " friends"] {
+            msgs.extend(hypothesis.push(text))
+        }
+        assert_eq!(msgs[3], TextMessage::Commit { upto: 1 });
+        assert_eq!(msgs.last(), Some(&TextMessage::Commit { upto: 2 }));
+        assert_eq!(hypothesis.committed_text(), "Hello my");
+        assert_eq!(hypothesis.tentative_text(), " dear friends");
+        assert_eq!(hypothesis.commit(), Some(TextMessage::Commit { upto: 4 }));
+        assert_eq!(hypothesis.commit(), None);
+    }
+
+    #[test]
+    fn transcript_follows_hypothesis() {
+        let mut hypothesis = Hypothesis::new(3);
+        let mut transcript = Transcript::default();
+        let mut apply = |msgs: Vec<TextMessage>| {
+            for msg in msgs.iter() {
+                // The messages go through json as on the wire.
+                let msg = serde_json::from_str(&serde_json::to_string(msg).unwrap()).unwrap();
+                transcript.apply(&msg).unwrap()
+            }
+        };
+        apply(hypothesis.push("Good"));
+        apply(hypothesis.push(" morning"));
+        apply(hypothesis.revise(1, &[" evening", " everyone"]).unwrap());
+        apply(hypothesis.commit().into_iter().collect());
+        assert_eq!(transcript.committed_text(), "Good evening everyone");
+        assert_eq!(transcript.tentative_text(), "");
+        // Committed segments cannot be revised anymore.
+        assert!(hypothesis.revise(1, &[" night"]).is_err());
+        assert!(transcript.apply(&TextMessage::Revise { from: 1 }).is_err());
+    }
+
+    #[test]
+    fn transcript_rejects_gaps() {
+        let mut transcript = Transcript::default();
+        let msg = TextMessage::Text { index: 1, text: "skipped".into() };
+        assert!(transcript.apply(&msg).is_err());
+        assert!(transcript.apply(&TextMessage::Commit { upto: 1 }).is_err());
+        let code = ErrorCode::DeviceLost;
+        transcript.apply(&TextMessage::Error { code, message: "unplugged".into() }).unwrap();
+        assert_eq!(transcript.error().map(|v| v.0), Some(ErrorCode::DeviceLost));
+    }
+
+    #[test]
+    fn message_format() {
+        let msg = TextMessage::Lag { input_ms: 2000, output_ms: 1500, lag_ms: 600 };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(json, r#"{"type":"lag","input_ms":2000,"output_ms":1500,"lag_ms":600}"#);
+        let msg = TextMessage::Warning { code: ErrorCode::StepDeadlineMissed, message: "".into() };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""code":"step_deadline_missed""#), "{json}");
+    }
+}
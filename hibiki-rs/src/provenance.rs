@@ -0,0 +1,61 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Provenance of the generated outputs: the hashes of the checkpoints, the model config and the
+// sampling parameters, embedded in the sidecar files so that results can be reproduced and
+// audited long after they have been generated.
+
+use anyhow::Result;
+use std::io::Read;
+
+fn sha256(path: &std::path::Path) -> Result<String> {
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        ctx.update(&buf[..n])
+    }
+    Ok(ctx.finish().as_ref().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn checkpoint(path: &std::path::Path) -> Result<serde_json::Value> {
+    let name = path.file_name().map_or_else(String::new, |v| v.to_string_lossy().into());
+    Ok(serde_json::json!({ "file": name, "sha256": sha256(path)? }))
+}
+
+/// Builds the provenance record for a generation, this hashes the checkpoints so it is only
+/// computed when a sidecar file is written.
+pub fn provenance(args: &crate::gen::Args) -> Result<serde_json::Value> {
+    tracing::info!("hashing the checkpoints for the provenance record");
+    let config: toml::Value = toml::from_str(&std::fs::read_to_string(&args.config_file)?)?;
+    let (audio_sampling, text_sampling) = crate::gen::samplings(args);
+    Ok(serde_json::json!({
+        "hibiki_version": env!("CARGO_PKG_VERSION"),
+        "lm_model": checkpoint(&args.lm_model_file)?,
+        "mimi_model": checkpoint(&args.mimi_model_file)?,
+        "text_tokenizer": checkpoint(&args.text_tokenizer)?,
+        "config": config,
+        "sampling": {
+            "seed": args.seed,
+            "audio": format!("{audio_sampling:?}"),
+            "text": format!("{text_sampling:?}"),
+            "audio_boundary": crate::gen::boundary_sampling(args).map(|v| format!("{v:?}")),
+            "audio_anneal_window": args.audio_anneal_window,
+            "cfg_alpha": args.cfg_alpha,
+            "pad_bias": args.pad_bias,
+            "condition_mix": args.condition_mix,
+            "keep_text": args.keep_text,
+            "num_takes": args.num_takes,
+        },
+        "dtype": format!("{:?}", args.dtype),
+        "quantize_on_load": args.quantize_on_load.map(|v| format!("{v:?}")),
+        "frames_per_batch": args.frames_per_batch,
+        "max_steps": args.max_steps,
+        "skip_silent_depformer": args.skip_silent_depformer,
+    }))
+}
@@ -0,0 +1,119 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Weight-only quantization of the lm when loading a safetensors file, so that no pre-converted
+// gguf file is needed. Pre-quantized gguf files can also be used for the lm, the audio codec is
+// always run in full precision and a quantized codec file is dequantized when loading it.
+
+use anyhow::Result;
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{DType, Device};
+
+/// Whether a weight file holds quantized weights, based on its extension. This is case sensitive
+/// as in the moshi loader, so that the lm file is always read the way it was checked here.
+pub fn is_gguf(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|v| v == "gguf")
+}
+
+/// Whether the lm runs with quantized weights, either from a gguf file or quantized on load.
+pub fn lm_is_quantized(args: &crate::gen::Args) -> bool {
+    args.quantize_on_load.is_some() || is_gguf(&args.lm_model_file)
+}
+
+// The quantization is done on the cpu, one tensor at a time to limit the peak memory.
+fn quantize(tensor: &candle::Tensor, qdtype: GgmlDType) -> Result<QTensor> {
+    let tensor = tensor.to_dtype(DType::F32)?;
+    let quantize = tensor.rank() == 2 && tensor.dim(1)? % qdtype.block_size() == 0;
+    let qdtype = if quantize { qdtype } else { GgmlDType::F32 };
+    Ok(QTensor::quantize(&tensor, qdtype)?)
+}
+
+// Quantizes the matrices of a safetensors file to `qdtype` and returns them as gguf data. The
+// other tensors, e.g. the norms, are kept in f32.
+fn quantized_gguf(model_file: &std::path::Path, qdtype: GgmlDType) -> Result<Vec<u8>> {
+    let mut tensors = vec![];
+    if crate::crypt::is_encrypted(model_file)? {
+        let st = candle::safetensors::BufferedSafetensors::new(crate::crypt::decrypt(model_file)?)?;
+        for (name, _) in st.tensors() {
+            let tensor = st.load(&name, &Device::Cpu)?;
+            tensors.push((name, quantize(&tensor, qdtype)?));
+        }
+    } else {
+        let st = unsafe { candle::safetensors::MmapedSafetensors::new(model_file)? };
+        for (name, _) in st.tensors() {
+            let tensor = st.load(&name, &Device::Cpu)?;
+            tensors.push((name, quantize(&tensor, qdtype)?));
+        }
+    }
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    let refs: Vec<_> = tensors.iter().map(|(name, t)| (name.as_str(), t)).collect();
+    candle::quantized::gguf_file::write(&mut buffer, &[], &refs)?;
+    Ok(buffer.into_inner())
+}
+
+/// Loads the lm from a safetensors file, quantizing the matrices to `qdtype`.
+pub fn load_lm_model(
+    cfg: moshi::lm::Config,
+    model_file: &std::path::Path,
+    qdtype: GgmlDType,
+    dev: &Device,
+) -> Result<moshi::lm::LmModel> {
+    let start_time = std::time::Instant::now();
+    // The quantized var builder can only be created from gguf data, the tensors are serialized
+    // to an in-memory gguf buffer first.
+    let buffer = quantized_gguf(model_file, qdtype)?;
+    let vb =
+        candle_transformers::quantized_var_builder::VarBuilder::from_gguf_buffer(&buffer, dev)?;
+    drop(buffer);
+    let model = moshi::lm::LmModel::new(&cfg, moshi::nn::MaybeQuantizedVarBuilder::Quantized(vb))?;
+    tracing::info!(?qdtype, "quantized the lm in {:.2}s", start_time.elapsed().as_secs_f32());
+    Ok(model)
+}
+
+/// Writes a quantized gguf copy of a safetensors lm file, to be loaded with --quantized.
+pub fn write_gguf(
+    input: &std::path::Path,
+    output: &std::path::Path,
+    qdtype: GgmlDType,
+) -> Result<()> {
+    let buffer = quantized_gguf(input, qdtype)?;
+    std::fs::write(output, buffer)?;
+    tracing::info!(?input, ?output, ?qdtype, "wrote the quantized model");
+    Ok(())
+}
+
+/// A full precision var builder for a gguf file, the tensors are dequantized to f32. This is
+/// used for the audio codec whose quality degrades too much with quantized weights.
+pub fn dequantized_var_builder(
+    model_file: &std::path::Path,
+    dev: &Device,
+) -> Result<candle_nn::VarBuilder<'static>> {
+    let data = if crate::crypt::is_encrypted(model_file)? {
+        crate::crypt::decrypt(model_file)?
+    } else {
+        std::fs::read(model_file)?
+    };
+    let mut reader = std::io::Cursor::new(data);
+    let content = candle::quantized::gguf_file::Content::read(&mut reader)?;
+    let mut tensors = std::collections::HashMap::new();
+    for name in content.tensor_infos.keys() {
+        let tensor = content.tensor(&mut reader, name, &Device::Cpu)?;
+        tensors.insert(name.clone(), tensor.dequantize(&Device::Cpu)?.to_device(dev)?);
+    }
+    Ok(candle_nn::VarBuilder::from_tensors(tensors, DType::F32, dev))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gguf_extension() {
+        assert!(is_gguf(std::path::Path::new("lm.q8.gguf")));
+        // moshi loads these as safetensors.
+        assert!(!is_gguf(std::path::Path::new("lm.GGUF")));
+        assert!(!is_gguf(std::path::Path::new("lm.safetensors")));
+        assert!(!is_gguf(std::path::Path::new("gguf")));
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Single-shot mode for scripts, e.g. Apple Shortcuts, Hazel or Makefiles. Nothing is logged and
+// a single json line is printed once the generation is over, with the status and the paths of
+// the outputs. The exit codes are stable:
+//   0 the translation was written, including translation memory hits
+//   1 the generation failed
+//   2 the command line or the configuration is invalid, as for the clap errors
+//   3 the input cannot be read
+//   4 the models cannot be downloaded or loaded
+//   130 the generation was interrupted, the outputs only cover part of the input
+
+use anyhow::Result;
+
+/// The failures that have their own exit code, attached as a context to the errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Usage,
+    Input,
+    Models,
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::Usage => 2,
+            Self::Input => 3,
+            Self::Models => 4,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Usage => write!(f, "invalid arguments"),
+            Self::Input => write!(f, "cannot read the input"),
+            Self::Models => write!(f, "cannot load the models"),
+        }
+    }
+}
+
+/// Marks the errors that do not have a more specific failure as usage errors, e.g. for the ones
+/// returned while resolving the arguments.
+pub fn or_usage(err: anyhow::Error) -> anyhow::Error {
+    if err.downcast_ref::<Failure>().is_some() {
+        err
+    } else {
+        err.context(Failure::Usage)
+    }
+}
+
+fn outputs(args: &crate::gen::Args) -> serde_json::Value {
+    let num_takes = args.num_takes.max(1);
+    let paths = |path: &std::path::Path| -> Vec<_> {
+        (0..num_takes).map(|take| crate::gen::take_path(path, take, num_takes)).collect()
+    };
+    let mut outputs = serde_json::Map::new();
+    outputs.insert("audio".to_string(), serde_json::json!(paths(&args.audio_output_file)));
+    let optional = [
+        ("transcript", &args.transcript_file),
+        ("subtitles", &args.subtitles),
+        ("cue_stats", &args.cue_stats),
+        ("chapters", &args.chapters),
+        ("clip_markers", &args.clip_markers),
+        ("word_alignment", &args.word_alignment),
+        ("json_output", &args.json_output),
+        ("token_ids", &args.emit_token_ids),
+        ("trace", &args.trace),
+    ];
+    for (name, path) in optional {
+        if let Some(path) = path.as_ref() {
+            outputs.insert(name.to_string(), serde_json::json!(paths(path)));
+        }
+    }
+    serde_json::Value::Object(outputs)
+}
+
+/// Prints the status line for the outcome of a generation and returns the exit code.
+pub fn report(res: Result<(crate::gen::Args, crate::gen::Summary)>) -> i32 {
+    let (line, exit_code) = match res {
+        Ok((args, summary)) => {
+            let line = serde_json::json!({
+                "status": "ok",
+                "cancelled": summary.cancelled,
+                "input": args.audio_input_file,
+                "outputs": outputs(&args),
+                "steps": summary.steps,
+                "text_tokens": summary.text_tokens,
+                "elapsed_s": summary.elapsed.as_secs_f64(),
+                "stats": summary.stats,
+            });
+            (line, if summary.cancelled { 130 } else { 0 })
+        }
+        Err(err) => {
+            let exit_code = err.downcast_ref::<Failure>().map_or(1, Failure::exit_code);
+            let line = serde_json::json!({
+                "status": "error",
+                "exit_code": exit_code,
+                "error": format!("{err:#}"),
+            });
+            (line, exit_code)
+        }
+    };
+    println!("{line}");
+    exit_code
+}
@@ -0,0 +1,241 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Tracking of the processing time against the real-time budget of one frame per step, and of
+// how far behind the speaker the translation is. Under load, `LoadShedder` decides when the
+// quality should be reduced so that the processing keeps up.
+
+use std::time::Duration;
+
+/// A batch of steps that took longer than the audio duration it covers.
+#[derive(Debug, Clone, Copy)]
+pub struct Breach {
+    pub step_idx: usize,
+    pub elapsed: Duration,
+    pub budget: Duration,
+    /// How far behind real-time the processing is, accumulated since the start.
+    pub lag: Duration,
+}
+
+#[derive(Debug)]
+pub struct LagMonitor {
+    step_duration: Duration,
+    lag: Duration,
+    peak_lag: Duration,
+    num_breaches: usize,
+}
+
+impl LagMonitor {
+    pub fn new(step_duration: Duration) -> Self {
+        Self { step_duration, lag: Duration::ZERO, peak_lag: Duration::ZERO, num_breaches: 0 }
+    }
+
+    /// Records the processing of `num_steps` steps starting at `step_idx`. Time spent under the
+    /// budget reduces the accumulated lag so that short hiccups are absorbed.
+    pub fn record(
+        &mut self,
+        step_idx: usize,
+        num_steps: usize,
+        elapsed: Duration,
+    ) -> Option<Breach> {
+        let budget = self.step_duration * num_steps as u32;
+        if elapsed <= budget {
+            self.lag = self.lag.saturating_sub(budget - elapsed);
+            return None;
+        }
+        self.lag += elapsed - budget;
+        self.peak_lag = self.peak_lag.max(self.lag);
+        self.num_breaches += 1;
+        Some(Breach { step_idx, elapsed, budget, lag: self.lag })
+    }
+
+    pub fn lag(&self) -> Duration {
+        self.lag
+    }
+
+    /// The largest accumulated lag seen so far.
+    pub fn peak_lag(&self) -> Duration {
+        self.peak_lag
+    }
+
+    pub fn num_breaches(&self) -> usize {
+        self.num_breaches
+    }
+}
+
+// The quality is reduced once the average step latency is above this fraction of the budget,
+// and restored once it is below the lower one for long enough. The average covers ~2s of steps.
+const DEGRADE_RATIO: f64 = 0.9;
+const RESTORE_RATIO: f64 = 0.6;
+const LOAD_AVERAGE_STEPS: f64 = 25.;
+// The quality is kept reduced for at least 10s so that it does not flip on every hiccup.
+const MIN_DEGRADED_STEPS: usize = 125;
+
+/// Decides when to reduce the cost of the steps from their latency: the steps are degraded when
+/// their average latency approaches the real-time budget, and restored once the load drops.
+#[derive(Debug)]
+pub struct LoadShedder {
+    budget_ms: f64,
+    average_ms: f64,
+    degraded_steps: Option<usize>,
+}
+
+impl LoadShedder {
+    pub fn new(step_duration: Duration) -> Self {
+        let budget_ms = step_duration.as_secs_f64() * 1000.;
+        Self { budget_ms, average_ms: 0., degraded_steps: None }
+    }
+
+    /// Records the latency of a step, returns the new state when the quality should change:
+    /// `Some(true)` to degrade it and `Some(false)` to restore it.
+    pub fn record(&mut self, elapsed: Duration) -> Option<bool> {
+        let ms = elapsed.as_secs_f64() * 1000.;
+        self.average_ms += (ms - self.average_ms) / LOAD_AVERAGE_STEPS;
+        let ratio = self.average_ms / self.budget_ms;
+        match self.degraded_steps.as_mut() {
+            None if ratio >= DEGRADE_RATIO => {
+                self.degraded_steps = Some(0);
+                Some(true)
+            }
+            None => None,
+            Some(steps) => {
+                *steps += 1;
+                if *steps >= MIN_DEGRADED_STEPS && ratio < RESTORE_RATIO {
+                    self.degraded_steps = None;
+                    Some(false)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded_steps.is_some()
+    }
+}
+
+// Frames above this level are counted as speech.
+const SPEECH_DB: f32 = -50.;
+// When both sides have been silent for this many steps, i.e. 2s, the translation is considered to
+// have caught up with the source.
+const CAUGHT_UP_STEPS: usize = 25;
+// When the output audio is not decoded, the translation is taken to be speaking until this many
+// steps after its last text token, i.e. 400ms.
+const TEXT_SPEECH_STEPS: usize = 5;
+
+/// Estimate of the interpretation lag, the translation of a stretch of speech is expected to be
+/// about as long as the source. The output position is the point in the input where as much
+/// speech had been captured as the translation produced so far, the translation then speaks
+/// what the speaker said at that point. The estimate is resynchronized on the pauses so that the
+/// difference of speech rate between the languages does not accumulate.
+#[derive(Debug)]
+pub struct InterpretationLag {
+    step_duration: f64,
+    // Number of input steps with speech up to each step.
+    input_speech: Vec<usize>,
+    output_speech: usize,
+    silent_steps: usize,
+    steps_since_text: usize,
+}
+
+impl InterpretationLag {
+    pub fn new(step_duration: f64) -> Self {
+        Self {
+            step_duration,
+            input_speech: vec![],
+            output_speech: 0,
+            silent_steps: 0,
+            steps_since_text: usize::MAX,
+        }
+    }
+
+    /// Records a step with its input frame and the output audio if any.
+    pub fn record(&mut self, input_pcm: &[f32], output_pcm: Option<&[f32]>) {
+        let output_is_speech =
+            output_pcm.is_some_and(|pcm| crate::events::frame_features(pcm).db >= SPEECH_DB);
+        self.record_speech(input_pcm, output_is_speech)
+    }
+
+    /// Records a step whose output audio is not decoded, whether the translation speaks is then
+    /// derived from the timing of its text.
+    pub fn record_text(&mut self, input_pcm: &[f32], has_text: bool) {
+        self.steps_since_text = if has_text { 0 } else { self.steps_since_text.saturating_add(1) };
+        self.record_speech(input_pcm, self.steps_since_text < TEXT_SPEECH_STEPS)
+    }
+
+    fn record_speech(&mut self, input_pcm: &[f32], output_is_speech: bool) {
+        let input_is_speech = crate::events::frame_features(input_pcm).db >= SPEECH_DB;
+        let input_speech =
+            self.input_speech.last().copied().unwrap_or(0) + input_is_speech as usize;
+        self.input_speech.push(input_speech);
+        self.output_speech += output_is_speech as usize;
+        self.silent_steps =
+            if input_is_speech || output_is_speech { 0 } else { self.silent_steps + 1 };
+        if self.silent_steps >= CAUGHT_UP_STEPS {
+            self.output_speech = self.output_speech.max(input_speech)
+        }
+    }
+
+    /// The current input and output positions in seconds.
+    pub fn positions(&self) -> (f64, f64) {
+        let input_steps = self.input_speech.len();
+        let output_steps = match self.input_speech.last() {
+            Some(&speech) if self.output_speech < speech => {
+                self.input_speech.partition_point(|&v| v <= self.output_speech)
+            }
+            _ => input_steps,
+        };
+        (input_steps as f64 * self.step_duration, output_steps as f64 * self.step_duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_shedder_degrades_and_restores() {
+        let ms = Duration::from_millis;
+        let mut shedder = LoadShedder::new(ms(80));
+        // Short spikes are absorbed by the average.
+        assert_eq!(shedder.record(ms(200)), None);
+        for _ in 0..30 {
+            assert_eq!(shedder.record(ms(40)), None)
+        }
+        let steps = (0..100).position(|_| shedder.record(ms(78)).is_some());
+        assert!(steps.is_some_and(|v| v > 10), "{steps:?}");
+        assert!(shedder.is_degraded());
+        // The quality is kept reduced for a while even when the load drops right away.
+        let steps = (0..1000).position(|_| shedder.record(ms(20)).is_some());
+        assert_eq!(steps, Some(MIN_DEGRADED_STEPS - 1));
+        assert!(!shedder.is_degraded());
+    }
+
+    #[test]
+    fn interpretation_lag_from_the_text() {
+        let speech = vec![0.5f32; 1920];
+        let silence = vec![0f32; 1920];
+        let mut lag = InterpretationLag::new(0.08);
+        // The translation starts speaking 12 steps after the speaker, with a word every 3 steps.
+        for step in 0..50 {
+            lag.record_text(&speech, step >= 12 && step % 3 == 0)
+        }
+        let (input, output) = lag.positions();
+        assert!((input - 4.).abs() < 1e-9);
+        assert!((input - output - 0.96).abs() < 1e-9, "{input} {output}");
+        // Without any text the translation falls behind, until both sides pause.
+        let mut lag = InterpretationLag::new(0.08);
+        for _ in 0..25 {
+            lag.record_text(&speech, false)
+        }
+        let (input, output) = lag.positions();
+        assert!((input - 2.).abs() < 1e-9 && output == 0., "{input} {output}");
+        for _ in 0..CAUGHT_UP_STEPS {
+            lag.record_text(&silence, false)
+        }
+        let (input, output) = lag.positions();
+        assert!((input - output).abs() < 1e-9, "{input} {output}");
+    }
+}
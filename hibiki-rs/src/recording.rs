@@ -0,0 +1,140 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Recordings of the messages received by the serve sessions, so that the issues reported in live
+// use can be reproduced. The file starts with a header line and the path of the request, with its
+// query parameters, followed by the messages: the microseconds since the start of the session
+// as a u64, the kind of message as a byte (1 for text, 2 for binary), the length of the payload
+// as a u32, all little-endian, and the payload.
+
+use anyhow::{Context, Result};
+use std::io::{BufRead, Read, Write};
+
+use crate::websocket::Message;
+
+const HEADER: &str = "hibiki-recording 1";
+const KIND_TEXT: u8 = 1;
+const KIND_BINARY: u8 = 2;
+
+/// Appends the messages of a session to a file as they are received.
+pub struct Recorder {
+    file: std::io::BufWriter<std::fs::File>,
+    start: std::time::Instant,
+}
+
+impl Recorder {
+    /// Creates the recording of a session for a request to `path` in `dir`, the files are named
+    /// after the time at which the sessions start.
+    pub fn create(dir: &std::path::Path, path: &str) -> Result<(Self, std::path::PathBuf)> {
+        std::fs::create_dir_all(dir)?;
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let file_path = dir.join(format!("session-{}.rec", now.as_millis()));
+        let file = std::fs::File::create_new(&file_path)
+            .with_context(|| format!("cannot create {file_path:?}"))?;
+        let mut file = std::io::BufWriter::new(file);
+        writeln!(file, "{HEADER}\n{path}")?;
+        file.flush()?;
+        Ok((Self { file, start: std::time::Instant::now() }, file_path))
+    }
+
+    /// Appends a message, it is flushed right away so that the recording survives a crash.
+    pub fn record(&mut self, message: &Message) -> Result<()> {
+        let (kind, payload) = match message {
+            Message::Text(text) => (KIND_TEXT, text.as_bytes()),
+            Message::Binary(data) => (KIND_BINARY, data.as_slice()),
+        };
+        let micros = self.start.elapsed().as_micros() as u64;
+        self.file.write_all(&micros.to_le_bytes())?;
+        self.file.write_all(&[kind])?;
+        self.file.write_all(&u32::try_from(payload.len())?.to_le_bytes())?;
+        self.file.write_all(payload)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// A recorded message with the time at which it was received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub at: std::time::Duration,
+    pub message: Message,
+}
+
+/// A recorded session.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    /// The path of the request, with the query parameters selecting the audio format.
+    pub path: String,
+    pub entries: Vec<Entry>,
+}
+
+/// Reads a recording written by `Recorder`, a message truncated by a crash ends it.
+pub fn read(path: &std::path::Path) -> Result<Recording> {
+    let file = std::fs::File::open(path).with_context(|| format!("cannot open {path:?}"))?;
+    let mut file = std::io::BufReader::new(file);
+    let mut line = String::new();
+    file.read_line(&mut line)?;
+    if line.trim_end() != HEADER {
+        anyhow::bail!("{path:?} is not a session recording")
+    }
+    line.clear();
+    file.read_line(&mut line)?;
+    let request_path = line.trim_end().to_string();
+    let mut entries = vec![];
+    loop {
+        let mut header = [0u8; 13];
+        match file.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+        let micros = u64::from_le_bytes(header[..8].try_into()?);
+        let len = u32::from_le_bytes(header[9..].try_into()?) as usize;
+        let mut payload = vec![0u8; len];
+        if let Err(err) = file.read_exact(&mut payload) {
+            tracing::warn!(?path, ?err, "truncated recording");
+            break;
+        }
+        let message = match header[8] {
+            KIND_TEXT => Message::Text(String::from_utf8(payload)?),
+            KIND_BINARY => Message::Binary(payload),
+            kind => anyhow::bail!("unknown message kind {kind} in {path:?}"),
+        };
+        entries.push(Entry { at: std::time::Duration::from_micros(micros), message })
+    }
+    Ok(Recording { path: request_path, entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("hibiki-recording-{}", std::process::id()));
+        let (mut recorder, path) = Recorder::create(&dir, "/?format=s16le&sample_rate=48000")?;
+        let messages = [
+            Message::Binary(vec![]),
+            Message::Text(r#"{"type": "pause"}"#.to_string()),
+            Message::Binary(vec![1, 2, 3, 4]),
+        ];
+        for message in messages.iter() {
+            recorder.record(message)?
+        }
+        drop(recorder);
+        let recording = read(&path)?;
+        assert_eq!(recording.path, "/?format=s16le&sample_rate=48000");
+        let read_messages: Vec<_> = recording.entries.iter().map(|e| e.message.clone()).collect();
+        assert_eq!(read_messages, messages);
+        assert!(recording.entries.windows(2).all(|v| v[0].at <= v[1].at));
+        // A message cut by a crash is dropped, the ones before it are kept.
+        let len = std::fs::metadata(&path)?.len();
+        std::fs::OpenOptions::new().write(true).open(&path)?.set_len(len - 2)?;
+        assert_eq!(read(&path)?.entries.len(), 2);
+        std::fs::OpenOptions::new().write(true).open(&path)?.set_len(len - 18)?;
+        assert_eq!(read(&path)?.entries.len(), 1);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Estimates of the memory required by the generation and of the memory available on the device.
+
+use anyhow::Result;
+use candle::Device;
+
+/// Default maximum number of generation steps, i.e. 200s of audio.
+pub const DEFAULT_MAX_STEPS: usize = 2500;
+// Extra kv-cache positions on top of the maximum number of steps, so that the last batch of
+// frames never overflows the cache.
+const CACHE_HEADROOM: usize = 20;
+
+/// The number of positions to allocate in the kv-cache of the lm for a given number of steps.
+pub fn cache_len(max_steps: usize) -> usize {
+    max_steps + CACHE_HEADROOM
+}
+
+/// The size in bytes of the kv-cache of the main transformer, it is allocated in full on the
+/// first step. The batch size is 2 when using classifier free guidance.
+pub fn kv_cache_bytes(
+    lm_config: &moshi::lm::Config,
+    cache_len: usize,
+    dtype: candle::DType,
+    batch_size: usize,
+) -> usize {
+    let cfg = &lm_config.transformer;
+    let kv_dim = cfg.d_model / cfg.kv_repeat.max(1);
+    // One key and one value tensor per layer.
+    2 * cfg.num_layers * batch_size * cache_len * kv_dim * dtype.size_in_bytes()
+}
+
+/// The dtype of the kv-cache, the activations of the quantized lm are in f32.
+pub fn kv_cache_dtype(dtype: candle::DType, quantized: bool) -> candle::DType {
+    if quantized {
+        candle::DType::F32
+    } else {
+        dtype
+    }
+}
+
+/// Raised when the memory required by the generation is known to exceed the available memory
+/// before allocating it.
+#[derive(Debug)]
+pub struct OutOfMemory(pub String);
+
+impl std::fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+/// Whether the error comes from an allocation failure, either detected upfront or reported by
+/// the cuda or metal backends.
+pub fn is_out_of_memory(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        let msg = err.to_string().to_lowercase();
+        err.is::<OutOfMemory>()
+            || msg.contains("out of memory")
+            || msg.contains("out_of_memory")
+            || msg.contains("failed to allocate")
+    })
+}
+
+// Reads a field given in kB from one of the /proc files.
+fn proc_kb(file: &str, field: &str) -> Option<usize> {
+    let content = std::fs::read_to_string(file).ok()?;
+    let line =
+        content.lines().find(|l| l.strip_prefix(field).is_some_and(|l| l.starts_with(':')))?;
+    let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn cpu_memory(field: &str) -> Option<usize> {
+    proc_kb("/proc/meminfo", field)
+}
+
+/// The resident memory of the process in bytes, `None` if it cannot be determined.
+pub fn process_memory() -> Option<usize> {
+    proc_kb("/proc/self/status", "VmRSS")
+}
+
+/// The peak resident memory of the process in bytes, `None` if it cannot be determined.
+pub fn peak_process_memory() -> Option<usize> {
+    proc_kb("/proc/self/status", "VmHWM")
+}
+
+fn cuda_memory(gpu_id: usize, query: &str) -> Option<usize> {
+    let output = std::process::Command::new("nvidia-smi")
+        .arg(format!("--query-gpu={query}"))
+        .args(["--format=csv,noheader,nounits", "-i"])
+        .arg(gpu_id.to_string())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let mb: usize = String::from_utf8_lossy(&output.stdout).trim().parse().ok()?;
+    Some(mb * 1024 * 1024)
+}
+
+/// The memory currently available on the device in bytes, `None` if it cannot be determined.
+pub fn available_memory(dev: &Device) -> Option<usize> {
+    match dev.location() {
+        candle::DeviceLocation::Cpu => cpu_memory("MemAvailable"),
+        candle::DeviceLocation::Cuda { gpu_id } => cuda_memory(gpu_id, "memory.free"),
+        candle::DeviceLocation::Metal { .. } => None,
+    }
+}
+
+/// The total memory of the device in bytes, `None` if it cannot be determined.
+pub fn total_memory(dev: &Device) -> Option<usize> {
+    match dev.location() {
+        candle::DeviceLocation::Cpu => cpu_memory("MemTotal"),
+        candle::DeviceLocation::Cuda { gpu_id } => cuda_memory(gpu_id, "memory.total"),
+        candle::DeviceLocation::Metal { .. } => None,
+    }
+}
+
+/// Approximate size of the lm weights once loaded with the given dtype, based on the size of the
+/// weight file which is stored in bf16. When quantizing on load, int8 takes about half the
+/// size of bf16 and int4 a quarter. Gguf files are loaded as they are stored.
+pub fn lm_weights_bytes(
+    lm_model_file: &std::path::Path,
+    dtype: candle::DType,
+    quantize_on_load: Option<candle::quantized::GgmlDType>,
+) -> Result<usize> {
+    let file_size = std::fs::metadata(lm_model_file)?.len() as usize;
+    if crate::quantize::is_gguf(lm_model_file) {
+        return Ok(file_size);
+    }
+    match quantize_on_load {
+        Some(qdtype) => Ok(file_size / 2 * qdtype.type_size() / qdtype.block_size()),
+        None => Ok(file_size / 2 * dtype.size_in_bytes()),
+    }
+}
+
+/// The largest number of steps for which the lm weights and kv-cache fit in `budget` bytes.
+pub fn max_steps_for_budget(
+    lm_config: &moshi::lm::Config,
+    weights_bytes: usize,
+    budget: usize,
+    dtype: candle::DType,
+    batch_size: usize,
+) -> Result<usize> {
+    let per_step = kv_cache_bytes(lm_config, 1, dtype, batch_size);
+    let cache_budget = match budget.checked_sub(weights_bytes) {
+        Some(v) if v / per_step > CACHE_HEADROOM => v,
+        _ => anyhow::bail!(
+            "the memory budget of {}MB is too small for the lm weights ({}MB)",
+            budget >> 20,
+            weights_bytes >> 20
+        ),
+    };
+    Ok(cache_budget / per_step - CACHE_HEADROOM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle::quantized::GgmlDType;
+    use candle::DType;
+
+    // 32 layers with 4096 dims, the kv-cache of a step takes 512kB in bf16.
+    const STEP_BYTES: usize = 2 * 32 * 4096 * 2;
+
+    #[test]
+    fn kv_cache_size() {
+        let cfg = moshi::lm::Config::v0_1();
+        assert_eq!(kv_cache_bytes(&cfg, 100, DType::BF16, 1), 100 * STEP_BYTES);
+        // Classifier free guidance doubles the batch, f32 the size of each element.
+        assert_eq!(kv_cache_bytes(&cfg, 100, DType::BF16, 2), 200 * STEP_BYTES);
+        assert_eq!(kv_cache_bytes(&cfg, 100, DType::F32, 1), 200 * STEP_BYTES);
+        let mut gqa = cfg.clone();
+        gqa.transformer.kv_repeat = 4;
+        assert_eq!(kv_cache_bytes(&gqa, 100, DType::BF16, 1), 25 * STEP_BYTES);
+        assert_eq!(kv_cache_dtype(DType::BF16, true), DType::F32);
+        assert_eq!(kv_cache_dtype(DType::BF16, false), DType::BF16);
+    }
+
+    #[test]
+    fn weights_size() {
+        let dir = std::env::temp_dir().join(format!("hibiki-resources-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let safetensors = dir.join("lm.safetensors");
+        let gguf = dir.join("lm.gguf");
+        for path in [&safetensors, &gguf] {
+            std::fs::write(path, vec![0u8; 3200]).unwrap();
+        }
+        let size = |path, dtype, qdtype| lm_weights_bytes(path, dtype, qdtype).unwrap();
+        assert_eq!(size(&safetensors, DType::BF16, None), 3200);
+        assert_eq!(size(&safetensors, DType::F32, None), 6400);
+        // Blocks of 32 weights take 34 bytes in q8_0 and 18 bytes in q4_0.
+        assert_eq!(size(&safetensors, DType::BF16, Some(GgmlDType::Q8_0)), 1700);
+        assert_eq!(size(&safetensors, DType::BF16, Some(GgmlDType::Q4_0)), 900);
+        assert_eq!(size(&gguf, DType::F32, Some(GgmlDType::Q4_0)), 3200);
+        assert!(lm_weights_bytes(&dir.join("missing.safetensors"), DType::BF16, None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn steps_for_budget() {
+        let cfg = moshi::lm::Config::v0_1();
+        let weights = 1 << 30;
+        let steps = |budget, dtype, batch_size| {
+            max_steps_for_budget(&cfg, weights, budget, dtype, batch_size)
+        };
+        // The headroom positions come out of the budget.
+        assert_eq!(steps(weights + 100 * STEP_BYTES, DType::BF16, 1).unwrap(), 80);
+        assert_eq!(steps(weights + 100 * STEP_BYTES + 1, DType::BF16, 1).unwrap(), 80);
+        assert_eq!(steps(weights + 100 * STEP_BYTES, DType::BF16, 2).unwrap(), 30);
+        // The quantized lm has its kv-cache in f32.
+        assert_eq!(steps(weights + 100 * STEP_BYTES, DType::F32, 1).unwrap(), 30);
+        assert_eq!(steps(weights + 21 * STEP_BYTES, DType::BF16, 1).unwrap(), 1);
+        // Nothing left after the weights and the headroom.
+        assert!(steps(weights + 20 * STEP_BYTES, DType::BF16, 1).is_err());
+        assert!(steps(weights / 2, DType::BF16, 1).is_err());
+        assert!(steps(weights + 40 * STEP_BYTES, DType::BF16, 2).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Multi-gpu deployment of the WebSocket server: a router process accepts the client connections
+// and forwards each of them to one of N `serve` worker processes, each owning one gpu through
+// CUDA_VISIBLE_DEVICES and listening on a local port. A connection stays on the worker that
+// accepted it for its whole duration, the workers refuse new sessions with a 503 while they are
+// busy and the router then tries the next one. The workers that exit are restarted, and the
+// clients of a worker that went down are served by the others when they reconnect.
+//
+// Listen-only clients connecting to `/listen` are forwarded to the worker of the most recent
+// session, and `worker=<index>` in the query pins a connection to a given worker.
+
+use anyhow::{Context, Result};
+use std::io::{BufRead, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Bounds on the http request and response heads read by the router itself.
+const MAX_HEAD_LEN: usize = 16 << 10;
+const HEAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// How often the worker processes are checked, and restarted once they have exited.
+const SUPERVISE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The address the clients connect to.
+    pub addr: String,
+    pub num_workers: usize,
+    /// The workers listen on consecutive local ports starting at this one.
+    pub worker_base_port: u16,
+    /// The arguments of the serve subcommand of the workers, e.g. the model files.
+    pub serve_args: Vec<String>,
+}
+
+struct Worker {
+    addr: std::net::SocketAddr,
+    child: Mutex<Option<std::process::Child>>,
+}
+
+impl Worker {
+    fn spawn(&self, idx: usize, serve_args: &[String]) -> Result<()> {
+        let exe = std::env::current_exe()?;
+        let mut command = std::process::Command::new(exe);
+        command
+            .arg("serve")
+            .args(serve_args)
+            .arg("--addr")
+            .arg(self.addr.to_string())
+            .env("CUDA_VISIBLE_DEVICES", idx.to_string())
+            .stdin(std::process::Stdio::null());
+        // The workers are stopped together with the router, however it exits.
+        #[cfg(target_os = "linux")]
+        unsafe {
+            use std::os::unix::process::CommandExt;
+            command.pre_exec(|| {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn().with_context(|| format!("cannot start worker {idx}"))?;
+        tracing::info!(worker = idx, addr = %self.addr, pid = child.id(), "started worker");
+        *self.child.lock().unwrap() = Some(child);
+        Ok(())
+    }
+}
+
+/// Reads an http head, up to and including the empty line ending it.
+fn read_head(stream: &mut std::io::BufReader<std::net::TcpStream>) -> Result<Vec<u8>> {
+    let mut head = vec![];
+    loop {
+        let len = head.len();
+        if stream.read_until(b'\n', &mut head)? == 0 {
+            anyhow::bail!("connection closed in the http head")
+        }
+        if head.len() > MAX_HEAD_LEN {
+            anyhow::bail!("http head larger than {MAX_HEAD_LEN} bytes")
+        }
+        if matches!(&head[len..], b"\r\n" | b"\n") {
+            return Ok(head);
+        }
+    }
+}
+
+/// The path of a request from its head.
+fn request_path(head: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(head).ok()?.lines().next()?;
+    line.split_whitespace().nth(1)
+}
+
+/// The status code of a response from its head.
+fn status_code(head: &[u8]) -> Option<u16> {
+    let line = std::str::from_utf8(head).ok()?.lines().next()?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+// The order in which the workers are tried for a connection.
+fn candidates(num_workers: usize, pinned: Option<usize>, first: usize) -> Vec<usize> {
+    match pinned {
+        Some(idx) if idx < num_workers => vec![idx],
+        _ => (0..num_workers).map(|i| (first + i) % num_workers).collect(),
+    }
+}
+
+/// Copies the bytes from one side to the other until either closes.
+fn pipe(mut from: impl Read, to: &std::net::TcpStream) {
+    let mut to = to;
+    let _ = std::io::copy(&mut from, &mut to);
+    let _ = to.shutdown(std::net::Shutdown::Both);
+}
+
+struct Router {
+    workers: Vec<Worker>,
+    // The worker tried first for the next session, rotated so that the load is spread.
+    next: AtomicUsize,
+    // The worker of the most recent session, for the listeners.
+    last_session: AtomicUsize,
+}
+
+impl Router {
+    fn route(&self, client: std::net::TcpStream) -> Result<()> {
+        client.set_read_timeout(Some(HEAD_TIMEOUT))?;
+        let mut client_reader = std::io::BufReader::new(client.try_clone()?);
+        let request = read_head(&mut client_reader)?;
+        let path = request_path(&request).unwrap_or("/").to_string();
+        let listen = path.split('?').next() == Some("/listen");
+        let pinned = crate::websocket::query(&path, "worker").and_then(|v| v.parse().ok());
+        let first = if listen {
+            self.last_session.load(Ordering::SeqCst)
+        } else {
+            self.next.fetch_add(1, Ordering::SeqCst)
+        };
+        for idx in candidates(self.workers.len(), pinned, first) {
+            let worker = &self.workers[idx];
+            let mut upstream = match std::net::TcpStream::connect(worker.addr) {
+                Ok(upstream) => upstream,
+                Err(err) => {
+                    tracing::info!(worker = idx, ?err, "worker unavailable");
+                    continue;
+                }
+            };
+            upstream.set_read_timeout(Some(HEAD_TIMEOUT))?;
+            upstream.write_all(&request)?;
+            let mut upstream_reader = std::io::BufReader::new(upstream.try_clone()?);
+            let response = match read_head(&mut upstream_reader) {
+                Ok(response) => response,
+                Err(err) => {
+                    tracing::info!(worker = idx, ?err, "worker failed during the handshake");
+                    continue;
+                }
+            };
+            if status_code(&response) == Some(503) {
+                tracing::info!(worker = idx, "worker busy");
+                continue;
+            }
+            if !listen {
+                self.last_session.store(idx, Ordering::SeqCst)
+            }
+            tracing::info!(worker = idx, path, "forwarding connection");
+            client.set_read_timeout(None)?;
+            upstream.set_read_timeout(None)?;
+            let mut client_writer = &client;
+            client_writer.write_all(&response)?;
+            // The readers first return the bytes they buffered past the heads.
+            let to_client = {
+                let client = client.try_clone()?;
+                std::thread::spawn(move || pipe(upstream_reader, &client))
+            };
+            pipe(client_reader, &upstream);
+            let _ = to_client.join();
+            return Ok(());
+        }
+        let mut client_writer = &client;
+        write!(
+            client_writer,
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )?;
+        anyhow::bail!("no worker available for {path}")
+    }
+}
+
+/// Starts the workers and forwards the connections to them until interrupted.
+pub fn run(options: &Options) -> Result<()> {
+    if options.num_workers == 0 {
+        anyhow::bail!("--workers must be at least 1")
+    }
+    let workers = (0..options.num_workers)
+        .map(|idx| {
+            let port = options.worker_base_port as usize + idx;
+            let port = u16::try_from(port).context("--worker-base-port is too high")?;
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            Ok(Worker { addr, child: Mutex::new(None) })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (idx, worker) in workers.iter().enumerate() {
+        worker.spawn(idx, &options.serve_args)?
+    }
+    let router =
+        Arc::new(Router { workers, next: AtomicUsize::new(0), last_session: AtomicUsize::new(0) });
+    {
+        let router = router.clone();
+        let serve_args = options.serve_args.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(SUPERVISE_INTERVAL);
+            for (idx, worker) in router.workers.iter().enumerate() {
+                let status = match worker.child.lock().unwrap().as_mut() {
+                    None => continue,
+                    Some(child) => child.try_wait(),
+                };
+                match status {
+                    Ok(None) => {}
+                    Ok(Some(status)) => {
+                        tracing::warn!(worker = idx, %status, "worker exited, restarting it");
+                        if let Err(err) = worker.spawn(idx, &serve_args) {
+                            tracing::error!(worker = idx, ?err, "cannot restart worker")
+                        }
+                    }
+                    Err(err) => tracing::warn!(worker = idx, ?err, "cannot check worker"),
+                }
+            }
+        });
+    }
+    let listener = std::net::TcpListener::bind(&options.addr)?;
+    tracing::info!(addr = %listener.local_addr()?, workers = options.num_workers, "routing");
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::warn!(?err, "cannot accept connection");
+                continue;
+            }
+        };
+        let router = router.clone();
+        std::thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            match router.route(stream) {
+                Ok(()) => tracing::info!(?peer, "connection closed"),
+                Err(err) => tracing::warn!(?peer, ?err, "connection failed"),
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heads() {
+        let request = b"GET /listen?format=s16le HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert_eq!(request_path(request), Some("/listen?format=s16le"));
+        assert_eq!(status_code(b"HTTP/1.1 503 Service Unavailable\r\n\r\n"), Some(503));
+        assert_eq!(status_code(b"HTTP/1.1 101 Switching Protocols\r\n\r\n"), Some(101));
+        assert_eq!(status_code(b"garbage"), None);
+    }
+
+    #[test]
+    fn worker_order() {
+        assert_eq!(candidates(3, None, 4), [1, 2, 0]);
+        assert_eq!(candidates(3, Some(2), 0), [2]);
+        // An out of range pin falls back to all the workers.
+        assert_eq!(candidates(2, Some(5), 0), [0, 1]);
+    }
+}
@@ -0,0 +1,677 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// WebSocket server translating the audio streamed by a client, e.g. a browser frontend. The
+// client sends binary messages of mono pcm and receives the translated pcm as binary messages
+// together with json messages of the streaming text protocol, the text and the lag estimates.
+// The sample format and rate are selected with the `format` (f32le or s16le) and `sample_rate`
+// query parameters of the request, e.g. `ws://localhost:8998/?format=s16le&sample_rate=48000`,
+// the translated audio uses the same ones. Sending `{"type": "end"}` marks the end of the
+// input, the server then sends the rest of the translation and closes the connection. With
+// `{"type": "pause"}`, the audio received is dropped until `{"type": "resume"}`, e.g. during the
+// breaks of an event, the session and its context are kept.
+//
+// The models are loaded once, and a single connection is served at a time as the generation
+// runs in real-time: the connections arriving in the meantime are refused with a 503. Any number
+// of listen-only clients can connect to `/listen`, with the same query parameters, to receive
+// the outputs of the sessions, e.g. the attendees choosing the translated channel of a talk.
+// The listeners that do not keep up with the generation are disconnected. With `audio=false` in
+// the query, a client only receives the text messages, and the audio is not decoded at all when
+// neither the speaker nor the listeners want it.
+//
+// When the steps get close to the real-time budget, the depformer is skipped on the silent steps
+// at the cost of some audio quality, until the load drops again.
+//
+// A client that stops sending audio without ending its input would keep the others out, its
+// session is finalized once it has been idle for `idle_timeout`, unless it is paused: the rest of
+// the translation is sent and the connection is closed.
+//
+// With `record_dir`, the messages received by each session are recorded with their timing, and
+// `replay` feeds such a recording to the models again at the same pace to reproduce its issues.
+//
+// With `metrics_addr`, Prometheus metrics are served over http: the active sessions and the
+// listeners, the step latencies of each active session, the text tokens and the failed sessions,
+// see the metrics module.
+
+use anyhow::Result;
+use candle::Device;
+
+use crate::audio_io::RawFormat;
+use crate::fanout::Fanout;
+use crate::protocol::{ErrorCode, TextMessage};
+use std::sync::mpsc::RecvTimeoutError;
+
+fn decode(format: RawFormat, data: &[u8]) -> Result<Vec<f32>> {
+    let sample_size = format.sample_size();
+    if !data.len().is_multiple_of(sample_size) {
+        anyhow::bail!("message of {} bytes, the {format:?} samples have {sample_size}", data.len())
+    }
+    let mut pcm = vec![0f32; data.len() / sample_size];
+    format.decode(data, &mut pcm);
+    Ok(pcm)
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    End,
+    Pause,
+    Resume,
+}
+
+enum Input {
+    Pcm(Vec<f32>),
+    End,
+    Pause,
+    Resume,
+}
+
+// The listen-only clients, with the ones that receive the audio.
+#[derive(Clone)]
+struct Listeners {
+    fanout: Fanout<Event>,
+    with_audio: std::sync::Arc<std::sync::Mutex<std::collections::HashSet<usize>>>,
+}
+
+impl Listeners {
+    fn new() -> Self {
+        let fanout = Fanout::new(crate::fanout::DEFAULT_BUFFER_LEN);
+        Self { fanout, with_audio: Default::default() }
+    }
+
+    fn subscribe(&self, with_audio: bool) -> (usize, std::sync::mpsc::Receiver<Event>) {
+        let (id, rx) = self.fanout.subscribe();
+        if with_audio {
+            self.with_audio.lock().unwrap().insert(id);
+        }
+        (id, rx)
+    }
+
+    fn unsubscribe(&self, id: usize) {
+        self.with_audio.lock().unwrap().remove(&id);
+        self.fanout.unsubscribe(id)
+    }
+
+    fn want_audio(&self) -> bool {
+        !self.with_audio.lock().unwrap().is_empty()
+    }
+}
+
+// The outputs of the sessions published to the listeners.
+#[derive(Debug, Clone)]
+enum Event {
+    Text(String),
+    // The translated pcm, at the sample rate of the codec.
+    Pcm(std::sync::Arc<Vec<f32>>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AudioFormat {
+    format: RawFormat,
+    sample_rate: usize,
+    // Whether the translated audio is sent, or only the text messages.
+    with_audio: bool,
+}
+
+impl AudioFormat {
+    /// The format of the pcm messages from the query parameters of the request path, f32le at
+    /// the sample rate of the codec by default.
+    fn from_query(path: &str, default_rate: usize) -> Option<Self> {
+        let format = match crate::websocket::query(path, "format") {
+            None => RawFormat::F32le,
+            Some(v) => <RawFormat as clap::ValueEnum>::from_str(v, false).ok()?,
+        };
+        let sample_rate = match crate::websocket::query(path, "sample_rate") {
+            None => default_rate,
+            Some(v) => v.parse::<usize>().ok().filter(|&v| v > 0)?,
+        };
+        let with_audio = match crate::websocket::query(path, "audio") {
+            None => true,
+            Some(v) => v.parse::<bool>().ok()?,
+        };
+        Some(Self { format, sample_rate, with_audio })
+    }
+}
+
+fn input(format: RawFormat, message: &crate::websocket::Message) -> Result<Input> {
+    match message {
+        crate::websocket::Message::Binary(data) => decode(format, data).map(Input::Pcm),
+        crate::websocket::Message::Text(text) => {
+            let input = match serde_json::from_str::<ClientMessage>(text)? {
+                ClientMessage::End => Input::End,
+                ClientMessage::Pause => Input::Pause,
+                ClientMessage::Resume => Input::Resume,
+            };
+            Ok(input)
+        }
+    }
+}
+
+/// Forwards the audio received from the client, the channel is closed when the client
+/// disconnects.
+fn receive(
+    mut receiver: crate::websocket::Receiver,
+    format: RawFormat,
+    tx: std::sync::mpsc::Sender<Input>,
+    sender: crate::websocket::Sender,
+    mut recorder: Option<crate::recording::Recorder>,
+) {
+    loop {
+        let message = match receiver.recv() {
+            Ok(None) => break,
+            Ok(Some(message)) => message,
+            Err(err) => {
+                tracing::warn!(?err, "websocket error");
+                break;
+            }
+        };
+        if let Some(rec) = recorder.as_mut() {
+            if let Err(err) = rec.record(&message) {
+                tracing::warn!(?err, "cannot record the session, the recording stops");
+                recorder = None
+            }
+        }
+        match input(format, &message) {
+            Ok(input) => {
+                if tx.send(input).is_err() {
+                    break;
+                }
+            }
+            Err(err) => {
+                let code = ErrorCode::UnsupportedInput;
+                let _ = send_message(
+                    &sender,
+                    &TextMessage::Error { code, message: format!("{err:#}") },
+                );
+                let _ = sender.close(crate::websocket::CLOSE_UNSUPPORTED_DATA, &err.to_string());
+                break;
+            }
+        }
+    }
+}
+
+fn send_message(sender: &crate::websocket::Sender, msg: &TextMessage) -> Result<()> {
+    sender.send_text(&serde_json::to_string(msg)?)
+}
+
+// The client streaming the audio, its outputs are also published to the listeners. There is no
+// client when replaying a recording, the outputs only go to the listeners.
+struct Speaker<'a> {
+    sender: Option<&'a crate::websocket::Sender>,
+    listeners: &'a Listeners,
+}
+
+impl Speaker<'_> {
+    fn send_message(&self, msg: &TextMessage) -> Result<()> {
+        let msg = serde_json::to_string(msg)?;
+        self.listeners.fanout.publish(Event::Text(msg.clone()));
+        match self.sender {
+            Some(sender) => sender.send_text(&msg),
+            None => Ok(()),
+        }
+    }
+
+    fn send_binary(&self, data: &[u8]) -> Result<()> {
+        match self.sender {
+            Some(sender) => sender.send_binary(data),
+            None => Ok(()),
+        }
+    }
+
+    fn send_text(&self, hypothesis: &mut crate::protocol::Hypothesis, text: &str) -> Result<()> {
+        for msg in hypothesis.push(text) {
+            self.send_message(&msg)?
+        }
+        Ok(())
+    }
+}
+
+/// The settings of the server on top of the generation ones.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The address to listen on.
+    pub addr: String,
+    /// The session of a client that has not sent any message for this long is finalized.
+    pub idle_timeout: Option<std::time::Duration>,
+    /// The directory where the messages received by the sessions are recorded.
+    pub record_dir: Option<std::path::PathBuf>,
+    /// Whether the quality is reduced when the steps get close to the real-time budget.
+    pub adaptive_quality: bool,
+    /// The address on which the Prometheus metrics are served.
+    pub metrics_addr: Option<std::net::SocketAddr>,
+}
+
+/// Translates the audio received on `rx` and streams the result back, until the end of the input
+/// or until the client disconnects.
+#[allow(clippy::too_many_arguments)]
+fn translate(
+    args: &crate::gen::Args,
+    options: &Options,
+    dev: &Device,
+    models: &mut crate::gen::Models,
+    rx: std::sync::mpsc::Receiver<Input>,
+    speaker: &Speaker,
+    audio: AudioFormat,
+    metrics: &std::sync::Mutex<crate::metrics::ServeMetrics>,
+) -> Result<()> {
+    let metrics = crate::metrics::ServeMetrics::start_session(metrics);
+    let AudioFormat { format, sample_rate, with_audio } = audio;
+    let frame_size = models.codec.frame_size();
+    let codec_sample_rate = models.codec.sample_rate();
+    let step_duration = frame_size as f64 / codec_sample_rate as f64;
+    let mut resample_in = crate::audio_io::StreamingResampler::new(sample_rate, codec_sample_rate)?;
+    let mut resample_out =
+        crate::audio_io::StreamingResampler::new(codec_sample_rate, sample_rate)?;
+    let mut pacer = crate::pacing::TextPacer::new(args.max_text_updates_per_sec);
+    let mut hypothesis = crate::protocol::Hypothesis::new(crate::live::TENTATIVE_SEGMENTS);
+    let mut interpretation_lag = crate::realtime::InterpretationLag::new(step_duration);
+    let mut input_ended = false;
+    let mut idle = false;
+    let mut paused = false;
+    let mut pending = vec![];
+    let mut num_steps = 0;
+    let mut segment = 0;
+    let mut stop = crate::stop::StopSequences::new(&args.stop_sequences);
+    let step_budget = std::time::Duration::from_secs_f64(step_duration);
+    let mut load = options.adaptive_quality.then(|| crate::realtime::LoadShedder::new(step_budget));
+    'segments: loop {
+        let mut session = crate::session::GenSession::new(args, models, segment, dev)?;
+        let degraded = load.as_ref().is_some_and(|v| v.is_degraded());
+        session.set_skip_silent_depformer(args.skip_silent_depformer || degraded);
+        stop.reset();
+        let mut tail_pad_steps = 0;
+        let mut tail_steps = 0;
+        let mut lag_monitor =
+            crate::realtime::LagMonitor::new(std::time::Duration::from_secs_f64(step_duration));
+        let mut stats = crate::stats::Stats::default();
+        let mut behind = false;
+        while session.state().step_idx() < args.max_steps {
+            while pending.len() < frame_size && !input_ended {
+                // A paused client is waited for, as the breaks of an event can be long.
+                let input = match options.idle_timeout.filter(|_| !paused) {
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                    Some(timeout) => rx.recv_timeout(timeout),
+                };
+                match input {
+                    // The generation waits for the end of the pause with the state it had.
+                    Ok(Input::Pcm(_)) if paused => {}
+                    Ok(Input::Pcm(pcm)) => pending.extend(resample_in.push(&pcm)?),
+                    Ok(Input::Pause) => {
+                        tracing::info!("paused");
+                        paused = true
+                    }
+                    Ok(Input::Resume) => {
+                        tracing::info!("resumed");
+                        paused = false
+                    }
+                    Ok(Input::End) => {
+                        pending.extend(resample_in.flush()?);
+                        input_ended = true
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        tracing::info!(?options.idle_timeout, "idle client, ending its input");
+                        pending.extend(resample_in.flush()?);
+                        input_ended = true;
+                        idle = true
+                    }
+                    // The client is gone, there is nobody to send the translation to.
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+            }
+            if pending.len() < frame_size {
+                // Once the input is over, silence is fed until the model has finished
+                // translating.
+                pending.resize(frame_size, 0.);
+                tail_steps += 1
+            }
+            let frame = pending.drain(..frame_size).collect::<Vec<_>>();
+            let step_start = std::time::Instant::now();
+            let step_idx = session.state().step_idx();
+            let decode_audio = with_audio || speaker.listeners.want_audio();
+            session.set_decode_audio(decode_audio);
+            session.push_pcm(&frame);
+            let mut stopped = false;
+            let mut text_tokens = 0;
+            for output in session.step()? {
+                stats.record(output.text.as_deref());
+                text_tokens += output.text.is_some() as usize;
+                let stop_state = stop.step(output.text.as_deref());
+                let text_stopped = stop.matched() && stop_state != crate::stop::Stop::Matched;
+                let text = output.text.as_deref().filter(|_| !text_stopped);
+                if let Some(text) = text.and_then(|v| pacer.push(v)) {
+                    speaker.send_text(&mut hypothesis, &text)?
+                }
+                let pcm = match output.pcm.as_ref() {
+                    Some(pcm) => Some(pcm.flatten_all()?.to_vec1::<f32>()?),
+                    None => None,
+                };
+                // Without any audio, the lag is estimated from the text so that it does not
+                // grow as if the translation stayed silent.
+                if decode_audio {
+                    interpretation_lag.record(&frame, pcm.as_deref())
+                } else {
+                    interpretation_lag.record_text(&frame, !output.is_pad())
+                }
+                if let Some(pcm) = pcm {
+                    let resampled = if with_audio { resample_out.push(&pcm)? } else { vec![] };
+                    speaker.listeners.fanout.publish(Event::Pcm(std::sync::Arc::new(pcm)));
+                    if !resampled.is_empty() {
+                        speaker.send_binary(&format.encode(&resampled))?
+                    }
+                }
+                if tail_steps > 0 {
+                    tail_pad_steps = if output.is_pad() { tail_pad_steps + 1 } else { 0 };
+                }
+                match stop_state {
+                    crate::stop::Stop::Continue => {}
+                    crate::stop::Stop::Matched => session.end_text(),
+                    crate::stop::Stop::Done => stopped = true,
+                }
+            }
+            let elapsed = step_start.elapsed();
+            metrics.record_step(elapsed, text_tokens);
+            match load.as_mut().and_then(|v| v.record(elapsed)) {
+                None => {}
+                Some(true) => {
+                    session.set_skip_silent_depformer(true);
+                    let code = ErrorCode::StepDeadlineMissed;
+                    let message = "the audio quality is reduced to keep up with the speaker";
+                    tracing::warn!(step = step_idx, message);
+                    speaker.send_message(&TextMessage::Warning { code, message: message.into() })?
+                }
+                Some(false) => {
+                    session.set_skip_silent_depformer(args.skip_silent_depformer);
+                    tracing::info!(step = step_idx, "the load dropped, restoring the audio quality")
+                }
+            }
+            let breach = lag_monitor.record(step_idx, 1, elapsed);
+            if let Some(breach) = breach.filter(|_| args.warn_slow_steps) {
+                tracing::warn!(
+                    step = breach.step_idx,
+                    lag_ms = breach.lag.as_millis() as u64,
+                    "processing is slower than real-time"
+                );
+            }
+            let lag = lag_monitor.lag();
+            if lag.is_zero() {
+                behind = false
+            } else if lag >= crate::live::BEHIND_WARNING_LAG && !behind {
+                behind = true;
+                let code = ErrorCode::StepDeadlineMissed;
+                let message = format!("the processing is {}ms behind the speaker", lag.as_millis());
+                speaker.send_message(&TextMessage::Warning { code, message })?
+            }
+            stats.maybe_dump(&lag_monitor, dev);
+            num_steps += 1;
+            if num_steps % crate::live::LAG_INTERVAL_STEPS == 0 {
+                speaker.send_message(&TextMessage::lag(&interpretation_lag, lag))?
+            }
+            if tail_pad_steps >= crate::dubbing::END_PAD_STEPS
+                || tail_steps >= crate::dubbing::MAX_TAIL_STEPS
+            {
+                break 'segments;
+            }
+            if stopped {
+                break;
+            }
+        }
+        if stop.matched() {
+            tracing::info!(segment, "stop sequence generated, starting a new context")
+        } else {
+            tracing::info!(segment, "reached --max-steps, starting a new context")
+        }
+        if let Some(text) = pacer.flush() {
+            speaker.send_text(&mut hypothesis, &text)?
+        }
+        if let Some(msg) = hypothesis.commit() {
+            speaker.send_message(&msg)?
+        }
+        segment += 1;
+    }
+    if let Some(text) = pacer.flush() {
+        speaker.send_text(&mut hypothesis, &text)?
+    }
+    if let Some(msg) = hypothesis.commit() {
+        speaker.send_message(&msg)?
+    }
+    let pcm = if with_audio { resample_out.flush()? } else { vec![] };
+    if !pcm.is_empty() {
+        speaker.send_binary(&format.encode(&pcm))?
+    }
+    if let Some(sender) = speaker.sender {
+        sender.close(crate::websocket::CLOSE_NORMAL, if idle { "idle timeout" } else { "" })?
+    }
+    Ok(())
+}
+
+fn serve_connection(
+    args: &crate::gen::Args,
+    options: &Options,
+    dev: &Device,
+    models: &mut crate::gen::Models,
+    handshake: crate::websocket::Handshake,
+    listeners: &Listeners,
+    metrics: &std::sync::Mutex<crate::metrics::ServeMetrics>,
+) -> Result<()> {
+    let Some(audio) = AudioFormat::from_query(handshake.path(), models.codec.sample_rate()) else {
+        handshake.reject("400 Bad Request")?;
+        anyhow::bail!("invalid query parameters")
+    };
+    let recorder = match options.record_dir.as_ref() {
+        None => None,
+        Some(dir) => {
+            let (recorder, path) = crate::recording::Recorder::create(dir, handshake.path())?;
+            tracing::info!(?path, "recording the session");
+            Some(recorder)
+        }
+    };
+    let (receiver, sender) = handshake.accept()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let receiver = {
+        let sender = sender.clone();
+        std::thread::spawn(move || receive(receiver, audio.format, tx, sender, recorder))
+    };
+    let speaker = Speaker { sender: Some(&sender), listeners };
+    let res = translate(args, options, dev, models, rx, &speaker, audio, metrics);
+    if let Err(err) = res.as_ref() {
+        let msg = TextMessage::Error { code: ErrorCode::of(err), message: format!("{err:#}") };
+        let _ = send_message(&sender, &msg);
+        let _ = sender.close(crate::websocket::CLOSE_INTERNAL_ERROR, &err.to_string());
+    }
+    // The receiver thread ends once the client acknowledges the close, or disconnects.
+    let _ = receiver.join();
+    res
+}
+
+/// Streams the outputs of the sessions to a listen-only client, until it disconnects or gets
+/// evicted for not keeping up.
+fn listen(
+    handshake: crate::websocket::Handshake,
+    listeners: &Listeners,
+    codec_sample_rate: usize,
+) -> Result<()> {
+    let Some(audio) = AudioFormat::from_query(handshake.path(), codec_sample_rate) else {
+        handshake.reject("400 Bad Request")?;
+        anyhow::bail!("invalid query parameters")
+    };
+    let (mut receiver, sender) = handshake.accept()?;
+    let (id, rx) = listeners.subscribe(audio.with_audio);
+    let gone = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    {
+        let listeners = listeners.clone();
+        let gone = gone.clone();
+        // The messages of the listeners are ignored, this only answers the pings and notices
+        // when the client leaves.
+        std::thread::spawn(move || {
+            while let Ok(Some(_)) = receiver.recv() {}
+            gone.store(true, std::sync::atomic::Ordering::SeqCst);
+            listeners.unsubscribe(id)
+        });
+    }
+    let mut resample =
+        crate::audio_io::StreamingResampler::new(codec_sample_rate, audio.sample_rate)?;
+    let mut forward = || -> Result<()> {
+        for event in rx.iter() {
+            match event {
+                Event::Text(msg) => sender.send_text(&msg)?,
+                Event::Pcm(_) if !audio.with_audio => {}
+                Event::Pcm(pcm) => {
+                    let pcm = resample.push(&pcm)?;
+                    if !pcm.is_empty() {
+                        sender.send_binary(&audio.format.encode(&pcm))?
+                    }
+                }
+            }
+        }
+        Ok(())
+    };
+    let res = forward();
+    listeners.unsubscribe(id);
+    if res.is_ok() && !gone.load(std::sync::atomic::Ordering::SeqCst) {
+        let reason = "evicted for not keeping up with the translation";
+        sender.close(crate::websocket::CLOSE_POLICY_VIOLATION, reason)?
+    }
+    res
+}
+
+/// Translates a session recorded in `record_dir` again, the messages are fed at the pace at which
+/// they were received. The protocol messages are printed as json lines, and the audio is played
+/// with --play.
+pub fn replay(
+    args: &crate::gen::Args,
+    dev: &Device,
+    options: &Options,
+    path: &std::path::Path,
+) -> Result<()> {
+    let recording = crate::recording::read(path)?;
+    let mut models = crate::gen::Models::load(args, dev)?;
+    let codec_sample_rate = models.codec.sample_rate();
+    let Some(audio) = AudioFormat::from_query(&recording.path, codec_sample_rate) else {
+        anyhow::bail!("invalid query parameters in {:?}", recording.path)
+    };
+    tracing::info!(?path, messages = recording.entries.len(), "replaying the session");
+    let playback = match args.play.as_deref() {
+        None => None,
+        Some(device) => {
+            Some(crate::audio_io::Playback::open(device, codec_sample_rate, args.play_buffer)?)
+        }
+    };
+    let listeners = Listeners::new();
+    // The audio is decoded as when the session was recorded, even if it is not played.
+    let (id, events) = listeners.subscribe(true);
+    let outputs = std::thread::spawn(move || {
+        let writer = crate::output::TextWriter::stdout();
+        for event in events.iter() {
+            match event {
+                Event::Text(msg) => writer.write(&format!("{msg}\n")),
+                Event::Pcm(pcm) => {
+                    if let Some(playback) = playback.as_ref() {
+                        playback.push(&pcm)
+                    }
+                }
+            }
+        }
+    });
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let start = std::time::Instant::now();
+        for entry in recording.entries.iter() {
+            std::thread::sleep(entry.at.saturating_sub(start.elapsed()));
+            let input = match input(audio.format, &entry.message) {
+                Ok(input) => input,
+                Err(err) => {
+                    tracing::warn!(?err, "the session was closed on this message");
+                    break;
+                }
+            };
+            if tx.send(input).is_err() {
+                break;
+            }
+        }
+    });
+    let speaker = Speaker { sender: None, listeners: &listeners };
+    let metrics = Default::default();
+    let res = translate(args, options, dev, &mut models, rx, &speaker, audio, &metrics);
+    listeners.unsubscribe(id);
+    let _ = outputs.join();
+    res
+}
+
+/// Listens for WebSocket connections and translates their audio until interrupted.
+pub fn run(args: &crate::gen::Args, dev: &Device, options: &Options) -> Result<()> {
+    let mut models = crate::gen::Models::load(args, dev)?;
+    let codec_sample_rate = models.codec.sample_rate();
+    let listeners = Listeners::new();
+    let metrics =
+        std::sync::Arc::new(std::sync::Mutex::new(crate::metrics::ServeMetrics::default()));
+    if let Some(addr) = options.metrics_addr {
+        let (metrics, listeners, dev) = (metrics.clone(), listeners.clone(), dev.clone());
+        crate::metrics::serve(addr, move || {
+            let listeners = listeners.fanout.num_subscribers();
+            metrics.lock().unwrap().render(listeners, &dev)
+        })?
+    }
+    let listener = std::net::TcpListener::bind(&options.addr)?;
+    tracing::info!(addr = %listener.local_addr()?, "listening");
+    let busy = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let (tx, rx) = std::sync::mpsc::channel();
+    {
+        let busy = busy.clone();
+        let listeners = listeners.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        tracing::warn!(?err, "cannot accept connection");
+                        continue;
+                    }
+                };
+                let busy = busy.clone();
+                let tx = tx.clone();
+                let listeners = listeners.clone();
+                // The handshake is read on its own thread so that a slow client cannot delay
+                // the others.
+                std::thread::spawn(move || {
+                    let peer = stream.peer_addr().ok();
+                    let handshake = match crate::websocket::Handshake::read(stream) {
+                        Ok(handshake) => handshake,
+                        Err(err) => {
+                            tracing::warn!(?peer, ?err, "invalid handshake");
+                            return;
+                        }
+                    };
+                    if handshake.path().split('?').next() == Some("/listen") {
+                        tracing::info!(?peer, "new listener");
+                        match listen(handshake, &listeners, codec_sample_rate) {
+                            Ok(()) => tracing::info!(?peer, "listener closed"),
+                            Err(err) => tracing::warn!(?peer, ?err, "listener failed"),
+                        }
+                    } else if busy.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                        tracing::info!(?peer, "already serving a connection");
+                        let _ = handshake.reject("503 Service Unavailable");
+                    } else if let Err(std::sync::mpsc::SendError((_, handshake))) =
+                        tx.send((peer, handshake))
+                    {
+                        let _ = handshake.reject("503 Service Unavailable");
+                    }
+                });
+            }
+        });
+    }
+    for (peer, handshake) in rx {
+        tracing::info!(?peer, path = handshake.path(), "new connection");
+        let res =
+            serve_connection(args, options, dev, &mut models, handshake, &listeners, &metrics);
+        busy.store(false, std::sync::atomic::Ordering::SeqCst);
+        match res {
+            Ok(()) => tracing::info!(?peer, "connection closed"),
+            Err(err) => {
+                tracing::warn!(?peer, ?err, "connection failed");
+                metrics.lock().unwrap().record_error(ErrorCode::of(&err))
+            }
+        }
+    }
+    Ok(())
+}
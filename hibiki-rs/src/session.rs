@@ -0,0 +1,261 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Streaming generation session: the source audio is pushed in arbitrary chunks and each call to
+// `step` returns the text tokens and the decoded audio for the frames that could be processed.
+// This is what `gen::generate` and the live mode are built on, and what an application embedding
+// hibiki should use rather than going through files.
+
+use anyhow::Result;
+use candle::{Device, IndexOp, Tensor};
+
+// Level below which the generated audio is considered as silent for skipping the depformer, and
+// the number of consecutive silent steps required, i.e. 400ms.
+const SILENT_OUTPUT_DB: f32 = -50.;
+const MIN_SILENT_STEPS: usize = 5;
+
+/// The output of a single generation step.
+pub struct StepOutput {
+    pub step_idx: usize,
+    pub text_token: u32,
+    /// The text for the token, `None` for the padding tokens.
+    pub text: Option<String>,
+    /// The decoded audio for the step, `None` until the acoustic delay has elapsed.
+    pub pcm: Option<Tensor>,
+}
+
+impl StepOutput {
+    pub fn is_pad(&self) -> bool {
+        self.text_token == 0 || self.text_token == 3
+    }
+}
+
+/// Time spent in each part of the steps. Device operations are asynchronous on gpus, so the
+/// split is only indicative there, the time is accounted to the part that waits for the device.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StepTimings {
+    pub encode: std::time::Duration,
+    pub lm: std::time::Duration,
+    pub decode: std::time::Duration,
+}
+
+impl std::ops::AddAssign for StepTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.encode += other.encode;
+        self.lm += other.lm;
+        self.decode += other.decode;
+    }
+}
+
+pub struct GenSession<'a> {
+    models: &'a mut crate::gen::Models,
+    state: crate::lm_state::State,
+    conditions: Option<moshi::conditioner::Condition>,
+    forced_text_tokens: Option<&'a [u32]>,
+    text_ended: bool,
+    skip_silent_depformer: bool,
+    decode_audio: bool,
+    frames_per_batch: usize,
+    generated_audio_codebooks: usize,
+    // Source audio at the codec sample rate that has not been encoded yet.
+    pending: Vec<f32>,
+    prev_text_token: u32,
+    silent_steps: usize,
+    timings: StepTimings,
+    dev: Device,
+}
+
+impl<'a> GenSession<'a> {
+    /// Starts a session with a fresh lm state, `take` is used to derive the sampling seeds so
+    /// that multiple takes of the same input differ.
+    pub fn new(
+        args: &crate::gen::Args,
+        models: &'a mut crate::gen::Models,
+        take: usize,
+        dev: &Device,
+    ) -> Result<Self> {
+        let config = crate::gen::multistream_config(&args.lm_config);
+        let conditions = crate::gen::conditions(args, &models.lm_model)?;
+        let cfg_alpha = if args.cfg_alpha == Some(1.) { None } else { args.cfg_alpha };
+        let (audio_sampling, text_sampling) = crate::gen::samplings(args);
+        let audio_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
+            args.seed + take as u64,
+            audio_sampling,
+        );
+        let text_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
+            args.seed + if args.keep_text { 0 } else { take as u64 },
+            text_sampling,
+        );
+        let generated_audio_codebooks = config.generated_audio_codebooks;
+        let prev_text_token = config.text_start_token;
+        let mut state = crate::lm_state::State::new(
+            models.lm_model.clone(),
+            crate::resources::cache_len(args.max_steps),
+            audio_lp,
+            text_lp,
+            args.pad_bias,
+            None,
+            cfg_alpha,
+            config,
+        );
+        if let Some(sampling) = crate::gen::boundary_sampling(args) {
+            let boundary_lp = candle_transformers::generation::LogitsProcessor::from_sampling(
+                args.seed + take as u64,
+                sampling,
+            );
+            state.set_audio_annealing(args.audio_anneal_window, boundary_lp)
+        }
+        models.codec.reset_state();
+        Ok(Self {
+            models,
+            state,
+            conditions,
+            forced_text_tokens: None,
+            text_ended: false,
+            skip_silent_depformer: args.skip_silent_depformer,
+            decode_audio: true,
+            frames_per_batch: args.frames_per_batch.max(1),
+            generated_audio_codebooks,
+            pending: vec![],
+            prev_text_token,
+            silent_steps: 0,
+            timings: StepTimings::default(),
+            dev: dev.clone(),
+        })
+    }
+
+    /// Forces the text token of each step rather than sampling it, e.g. to keep the text of a
+    /// previous take.
+    pub fn with_forced_text_tokens(mut self, tokens: &'a [u32]) -> Self {
+        self.forced_text_tokens = Some(tokens);
+        self
+    }
+
+    /// Constrains the text to follow a draft translation.
+    pub fn with_draft(mut self, draft: crate::draft::Draft) -> Self {
+        self.state.set_draft(draft);
+        self
+    }
+
+    /// Forces the text to padding for the following steps, the audio of the text generated so
+    /// far is still completed.
+    pub fn end_text(&mut self) {
+        self.text_ended = true
+    }
+
+    /// Skips the depformer on the silent steps as with --skip-silent-depformer, this can be
+    /// changed during the session, e.g. to reduce the cost of the steps under load.
+    pub fn set_skip_silent_depformer(&mut self, skip: bool) {
+        self.skip_silent_depformer = skip;
+        if !skip {
+            self.silent_steps = 0;
+            self.state.set_audio_silent(false)
+        }
+    }
+
+    /// Stops decoding the generated audio when nobody listens to it, the steps then have no pcm
+    /// and only the text is generated. The decoder picks up from its previous state once this is
+    /// enabled again, so the first frames may have artifacts.
+    pub fn set_decode_audio(&mut self, decode: bool) {
+        self.decode_audio = decode
+    }
+
+    /// Appends source audio, at the sample rate of the codec.
+    pub fn push_pcm(&mut self, pcm: &[f32]) {
+        self.pending.extend_from_slice(pcm)
+    }
+
+    /// Removes the source audio that has not been processed yet, e.g. to push it to a new session.
+    pub fn take_pending(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// The number of complete frames that have been pushed but not processed yet.
+    pub fn pending_frames(&self) -> usize {
+        self.pending.len() / self.models.codec.frame_size()
+    }
+
+    /// Processes up to `frames_per_batch` pending frames, returns the outputs of the generation
+    /// steps that were run, this is empty when less than a frame is pending.
+    pub fn step(&mut self) -> Result<Vec<StepOutput>> {
+        let frame_size = self.models.codec.frame_size();
+        let num_frames = self.pending_frames().min(self.frames_per_batch);
+        if num_frames == 0 {
+            return Ok(vec![]);
+        }
+        let pcm: Vec<f32> = self.pending.drain(..num_frames * frame_size).collect();
+        let pcm = Tensor::from_vec(pcm, (1, 1, num_frames * frame_size), &self.dev)?;
+        let mut outputs = vec![];
+        let encode_start = std::time::Instant::now();
+        let codes = self.models.codec.encode_step(&pcm)?;
+        self.timings.encode += encode_start.elapsed();
+        let codes = match codes {
+            None => return Ok(outputs),
+            Some(codes) => codes,
+        };
+        let (_b, _codebooks, steps) = codes.dims3()?;
+        for step in 0..steps {
+            let codes = codes.i((0, .., step))?.to_vec1::<u32>()?;
+            let step_idx = self.state.step_idx();
+            let force_text_token = if self.text_ended {
+                Some(self.state.config().text_pad_token)
+            } else {
+                self.forced_text_tokens.and_then(|v| v.get(step_idx).copied())
+            };
+            let lm_start = std::time::Instant::now();
+            let text_token = self.state.step_(
+                Some(self.prev_text_token),
+                &codes,
+                force_text_token,
+                self.conditions.as_ref(),
+            )?;
+            let text_start_token = self.state.config().text_start_token;
+            let text = if text_token == 0 || text_token == 3 {
+                None
+            } else {
+                crate::gen::text(
+                    &self.models.text_tokenizer,
+                    self.prev_text_token,
+                    text_token,
+                    text_start_token,
+                )
+            };
+            self.prev_text_token = text_token;
+            self.timings.lm += lm_start.elapsed();
+            let decode_start = std::time::Instant::now();
+            let pcm = match self.state.last_audio_tokens().filter(|_| self.decode_audio) {
+                None => None,
+                Some(audio_tokens) => {
+                    let audio_tokens =
+                        Tensor::new(&audio_tokens[..self.generated_audio_codebooks], &self.dev)?
+                            .reshape((1, 1, ()))?
+                            .t()?;
+                    self.models.codec.decode_step(&audio_tokens)?
+                }
+            };
+            self.timings.decode += decode_start.elapsed();
+            if let Some(pcm) = pcm.as_ref().filter(|_| self.skip_silent_depformer) {
+                let pcm = pcm.flatten_all()?.to_vec1::<f32>()?;
+                let db = crate::events::frame_features(&pcm).db;
+                self.silent_steps = if db < SILENT_OUTPUT_DB { self.silent_steps + 1 } else { 0 };
+                self.state.set_audio_silent(self.silent_steps >= MIN_SILENT_STEPS);
+            }
+            outputs.push(StepOutput { step_idx, text_token, text, pcm })
+        }
+        Ok(outputs)
+    }
+
+    pub fn state(&self) -> &crate::lm_state::State {
+        &self.state
+    }
+
+    pub fn timings(&self) -> StepTimings {
+        self.timings
+    }
+
+    /// Ends the session, returning the lm state with the full token history.
+    pub fn into_state(self) -> crate::lm_state::State {
+        self.state
+    }
+}
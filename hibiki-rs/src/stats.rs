@@ -0,0 +1,158 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Statistics of the running session dumped on SIGUSR1, e.g. `kill -USR1 $(pidof hibiki)`, so
+// that long unattended runs can be diagnosed without interrupting the generation.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// The length of the text tail included in the dump, in characters.
+const LAST_TEXT_CHARS: usize = 80;
+
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_sigusr1(_: libc::c_int) {
+    DUMP_REQUESTED.store(true, Ordering::Relaxed)
+}
+
+/// Installs the SIGUSR1 handler, the signal only sets a flag that is checked between steps.
+pub fn install_handler() {
+    #[cfg(unix)]
+    unsafe {
+        libc::signal(libc::SIGUSR1, on_sigusr1 as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+pub struct Stats {
+    start: std::time::Instant,
+    steps: usize,
+    text_tokens: usize,
+    last_text: String,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+            steps: 0,
+            text_tokens: 0,
+            last_text: String::new(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn record(&mut self, text: Option<&str>) {
+        self.steps += 1;
+        if let Some(text) = text {
+            self.text_tokens += 1;
+            self.last_text.push_str(text);
+            let len = self.last_text.chars().count();
+            if len > LAST_TEXT_CHARS {
+                self.last_text = self.last_text.chars().skip(len - LAST_TEXT_CHARS).collect()
+            }
+        }
+    }
+
+    /// Logs the statistics if a dump has been requested since the last call.
+    pub fn maybe_dump(&self, lag_monitor: &crate::realtime::LagMonitor, dev: &candle::Device) {
+        if !DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mb = |v: Option<usize>| {
+            v.map_or_else(|| "unknown".to_string(), |v| format!("{}MB", v >> 20))
+        };
+        tracing::info!(
+            steps = self.steps,
+            text_tokens = self.text_tokens,
+            elapsed_s = format!("{elapsed:.1}"),
+            ms_per_step = format!("{:.1}", elapsed * 1000. / self.steps.max(1) as f64),
+            lag_ms = lag_monitor.lag().as_millis() as u64,
+            peak_lag_ms = lag_monitor.peak_lag().as_millis() as u64,
+            slow_batches = lag_monitor.num_breaches(),
+            process_memory = mb(crate::resources::process_memory()),
+            available_memory = mb(crate::resources::available_memory(dev)),
+            last_text = self.last_text.trim_start(),
+            "session stats"
+        );
+    }
+}
+
+/// Distribution of the processing time per step, in ms.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct Latency {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Latency {
+    fn new(mut samples: Vec<f64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_by(f64::total_cmp);
+        let quantile = |q: f64| samples[((samples.len() - 1) as f64 * q).round() as usize];
+        Self {
+            mean: samples.iter().sum::<f64>() / samples.len() as f64,
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            p99: quantile(0.99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// Performance metrics of a generation, e.g. for benchmarking scripts. The real-time factor is
+/// the processing time over the duration of the processed audio, below 1 is faster than
+/// real-time.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct GenStats {
+    pub wall_time_s: f64,
+    pub audio_s: f64,
+    pub real_time_factor: f64,
+    pub step_latency_ms: Latency,
+    pub encode_s: f64,
+    pub lm_s: f64,
+    pub decode_s: f64,
+    pub peak_memory_bytes: Option<usize>,
+}
+
+/// Collects the per-step latencies and the timings of the sessions during a generation.
+#[derive(Debug, Default)]
+pub struct GenStatsRecorder {
+    // The latency of each step, the steps of a batch get the average over the batch.
+    step_ms: Vec<f64>,
+    timings: crate::session::StepTimings,
+}
+
+impl GenStatsRecorder {
+    pub fn record_batch(&mut self, num_steps: usize, elapsed: std::time::Duration) {
+        let ms = elapsed.as_secs_f64() * 1000. / num_steps.max(1) as f64;
+        self.step_ms.extend(std::iter::repeat_n(ms, num_steps))
+    }
+
+    pub fn record_session(&mut self, timings: crate::session::StepTimings) {
+        self.timings += timings
+    }
+
+    pub fn finish(self, wall_time: std::time::Duration, step_duration: f64) -> GenStats {
+        let audio_s = self.step_ms.len() as f64 * step_duration;
+        let processing_s = self.step_ms.iter().sum::<f64>() / 1000.;
+        GenStats {
+            wall_time_s: wall_time.as_secs_f64(),
+            audio_s,
+            real_time_factor: if audio_s > 0. { processing_s / audio_s } else { 0. },
+            step_latency_ms: Latency::new(self.step_ms),
+            encode_s: self.timings.encode.as_secs_f64(),
+            lm_s: self.timings.lm.as_secs_f64(),
+            decode_s: self.timings.decode.as_secs_f64(),
+            peak_memory_bytes: crate::resources::peak_process_memory(),
+        }
+    }
+}
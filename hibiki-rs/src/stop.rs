@@ -0,0 +1,120 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Stop sequences that end the current segment once they appear in the translated text, e.g. the
+// closing phrase of a templated announcement. After a match the text is forced to padding for a
+// few steps so that the audio of the last words is completed, then the segment ends.
+
+// The audio lags behind the text, 1s is enough for the last words to be spoken.
+const TAIL_STEPS: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Continue,
+    /// A stop sequence has just been generated, the text should be forced to padding.
+    Matched,
+    /// The tail after the match is over, the segment should end.
+    Done,
+}
+
+pub struct StopSequences {
+    sequences: Vec<String>,
+    max_len: usize,
+    // The end of the text generated so far, long enough to contain any of the sequences.
+    recent: String,
+    steps_since_match: Option<usize>,
+}
+
+impl StopSequences {
+    pub fn new(sequences: &[String]) -> Self {
+        let sequences: Vec<String> = sequences.iter().filter(|v| !v.is_empty()).cloned().collect();
+        let max_len = sequences.iter().map(|v| v.len()).max().unwrap_or(0);
+        Self { sequences, max_len, recent: String::new(), steps_since_match: None }
+    }
+
+    /// Whether a stop sequence has been generated in the current segment, the text that follows
+    /// it in the same batch of steps should be dropped.
+    pub fn matched(&self) -> bool {
+        self.steps_since_match.is_some()
+    }
+
+    /// Processes the text generated at a step, if any.
+    pub fn step(&mut self, text: Option<&str>) -> Stop {
+        if self.sequences.is_empty() {
+            return Stop::Continue;
+        }
+        if let Some(steps) = self.steps_since_match.as_mut() {
+            *steps += 1;
+            return if *steps >= TAIL_STEPS { Stop::Done } else { Stop::Continue };
+        }
+        let text = match text {
+            None => return Stop::Continue,
+            Some(text) => text,
+        };
+        self.recent.push_str(text);
+        if self.sequences.iter().any(|v| self.recent.contains(v.as_str())) {
+            self.steps_since_match = Some(0);
+            return Stop::Matched;
+        }
+        // Only keep what can still be the start of a match.
+        if self.recent.len() > self.max_len {
+            let mut start = self.recent.len() - self.max_len;
+            while !self.recent.is_char_boundary(start) {
+                start += 1
+            }
+            self.recent.drain(..start);
+        }
+        Stop::Continue
+    }
+
+    /// Starts matching again for a new segment.
+    pub fn reset(&mut self) {
+        self.recent.clear();
+        self.steps_since_match = None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequences(sequences: &[&str]) -> StopSequences {
+        StopSequences::new(&sequences.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn match_across_steps() {
+        let mut stop = sequences(&["thank you", ""]);
+        for text in [" Well", ",", " thank"] {
+            assert_eq!(stop.step(Some(text)), Stop::Continue)
+        }
+        assert_eq!(stop.step(None), Stop::Continue);
+        assert!(!stop.matched());
+        assert_eq!(stop.step(Some(" you")), Stop::Matched);
+        assert!(stop.matched());
+        for _ in 1..TAIL_STEPS {
+            assert_eq!(stop.step(Some(" more")), Stop::Continue)
+        }
+        assert_eq!(stop.step(None), Stop::Done);
+        stop.reset();
+        assert!(!stop.matched());
+        assert_eq!(stop.step(Some(" you")), Stop::Continue);
+    }
+
+    #[test]
+    fn no_sequences() {
+        let mut stop = sequences(&[""]);
+        assert_eq!(stop.step(Some("")), Stop::Continue);
+        assert!(!stop.matched());
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let mut stop = sequences(&["merci"]);
+        for text in ["é", "té", " à", " tous", "… ", "mer"] {
+            assert_eq!(stop.step(Some(text)), Stop::Continue)
+        }
+        assert_eq!(stop.step(Some("ci")), Stop::Matched);
+    }
+}
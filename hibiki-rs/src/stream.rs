@@ -0,0 +1,157 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Asynchronous variant of the session API, for embedding hibiki in async servers. The models and
+// the session are moved to a dedicated thread that runs the blocking generation steps, the source
+// audio is sent to it and the outputs come back as a stream of events. Both channels are bounded:
+// when the consumer of the events is slow, the steps block and then so does pushing the audio.
+
+use anyhow::Result;
+use candle::Device;
+
+// The number of pcm chunks and of events that can be queued in each direction.
+pub const CHANNEL_LEN: usize = 32;
+
+/// An output of the session.
+#[derive(Debug)]
+pub enum Event {
+    /// Translated text, a newline is sent when a new context is started after `max_steps` steps.
+    Text(String),
+    /// Translated audio, at the sample rate of the codec.
+    Audio(Vec<f32>),
+    /// The session failed, this is the last event.
+    Error(anyhow::Error),
+}
+
+enum Input {
+    Pcm(Vec<f32>),
+    End,
+}
+
+/// The sending half of an asynchronous session, the events are received on the `EventStream`
+/// returned by `spawn`.
+pub struct AsyncSession {
+    input: tokio::sync::mpsc::Sender<Input>,
+    sample_rate: usize,
+}
+
+impl AsyncSession {
+    /// Starts the generation thread, it owns the models until the session ends.
+    pub fn spawn(
+        args: crate::gen::Args,
+        models: crate::gen::Models,
+        dev: &Device,
+    ) -> (Self, EventStream) {
+        let (input_tx, input_rx) = tokio::sync::mpsc::channel(CHANNEL_LEN);
+        let (events_tx, events_rx) = tokio::sync::mpsc::channel(CHANNEL_LEN);
+        let sample_rate = models.codec.sample_rate();
+        let dev = dev.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = run(&args, models, &dev, input_rx, &events_tx) {
+                let _ = events_tx.blocking_send(Event::Error(err));
+            }
+        });
+        (Self { input: input_tx, sample_rate }, EventStream { events: events_rx })
+    }
+
+    /// The sample rate of the pushed and generated audio.
+    pub fn sample_rate(&self) -> usize {
+        self.sample_rate
+    }
+
+    /// Appends source audio, at the sample rate of the codec. This waits while the generation is
+    /// behind, and fails once the session has ended, e.g. on errors or if the stream was dropped.
+    pub async fn push_pcm(&self, pcm: Vec<f32>) -> Result<()> {
+        self.input.send(Input::Pcm(pcm)).await.map_err(|_| anyhow::anyhow!("the session has ended"))
+    }
+
+    /// Signals the end of the source audio, silence is then fed until the model has finished
+    /// translating and the event stream ends. Dropping the session instead stops it as soon as
+    /// the pushed audio has been processed.
+    pub async fn end(self) -> Result<()> {
+        self.input.send(Input::End).await.map_err(|_| anyhow::anyhow!("the session has ended"))
+    }
+}
+
+/// The events generated by a session, this ends after the session.
+pub struct EventStream {
+    events: tokio::sync::mpsc::Receiver<Event>,
+}
+
+impl EventStream {
+    /// Returns the next event, `None` once the session has ended.
+    pub async fn next(&mut self) -> Option<Event> {
+        self.events.recv().await
+    }
+}
+
+impl futures_core::Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Event>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+fn run(
+    args: &crate::gen::Args,
+    mut models: crate::gen::Models,
+    dev: &Device,
+    mut input: tokio::sync::mpsc::Receiver<Input>,
+    events: &tokio::sync::mpsc::Sender<Event>,
+) -> Result<()> {
+    let send = |event| {
+        events.blocking_send(event).map_err(|_| anyhow::anyhow!("the event stream was dropped"))
+    };
+    let frame_size = models.codec.frame_size();
+    let mut segment = 0;
+    let mut pending = vec![];
+    let mut ended = false;
+    // Once the source has ended, silence is fed until the model has finished translating.
+    let mut tail_steps = 0;
+    let mut tail_pad_steps = 0;
+    loop {
+        let mut session = crate::session::GenSession::new(args, &mut models, segment, dev)?;
+        session.push_pcm(&pending);
+        while session.state().step_idx() < args.max_steps {
+            if session.pending_frames() == 0 {
+                if ended {
+                    session.push_pcm(&vec![0.; frame_size]);
+                    tail_steps += 1
+                } else {
+                    match input.blocking_recv() {
+                        Some(Input::Pcm(pcm)) => session.push_pcm(&pcm),
+                        Some(Input::End) => ended = true,
+                        None => return Ok(()),
+                    }
+                    continue;
+                }
+            }
+            for output in session.step()? {
+                if let Some(text) = output.text.as_ref() {
+                    send(Event::Text(text.to_string()))?
+                }
+                if tail_steps > 0 {
+                    tail_pad_steps = if output.is_pad() { tail_pad_steps + 1 } else { 0 };
+                }
+                if let Some(pcm) = output.pcm {
+                    send(Event::Audio(pcm.flatten_all()?.to_vec1::<f32>()?))?
+                }
+            }
+            if tail_pad_steps >= crate::dubbing::END_PAD_STEPS
+                || tail_steps >= crate::dubbing::MAX_TAIL_STEPS
+            {
+                return Ok(());
+            }
+        }
+        tracing::info!(segment, "reached --max-steps, starting a new context");
+        // The audio that has not been processed yet goes to the new context.
+        pending = session.take_pending();
+        send(Event::Text("\n".to_string()))?;
+        segment += 1
+    }
+}
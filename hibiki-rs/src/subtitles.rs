@@ -0,0 +1,469 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Subtitles for the translated text, the cues are timed with the audio of their words in the
+// output, or with the steps at which the words were emitted in live mode, so that they follow
+// the source video. The format is picked from the file extension,
+// WebVTT for `.vtt` and SRT otherwise. In live mode the cues are appended to the file as they are
+// finalized, and can also be published as a HLS stream of WebVTT segments. The generation
+// statistics of the cues can be written alongside, so that reviewers can start with the cues the
+// model was the least confident about.
+
+use anyhow::{Context, Result};
+use std::io::Write;
+
+// Cues are split on sentence ends, on pauses and when they get too long to be read.
+const MAX_LINE_CHARS: usize = 42;
+const MAX_CUE_CHARS: usize = 2 * MAX_LINE_CHARS;
+const MAX_CUE_STEPS: usize = 75;
+const MIN_PAUSE_STEPS: usize = 10;
+// The same tail as for the alignment words, in live mode a word ends this many steps after its
+// last token when no other word follows.
+const MAX_WORD_TAIL_STEPS: usize = 6;
+// The duration of the HLS segments, in seconds.
+const HLS_SEGMENT_SECS: f64 = 6.;
+const HLS_PLAYLIST: &str = "subtitles.m3u8";
+
+struct Cue {
+    text: String,
+    start_step: usize,
+    end_step: usize,
+}
+
+// Groups the words in cues as they come, a cue is complete once the next word starts a new one.
+#[derive(Default)]
+struct CueBuilder {
+    current: Option<Cue>,
+    ends_sentence: bool,
+}
+
+impl CueBuilder {
+    fn push(&mut self, word: &crate::alignment::Word) -> Option<Cue> {
+        let split = match self.current.as_ref() {
+            None => true,
+            Some(cue) => {
+                self.ends_sentence
+                    || word.start_step >= cue.end_step + MIN_PAUSE_STEPS
+                    || word.end_step > cue.start_step + MAX_CUE_STEPS
+                    || cue.text.chars().count() + 1 + word.text.chars().count() > MAX_CUE_CHARS
+            }
+        };
+        self.ends_sentence = word.text.ends_with(['.', '!', '?']);
+        match self.current.as_mut() {
+            Some(cue) if !split => {
+                cue.text.push(' ');
+                cue.text.push_str(&word.text);
+                cue.end_step = word.end_step;
+                None
+            }
+            _ => self.current.replace(Cue {
+                text: word.text.clone(),
+                start_step: word.start_step,
+                end_step: word.end_step,
+            }),
+        }
+    }
+
+    fn finish(&mut self) -> Option<Cue> {
+        self.current.take()
+    }
+}
+
+fn cues(words: &[crate::alignment::Word]) -> Vec<Cue> {
+    let mut builder = CueBuilder::default();
+    let mut cues: Vec<Cue> = words.iter().filter_map(|word| builder.push(word)).collect();
+    cues.extend(builder.finish());
+    cues
+}
+
+// Breaks the cue text in two lines at the space closest to the middle when it is too long.
+fn wrap(text: &str) -> String {
+    if text.chars().count() <= MAX_LINE_CHARS {
+        return text.to_string();
+    }
+    let middle = text.len() / 2;
+    let split = text
+        .match_indices(' ')
+        .map(|(idx, _)| idx)
+        .min_by_key(|&idx| idx.abs_diff(middle))
+        .unwrap_or(text.len());
+    match text.split_at(split) {
+        (first, "") => first.to_string(),
+        (first, second) => format!("{first}\n{}", &second[1..]),
+    }
+}
+
+fn timestamp(secs: f64, separator: char) -> String {
+    let ms = (secs * 1000.).round() as u64;
+    let (h, m, s, ms) = (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000);
+    format!("{h:02}:{m:02}:{s:02}{separator}{ms:03}")
+}
+
+fn is_vtt(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|v| v.eq_ignore_ascii_case("vtt"))
+}
+
+fn vtt_header(language: &crate::lang::Language) -> String {
+    format!("WEBVTT\nLanguage: {}\n\n", language.tag())
+}
+
+// Formats a cue, `secs` gives the time of a step in seconds.
+fn format_cue(
+    idx: usize,
+    cue: &Cue,
+    secs: impl Fn(usize) -> f64,
+    separator: char,
+    language: &crate::lang::Language,
+) -> String {
+    let start = timestamp(secs(cue.start_step), separator);
+    let end = timestamp(secs(cue.end_step), separator);
+    let text = language.isolate(&wrap(&cue.text));
+    format!("{}\n{start} --> {end}\n{text}\n\n", idx + 1)
+}
+
+/// Writes the subtitles for the words, timed with their audio in the final output.
+pub fn write(
+    path: &std::path::Path,
+    words: &[crate::alignment::Word],
+    timeline: &crate::alignment::Timeline,
+    language: &crate::lang::Language,
+) -> Result<usize> {
+    let vtt = is_vtt(path);
+    let mut out = String::new();
+    if vtt {
+        out.push_str(&vtt_header(language))
+    }
+    let separator = if vtt { '.' } else { ',' };
+    let cues = cues(words);
+    for (idx, cue) in cues.iter().enumerate() {
+        out.push_str(&format_cue(idx, cue, |step| timeline.secs(step), separator, language))
+    }
+    std::fs::write(path, out)?;
+    Ok(cues.len())
+}
+
+// The statistics of a cue, over the steps from its start to its end.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct CueStats {
+    index: usize,
+    start: f64,
+    end: f64,
+    text: String,
+    // The mean and lowest log-probabilities of the text tokens, padding excluded.
+    mean_logprob: f32,
+    min_logprob: f32,
+    mean_step_ms: f64,
+}
+
+fn cue_stats(
+    idx: usize,
+    cue: &Cue,
+    history: &crate::longform::History,
+    step_latency_ms: &[f64],
+    timeline: &crate::alignment::Timeline,
+) -> CueStats {
+    let steps = |len: usize| cue.start_step.min(len)..cue.end_step.min(len);
+    let logprobs: Vec<f32> = history.text_tokens[steps(history.text_tokens.len())]
+        .iter()
+        .zip(history.text_logprobs[steps(history.text_logprobs.len())].iter())
+        .filter(|(&token, _)| token != 0 && token != 3)
+        .map(|(_, &logprob)| logprob)
+        .collect();
+    let latencies = &step_latency_ms[steps(step_latency_ms.len())];
+    CueStats {
+        index: idx + 1,
+        start: timeline.secs(cue.start_step),
+        end: timeline.secs(cue.end_step),
+        text: cue.text.clone(),
+        mean_logprob: logprobs.iter().sum::<f32>() / logprobs.len().max(1) as f32,
+        min_logprob: logprobs.iter().copied().reduce(f32::min).unwrap_or(0.),
+        mean_step_ms: latencies.iter().sum::<f64>() / latencies.len().max(1) as f64,
+    }
+}
+
+/// Writes the statistics of the cues of `write` as json, in the order of the cues: the mean and
+/// lowest log-probabilities of their text tokens and the mean latency of their steps.
+pub fn write_stats(
+    path: &std::path::Path,
+    words: &[crate::alignment::Word],
+    history: &crate::longform::History,
+    step_latency_ms: &[f64],
+    timeline: &crate::alignment::Timeline,
+) -> Result<usize> {
+    let stats: Vec<_> = cues(words)
+        .iter()
+        .enumerate()
+        .map(|(idx, cue)| cue_stats(idx, cue, history, step_latency_ms, timeline))
+        .collect();
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(file, &serde_json::json!({ "cues": stats }))?;
+    Ok(stats.len())
+}
+
+// A HLS subtitle stream, the WebVTT segments are written once no cue can start in them anymore
+// and the playlist is then replaced atomically so that players never read a partial one.
+struct HlsStream {
+    dir: std::path::PathBuf,
+    segment_steps: usize,
+    // The finalized cues that may still overlap the segments to come.
+    cues: Vec<(usize, Cue)>,
+    num_segments: usize,
+}
+
+impl HlsStream {
+    fn new(dir: &std::path::Path, step_duration: f64) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("cannot create the hls directory {dir:?}"))?;
+        let segment_steps = (HLS_SEGMENT_SECS / step_duration).round().max(1.) as usize;
+        let stream = Self { dir: dir.to_path_buf(), segment_steps, cues: vec![], num_segments: 0 };
+        stream.write_playlist(false)?;
+        Ok(stream)
+    }
+
+    fn write_playlist(&self, ended: bool) -> Result<()> {
+        let mut out = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n",
+            HLS_SEGMENT_SECS.ceil()
+        );
+        for idx in 0..self.num_segments {
+            out.push_str(&format!("#EXTINF:{HLS_SEGMENT_SECS:.3},\nsegment{idx:05}.vtt\n"))
+        }
+        if ended {
+            out.push_str("#EXT-X-ENDLIST\n")
+        }
+        let tmp = self.dir.join(format!("{HLS_PLAYLIST}.tmp"));
+        std::fs::write(&tmp, out)?;
+        std::fs::rename(&tmp, self.dir.join(HLS_PLAYLIST))?;
+        Ok(())
+    }
+
+    // Writes the segments that end before `step`, the cues starting after it are not known yet.
+    fn write_segments(
+        &mut self,
+        step: usize,
+        step_duration: f64,
+        language: &crate::lang::Language,
+    ) -> Result<()> {
+        let mut written = false;
+        while (self.num_segments + 1) * self.segment_steps <= step {
+            let start = self.num_segments * self.segment_steps;
+            let end = start + self.segment_steps;
+            // The timestamps are relative to the start of the capture.
+            let mut out = "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:0,LOCAL:00:00:00.000\n\n".to_string();
+            for (idx, cue) in self.cues.iter() {
+                if cue.start_step < end && cue.end_step > start {
+                    let secs = |step| step as f64 * step_duration;
+                    out.push_str(&format_cue(*idx, cue, secs, '.', language))
+                }
+            }
+            let path = self.dir.join(format!("segment{:05}.vtt", self.num_segments));
+            std::fs::write(&path, out)?;
+            self.cues.retain(|(_, cue)| cue.end_step > end);
+            self.num_segments += 1;
+            written = true
+        }
+        if written {
+            self.write_playlist(false)?
+        }
+        Ok(())
+    }
+}
+
+/// Subtitles written while translating live, fed with the text generated at each step.
+pub struct LiveSubtitles {
+    file: Option<std::fs::File>,
+    separator: char,
+    hls: Option<HlsStream>,
+    step_duration: f64,
+    language: crate::lang::Language,
+    builder: CueBuilder,
+    word: Option<crate::alignment::Word>,
+    last_token_step: usize,
+    num_cues: usize,
+}
+
+impl LiveSubtitles {
+    /// Starts the subtitles file at `path` and the HLS stream in `hls_dir`, both are optional.
+    pub fn new(
+        path: Option<&std::path::Path>,
+        hls_dir: Option<&std::path::Path>,
+        step_duration: f64,
+        language: &crate::lang::Language,
+    ) -> Result<Self> {
+        let file = match path {
+            None => None,
+            Some(path) => {
+                let mut file = std::fs::File::create(path)
+                    .with_context(|| format!("cannot create the subtitles file {path:?}"))?;
+                if is_vtt(path) {
+                    file.write_all(vtt_header(language).as_bytes())?
+                }
+                Some(file)
+            }
+        };
+        let separator = if path.is_some_and(is_vtt) { '.' } else { ',' };
+        let hls = match hls_dir {
+            None => None,
+            Some(dir) => Some(HlsStream::new(dir, step_duration)?),
+        };
+        Ok(Self {
+            file,
+            separator,
+            hls,
+            step_duration,
+            language: language.clone(),
+            builder: CueBuilder::default(),
+            word: None,
+            last_token_step: 0,
+            num_cues: 0,
+        })
+    }
+
+    fn publish(&mut self, cue: Cue) -> Result<()> {
+        let idx = self.num_cues;
+        self.num_cues += 1;
+        if let Some(file) = self.file.as_mut() {
+            let secs = |step| step as f64 * self.step_duration;
+            let cue = format_cue(idx, &cue, secs, self.separator, &self.language);
+            file.write_all(cue.as_bytes())?;
+        }
+        if let Some(hls) = self.hls.as_mut() {
+            hls.cues.push((idx, cue))
+        }
+        Ok(())
+    }
+
+    fn end_word(&mut self, step: usize) -> Result<()> {
+        if let Some(mut word) = self.word.take() {
+            word.end_step = step.min(self.last_token_step + MAX_WORD_TAIL_STEPS);
+            if let Some(cue) = self.builder.push(&word) {
+                self.publish(cue)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes the text generated at `step`, counted from the start of the capture.
+    pub fn push(&mut self, step: usize, text: Option<&str>) -> Result<()> {
+        match text.filter(|v| !v.is_empty()) {
+            Some(text) => {
+                if text.starts_with(char::is_whitespace) || self.word.is_none() {
+                    self.end_word(step)?;
+                    let text = text.trim_start();
+                    if !text.is_empty() {
+                        self.word = Some(crate::alignment::Word {
+                            text: text.to_string(),
+                            start_step: step,
+                            end_step: step,
+                        })
+                    }
+                } else if let Some(word) = self.word.as_mut() {
+                    word.text.push_str(text)
+                }
+                self.last_token_step = step
+            }
+            // Without new words the last cue is complete after a pause, rather than being held
+            // until the speaker resumes.
+            None if step >= self.last_token_step + MAX_WORD_TAIL_STEPS + MIN_PAUSE_STEPS => {
+                self.flush(step)?
+            }
+            None => {}
+        }
+        let pending_start = match (self.word.as_ref(), self.builder.current.as_ref()) {
+            (_, Some(cue)) => cue.start_step,
+            (Some(word), None) => word.start_step,
+            (None, None) => step,
+        };
+        if let Some(hls) = self.hls.as_mut() {
+            hls.write_segments(pending_start, self.step_duration, &self.language)?
+        }
+        Ok(())
+    }
+
+    /// Publishes the pending text, e.g. before starting a new context.
+    pub fn flush(&mut self, step: usize) -> Result<()> {
+        self.end_word(step)?;
+        match self.builder.finish() {
+            Some(cue) => self.publish(cue),
+            None => Ok(()),
+        }
+    }
+
+    /// Publishes the remaining text and ends the HLS stream, once the capture is over.
+    pub fn finish(&mut self, step: usize) -> Result<()> {
+        self.flush(step)?;
+        if let Some(hls) = self.hls.as_mut() {
+            // The last segment is complete once all the cues are known.
+            let end = hls.cues.iter().map(|(_, cue)| cue.end_step).max().unwrap_or(0).max(step);
+            let end = end.div_ceil(hls.segment_steps) * hls.segment_steps;
+            hls.write_segments(end, self.step_duration, &self.language)?;
+            hls.write_playlist(true)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_long_lines() {
+        assert_eq!(wrap("A short line."), "A short line.");
+        let text = "The conference will start again in about fifteen minutes.";
+        let wrapped = wrap(text);
+        assert_eq!(wrapped, "The conference will start\nagain in about fifteen minutes.");
+        assert!(wrapped.lines().all(|v| v.chars().count() <= MAX_LINE_CHARS));
+        // Without any space the text is kept on a single line.
+        let long_word = "a".repeat(MAX_LINE_CHARS + 1);
+        assert_eq!(wrap(&long_word), long_word);
+    }
+
+    #[test]
+    fn wrap_counts_chars() {
+        // 42 chars but more bytes, this fits on a line.
+        let text = "é".repeat(20) + " " + &"è".repeat(21);
+        assert_eq!(wrap(&text), text);
+    }
+
+    #[test]
+    fn stats_cover_the_cue_steps() {
+        let history = crate::longform::History {
+            text_tokens: vec![0, 10, 11, 3, 0, 12, 0, 0],
+            text_logprobs: vec![0., -1., -3., 0., 0., -0.5, 0., 0.],
+            ..Default::default()
+        };
+        let latency = [10., 20., 30., 40., 50., 60., 70., 80.];
+        // 80ms of audio per step, the first step being decoded one step later.
+        let step_offsets: Vec<usize> =
+            (0..=8usize).map(|step| step.saturating_sub(1) * 80).collect();
+        let timeline = crate::alignment::Timeline {
+            step_offsets: &step_offsets,
+            acoustic_delay: 1,
+            skip: 0,
+            scale: 1.,
+            sample_rate: 1000,
+        };
+        let cue = Cue { text: "Hello there.".to_string(), start_step: 1, end_step: 4 };
+        let stats = cue_stats(0, &cue, &history, &latency, &timeline);
+        assert_eq!(stats.index, 1);
+        assert_eq!(stats.mean_logprob, -2.);
+        assert_eq!(stats.min_logprob, -3.);
+        assert_eq!(stats.mean_step_ms, 30.);
+        // The steps past the generated ones are ignored.
+        let cue = Cue { text: "Bye.".to_string(), start_step: 5, end_step: 12 };
+        let stats = cue_stats(1, &cue, &history, &latency, &timeline);
+        assert_eq!((stats.mean_logprob, stats.min_logprob), (-0.5, -0.5));
+        assert_eq!(stats.mean_step_ms, 70.);
+        // The cues are timed with the output audio, which ends after the generated steps.
+        assert!((stats.start - 0.4).abs() < 1e-9);
+        assert!((stats.end - 0.56).abs() < 1e-9);
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(0., ','), "00:00:00,000");
+        assert_eq!(timestamp(3723.4567, '.'), "01:02:03.457");
+    }
+}
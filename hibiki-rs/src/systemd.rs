@@ -0,0 +1,54 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Minimal support for the systemd socket activation and notification protocols, see
+// sd_listen_fds(3) and sd_notify(3).
+
+use anyhow::Result;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+
+// The first file descriptor passed by systemd, the following ones are numbered sequentially.
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Returns the listening socket passed by systemd when the process has been socket activated.
+/// Only the first passed socket is used, it is expected to be a unix stream socket.
+pub fn listener() -> Result<Option<UnixListener>> {
+    use std::os::fd::FromRawFd;
+
+    let pid = std::env::var("LISTEN_PID").ok().and_then(|v| v.parse::<u32>().ok());
+    let num_fds = std::env::var("LISTEN_FDS").ok().and_then(|v| v.parse::<i32>().ok());
+    // The variables are not meant to be inherited by child processes.
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    match (pid, num_fds) {
+        (Some(pid), Some(num_fds)) if pid == std::process::id() && num_fds >= 1 => {
+            if num_fds > 1 {
+                tracing::warn!(num_fds, "only the first socket passed by systemd is used")
+            }
+            // Safety: systemd guarantees that the descriptor is open and owned by this process.
+            let listener = unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+            Ok(Some(listener))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Sends a state update such as `READY=1` to the service manager, this does nothing when not
+/// running under systemd.
+pub fn notify(state: &str) -> Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else { return Ok(()) };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    // Names starting with @ refer to the linux abstract socket namespace.
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path.as_ref())?;
+    Ok(())
+}
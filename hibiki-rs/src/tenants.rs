@@ -0,0 +1,202 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Tenants of a shared daemon, given with `--tenants tenants.toml`:
+//   root = "/srv/hibiki"
+//   [tenants.acme]
+//   api_key = "..."
+//   retention_days = 30
+// The requests then carry an "api_key" field. The paths of the jobs are relative to the
+// directory of their tenant under the root, the translation memory and the duplicate detection
+// are kept per tenant, and the jobs of the other tenants are not visible. The files of a tenant,
+// outputs and translation memory included, are deleted once older than its retention period.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    pub api_key: String,
+    /// The files of the tenant are kept forever when not set.
+    pub retention_days: Option<f64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    root: PathBuf,
+    tenants: BTreeMap<String, Tenant>,
+}
+
+pub struct Tenants {
+    root: PathBuf,
+    tenants: BTreeMap<String, Tenant>,
+}
+
+impl Tenants {
+    pub fn load(file: &Path) -> Result<Self> {
+        let config = std::fs::read_to_string(file)
+            .with_context(|| format!("cannot read the tenants file {file:?}"))?;
+        let Config { root, tenants } = toml::from_str(&config)?;
+        let mut api_keys = std::collections::HashSet::new();
+        for (name, tenant) in tenants.iter() {
+            // The names are used as directory names.
+            let valid = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if name.is_empty() || !valid {
+                anyhow::bail!("invalid tenant name '{name}', only letters, digits, - and _")
+            }
+            if tenant.api_key.is_empty() || !api_keys.insert(tenant.api_key.as_str()) {
+                anyhow::bail!("the api key of tenant '{name}' is empty or not unique")
+            }
+        }
+        if tenants.is_empty() {
+            anyhow::bail!("no tenants in {file:?}")
+        }
+        for name in tenants.keys() {
+            std::fs::create_dir_all(root.join(name))?
+        }
+        Ok(Self { root, tenants })
+    }
+
+    /// The tenant with this api key.
+    pub fn authenticate(&self, api_key: Option<&str>) -> Result<&str> {
+        let Some(api_key) = api_key else { anyhow::bail!("missing api_key") };
+        match self.tenants.iter().find(|(_, tenant)| tenant.api_key == api_key) {
+            Some((name, _)) => Ok(name),
+            None => anyhow::bail!("invalid api_key"),
+        }
+    }
+
+    pub fn dir(&self, tenant: &str) -> PathBuf {
+        self.root.join(tenant)
+    }
+
+    /// Resolves a path given by a tenant within its directory, the paths that could escape it are
+    /// rejected.
+    pub fn resolve(&self, tenant: &str, path: &Path) -> Result<PathBuf> {
+        if path.as_os_str().is_empty()
+            || !path.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            anyhow::bail!("invalid path {path:?}, expected a relative path without ..")
+        }
+        Ok(self.dir(tenant).join(path))
+    }
+
+    /// Deletes the files older than the retention period of their tenant, including the entries
+    /// of the translation memory under `memory_dir`. Returns the number of deleted files.
+    pub fn sweep(&self, memory_dir: Option<&Path>) -> usize {
+        let now = std::time::SystemTime::now();
+        let mut deleted = 0;
+        for (name, tenant) in self.tenants.iter() {
+            let Some(days) = tenant.retention_days else { continue };
+            let retention = std::time::Duration::from_secs_f64(days.max(0.) * 86400.);
+            let cutoff = now.checked_sub(retention).unwrap_or(std::time::UNIX_EPOCH);
+            let dirs = std::iter::once(self.dir(name)).chain(memory_dir.map(|v| v.join(name)));
+            for dir in dirs {
+                match sweep_dir(&dir, cutoff) {
+                    Ok(count) => deleted += count,
+                    Err(err) => tracing::warn!(tenant = name, ?dir, ?err, "retention sweep failed"),
+                }
+            }
+        }
+        deleted
+    }
+}
+
+fn sweep_dir(dir: &Path, cutoff: std::time::SystemTime) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut deleted = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            deleted += sweep_dir(&entry.path(), cutoff)?;
+            // Only succeeds once the directory is empty, e.g. for the translation memory entries.
+            let _ = std::fs::remove_dir(entry.path());
+        } else if entry.metadata()?.modified()? < cutoff {
+            std::fs::remove_file(entry.path())?;
+            deleted += 1
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, tenants: &str) -> (Result<Tenants>, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("hibiki-tenants-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("tenants.toml");
+        let root = dir.join("root");
+        std::fs::write(&file, format!("root = {root:?}\n{tenants}")).unwrap();
+        (Tenants::load(&file), dir)
+    }
+
+    #[test]
+    fn authenticate_and_resolve() -> Result<()> {
+        let config = "[tenants.acme]\napi_key = \"k1\"\n[tenants.globex]\napi_key = \"k2\"\n";
+        let (tenants, dir) = load("resolve", config);
+        let tenants = tenants?;
+        assert_eq!(tenants.authenticate(Some("k2"))?, "globex");
+        assert!(tenants.authenticate(Some("k3")).is_err());
+        assert!(tenants.authenticate(None).is_err());
+        assert!(dir.join("root/acme").is_dir());
+        let path = tenants.resolve("acme", Path::new("in/talk.wav"))?;
+        assert_eq!(path, dir.join("root/acme/in/talk.wav"));
+        for path in ["../globex/talk.wav", "/etc/passwd", "in/../../x", ""] {
+            assert!(tenants.resolve("acme", Path::new(path)).is_err(), "{path}")
+        }
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_configs() {
+        for (name, config) in [
+            ("name", "[tenants.\"a/b\"]\napi_key = \"k\"\n"),
+            ("dup", "[tenants.a]\napi_key = \"k\"\n[tenants.b]\napi_key = \"k\"\n"),
+            ("empty-key", "[tenants.a]\napi_key = \"\"\n"),
+            ("none", "[tenants]\n"),
+            ("unknown", "[tenants.a]\napi_key = \"k\"\nquota = 3\n"),
+        ] {
+            let (tenants, dir) = load(name, config);
+            assert!(tenants.is_err(), "{name}");
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn sweep_old_files() -> Result<()> {
+        let config = "[tenants.a]\napi_key = \"k1\"\nretention_days = 1\n\
+                      [tenants.b]\napi_key = \"k2\"\n";
+        let (tenants, dir) = load("sweep", config);
+        let tenants = tenants?;
+        let memory_dir = dir.join("memory");
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 86400);
+        let write = |path: PathBuf, modified| -> Result<()> {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::File::create(&path)?.set_modified(modified)?;
+            Ok(())
+        };
+        write(dir.join("root/a/old.wav"), old)?;
+        write(dir.join("root/a/new.wav"), std::time::SystemTime::now())?;
+        write(memory_dir.join("a/entry/out.wav"), old)?;
+        // The tenants without a retention period keep their files.
+        write(dir.join("root/b/old.wav"), old)?;
+        assert_eq!(tenants.sweep(Some(&memory_dir)), 2);
+        assert!(dir.join("root/a/new.wav").exists());
+        assert!(!dir.join("root/a/old.wav").exists());
+        assert!(!memory_dir.join("a/entry").exists());
+        assert!(dir.join("root/b/old.wav").exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
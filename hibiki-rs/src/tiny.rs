@@ -0,0 +1,207 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// A random-weight model with the same structure as hibiki but a tiny lm, so that the whole
+// pipeline can be run locally in seconds without downloading the checkpoints. The audio codec
+// keeps the mimi architecture as its config is fixed, only its weights are random.
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use std::path::Path;
+
+const CONFIG_FILE: &str = "config.toml";
+const LM_FILE: &str = "tiny-lm.safetensors";
+const MIMI_FILE: &str = "tiny-mimi.safetensors";
+const TOKENIZER_FILE: &str = "tiny-tokenizer.model";
+
+// Ids 0 to 3 are the unknown, bos, eos and padding pieces, the lm uses 0 as the end of padding
+// token and 3 as the padding token.
+const SPECIAL_PIECES: [(&str, u64); 4] = [("<unk>", 2), ("<s>", 3), ("</s>", 3), ("<pad>", 3)];
+const CHARS: &str = "abcdefghijklmnopqrstuvwxyz0123456789.,'?!-";
+
+fn transformer_config(d_model: usize, num_layers: usize, context: usize, rope: bool) -> String {
+    let positional_embedding = if rope { "Rope" } else { "None" };
+    format!(
+        r#"d_model = {d_model}
+num_heads = 4
+num_layers = {num_layers}
+dim_feedforward = {}
+causal = true
+norm_first = true
+bias_ff = false
+bias_attn = false
+context = {context}
+max_period = 10000
+use_conv_block = false
+use_conv_bias = true
+gating = "silu"
+norm = "RmsNorm"
+positional_embedding = "{positional_embedding}"
+conv_layout = false
+conv_kernel_size = 3
+kv_repeat = 1
+max_seq_len = 4096
+"#,
+        d_model * 4
+    )
+}
+
+fn config(text_vocab_size: usize) -> String {
+    let transformer = transformer_config(64, 2, 3000, true);
+    let depformer = transformer_config(64, 1, 8, false);
+    format!(
+        r#"mimi_name = "{MIMI_FILE}"
+moshi_name = "{LM_FILE}"
+tokenizer_name = "{TOKENIZER_FILE}"
+
+[model]
+text_in_vocab_size = {}
+text_out_vocab_size = {text_vocab_size}
+audio_vocab_size = 2049
+audio_codebooks = 16
+
+[model.transformer]
+{transformer}
+[model.depformer]
+num_slices = 8
+
+[model.depformer.transformer]
+{depformer}
+[model.conditioners.description]
+type = "Lut"
+n_bins = 5
+dim = 16
+possible_values = ["very_bad", "bad", "neutral", "good", "very_good"]
+"#,
+        text_vocab_size + 1
+    )
+}
+
+// Minimal protobuf encoding, enough for the sentencepiece model proto.
+fn varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8)
+}
+
+fn field_varint(buf: &mut Vec<u8>, field: u64, v: u64) {
+    varint(buf, field << 3);
+    varint(buf, v)
+}
+
+fn field_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(buf, (field << 3) | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes)
+}
+
+fn field_f32(buf: &mut Vec<u8>, field: u64, v: f32) {
+    varint(buf, (field << 3) | 5);
+    buf.extend_from_slice(&v.to_le_bytes())
+}
+
+/// A character level sentencepiece model, returns the serialized proto and the vocab size.
+fn tokenizer() -> (Vec<u8>, usize) {
+    let mut pieces: Vec<(String, u64)> =
+        SPECIAL_PIECES.iter().map(|(p, t)| (p.to_string(), *t)).collect();
+    pieces.push(("\u{2581}".to_string(), 1));
+    pieces.extend(CHARS.chars().map(|c| (c.to_string(), 1)));
+    let mut model = vec![];
+    for (piece, type_) in pieces.iter() {
+        let mut p = vec![];
+        field_bytes(&mut p, 1, piece.as_bytes());
+        field_f32(&mut p, 2, 0.);
+        field_varint(&mut p, 3, *type_);
+        field_bytes(&mut model, 1, &p);
+    }
+    let mut trainer_spec = vec![];
+    field_varint(&mut trainer_spec, 3, 4); // CHAR model
+    field_varint(&mut trainer_spec, 4, pieces.len() as u64);
+    field_varint(&mut trainer_spec, 43, 3); // pad_id
+    field_bytes(&mut model, 2, &trainer_spec);
+    let mut normalizer_spec = vec![];
+    field_bytes(&mut normalizer_spec, 1, b"identity");
+    field_bytes(&mut model, 3, &normalizer_spec);
+    (model, pieces.len())
+}
+
+// Splitmix64, the cpu device of candle cannot be seeded and the weights should only depend on
+// the seed.
+struct Rng(u64);
+
+impl Rng {
+    // Uniform in [-0.035, 0.035), i.e. a standard deviation of 0.02.
+    fn weight(&mut self) -> f32 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        ((z >> 40) as f32 / (1u64 << 24) as f32 - 0.5) * 0.07
+    }
+}
+
+// Replaces the zero initialized variables with random values, the normalization weights are set
+// to one so that the activations do not vanish. The variables are sorted by name so that the
+// weights are deterministic.
+fn randomize(varmap: &candle_nn::VarMap, rng: &mut Rng) -> Result<()> {
+    let data = varmap.data().lock().unwrap();
+    let mut vars: Vec<_> = data.iter().collect();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, var) in vars {
+        let t = var.as_tensor();
+        let value = if name.contains("norm") || name.ends_with("alpha") {
+            t.ones_like()?
+        } else {
+            let values: Vec<f32> = (0..t.elem_count()).map(|_| rng.weight()).collect();
+            Tensor::from_vec(values, t.shape(), t.device())?
+        };
+        var.set(&value)?
+    }
+    Ok(())
+}
+
+fn save(varmap: &candle_nn::VarMap, path: &Path) -> Result<()> {
+    let tensors = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| Ok((name.clone(), var.as_tensor().to_dtype(DType::BF16)?)))
+        .collect::<Result<std::collections::HashMap<_, _>>>()?;
+    candle::safetensors::save(&tensors, path)?;
+    Ok(())
+}
+
+/// Writes the config, weights and tokenizer of a random tiny model to `out_dir`, this can be used
+/// with `--config` and the model file flags of the gen subcommand.
+pub fn write(out_dir: &Path, seed: u64) -> Result<()> {
+    let dev = Device::Cpu;
+    let mut rng = Rng(seed);
+    std::fs::create_dir_all(out_dir)?;
+
+    let (tokenizer, text_vocab_size) = tokenizer();
+    std::fs::write(out_dir.join(TOKENIZER_FILE), tokenizer)?;
+    let config_str = config(text_vocab_size);
+    let config: crate::gen::Config = toml::from_str(&config_str)?;
+    std::fs::write(out_dir.join(CONFIG_FILE), config_str)?;
+
+    tracing::info!("creating the lm");
+    let varmap = candle_nn::VarMap::new();
+    let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    moshi::lm::LmModel::new(&config.model, moshi::nn::MaybeQuantizedVarBuilder::Real(vb))?;
+    randomize(&varmap, &mut rng)?;
+    save(&varmap, &out_dir.join(LM_FILE))?;
+
+    tracing::info!("creating the audio tokenizer");
+    let varmap = candle_nn::VarMap::new();
+    let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &dev);
+    moshi::mimi::Mimi::new(moshi::mimi::Config::v0_1(None), vb)?;
+    randomize(&varmap, &mut rng)?;
+    save(&varmap, &out_dir.join(MIMI_FILE))?;
+    tracing::info!(?out_dir, "wrote the tiny model");
+    Ok(())
+}
@@ -0,0 +1,117 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Generation traces for inspecting a run, given with `--trace trace.json`: for every step the
+// text token and its log-probability and the levels of the input and of the generated audio, and
+// for every batch of steps the processing time and the lag behind real-time. With a `.html`
+// extension the trace is embedded in the bundled viewer, a standalone page that plots it so that
+// the hesitations and the slow steps can be spotted without writing analysis scripts.
+
+use anyhow::Result;
+
+const VIEWER: &str = include_str!("trace.html");
+// Replaced by the trace in the viewer, the page can also load a json trace when opened as is.
+const DATA_MARKER: &str = "/*TRACE_DATA*/null";
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct Batch {
+    start_step: usize,
+    num_steps: usize,
+    elapsed_ms: f64,
+    lag_ms: f64,
+}
+
+pub struct Trace {
+    step_duration: f64,
+    frame_size: usize,
+    text_start_token: u32,
+    batches: Vec<Batch>,
+}
+
+fn level_db(pcm: &[f32]) -> Option<f64> {
+    if pcm.is_empty() {
+        return None;
+    }
+    // Rounded to keep the traces of long runs small.
+    let db = crate::events::frame_features(pcm).db as f64;
+    Some((db * 10.).round() / 10.)
+}
+
+impl Trace {
+    pub fn new(step_duration: f64, frame_size: usize, text_start_token: u32) -> Self {
+        Self { step_duration, frame_size, text_start_token, batches: vec![] }
+    }
+
+    pub fn record_batch(
+        &mut self,
+        start_step: usize,
+        num_steps: usize,
+        elapsed: std::time::Duration,
+        lag: std::time::Duration,
+    ) {
+        self.batches.push(Batch {
+            start_step,
+            num_steps,
+            elapsed_ms: elapsed.as_secs_f64() * 1000.,
+            lag_ms: lag.as_secs_f64() * 1000.,
+        })
+    }
+
+    /// Writes the trace of a take from the text tokens and log-probabilities of its history, the
+    /// input audio of step `i` is in frame `i` of the input while the output audio of each
+    /// step is located with the `timeline`, as it is only decoded a few steps later.
+    pub fn write(
+        &self,
+        path: &std::path::Path,
+        history: &crate::longform::History,
+        text_tokenizer: &sentencepiece::SentencePieceProcessor,
+        in_pcm: &[f32],
+        out_pcm: &[f32],
+        timeline: &crate::alignment::Timeline,
+    ) -> Result<()> {
+        let frame = |pcm: &[f32], step: usize| {
+            let start = (step * self.frame_size).min(pcm.len());
+            let end = ((step + 1) * self.frame_size).min(pcm.len());
+            level_db(&pcm[start..end])
+        };
+        let mut prev_text_token = self.text_start_token;
+        let mut steps = Vec::with_capacity(history.text_tokens.len());
+        for (step, &text_token) in history.text_tokens.iter().enumerate() {
+            let text = if text_token == 0 || text_token == 3 {
+                None
+            } else {
+                let text = crate::gen::text(
+                    text_tokenizer,
+                    prev_text_token,
+                    text_token,
+                    self.text_start_token,
+                );
+                prev_text_token = text_token;
+                text
+            };
+            steps.push(serde_json::json!({
+                "text_token": text_token,
+                "text": text,
+                "text_logprob": history.text_logprobs.get(step),
+                "input_db": frame(in_pcm, step),
+                "output_db": out_pcm.get(timeline.samples(step)).and_then(level_db),
+            }))
+        }
+        let trace = serde_json::json!({
+            "step_duration": self.step_duration,
+            "steps": steps,
+            "batches": self.batches,
+        });
+        let is_html = path.extension().is_some_and(|v| v.eq_ignore_ascii_case("html"));
+        if is_html {
+            // The closing script tags cannot appear in the embedded json.
+            let data = serde_json::to_string(&trace)?.replace("</", "<\\/");
+            std::fs::write(path, VIEWER.replace(DATA_MARKER, &data))?
+        } else {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            serde_json::to_writer(file, &trace)?
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Periodic saving of the transcript during long generations.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub struct Autosave {
+    path: PathBuf,
+    interval: Duration,
+    last_save: Instant,
+    saved_len: usize,
+    language: crate::lang::Language,
+}
+
+impl Autosave {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        interval: Duration,
+        language: crate::lang::Language,
+    ) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            interval,
+            last_save: Instant::now(),
+            saved_len: 0,
+            language,
+        }
+    }
+
+    /// Saves the transcript if the interval has elapsed since the last save and the text has
+    /// changed.
+    pub fn maybe_save(&mut self, text: &str) -> Result<()> {
+        if self.last_save.elapsed() >= self.interval && text.len() != self.saved_len {
+            self.save(text)?
+        }
+        Ok(())
+    }
+
+    /// Writes the transcript to a temporary file first and renames it so that the file on disk
+    /// is never left half written. Right-to-left text is wrapped in directional isolates.
+    pub fn save(&mut self, text: &str) -> Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, self.language.isolate(text))?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.last_save = Instant::now();
+        self.saved_len = text.len();
+        tracing::debug!(path = ?self.path, len = text.len(), "saved the transcript");
+        Ok(())
+    }
+}
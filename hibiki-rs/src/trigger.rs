@@ -0,0 +1,136 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Start and stop of the translation on signal tones, so that a live source is only translated
+// between the two tones rather than continuously.
+
+// Fraction of the frame energy that has to be at the tone frequency.
+const TONE_ENERGY_RATIO: f32 = 0.5;
+// Minimum energy of a tone frame, about -40dB.
+const MIN_TONE_ENERGY: f32 = 1e-4;
+// Number of consecutive 80ms frames the tone has to be present for, i.e. 240ms.
+const MIN_TONE_FRAMES: usize = 3;
+
+/// Detects a pure tone at a given frequency using the Goertzel algorithm.
+#[derive(Debug, Clone)]
+pub struct ToneDetector {
+    freq: f32,
+    sample_rate: usize,
+    frames_with_tone: usize,
+}
+
+impl ToneDetector {
+    pub fn new(freq: f32, sample_rate: usize) -> Self {
+        Self { freq, sample_rate, frames_with_tone: 0 }
+    }
+
+    // The energy at the tone frequency, normalized so that it is comparable to the mean energy
+    // of the frame.
+    fn tone_energy(&self, frame: &[f32]) -> f32 {
+        let n = frame.len();
+        let k = (0.5 + n as f32 * self.freq / self.sample_rate as f32).floor();
+        let coeff = 2. * (2. * std::f32::consts::PI * k / n as f32).cos();
+        let (mut s1, mut s2) = (0f32, 0f32);
+        for &x in frame.iter() {
+            let s = x + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s;
+        }
+        let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+        2. * power / (n * n) as f32
+    }
+
+    /// Processes a frame of audio, returns true once the tone has been present long enough.
+    /// The detection fires a single time per tone.
+    pub fn step(&mut self, frame: &[f32]) -> bool {
+        if frame.is_empty() {
+            return false;
+        }
+        let energy = frame.iter().map(|v| v * v).sum::<f32>() / frame.len() as f32;
+        let has_tone =
+            energy > MIN_TONE_ENERGY && self.tone_energy(frame) > TONE_ENERGY_RATIO * energy;
+        self.frames_with_tone = if has_tone { self.frames_with_tone + 1 } else { 0 };
+        self.frames_with_tone == MIN_TONE_FRAMES
+    }
+}
+
+/// Gate that opens on the start tone and closes on the stop tone, the frames on which the tones
+/// are detected are not passed through.
+#[derive(Debug, Clone)]
+pub struct ToneTrigger {
+    start: ToneDetector,
+    stop: ToneDetector,
+    active: bool,
+}
+
+impl ToneTrigger {
+    pub fn new(start_freq: f32, stop_freq: f32, sample_rate: usize) -> Self {
+        Self {
+            start: ToneDetector::new(start_freq, sample_rate),
+            stop: ToneDetector::new(stop_freq, sample_rate),
+            active: false,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Processes a frame and returns whether it should be translated.
+    pub fn step(&mut self, frame: &[f32]) -> bool {
+        if self.active {
+            if self.stop.step(frame) {
+                tracing::info!("stop tone detected");
+                self.active = false
+            }
+        } else if self.start.step(frame) {
+            tracing::info!("start tone detected");
+            self.active = true;
+            return false;
+        }
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_SIZE: usize = 1920;
+
+    fn tone(freq: f32, frame_idx: usize) -> Vec<f32> {
+        (frame_idx * FRAME_SIZE..(frame_idx + 1) * FRAME_SIZE)
+            .map(|i| 0.3 * (2. * std::f32::consts::PI * freq * i as f32 / 24000.).sin())
+            .collect()
+    }
+
+    #[test]
+    fn detect_the_tone_once() {
+        let mut detector = ToneDetector::new(1000., 24000);
+        let detections: Vec<bool> = (0..6).map(|i| detector.step(&tone(1000., i))).collect();
+        assert_eq!(detections, [false, false, true, false, false, false]);
+        // Other frequencies and quiet frames are ignored.
+        let mut detector = ToneDetector::new(1000., 24000);
+        assert!(!(0..6).any(|i| detector.step(&tone(1500., i))));
+        let quiet: Vec<f32> = tone(1000., 0).iter().map(|v| v * 0.01).collect();
+        assert!(!(0..6).any(|_| detector.step(&quiet)));
+        assert!(!detector.step(&[]));
+    }
+
+    #[test]
+    fn gate_between_the_tones() {
+        let mut trigger = ToneTrigger::new(1000., 2000., 24000);
+        let speech = tone(440., 0);
+        assert!(!trigger.step(&speech));
+        let started: Vec<bool> = (0..3).map(|i| trigger.step(&tone(1000., i))).collect();
+        assert_eq!(started, [false; 3]);
+        assert!(trigger.is_active());
+        assert!(trigger.step(&speech));
+        // The frames of the stop tone pass until it is detected.
+        let stopped: Vec<bool> = (0..3).map(|i| trigger.step(&tone(2000., i))).collect();
+        assert_eq!(stopped, [true, true, false]);
+        assert!(!trigger.is_active());
+        assert!(!trigger.step(&speech));
+    }
+}
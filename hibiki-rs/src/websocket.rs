@@ -0,0 +1,467 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Minimal server side of the WebSocket protocol (RFC 6455) on top of a blocking tcp stream, this
+// only covers what the serve subcommand needs: the opening handshake, reassembling fragmented
+// messages, answering pings and the closing handshake. There are no extensions, in particular
+// no compression, and the handshake and the messages are bounded by MAX_HANDSHAKE_BYTES and
+// MAX_MESSAGE_LEN.
+
+use anyhow::{Context, Result};
+use std::io::{BufRead, Read, Write};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADER_LINES: usize = 100;
+// The request line and the headers together, a client cannot make the server buffer more.
+const MAX_HANDSHAKE_BYTES: u64 = 16 << 10;
+const MAX_MESSAGE_LEN: usize = 16 << 20;
+// Time left to the client to answer a closing handshake started by the server.
+const CLOSE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Close status codes, see section 7.4.1 of the RFC.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let v = chunk.iter().enumerate().fold(0u32, |v, (i, &b)| v | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(v >> (18 - 6 * i) & 0x3f) as usize] as char)
+            } else {
+                out.push('=')
+            }
+        }
+    }
+    out
+}
+
+fn accept_key(key: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{ACCEPT_GUID}").as_bytes(),
+    );
+    base64(digest.as_ref())
+}
+
+/// Returns the value of a parameter from the query string of a request path.
+pub fn query<'a>(path: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = path.split_once('?')?;
+    query.split('&').find_map(|kv| match kv.split_once('=') {
+        Some((k, v)) if k == name => Some(v),
+        _ => None,
+    })
+}
+
+// Reads a line of the handshake, the requests that go over MAX_HANDSHAKE_BYTES are rejected.
+fn read_header_line(
+    stream: &mut std::io::Take<&mut std::io::BufReader<std::net::TcpStream>>,
+    line: &mut String,
+) -> Result<usize> {
+    let len = stream.read_line(line)?;
+    if stream.limit() == 0 && !line.ends_with('\n') {
+        let mut writer = stream.get_ref().get_ref();
+        write!(
+            writer,
+            "HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\n\r\n"
+        )?;
+        anyhow::bail!("the handshake is larger than {MAX_HANDSHAKE_BYTES} bytes")
+    }
+    Ok(len)
+}
+
+/// An upgrade request whose headers have been read, to be accepted or rejected.
+pub struct Handshake {
+    stream: std::io::BufReader<std::net::TcpStream>,
+    path: String,
+    key: Option<String>,
+}
+
+impl Handshake {
+    /// Reads the http request opening the connection.
+    pub fn read(stream: std::net::TcpStream) -> Result<Self> {
+        // Do not let a client that never sends its headers hold the connection.
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+        let mut stream = std::io::BufReader::new(stream);
+        let mut limited = (&mut stream).take(MAX_HANDSHAKE_BYTES);
+        let mut line = String::new();
+        read_header_line(&mut limited, &mut line)?;
+        let path = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["GET", path, version] if version.starts_with("HTTP/") => path.to_string(),
+            _ => anyhow::bail!("unexpected request {:?}", line.trim_end()),
+        };
+        let mut key = None;
+        let mut upgrade = false;
+        for _ in 0..MAX_HEADER_LINES {
+            line.clear();
+            if read_header_line(&mut limited, &mut line)? == 0 {
+                anyhow::bail!("connection closed during the handshake")
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                stream.get_ref().set_read_timeout(None)?;
+                let key = if upgrade { key } else { None };
+                return Ok(Self { stream, path, key });
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+                if name.eq_ignore_ascii_case("upgrade") {
+                    upgrade = value.eq_ignore_ascii_case("websocket")
+                } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                    key = Some(value.to_string())
+                }
+            }
+        }
+        anyhow::bail!("too many headers in the request")
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the value of a parameter from the query string of the request path.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        query(&self.path, name)
+    }
+
+    /// Completes the handshake, returns the two halves of the connection so that messages can be
+    /// received on one thread and sent from another.
+    pub fn accept(self) -> Result<(Receiver, Sender)> {
+        let Self { stream, key, .. } = self;
+        let Some(key) = key else {
+            let mut stream = stream.into_inner();
+            write!(stream, "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+            anyhow::bail!("not a websocket upgrade request")
+        };
+        let mut writer = stream.get_ref().try_clone()?;
+        write!(
+            writer,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        )?;
+        let sender = Sender { stream: std::sync::Arc::new(std::sync::Mutex::new(writer)) };
+        Ok((Receiver { stream, sender: sender.clone() }, sender))
+    }
+
+    /// Refuses the connection with an http error, e.g. `503 Service Unavailable`.
+    pub fn reject(self, status: &str) -> Result<()> {
+        let mut stream = self.stream.into_inner();
+        write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// The sending half of a connection, this can be cloned and used from multiple threads.
+#[derive(Clone)]
+pub struct Sender {
+    stream: std::sync::Arc<std::sync::Mutex<std::net::TcpStream>>,
+}
+
+impl Sender {
+    fn send_frame(&self, opcode: u8, payload: &[u8]) -> Result<()> {
+        // Server frames are never masked.
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes())
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes())
+            }
+        }
+        frame.extend_from_slice(payload);
+        let mut stream = match self.stream.lock() {
+            Ok(stream) => stream,
+            Err(_) => anyhow::bail!("websocket sender poisoned"),
+        };
+        stream.write_all(&frame)?;
+        Ok(())
+    }
+
+    pub fn send_text(&self, text: &str) -> Result<()> {
+        self.send_frame(OP_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> Result<()> {
+        self.send_frame(OP_BINARY, data)
+    }
+
+    /// Starts the closing handshake, the reason is truncated to fit in a control frame.
+    pub fn close(&self, code: u16, reason: &str) -> Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        let mut len = reason.len().min(123);
+        while !reason.is_char_boundary(len) {
+            len -= 1
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..len]);
+        self.send_frame(OP_CLOSE, &payload)?;
+        // The sender and the receiver share the same socket.
+        self.stream
+            .lock()
+            .map_err(|_| anyhow::anyhow!("websocket sender poisoned"))?
+            .set_read_timeout(Some(CLOSE_TIMEOUT))?;
+        Ok(())
+    }
+}
+
+/// The receiving half of a connection, pings are answered through the associated sender.
+pub struct Receiver {
+    stream: std::io::BufReader<std::net::TcpStream>,
+    sender: Sender,
+}
+
+impl Receiver {
+    fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>)> {
+        let mut header = [0u8; 2];
+        self.stream.read_exact(&mut header)?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        if header[0] & 0x70 != 0 {
+            anyhow::bail!("unexpected reserved bits, no extension has been negotiated")
+        }
+        if header[1] & 0x80 == 0 {
+            anyhow::bail!("client frames must be masked")
+        }
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                self.stream.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as usize
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                self.stream.read_exact(&mut len)?;
+                usize::try_from(u64::from_be_bytes(len))?
+            }
+            len => len as usize,
+        };
+        if len > MAX_MESSAGE_LEN {
+            anyhow::bail!("frame of {len} bytes is too large")
+        }
+        let mut mask = [0u8; 4];
+        self.stream.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; len];
+        self.stream.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4]
+        }
+        Ok((fin, opcode, payload))
+    }
+
+    /// Returns the next data message, or None once the client has closed the connection.
+    pub fn recv(&mut self) -> Result<Option<Message>> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let (fin, opcode, payload) = match self.read_frame() {
+                Ok(frame) => frame,
+                Err(err) => match err.downcast_ref::<std::io::Error>() {
+                    // Some clients drop the connection without a closing handshake, and the read
+                    // timeout is only set once the server has started closing the connection.
+                    Some(e)
+                        if matches!(
+                            e.kind(),
+                            std::io::ErrorKind::UnexpectedEof
+                                | std::io::ErrorKind::WouldBlock
+                                | std::io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        return Ok(None)
+                    }
+                    _ => return Err(err),
+                },
+            };
+            match opcode {
+                OP_PING => self.sender.send_frame(OP_PONG, &payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    // Echo the status code to complete the closing handshake.
+                    let _ = self.sender.send_frame(OP_CLOSE, &payload[..payload.len().min(2)]);
+                    return Ok(None);
+                }
+                // Control frames can be interleaved with the fragments of a data message.
+                OP_TEXT | OP_BINARY if message.is_none() => message = Some((opcode, payload)),
+                OP_CONTINUATION if message.is_some() => {
+                    let (_, data) = message.as_mut().context("no message to continue")?;
+                    if data.len() + payload.len() > MAX_MESSAGE_LEN {
+                        anyhow::bail!("message is too large")
+                    }
+                    data.extend_from_slice(&payload)
+                }
+                opcode => anyhow::bail!("unexpected frame with opcode {opcode}"),
+            }
+            if fin && opcode & 0x8 == 0 {
+                match message.take() {
+                    Some((OP_TEXT, data)) => {
+                        return Ok(Some(Message::Text(String::from_utf8(data)?)))
+                    }
+                    Some((_, data)) => return Ok(Some(Message::Binary(data))),
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Connects a client to a local server and sends the http request.
+    fn connect(request: &str) -> (Handshake, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (Handshake::read(stream).unwrap(), client)
+    }
+
+    fn upgrade(path: &str) -> (Handshake, std::net::TcpStream) {
+        connect(&format!(
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        ))
+    }
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn read_response(client: &mut std::net::TcpStream) -> String {
+        let mut response = vec![];
+        let mut byte = [0u8];
+        while !response.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            response.push(byte[0])
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn base64_vectors() {
+        // From RFC 4648.
+        for (input, output) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foobar", "Zm9vYmFy")] {
+            assert_eq!(base64(input.as_bytes()), output)
+        }
+    }
+
+    #[test]
+    fn handshake() {
+        let (handshake, mut client) = upgrade("/listen?format=f32le&sample_rate=16000");
+        assert_eq!(handshake.path(), "/listen?format=f32le&sample_rate=16000");
+        assert_eq!(handshake.query("sample_rate"), Some("16000"));
+        assert_eq!(handshake.query("rate"), None);
+        handshake.accept().unwrap();
+        let response = read_response(&mut client);
+        assert!(response.starts_with("HTTP/1.1 101 "), "{response}");
+        // The accept key of the example in the RFC.
+        assert!(
+            response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn not_an_upgrade() {
+        let (handshake, mut client) = connect("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(handshake.accept().is_err());
+        assert!(read_response(&mut client).starts_with("HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn oversized_handshake() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let writer = {
+            let mut client = client.try_clone().unwrap();
+            let request = format!("GET /{} HTTP/1.1\r\n", "a".repeat(MAX_HANDSHAKE_BYTES as usize));
+            // The server stops reading at the limit, the rest of the request is not sent.
+            std::thread::spawn(move || {
+                let _ = client.write_all(request.as_bytes());
+            })
+        };
+        assert!(Handshake::read(stream).is_err());
+        assert!(read_response(&mut client).starts_with("HTTP/1.1 431 "));
+        drop(client);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn fragmented_messages() {
+        let (handshake, mut client) = upgrade("/");
+        let (mut receiver, _sender) = handshake.accept().unwrap();
+        read_response(&mut client);
+        let mut frames = client_frame(false, OP_TEXT, b"Hel");
+        // Control frames can come between the fragments.
+        frames.extend(client_frame(true, OP_PING, b"ping"));
+        frames.extend(client_frame(true, OP_CONTINUATION, b"lo"));
+        frames.extend(client_frame(true, OP_BINARY, &[1, 2, 3]));
+        frames.extend(client_frame(true, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes()));
+        client.write_all(&frames).unwrap();
+        assert_eq!(receiver.recv().unwrap(), Some(Message::Text("Hello".into())));
+        assert_eq!(receiver.recv().unwrap(), Some(Message::Binary(vec![1, 2, 3])));
+        assert_eq!(receiver.recv().unwrap(), None);
+        let mut pong = [0u8; 6];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x80 | OP_PONG, 4, b'p', b'i', b'n', b'g']);
+        let mut close = [0u8; 4];
+        client.read_exact(&mut close).unwrap();
+        assert_eq!(close, [0x80 | OP_CLOSE, 2, 0x03, 0xe8]);
+    }
+
+    #[test]
+    fn unmasked_frames() {
+        let (handshake, mut client) = upgrade("/");
+        let (mut receiver, _sender) = handshake.accept().unwrap();
+        client.write_all(&[0x80 | OP_TEXT, 2, b'h', b'i']).unwrap();
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn server_frames() {
+        let (handshake, mut client) = upgrade("/");
+        let (receiver, sender) = handshake.accept().unwrap();
+        read_response(&mut client);
+        sender.send_text("hi").unwrap();
+        sender.send_binary(&[7; 300]).unwrap();
+        sender.close(CLOSE_POLICY_VIOLATION, "too slow").unwrap();
+        drop((receiver, sender));
+        let mut frames = vec![];
+        client.read_to_end(&mut frames).unwrap();
+        let (text, rest) = frames.split_at(4);
+        assert_eq!(text, [0x80 | OP_TEXT, 2, b'h', b'i']);
+        // Payloads above 125 bytes have an extended length.
+        let (binary, close) = rest.split_at(304);
+        assert_eq!(binary[..4], [0x80 | OP_BINARY, 126, 1, 44]);
+        assert!(binary[4..].iter().all(|&v| v == 7));
+        assert_eq!(close[..4], [0x80 | OP_CLOSE, 10, 0x03, 0xf0]);
+        assert_eq!(&close[4..], b"too slow");
+    }
+}
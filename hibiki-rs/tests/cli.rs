@@ -0,0 +1,65 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+mod common;
+
+fn hibiki(args: &[&str]) -> std::process::Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_hibiki")).args(args).output().unwrap()
+}
+
+#[test]
+fn quiet_dry_run_is_a_usage_error() {
+    let output = hibiki(&["gen", "--quiet", "--dry-run", "in.wav", "out.wav"]);
+    assert_eq!(output.status.code(), Some(2));
+    let line: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(line["status"], "error", "{line}");
+}
+
+#[test]
+fn batch_skips_duplicate_inputs() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("batch-dup-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let (input_dir, output_dir) = (dir.join("in"), dir.join("out"));
+    std::fs::create_dir_all(&input_dir).unwrap();
+    let pcm = common::noise(24000);
+    for name in ["a.wav", "b.wav"] {
+        let mut writer = std::fs::File::create(input_dir.join(name)).unwrap();
+        hibiki::audio_io::write_wav(&mut writer, &pcm, 24000, hibiki::audio_io::WavFormat::S16)
+            .unwrap();
+    }
+    let (args, _) = common::tiny_args(&[]);
+    let path = |v: &std::path::Path| v.to_string_lossy().into_owned();
+    let config = path(&args.lm_model_file.with_file_name("config.toml"));
+    let (lm, mimi, tokenizer) =
+        (path(&args.lm_model_file), path(&args.mimi_model_file), path(&args.text_tokenizer));
+    let (input_dir, output_dir_str) = (path(&input_dir), path(&output_dir));
+    let output = hibiki(&[
+        "batch",
+        "--quiet",
+        "--cpu",
+        "--no-calibrate",
+        "--config",
+        &config,
+        "--lm-model-file",
+        &lm,
+        "--mimi-model-file",
+        &mimi,
+        "--text-tokenizer",
+        &tokenizer,
+        &input_dir,
+        &output_dir_str,
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let report = std::fs::read(output_dir.join("report.json")).unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+    let entries = report["entries"].as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0]["steps"].as_u64().unwrap() > 0, "{report}");
+    assert!(entries[0]["duplicate_of"].is_null(), "{report}");
+    assert_eq!(entries[1]["ok"], true, "{report}");
+    assert_eq!(entries[1]["steps"], 0, "{report}");
+    assert_eq!(entries[1]["duplicate_of"], path(&output_dir.join("a.wav")), "{report}");
+    assert!(output_dir.join("a.wav").exists());
+    assert!(!output_dir.join("b.wav").exists());
+}
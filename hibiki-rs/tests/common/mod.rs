@@ -0,0 +1,72 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+// Helpers shared by the integration tests, these run on a random tiny model so that the whole
+// pipeline can be exercised on cpu without downloading any weights.
+
+use clap::Parser;
+
+#[derive(Parser)]
+struct Cli {
+    #[command(flatten)]
+    gen: hibiki::cli::GenArgs,
+}
+
+/// Writes the tiny model once per test binary and returns its directory.
+fn tiny_model_dir() -> &'static std::path::Path {
+    static DIR: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("tiny");
+        hibiki::tiny::write(&dir, 299_792_458).unwrap();
+        dir
+    })
+}
+
+/// The args of `gen` for the tiny model, with the extra `flags`.
+pub fn tiny_args(flags: &[&str]) -> (hibiki::gen::Args, candle::Device) {
+    tiny_gen_args("-", "-", flags)
+}
+
+/// The args of `gen` for the tiny model translating `input` to `output`.
+pub fn tiny_gen_args(
+    input: &str,
+    output: &str,
+    flags: &[&str],
+) -> (hibiki::gen::Args, candle::Device) {
+    let dir = tiny_model_dir();
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let (config, lm, mimi, tokenizer) = (
+        path("config.toml"),
+        path("tiny-lm.safetensors"),
+        path("tiny-mimi.safetensors"),
+        path("tiny-tokenizer.model"),
+    );
+    let mut args = vec!["hibiki", "--cpu", "--no-calibrate", "--config", &config];
+    args.extend([
+        "--lm-model-file",
+        &lm,
+        "--mimi-model-file",
+        &mimi,
+        "--text-tokenizer",
+        &tokenizer,
+    ]);
+    args.extend(flags);
+    let cli = Cli::try_parse_from(args).unwrap();
+    cli.gen.resolve(input.to_string(), output.to_string()).unwrap()
+}
+
+/// Deterministic noise, the tiny model translates anything.
+// Not all the test binaries translate audio.
+#[allow(dead_code)]
+pub fn noise(len: usize) -> Vec<f32> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 24) as f32 * 0.2 - 0.1
+        })
+        .collect()
+}
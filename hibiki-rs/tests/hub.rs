@@ -0,0 +1,30 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+mod common;
+
+// A repo that is not in the hub cache, nothing is downloaded in cache only mode.
+const MISSING_REPO: &str = "kyutai/hibiki-test-missing-repo";
+
+#[test]
+fn overrides_take_precedence() {
+    let (args, _) = common::tiny_args(&[]);
+    let repo = hibiki::hub::Repo::new(MISSING_REPO, true).unwrap();
+    let overrides = hibiki::hub::Overrides {
+        config_file: Some(args.config_file.clone()),
+        lm_model_file: Some(args.lm_model_file.clone()),
+        mimi_model_file: Some(args.mimi_model_file.clone()),
+        text_tokenizer: Some(args.text_tokenizer.clone()),
+    };
+    let files = hibiki::hub::resolve(&repo, overrides.clone()).unwrap();
+    assert_eq!(files.config_file, args.config_file);
+    assert_eq!(files.lm_model_file, args.lm_model_file);
+    assert_eq!(files.mimi_model_file, args.mimi_model_file);
+    assert_eq!(files.text_tokenizer, args.text_tokenizer);
+    assert_eq!(files.config.model.audio_codebooks, args.lm_config.audio_codebooks);
+    // The files that are not overridden are looked up in the cache only.
+    let overrides = hibiki::hub::Overrides { lm_model_file: None, ..overrides };
+    let err = hibiki::hub::resolve(&repo, overrides).err().unwrap();
+    assert!(format!("{err:#}").contains("not in the hub cache"), "{err:#}");
+}
@@ -0,0 +1,69 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+mod common;
+
+#[test]
+fn settings_change_the_key() {
+    let (args, _) = common::tiny_args(&[]);
+    let key = hibiki::gen::settings_key(&args);
+    let flags: &[&[&str]] = &[
+        &["--pad-bias", "1"],
+        &["--fit-duration"],
+        &["--max-stretch", "0.3"],
+        &["--pitch-shift", "2"],
+        &["--formant-shift", "2"],
+        &["--min-text-confidence", "0.5"],
+        &["--skip-silent-depformer"],
+        &["--bit-depth", "24"],
+        &["--max-steps", "1000"],
+        &["--keep-text"],
+        &["--mark-events"],
+        &["--target-language", "fr"],
+    ];
+    for flags in flags {
+        let (args, _) = common::tiny_args(flags);
+        assert_ne!(hibiki::gen::settings_key(&args), key, "{flags:?}")
+    }
+    // The output paths do not change what gets generated.
+    let (args, _) = common::tiny_gen_args("in.wav", "out.wav", &[]);
+    assert_eq!(hibiki::gen::settings_key(&args), key);
+}
+
+#[test]
+fn settings_change_misses_the_memory() {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("memory-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in.wav").to_string_lossy().into_owned();
+    let output = dir.join("out.wav").to_string_lossy().into_owned();
+    let memory = dir.join("memory").to_string_lossy().into_owned();
+    let mut writer = std::fs::File::create(&input).unwrap();
+    let pcm = common::noise(24000);
+    hibiki::audio_io::write_wav(&mut writer, &pcm, 24000, hibiki::audio_io::WavFormat::S16)
+        .unwrap();
+    let run = |flags: &[&str]| {
+        let flags = [&["--translation-memory", memory.as_str(), "--quiet"], flags].concat();
+        let (args, dev) = common::tiny_gen_args(&input, &output, &flags);
+        hibiki::gen::run(&args, &dev).unwrap().steps
+    };
+    assert!(run(&[]) > 0);
+    // Hits do not run any step.
+    assert_eq!(run(&[]), 0);
+    // The entry has no subtitles yet, they are generated and stored with the entry.
+    let outputs = [dir.join("out.wav"), dir.join("out.txt"), dir.join("out.vtt")];
+    let [_, transcript, subtitles] = outputs.clone().map(|v| v.to_string_lossy().into_owned());
+    let sidecars = ["--transcript-file", &transcript, "--subtitles", &subtitles];
+    assert!(run(&sidecars) > 0);
+    let generated = outputs.clone().map(|path| std::fs::read(path).unwrap());
+    for path in outputs.iter() {
+        std::fs::remove_file(path).unwrap()
+    }
+    // The hit restores the same outputs.
+    assert_eq!(run(&sidecars), 0);
+    assert_eq!(outputs.map(|path| std::fs::read(path).unwrap()), generated);
+    assert!(run(&["--pitch-shift", "2"]) > 0);
+    assert!(run(&["--bit-depth", "24"]) > 0);
+    assert_eq!(run(&["--pitch-shift", "2"]), 0);
+}
@@ -0,0 +1,75 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+mod common;
+
+use hibiki::session::{GenSession, StepOutput};
+
+// Pushes the pcm in chunks of `chunk_len` samples and runs the steps as the frames complete.
+fn translate(session: &mut GenSession, pcm: &[f32], chunk_len: usize) -> Vec<StepOutput> {
+    let mut outputs = vec![];
+    for chunk in pcm.chunks(chunk_len) {
+        session.push_pcm(chunk);
+        while session.pending_frames() > 0 {
+            outputs.extend(session.step().unwrap())
+        }
+    }
+    outputs
+}
+
+fn tokens(outputs: &[StepOutput]) -> Vec<u32> {
+    outputs.iter().map(|v| v.text_token).collect()
+}
+
+#[test]
+fn push_and_step() {
+    let (args, dev) = common::tiny_args(&["--frames-per-batch", "2"]);
+    let mut models = hibiki::gen::Models::load(&args, &dev).unwrap();
+    let frame_size = models.codec.frame_size();
+    let pcm = common::noise(10 * frame_size + 100);
+    let mut session = GenSession::new(&args, &mut models, 0, &dev).unwrap();
+    assert!(session.step().unwrap().is_empty());
+    let outputs = translate(&mut session, &pcm, 1234);
+    assert_eq!(outputs.len(), 10);
+    for (idx, output) in outputs.iter().enumerate() {
+        assert_eq!(output.step_idx, idx);
+        assert_eq!(output.text.is_none(), output.is_pad());
+        if let Some(pcm) = output.pcm.as_ref() {
+            assert_eq!(pcm.elem_count(), frame_size)
+        }
+    }
+    // The audio only starts once the acoustic delay has elapsed.
+    assert!(outputs[0].pcm.is_none());
+    assert!(outputs.last().unwrap().pcm.is_some());
+    assert_eq!(session.state().step_idx(), 10);
+    assert_eq!(session.pending_frames(), 0);
+    assert_eq!(session.take_pending().len(), 100);
+}
+
+#[test]
+fn chunking_does_not_matter() {
+    let (args, dev) = common::tiny_args(&[]);
+    let mut models = hibiki::gen::Models::load(&args, &dev).unwrap();
+    let frame_size = models.codec.frame_size();
+    let pcm = common::noise(8 * frame_size);
+    let mut session = GenSession::new(&args, &mut models, 0, &dev).unwrap();
+    let whole = tokens(&translate(&mut session, &pcm, pcm.len()));
+    let mut session = GenSession::new(&args, &mut models, 0, &dev).unwrap();
+    let chunked = tokens(&translate(&mut session, &pcm, 320));
+    assert_eq!(whole, chunked);
+}
+
+#[test]
+fn end_text() {
+    let (args, dev) = common::tiny_args(&[]);
+    let mut models = hibiki::gen::Models::load(&args, &dev).unwrap();
+    let frame_size = models.codec.frame_size();
+    let pcm = common::noise(4 * frame_size);
+    let mut session = GenSession::new(&args, &mut models, 0, &dev).unwrap();
+    translate(&mut session, &pcm[..2 * frame_size], frame_size);
+    session.end_text();
+    let outputs = translate(&mut session, &pcm[2 * frame_size..], frame_size);
+    assert!(outputs.iter().all(|v| v.is_pad() && v.text.is_none()));
+    assert_eq!(session.into_state().step_idx(), 4);
+}
@@ -0,0 +1,50 @@
+// Copyright (c) Kyutai, all rights reserved.
+// This source code is licensed under the license found in the
+// LICENSE file in the root directory of this source tree.
+
+mod common;
+
+use futures::StreamExt;
+use hibiki::stream::{AsyncSession, Event};
+
+#[test]
+fn stream_events() {
+    let (args, dev) = common::tiny_args(&[]);
+    let models = hibiki::gen::Models::load(&args, &dev).unwrap();
+    let frame_size = models.codec.frame_size();
+    let (session, events) = AsyncSession::spawn(args, models, &dev);
+    let pcm = common::noise(20 * frame_size);
+    let producer = async move {
+        for chunk in pcm.chunks(1000) {
+            session.push_pcm(chunk.to_vec()).await.unwrap()
+        }
+        session.end().await.unwrap()
+    };
+    let consumer = events.fold(0, |audio_len, event| async move {
+        match event {
+            Event::Text(_) => audio_len,
+            Event::Audio(pcm) => audio_len + pcm.len(),
+            Event::Error(err) => panic!("{err:#}"),
+        }
+    });
+    let ((), audio_len) = futures::executor::block_on(futures::future::join(producer, consumer));
+    // The tail is translated too once the source has ended.
+    assert!(audio_len >= 20 * frame_size, "{audio_len}");
+    assert_eq!(audio_len % frame_size, 0);
+}
+
+#[test]
+fn stream_stops_when_dropped() {
+    let (args, dev) = common::tiny_args(&[]);
+    let models = hibiki::gen::Models::load(&args, &dev).unwrap();
+    let (session, events) = AsyncSession::spawn(args, models, &dev);
+    drop(events);
+    let pcm = common::noise(session.sample_rate());
+    let res = futures::executor::block_on(async {
+        for _ in 0..hibiki::stream::CHANNEL_LEN + 10 {
+            session.push_pcm(pcm.clone()).await?
+        }
+        anyhow::Ok(())
+    });
+    assert!(res.is_err());
+}